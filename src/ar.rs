@@ -0,0 +1,242 @@
+#[cfg(feature = "sha512")]
+use crate::hash::{FileHasher, HashOptions};
+use std::io::{Read, Write};
+
+use crate::tar::TarStats;
+
+/// magic at the start of every ar archive
+const AR_MAGIC: &[u8; 8] = b"!<arch>\n";
+
+/// the largest size which fits into the 10 decimal digits of the size field
+const AR_MAX_SIZE: u64 = 9_999_999_999;
+
+fn invalid_name(name: &[u8], reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("{:?} {}", String::from_utf8_lossy(name), reason),
+    )
+}
+
+/// Writes deterministic ar archives in the GNU format, e.g. for .deb packages.
+///
+/// ar archives are flat: the main directory is not stored and the members are the files inside
+/// of it, a subdirectory is an error. Names are terminated with a slash like GNU ar does, so they
+/// can have at most 15 bytes because the table for longer names would have to be written before
+/// all members. The modification time, owner and group are 0 and the mode is 100644.
+///
+/// The statistics use the fields of [`TarStats`], the magic counts as header bytes.
+pub struct ArOutput<W: Write> {
+    out_ar: W,
+    pub stats: TarStats,
+}
+
+impl<W: Write> ArOutput<W> {
+    pub fn new(out_ar: W) -> ArOutput<W> {
+        ArOutput {
+            out_ar,
+            stats: TarStats::default(),
+        }
+    }
+
+    /// writes the magic before the first member
+    fn _write_magic(&mut self) -> Result<(), std::io::Error> {
+        if self.stats.header_bytes == 0 {
+            self.out_ar.write_all(AR_MAGIC)?;
+            self.stats.header_bytes += AR_MAGIC.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// writes the 60 byte member header
+    fn _write_header(&mut self, name: &[u8], size: u64) -> Result<(), std::io::Error> {
+        self._write_magic()?;
+        if name.len() > 15 {
+            return Err(invalid_name(name, "is longer than 15 bytes"));
+        }
+        if size > AR_MAX_SIZE {
+            return Err(invalid_name(name, "is too large for an ar archive"));
+        }
+        let mut header = Vec::with_capacity(60);
+        header.extend_from_slice(name);
+        header.push(b'/');
+        header.resize(16, b' ');
+        header.extend_from_slice(format!("{:<12}", 0).as_bytes()); // modification time
+        header.extend_from_slice(format!("{:<6}", 0).as_bytes()); // owner
+        header.extend_from_slice(format!("{:<6}", 0).as_bytes()); // group
+        header.extend_from_slice(format!("{:<8}", "100644").as_bytes()); // mode (octal)
+        header.extend_from_slice(format!("{:<10}", size).as_bytes());
+        header.extend_from_slice(b"`\n");
+        self.stats.header_bytes += header.len() as u64;
+        self.out_ar.write_all(&header)
+    }
+
+    /// checks that the directory is the main directory, nothing is written
+    pub fn ar_write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error> {
+        let name = name.strip_suffix(b"/").unwrap_or(name);
+        if name.contains(&b'/') {
+            return Err(invalid_name(
+                name,
+                "is a subdirectory, ar archives cannot contain directories",
+            ));
+        }
+        Ok(())
+    }
+
+    /// writes a file, the name is the path inside of the main directory
+    pub fn ar_write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut impl Write, HashOptions)>,
+        in_filedescriptor: &mut impl Read,
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let member = match name.iter().position(|b| *b == b'/') {
+            Some(slash) => &name[slash + 1..],
+            None => name,
+        };
+        if member.contains(&b'/') {
+            return Err(invalid_name(
+                name,
+                "is inside a subdirectory, ar archives cannot contain directories",
+            ));
+        }
+        self._write_header(member, *size)?;
+        self.stats.files += 1;
+
+        let mut already_read = 0u64;
+        let mut buffer = [0u8; 64 * 1024];
+        #[cfg(feature = "sha512")]
+        let mut hasher = out_hash
+            .as_ref()
+            .map(|(_, options)| FileHasher::new(options.algorithm));
+        loop {
+            let n = in_filedescriptor.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            };
+            already_read += n as u64;
+            self.out_ar.write_all(&buffer[0..n])?;
+            #[cfg(feature = "sha512")]
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[0..n]);
+            };
+        }
+        if already_read != *size {
+            panic!("size while reading different from stat");
+        }
+        // members start at even offsets, the padding is a newline
+        let padding = (already_read % 2) as usize;
+        self.out_ar.write_all(&b"\n"[..padding])?;
+        self.stats.content_bytes += already_read;
+        self.stats.padding_bytes += padding as u64;
+        #[cfg(feature = "sha512")]
+        if let (Some((out_hash, options)), Some(hasher)) = (out_hash.as_mut(), hasher) {
+            crate::hash::write_hash_line(out_hash, *options, hasher, name)?;
+        }
+        Ok(())
+    }
+
+    /// ar has no end marker, this only writes the magic if the archive is empty
+    pub fn ar_end_marker(&mut self) -> Result<(), std::io::Error> {
+        self._write_magic()
+    }
+
+    /// returns the underlying writer, usually after the end marker was written
+    pub fn into_inner(self) -> W {
+        self.out_ar
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn write_file(out: &mut ArOutput<Vec<u8>>, content: &[u8], name: &[u8]) {
+        out.ar_write_file(
+            #[cfg(feature = "sha512")]
+            None::<(&mut Vec<u8>, HashOptions)>,
+            &mut Cursor::new(content),
+            &(content.len() as u64),
+            name,
+        )
+        .unwrap();
+    }
+
+    /// returns the name, mode and content of each member
+    fn parse(ar: &[u8]) -> Vec<(Vec<u8>, String, Vec<u8>)> {
+        assert_eq!(&ar[..8], AR_MAGIC);
+        let mut members = Vec::new();
+        let mut offset = 8;
+        while offset < ar.len() {
+            let header = &ar[offset..offset + 60];
+            assert_eq!(&header[58..60], b"`\n");
+            let field = |range: std::ops::Range<usize>| {
+                String::from_utf8(header[range].to_vec())
+                    .unwrap()
+                    .trim_end()
+                    .to_string()
+            };
+            // deterministic like "ar D": no time, owner and group
+            assert_eq!(field(16..28), "0");
+            assert_eq!(field(28..34), "0");
+            assert_eq!(field(34..40), "0");
+            let name = field(0..16).strip_suffix('/').unwrap().as_bytes().to_vec();
+            let size: usize = field(48..58).parse().unwrap();
+            let content = ar[offset + 60..offset + 60 + size].to_vec();
+            members.push((name, field(40..48), content));
+            offset += 60 + size + size % 2;
+        }
+        assert_eq!(offset, ar.len());
+        members
+    }
+
+    #[test]
+    fn members_are_read_back() {
+        let mut out = ArOutput::new(Vec::new());
+        out.ar_write_dir(b"pkg/").unwrap();
+        write_file(&mut out, b"2.0\n", b"pkg/debian-binary");
+        write_file(&mut out, b"odd", b"pkg/control.tar");
+        write_file(&mut out, b"", b"pkg/data.tar");
+        out.ar_end_marker().unwrap();
+        let ar = out.into_inner();
+        let members = parse(&ar);
+        let mode = "100644".to_string();
+        assert_eq!(
+            members,
+            [
+                (b"debian-binary".to_vec(), mode.clone(), b"2.0\n".to_vec()),
+                (b"control.tar".to_vec(), mode.clone(), b"odd".to_vec()),
+                (b"data.tar".to_vec(), mode, vec![]),
+            ]
+        );
+        // the odd member is padded with a newline
+        assert_eq!(ar.len(), 8 + 3 * 60 + 4 + 4);
+    }
+
+    #[test]
+    fn empty_archive_is_only_the_magic() {
+        let mut out = ArOutput::new(Vec::new());
+        out.ar_write_dir(b"pkg/").unwrap();
+        out.ar_end_marker().unwrap();
+        assert_eq!(out.into_inner(), AR_MAGIC);
+    }
+
+    #[test]
+    fn directories_and_long_names_are_errors() {
+        let mut out = ArOutput::new(Vec::new());
+        assert!(out.ar_write_dir(b"pkg/sub/").is_err());
+        let mut content = Cursor::new(b"");
+        for name in [&b"pkg/sub/file"[..], b"pkg/sixteen-bytes-xy"] {
+            assert!(out
+                .ar_write_file(
+                    #[cfg(feature = "sha512")]
+                    None::<(&mut Vec<u8>, HashOptions)>,
+                    &mut content,
+                    &0,
+                    name,
+                )
+                .is_err());
+        }
+    }
+}
@@ -0,0 +1,716 @@
+use std::ffi::OsStr;
+use std::io::{Read, Seek, Write};
+use std::os::unix::ffi::OsStrExt;
+
+use crate::ar::ArOutput;
+#[cfg(any(not(feature = "zip"), not(feature = "squashfs")))]
+use crate::compress::disabled;
+use crate::cpio::CpioOutput;
+#[cfg(feature = "sha512")]
+use crate::hash::HashOptions;
+#[cfg(feature = "squashfs")]
+use crate::squashfs::SquashfsOutput;
+use crate::tar::{TarFormat, TarOutput, TarStats};
+#[cfg(feature = "zip")]
+use crate::zip::ZipOutput;
+
+/// The container format of the archive: one of the tar flavours, cpio, zip, ar or squashfs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar(TarFormat),
+    /// cpio "new ASCII" format, e.g. for the Linux initramfs
+    CpioNewc,
+    /// zip with the given compression method for files
+    Zip(ZipMethod),
+    /// GNU ar, e.g. for .deb packages
+    Ar,
+    /// squashfs 4.0 image, e.g. for firmware root filesystems
+    Squashfs,
+}
+
+/// deflate compression level of zip entries, like the default of zip and jar
+pub const ZIP_DEFLATE_LEVEL: u32 = 6;
+
+/// block size of squashfs images, like the default of mksquashfs
+pub const SQUASHFS_BLOCK_SIZE: u32 = 128 * 1024;
+/// zlib compression level of squashfs images, like the default of mksquashfs
+pub const SQUASHFS_GZIP_LEVEL: u32 = 9;
+
+/// describes all settings which influence the bytes of squashfs images, like
+/// [`crate::compress::Compression::parameters`]
+pub fn squashfs_parameters() -> String {
+    format!(
+        "compressor=gzip level={} window=15 block_size={} fragments=yes",
+        SQUASHFS_GZIP_LEVEL, SQUASHFS_BLOCK_SIZE
+    )
+}
+
+/// How files are stored in a zip archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZipMethod {
+    Store,
+    /// deflate with level 6
+    Deflate,
+}
+
+impl ZipMethod {
+    /// describes all settings which influence the stored bytes, like
+    /// [`crate::compress::Compression::parameters`]
+    pub fn parameters(&self) -> String {
+        match self {
+            ZipMethod::Store => "method=store".to_string(),
+            ZipMethod::Deflate => format!("method=deflate level={}", ZIP_DEFLATE_LEVEL),
+        }
+    }
+}
+
+impl std::str::FromStr for ZipMethod {
+    type Err = String;
+    fn from_str(s: &str) -> Result<ZipMethod, String> {
+        match s {
+            "store" => Ok(ZipMethod::Store),
+            "deflate" => Ok(ZipMethod::Deflate),
+            _ => Err(format!(
+                "unknown zip method {:?}, expected store or deflate",
+                s
+            )),
+        }
+    }
+}
+
+/// How the names of entries are encoded in the archive headers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameEncoding {
+    /// UTF-8, names which are not valid UTF-8 are rejected
+    Utf8,
+    /// ISO 8859-1 for legacy systems: UTF-8 names are transcoded and rejected if they contain
+    /// other characters, names which are not valid UTF-8 are taken as latin-1 already
+    Latin1,
+    /// the bytes of the file system without any check or transcoding
+    Raw,
+}
+
+impl NameEncoding {
+    /// returns the bytes of name in this encoding
+    pub fn encode(&self, name: &OsStr) -> Result<Vec<u8>, std::io::Error> {
+        let invalid = |reason: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("name {:?} {}", name, reason),
+            )
+        };
+        match (self, name.to_str()) {
+            (NameEncoding::Utf8, Some(name)) => Ok(name.as_bytes().to_vec()),
+            (NameEncoding::Utf8, None) => Err(invalid("is not valid UTF-8")),
+            (NameEncoding::Latin1, Some(name)) => name
+                .chars()
+                .map(|c| u8::try_from(c).map_err(|_| invalid("cannot be encoded in latin-1")))
+                .collect(),
+            (NameEncoding::Latin1, None) | (NameEncoding::Raw, _) => Ok(name.as_bytes().to_vec()),
+        }
+    }
+}
+
+impl std::str::FromStr for NameEncoding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<NameEncoding, String> {
+        match s {
+            "utf8" => Ok(NameEncoding::Utf8),
+            "latin1" => Ok(NameEncoding::Latin1),
+            "raw" => Ok(NameEncoding::Raw),
+            _ => Err(format!(
+                "unknown name encoding {:?}, expected utf8, latin1 or raw",
+                s
+            )),
+        }
+    }
+}
+
+/// How files with more than one name in the input (hardlinks) are archived.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HardlinkPolicy {
+    /// store the content again for each name
+    #[default]
+    Copy,
+    /// store the names after the first one as hardlink entries to it, only supported by tar
+    /// formats
+    Keep,
+}
+
+impl std::str::FromStr for HardlinkPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<HardlinkPolicy, String> {
+        match s {
+            "copy" => Ok(HardlinkPolicy::Copy),
+            "keep" => Ok(HardlinkPolicy::Keep),
+            _ => Err(format!(
+                "unknown hardlink policy {:?}, expected copy or keep",
+                s
+            )),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    /// returns true if names in the given encoding can be stored, pax headers are always UTF-8
+    pub fn supports_name_encoding(&self, encoding: NameEncoding) -> bool {
+        encoding == NameEncoding::Utf8 || *self != ArchiveFormat::Tar(TarFormat::Pax)
+    }
+
+    /// returns true if sparse files can be stored as such
+    pub fn supports_sparse(&self) -> bool {
+        match self {
+            ArchiveFormat::Tar(format) => format.header_format().sparse().is_some(),
+            ArchiveFormat::CpioNewc
+            | ArchiveFormat::Zip(_)
+            | ArchiveFormat::Ar
+            | ArchiveFormat::Squashfs => false,
+        }
+    }
+
+    /// returns true if a pax global header can be written
+    pub fn supports_pax_global(&self) -> bool {
+        !matches!(
+            self,
+            ArchiveFormat::Tar(TarFormat::V7)
+                | ArchiveFormat::CpioNewc
+                | ArchiveFormat::Zip(_)
+                | ArchiveFormat::Ar
+                | ArchiveFormat::Squashfs
+        )
+    }
+}
+
+impl std::str::FromStr for ArchiveFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<ArchiveFormat, String> {
+        match s {
+            "cpio-newc" => Ok(ArchiveFormat::CpioNewc),
+            "zip" => Ok(ArchiveFormat::Zip(ZipMethod::Deflate)),
+            "ar" => Ok(ArchiveFormat::Ar),
+            "squashfs" => Ok(ArchiveFormat::Squashfs),
+            _ => s.parse().map(ArchiveFormat::Tar).map_err(|_| {
+                format!(
+                    "unknown format {:?}, expected gnu, pax, ustar, ustar-strict, v7, cpio-newc, zip, ar or squashfs",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+fn unsupported(what: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("the archive format does not support {}", what),
+    )
+}
+
+/// A reader which can also seek, e.g. a file, see [`ArchiveWriter::write_file`].
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Writes the entries of a deterministic archive in one format. The supported
+/// [`ArchiveFormat`]s are created by [`with_format`], other backends or test doubles can
+/// implement it, too. Only directories and files are required, the other kinds of entries fail
+/// unless the format supports them.
+pub trait ArchiveWriter {
+    /// the writer which receives the archive, see [`ArchiveWriter::into_inner`]
+    type Inner;
+
+    fn stats(&self) -> &TarStats;
+
+    /// writes a directory, the name ends with a slash. ar only checks that it is the main
+    /// directory
+    fn write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error>;
+
+    /// writes a file, zip may read it twice
+    fn write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] out_hash: Option<(&mut dyn Write, HashOptions)>,
+        in_filedescriptor: &mut dyn ReadSeek,
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error>;
+
+    /// writes the end of the archive, e.g. the central directory of zip or the complete squashfs
+    /// image. eof_blocks and blocking_factor are only used by tar, see
+    /// [`TarOutput::tar_end_marker_padded`]
+    fn finish(&mut self, eof_blocks: u64, blocking_factor: u64) -> Result<(), std::io::Error>;
+
+    /// returns the underlying writer, usually after the archive was finished
+    fn into_inner(self: Box<Self>) -> Self::Inner;
+
+    /// sets the encoding of the names which are given to the write functions, the default is
+    /// UTF-8. Only zip stores it, by marking UTF-8 names as such. See
+    /// [`ArchiveFormat::supports_name_encoding`].
+    fn set_name_encoding(&mut self, _encoding: NameEncoding) {}
+
+    /// stores the next file uncompressed, only supported by zip, see [`ZipOutput::zip_store_next`]
+    fn store_next_file(&mut self) -> Result<(), std::io::Error> {
+        Err(unsupported("uncompressed entries"))
+    }
+
+    /// writes a pax global header, only supported by tar formats
+    fn write_pax_global(&mut self, _records: &[(String, String)]) -> Result<(), std::io::Error> {
+        Err(unsupported("pax global headers"))
+    }
+
+    /// adds a record to the pax extended header of the next file or directory, only supported by
+    /// the pax format, see [`TarOutput::tar_add_pax_record`]
+    fn add_pax_record(&mut self, _key: &str, _value: &[u8]) -> Result<(), std::io::Error> {
+        Err(unsupported("pax records"))
+    }
+
+    /// writes a directory with the given permissions (at most 0o7777, see [`check_dir_mode`])
+    /// instead of 0755 and, if given, a modification time in seconds since the epoch, e.g. for
+    /// the main directory. Only supported by tar, cpio and squashfs.
+    fn write_dir_with_metadata(
+        &mut self,
+        _name: &[u8],
+        _mode: u32,
+        _mtime: Option<u64>,
+    ) -> Result<(), std::io::Error> {
+        Err(unsupported("the mode and modification time of directories"))
+    }
+
+    /// writes a hardlink to a file which was written before, only supported by tar formats
+    fn write_hardlink(&mut self, _name: &[u8], _target: &[u8]) -> Result<(), std::io::Error> {
+        Err(unsupported("hardlinks"))
+    }
+
+    /// writes a character or block device or a fifo with the tar typeflag and the major and minor
+    /// device number, only supported by tar formats
+    fn write_special(
+        &mut self,
+        _name: &[u8],
+        _typeflag: u8,
+        _device: (u32, u32),
+    ) -> Result<(), std::io::Error> {
+        Err(unsupported("special files"))
+    }
+
+    /// writes a symlink with the target as it is, only supported by tar formats
+    fn write_symlink(&mut self, _name: &[u8], _target: &[u8]) -> Result<(), std::io::Error> {
+        Err(unsupported("symlinks"))
+    }
+
+    /// writes a file with holes, only supported by tar formats with sparse support
+    fn write_sparse_file(
+        &mut self,
+        #[cfg(feature = "sha512")] _out_hash: Option<(&mut dyn Write, HashOptions)>,
+        _in_filedescriptor: &mut dyn ReadSeek,
+        _map: &[(u64, u64)],
+        _size: &u64,
+        _name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        Err(unsupported("sparse files"))
+    }
+}
+
+/// fails if the permissions of [`ArchiveWriter::write_dir_with_metadata`] are more than 0o7777
+pub fn check_dir_mode(mode: u32) -> Result<(), std::io::Error> {
+    if mode > 0o7777 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid directory mode {:o}", mode),
+        ));
+    }
+    Ok(())
+}
+
+/// creates the writer of a deterministic archive in any of the supported [`ArchiveFormat`]s,
+/// fails if the format was disabled at compile time
+pub fn with_format<'a, W: Write + 'a>(
+    out: W,
+    format: ArchiveFormat,
+) -> Result<Box<dyn ArchiveWriter<Inner = W> + 'a>, std::io::Error> {
+    match format {
+        ArchiveFormat::Tar(format) => Ok(Box::new(TarOutput::with_format(out, format))),
+        ArchiveFormat::CpioNewc => Ok(Box::new(CpioOutput::new(out))),
+        #[cfg(feature = "zip")]
+        ArchiveFormat::Zip(method) => Ok(Box::new(ZipOutput::new(out, method))),
+        #[cfg(not(feature = "zip"))]
+        ArchiveFormat::Zip(_) => Err(disabled("zip output", "zip")),
+        ArchiveFormat::Ar => Ok(Box::new(ArOutput::new(out))),
+        #[cfg(feature = "squashfs")]
+        ArchiveFormat::Squashfs => Ok(Box::new(SquashfsOutput::new(out)?)),
+        #[cfg(not(feature = "squashfs"))]
+        ArchiveFormat::Squashfs => Err(disabled("squashfs output", "squashfs")),
+    }
+}
+
+impl<W: Write> ArchiveWriter for TarOutput<W> {
+    type Inner = W;
+
+    fn stats(&self) -> &TarStats {
+        &self.stats
+    }
+
+    fn write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error> {
+        self.tar_write_dir(name)
+    }
+
+    fn write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut dyn Write, HashOptions)>,
+        mut in_filedescriptor: &mut dyn ReadSeek,
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self.tar_write_file(
+            #[cfg(feature = "sha512")]
+            out_hash.as_mut().map(|(out, options)| (out, *options)),
+            &mut in_filedescriptor,
+            size,
+            name,
+        )
+    }
+
+    fn finish(&mut self, eof_blocks: u64, blocking_factor: u64) -> Result<(), std::io::Error> {
+        self.tar_end_marker_padded(eof_blocks, blocking_factor)
+    }
+
+    fn into_inner(self: Box<Self>) -> W {
+        TarOutput::into_inner(*self)
+    }
+
+    fn write_pax_global(&mut self, records: &[(String, String)]) -> Result<(), std::io::Error> {
+        self.tar_write_pax_global(records)
+    }
+
+    fn add_pax_record(&mut self, key: &str, value: &[u8]) -> Result<(), std::io::Error> {
+        self.tar_add_pax_record(key, value)
+    }
+
+    fn write_dir_with_metadata(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        mtime: Option<u64>,
+    ) -> Result<(), std::io::Error> {
+        check_dir_mode(mode)?;
+        self.tar_write_dir_with_metadata(name, mode, mtime)
+    }
+
+    fn write_hardlink(&mut self, name: &[u8], target: &[u8]) -> Result<(), std::io::Error> {
+        self.tar_write_hardlink(name, target)
+    }
+
+    fn write_special(
+        &mut self,
+        name: &[u8],
+        typeflag: u8,
+        device: (u32, u32),
+    ) -> Result<(), std::io::Error> {
+        self.tar_write_special(name, typeflag, device)
+    }
+
+    fn write_symlink(&mut self, name: &[u8], target: &[u8]) -> Result<(), std::io::Error> {
+        self.tar_write_symlink(name, target)
+    }
+
+    fn write_sparse_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut dyn Write, HashOptions)>,
+        mut in_filedescriptor: &mut dyn ReadSeek,
+        map: &[(u64, u64)],
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self.tar_write_sparse_file(
+            #[cfg(feature = "sha512")]
+            out_hash.as_mut().map(|(out, options)| (out, *options)),
+            &mut in_filedescriptor,
+            map,
+            size,
+            name,
+        )
+    }
+}
+
+impl<W: Write> ArchiveWriter for CpioOutput<W> {
+    type Inner = W;
+
+    fn stats(&self) -> &TarStats {
+        &self.stats
+    }
+
+    fn write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error> {
+        self.cpio_write_dir(name)
+    }
+
+    fn write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut dyn Write, HashOptions)>,
+        mut in_filedescriptor: &mut dyn ReadSeek,
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self.cpio_write_file(
+            #[cfg(feature = "sha512")]
+            out_hash.as_mut().map(|(out, options)| (out, *options)),
+            &mut in_filedescriptor,
+            size,
+            name,
+        )
+    }
+
+    fn finish(&mut self, _eof_blocks: u64, _blocking_factor: u64) -> Result<(), std::io::Error> {
+        self.cpio_end_marker()
+    }
+
+    fn into_inner(self: Box<Self>) -> W {
+        CpioOutput::into_inner(*self)
+    }
+
+    fn write_dir_with_metadata(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        mtime: Option<u64>,
+    ) -> Result<(), std::io::Error> {
+        check_dir_mode(mode)?;
+        self.cpio_write_dir_with_metadata(name, mode, mtime)
+    }
+}
+
+#[cfg(feature = "zip")]
+impl<W: Write> ArchiveWriter for ZipOutput<W> {
+    type Inner = W;
+
+    fn stats(&self) -> &TarStats {
+        &self.stats
+    }
+
+    fn write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error> {
+        self.zip_write_dir(name)
+    }
+
+    fn write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut dyn Write, HashOptions)>,
+        mut in_filedescriptor: &mut dyn ReadSeek,
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self.zip_write_file(
+            #[cfg(feature = "sha512")]
+            out_hash.as_mut().map(|(out, options)| (out, *options)),
+            &mut in_filedescriptor,
+            size,
+            name,
+        )
+    }
+
+    fn finish(&mut self, _eof_blocks: u64, _blocking_factor: u64) -> Result<(), std::io::Error> {
+        self.zip_end_marker()
+    }
+
+    fn into_inner(self: Box<Self>) -> W {
+        ZipOutput::into_inner(*self)
+    }
+
+    fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.set_utf8_names(encoding == NameEncoding::Utf8);
+    }
+
+    fn store_next_file(&mut self) -> Result<(), std::io::Error> {
+        self.zip_store_next();
+        Ok(())
+    }
+}
+
+impl<W: Write> ArchiveWriter for ArOutput<W> {
+    type Inner = W;
+
+    fn stats(&self) -> &TarStats {
+        &self.stats
+    }
+
+    fn write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error> {
+        self.ar_write_dir(name)
+    }
+
+    fn write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut dyn Write, HashOptions)>,
+        mut in_filedescriptor: &mut dyn ReadSeek,
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self.ar_write_file(
+            #[cfg(feature = "sha512")]
+            out_hash.as_mut().map(|(out, options)| (out, *options)),
+            &mut in_filedescriptor,
+            size,
+            name,
+        )
+    }
+
+    fn finish(&mut self, _eof_blocks: u64, _blocking_factor: u64) -> Result<(), std::io::Error> {
+        self.ar_end_marker()
+    }
+
+    fn into_inner(self: Box<Self>) -> W {
+        ArOutput::into_inner(*self)
+    }
+}
+
+#[cfg(feature = "squashfs")]
+impl<W: Write> ArchiveWriter for SquashfsOutput<W> {
+    type Inner = W;
+
+    fn stats(&self) -> &TarStats {
+        &self.stats
+    }
+
+    fn write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error> {
+        self.squashfs_write_dir(name)
+    }
+
+    fn write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut dyn Write, HashOptions)>,
+        mut in_filedescriptor: &mut dyn ReadSeek,
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self.squashfs_write_file(
+            #[cfg(feature = "sha512")]
+            out_hash.as_mut().map(|(out, options)| (out, *options)),
+            &mut in_filedescriptor,
+            size,
+            name,
+        )
+    }
+
+    fn finish(&mut self, _eof_blocks: u64, _blocking_factor: u64) -> Result<(), std::io::Error> {
+        self.squashfs_end_marker()
+    }
+
+    fn into_inner(self: Box<Self>) -> W {
+        SquashfsOutput::into_inner(*self)
+    }
+
+    fn write_dir_with_metadata(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        mtime: Option<u64>,
+    ) -> Result<(), std::io::Error> {
+        check_dir_mode(mode)?;
+        self.squashfs_write_dir_with_metadata(name, mode, mtime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// records the names of the entries instead of writing an archive
+    #[derive(Default)]
+    struct Recorder {
+        stats: TarStats,
+        names: Vec<Vec<u8>>,
+        finished: bool,
+    }
+
+    impl ArchiveWriter for Recorder {
+        type Inner = Vec<Vec<u8>>;
+
+        fn stats(&self) -> &TarStats {
+            &self.stats
+        }
+
+        fn write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error> {
+            self.names.push(name.to_vec());
+            Ok(())
+        }
+
+        fn write_file(
+            &mut self,
+            #[cfg(feature = "sha512")] _out_hash: Option<(&mut dyn Write, HashOptions)>,
+            in_filedescriptor: &mut dyn ReadSeek,
+            _size: &u64,
+            name: &[u8],
+        ) -> Result<(), std::io::Error> {
+            std::io::copy(in_filedescriptor, &mut std::io::sink())?;
+            self.names.push(name.to_vec());
+            Ok(())
+        }
+
+        fn finish(
+            &mut self,
+            _eof_blocks: u64,
+            _blocking_factor: u64,
+        ) -> Result<(), std::io::Error> {
+            self.finished = true;
+            Ok(())
+        }
+
+        fn into_inner(self: Box<Self>) -> Vec<Vec<u8>> {
+            self.names
+        }
+    }
+
+    /// writes a directory with a file through the trait
+    fn write_example(out: &mut dyn ArchiveWriter<Inner = Vec<u8>>) {
+        out.write_dir(b"a/").unwrap();
+        out.write_file(
+            #[cfg(feature = "sha512")]
+            None,
+            &mut std::io::Cursor::new(b"content"),
+            &7,
+            b"a/b",
+        )
+        .unwrap();
+        out.finish(2, 1).unwrap();
+    }
+
+    #[test]
+    fn test_double_can_be_plugged_in() {
+        let mut out: Box<dyn ArchiveWriter<Inner = Vec<Vec<u8>>>> = Box::<Recorder>::default();
+        out.write_dir(b"a/").unwrap();
+        out.write_file(
+            #[cfg(feature = "sha512")]
+            None,
+            &mut std::io::Cursor::new(b"content"),
+            &7,
+            b"a/b",
+        )
+        .unwrap();
+        // the optional kinds of entries are unsupported by default
+        assert!(out.write_symlink(b"a/c", b"b").is_err());
+        assert!(out.write_dir_with_metadata(b"a/d/", 0o700, None).is_err());
+        out.finish(2, 1).unwrap();
+        assert_eq!(out.into_inner(), vec![b"a/".to_vec(), b"a/b".to_vec()]);
+    }
+
+    #[test]
+    fn tar_through_trait_is_identical() {
+        let mut direct = TarOutput::with_format(Vec::new(), TarFormat::Gnu);
+        direct.tar_write_dir(b"a/").unwrap();
+        direct
+            .tar_write_file(
+                #[cfg(feature = "sha512")]
+                None::<(&mut Vec<u8>, HashOptions)>,
+                &mut std::io::Cursor::new(b"content"),
+                &7,
+                b"a/b",
+            )
+            .unwrap();
+        direct.tar_end_marker_padded(2, 1).unwrap();
+        let mut out = with_format(Vec::new(), ArchiveFormat::Tar(TarFormat::Gnu)).unwrap();
+        write_example(out.as_mut());
+        assert_eq!(out.into_inner(), direct.into_inner());
+    }
+
+    #[test]
+    fn dir_mode_is_checked() {
+        let mut out = with_format(Vec::new(), ArchiveFormat::Tar(TarFormat::Gnu)).unwrap();
+        assert!(out.write_dir_with_metadata(b"a/", 0o10000, None).is_err());
+        assert!(out.write_dir_with_metadata(b"a/", 0o755, Some(0)).is_ok());
+    }
+}
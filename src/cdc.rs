@@ -0,0 +1,61 @@
+/// returns a fixed table of pseudo random numbers for the gear hash, generated with splitmix64
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+const GEAR: [u64; 256] = gear_table();
+
+/// Finds content-defined boundaries with a gear rolling hash, e.g. for the chunk store and
+/// rsyncable gzip.
+///
+/// A chunk ends where the bits of the hash selected by `mask` are zero, but not before
+/// `min_size` bytes, and at the latest after `max_size` bytes. The hash restarts at each
+/// boundary, so the boundaries only depend on the data since the last boundary and an insertion
+/// only changes the chunks around it.
+pub struct GearChunker {
+    min_size: usize,
+    max_size: usize,
+    mask: u64,
+    hash: u64,
+    len: usize,
+}
+
+impl GearChunker {
+    pub fn new(min_size: usize, max_size: usize, mask: u64) -> GearChunker {
+        GearChunker {
+            min_size,
+            max_size,
+            mask,
+            hash: 0,
+            len: 0,
+        }
+    }
+
+    /// returns the number of bytes of `data` which belong to the current chunk if it ends within
+    /// `data`, the next call starts a new chunk. Returns None if the chunk continues after `data`.
+    pub fn next_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, b) in data.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[*b as usize]);
+            self.len += 1;
+            if (self.len >= self.min_size && self.hash & self.mask == 0)
+                || self.len == self.max_size
+            {
+                self.hash = 0;
+                self.len = 0;
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+}
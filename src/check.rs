@@ -0,0 +1,110 @@
+use crate::report_stdout;
+use deterministic_tar::reader::{ParseMode, ReaderOptions, TarReader};
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "deterministic-tar check",
+    about = "Check whether an existing tar archive from any tool follows the conventions of reproducible archives, e.g. to audit vendor tarballs. Entries with another modification time, a non-root owner, entries which are not sorted like the directory walk of deterministic-tar and PAX keywords which depend on the machine or time of the build are printed, followed by a summary. The exit code is 1 if there was any violation."
+)]
+pub struct CheckOpt {
+    /// tar archive which should be checked, it has to be uncompressed
+    #[structopt(parse(from_os_str))]
+    archive: PathBuf,
+
+    /// expected modification time of all entries in seconds since 1970, e.g. the SOURCE_DATE_EPOCH of the build
+    #[structopt(long, default_value = "0")]
+    mtime: i64,
+
+    /// accept archives which are malformed but still readable, like verify --lenient
+    #[structopt(long)]
+    lenient: bool,
+}
+
+/// PAX keywords which depend on the machine or the time of the build
+const UNSTABLE_PAX_KEYWORDS: &[&[u8]] = &[
+    b"atime",
+    b"ctime",
+    b"LIBARCHIVE.creationtime",
+    b"SCHILY.dev",
+    b"SCHILY.ino",
+    b"SCHILY.nlink",
+];
+
+pub fn check_main(opt: CheckOpt) {
+    let in_tar = File::open(&opt.archive)
+        .unwrap_or_else(|_| panic!("could not open file {:?}", &opt.archive));
+    let reader_options = ReaderOptions {
+        mode: if opt.lenient {
+            ParseMode::Lenient
+        } else {
+            ParseMode::Strict
+        },
+        ..ReaderOptions::default()
+    };
+    let mut reader = TarReader::with_options(BufReader::new(in_tar), reader_options);
+    let mut stdout = report_stdout();
+    let (mut checked, mut violations) = (0u64, 0u64);
+    let mut previous: Option<Vec<Vec<u8>>> = None;
+    while let Some(entry) = reader
+        .next_entry()
+        .unwrap_or_else(|e| panic!("could not read {:?}: {}", &opt.archive, e))
+    {
+        let name = String::from_utf8_lossy(&entry.name).to_string();
+        let mut report = |kind: &str, reason: String| {
+            writeln!(stdout, "{}\t{}\t{}", kind, name, reason).unwrap();
+            violations += 1;
+        };
+        if entry.mtime != opt.mtime {
+            report("MTIME", format!("{} instead of {}", entry.mtime, opt.mtime));
+        }
+        let root = |name: &[u8]| name.is_empty() || name == b"root";
+        if entry.uid != 0 || entry.gid != 0 || !root(&entry.uname) || !root(&entry.gname) {
+            report(
+                "OWNER",
+                format!(
+                    "{}/{} ({}/{}) instead of root/root (0/0)",
+                    String::from_utf8_lossy(&entry.uname),
+                    String::from_utf8_lossy(&entry.gname),
+                    entry.uid,
+                    entry.gid
+                ),
+            );
+        }
+        for keyword in &entry.pax_keywords {
+            if UNSTABLE_PAX_KEYWORDS.contains(&keyword.as_slice()) {
+                report(
+                    "PAX",
+                    format!("keyword {:?}", String::from_utf8_lossy(keyword)),
+                );
+            }
+        }
+        // like the directory walk: each directory right before its content, siblings by name
+        let components: Vec<Vec<u8>> = entry
+            .name
+            .split(|b| *b == b'/')
+            .filter(|c| !c.is_empty() && *c != b".")
+            .map(|c| c.to_vec())
+            .collect();
+        if let Some(previous) = previous.as_ref().filter(|p| **p >= components) {
+            report(
+                "UNSORTED",
+                format!("after {:?}", String::from_utf8_lossy(&previous.join(&b'/'))),
+            );
+        }
+        previous = Some(components);
+        checked += 1;
+    }
+    writeln!(
+        stdout,
+        "{} entries checked, {} violations",
+        checked, violations
+    )
+    .unwrap();
+    if violations > 0 {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{digest_of_file, report_stdout};
+use deterministic_tar::hash::{parse_hash_line, HashAlgorithm};
+use deterministic_tar::reader::invalid_data;
+use std::io::Write;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "deterministic-tar verify-extracted",
+    about = "Verify an extracted archive against the SHA512, SHA256, XXH3 or CRC32 hashes written by --output-hash. The files are hashed in parallel, each mismatch is printed in the order of the manifest, followed by a summary."
+)]
+pub struct VerifyExtractedOpt {
+    /// file written by --output-hash, each line is "<sha512, sha256, XXH3_xxh3 or crc32>  <name>" or in the BSD format of --hash-format bsd
+    #[structopt(parse(from_os_str))]
+    manifest: PathBuf,
+
+    /// directory into which the archive was extracted, the names in the manifest are relative to it
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    /// number of worker threads, the default is the number of available CPUs
+    #[structopt(long)]
+    threads: Option<usize>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "deterministic-tar check-hashes",
+    about = "Check files against a manifest written by --output-hash like \"sha512sum -c\", but without its quirks: each line is \"<digest>  <name>\" or in the BSD format of --hash-format bsd, the name is used verbatim (no backslash escapes) and relative to the given directory, and the algorithm follows from each digest. Each file is printed with \"OK\" or \"FAILED\", the exit code is 1 if any file did not match or could not be read."
+)]
+pub struct CheckHashesOpt {
+    /// file written by --output-hash
+    #[structopt(parse(from_os_str))]
+    hashfile: PathBuf,
+
+    /// directory which contains the main directory of the archive, e.g. where it was extracted
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    /// don't print the files which match
+    #[structopt(long)]
+    quiet: bool,
+
+    /// don't print anything, only set the exit code
+    #[structopt(long)]
+    status: bool,
+
+    /// don't fail or report anything for missing files
+    #[structopt(long)]
+    ignore_missing: bool,
+
+    /// number of worker threads, the default is the number of available CPUs
+    #[structopt(long)]
+    threads: Option<usize>,
+}
+
+/// reads a file written by --output-hash and returns the digest and the name of each line. The
+/// names are used verbatim, unlike sha512sum no backslash escapes are interpreted.
+fn read_hash_manifest(path: &std::path::Path) -> Vec<(String, Vec<u8>)> {
+    let manifest = std::fs::read(path).unwrap_or_else(|_| panic!("could not read file {:?}", path));
+    let manifest = manifest.strip_suffix(b"\n").unwrap_or(&manifest);
+    if manifest.is_empty() {
+        return vec![];
+    }
+    manifest
+        .split(|b| *b == b'\n')
+        .enumerate()
+        .map(|(i, line)| {
+            parse_hash_line(line)
+                .map(|(digest, name)| (digest, name.to_vec()))
+                .unwrap_or_else(|| panic!("invalid line {} in {:?}", i + 1, path))
+        })
+        .collect()
+}
+
+/// hashes the files of a manifest below dir with several threads and returns for each entry
+/// whether the content matches the digest
+fn check_hash_entries(
+    entries: &[(String, Vec<u8>)],
+    dir: &std::path::Path,
+    threads: Option<usize>,
+) -> Vec<Result<bool, std::io::Error>> {
+    use std::os::unix::ffi::OsStrExt;
+    let threads = threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1);
+
+    // the workers take the next entry from the shared counter, the results are sorted afterwards
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<bool, std::io::Error>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let Some((expected, name)) = entries.get(i) else {
+                            return results;
+                        };
+                        // the algorithm follows from the length of the digest
+                        let result = match HashAlgorithm::of_hex_digest(expected) {
+                            Some(algorithm) => {
+                                let path = dir.join(std::ffi::OsStr::from_bytes(name));
+                                digest_of_file(&path, algorithm).map(|digest| digest == *expected)
+                            }
+                            None => Err(invalid_data("invalid digest".to_string())),
+                        };
+                        results.push((i, result));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+pub fn verify_extracted_main(opt: VerifyExtractedOpt) {
+    let entries = read_hash_manifest(&opt.manifest);
+    let results = check_hash_entries(&entries, &opt.dir, opt.threads);
+
+    let mut stdout = report_stdout();
+    let mut failures = 0;
+    for ((_, name), result) in entries.iter().zip(results) {
+        let reason = match result {
+            Ok(true) => continue,
+            Ok(false) => "content differs".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let name = String::from_utf8_lossy(name);
+        writeln!(stdout, "FAILED\t{}\t{}", name, reason).unwrap();
+        failures += 1;
+    }
+    writeln!(
+        stdout,
+        "{} files verified, {} mismatches",
+        entries.len(),
+        failures
+    )
+    .unwrap();
+    if failures > 0 {
+        std::process::exit(1);
+    }
+}
+
+pub fn check_hashes_main(opt: CheckHashesOpt) {
+    let entries = read_hash_manifest(&opt.hashfile);
+    let results = check_hash_entries(&entries, &opt.dir, opt.threads);
+
+    let mut stdout = report_stdout();
+    let (mut verified, mut mismatches, mut unreadable) = (0u64, 0u64, 0u64);
+    for ((_, name), result) in entries.iter().zip(results) {
+        let status = match result {
+            Err(e) if opt.ignore_missing && e.kind() == std::io::ErrorKind::NotFound => continue,
+            Ok(true) => "OK",
+            Ok(false) => {
+                mismatches += 1;
+                "FAILED"
+            }
+            Err(_) => {
+                unreadable += 1;
+                "FAILED open or read"
+            }
+        };
+        verified += 1;
+        if opt.status || (opt.quiet && status == "OK") {
+            continue;
+        }
+        stdout.write_all(name).unwrap();
+        writeln!(stdout, ": {}", status).unwrap();
+    }
+    if !opt.status {
+        if unreadable > 0 {
+            eprintln!("WARNING: {} listed file(s) could not be read", unreadable);
+        }
+        if mismatches > 0 {
+            eprintln!("WARNING: {} computed checksum(s) did NOT match", mismatches);
+        }
+        if verified == 0 {
+            eprintln!("{:?}: no file was verified", &opt.hashfile);
+        }
+    }
+    if mismatches + unreadable > 0 || verified == 0 {
+        std::process::exit(1);
+    }
+}
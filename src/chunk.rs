@@ -0,0 +1,93 @@
+use crate::cdc::GearChunker;
+use sha2::{Digest, Sha512_256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// chunks are never smaller than this, except for the last one
+pub const CHUNK_MIN_SIZE: usize = 16 * 1024;
+/// a chunk ends where the lowest 16 bits of the rolling hash are zero, so chunks have an average
+/// size of about 64 KiB above the minimum size
+const CHUNK_MASK: u64 = (1 << 16) - 1;
+/// chunks are cut at this size if the rolling hash didn't find a boundary before
+pub const CHUNK_MAX_SIZE: usize = 256 * 1024;
+
+/// Splits everything written to it into content-defined chunks and stores each chunk as
+/// `<store>/<first 4 hex digits>/<id>.chunk`, where the id is the SHA512/256 of the chunk.
+/// Chunks which already exist in the store are not written again. The index, one line
+/// "<id> <length>" per chunk, is written to the inner writer; concatenating the chunks in the
+/// order of the index results in the original data.
+///
+/// The boundaries are found with a gear rolling hash which restarts at each chunk. They only
+/// depend on the data since the last boundary, so an insertion only changes the chunks around it.
+pub struct ChunkStoreWriter<W: Write> {
+    index: W,
+    store: PathBuf,
+    current: Vec<u8>,
+    chunker: GearChunker,
+    /// number of chunks in the index and how many of them were new in the store
+    pub chunks: u64,
+    pub new_chunks: u64,
+}
+
+impl<W: Write> ChunkStoreWriter<W> {
+    /// creates the store directory if it doesn't exist
+    pub fn new(index: W, store: &Path) -> Result<ChunkStoreWriter<W>, std::io::Error> {
+        std::fs::create_dir_all(store)?;
+        Ok(ChunkStoreWriter {
+            index,
+            store: store.to_path_buf(),
+            current: Vec::with_capacity(CHUNK_MAX_SIZE),
+            chunker: GearChunker::new(CHUNK_MIN_SIZE, CHUNK_MAX_SIZE, CHUNK_MASK),
+            chunks: 0,
+            new_chunks: 0,
+        })
+    }
+
+    /// stores the current chunk and adds it to the index
+    fn _write_chunk(&mut self) -> Result<(), std::io::Error> {
+        let id = hex::encode(Sha512_256::digest(&self.current));
+        let dir = self.store.join(&id[..4]);
+        let path = dir.join(format!("{}.chunk", id));
+        if !path.exists() {
+            std::fs::create_dir_all(&dir)?;
+            // concurrent runs with the same store never see a partial chunk
+            let tmp = dir.join(format!("{}.chunk.{}.tmp", id, std::process::id()));
+            std::fs::write(&tmp, &self.current)?;
+            std::fs::rename(&tmp, &path)?;
+            self.new_chunks += 1;
+        }
+        writeln!(self.index, "{} {}", id, self.current.len())?;
+        self.chunks += 1;
+        self.current.clear();
+        Ok(())
+    }
+
+    /// stores the last chunk and returns the inner writer
+    pub fn finish(mut self) -> Result<W, std::io::Error> {
+        if !self.current.is_empty() {
+            self._write_chunk()?;
+        }
+        Ok(self.index)
+    }
+}
+
+impl<W: Write> Write for ChunkStoreWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.chunker.next_boundary(buf) {
+            Some(n) => {
+                self.current.extend_from_slice(&buf[..n]);
+                self._write_chunk()?;
+                Ok(n)
+            }
+            None => {
+                self.current.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    /// only flushes the index, the current chunk stays buffered to keep the boundaries fixed
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.index.flush()
+    }
+}
@@ -0,0 +1,763 @@
+use std::io::Write;
+
+/// Compression of the whole archive. All settings are pinned, so the same tar stream always
+/// results in the same compressed bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// lzip with the settings of `lzip -6` in a single member
+    Lzip,
+    /// LZ4 frame format with 4 MiB independent blocks and a content checksum
+    Lz4,
+    /// brotli with quality 11 and a 4 MiB window
+    Brotli,
+    /// bzip2 with 900 kB blocks like `bzip2 -9`
+    Bzip2,
+    /// gzip with level 6, compressed in independent blocks of 128 KiB so it can use multiple threads
+    Gzip,
+    /// gzip with level 6 in independent blocks which end at content-defined boundaries, like
+    /// `gzip --rsyncable`
+    GzipRsyncable,
+    /// gzip compressed with zopfli, which is very slow but results in the smallest gzip output
+    Zopfli,
+    /// xz with the settings of `xz -6` in independent blocks of 24 MiB, so it can use multiple
+    /// threads. All blocks are in a single stream.
+    Xz,
+    /// zstd with the given level (1 to 19) in independent frames of 32 MiB with a content
+    /// checksum, so it can use multiple threads
+    Zstd(i32),
+    /// zstd with the given level in the seekable format: independent frames of 1 MiB followed
+    /// by a seek table, so parts of the archive can be decompressed without the rest
+    ZstdSeekable(i32),
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz" => Ok(Compression::Lzip),
+            "lz4" => Ok(Compression::Lz4),
+            "br" => Ok(Compression::Brotli),
+            "bzip2" => Ok(Compression::Bzip2),
+            "gz" => Ok(Compression::Gzip),
+            "gzip-rsyncable" => Ok(Compression::GzipRsyncable),
+            "xz" => Ok(Compression::Xz),
+            "zstd" => Ok(Compression::Zstd(ZSTD_DEFAULT_LEVEL)),
+            "zstd-seekable" => Ok(Compression::ZstdSeekable(ZSTD_DEFAULT_LEVEL)),
+            _ => Err(format!(
+                "unknown compression {:?}, expected none, gz, gzip-rsyncable, xz, lz, lz4, br, bzip2, zstd or zstd-seekable",
+                s
+            )),
+        }
+    }
+}
+
+/// lzip compression level, equivalent to `lzip -6`
+#[cfg(feature = "lzip")]
+const LZIP_PRESET: u32 = 6;
+
+/// xz compression level and the uncompressed size of each block, which is three times the
+/// dictionary size like `xz -6 --threads=N` uses
+#[cfg(feature = "xz")]
+const XZ_PRESET: u32 = 6;
+#[cfg(feature = "xz")]
+const XZ_BLOCK_SIZE: u64 = 24 * 1024 * 1024;
+
+/// xz options with the fixed block size
+#[cfg(feature = "xz")]
+fn xz_options() -> lzma_rust2::XzOptions {
+    let mut options = lzma_rust2::XzOptions::with_preset(XZ_PRESET);
+    options.set_block_size(std::num::NonZeroU64::new(XZ_BLOCK_SIZE));
+    options
+}
+
+/// fixed LZ4 frame settings, the default block size would depend on the size of the first write.
+/// No content size, dictionary id or block checksums are written.
+#[cfg(feature = "lz4")]
+fn lz4_frame_info() -> lz4_flex::frame::FrameInfo {
+    lz4_flex::frame::FrameInfo::new()
+        .block_size(lz4_flex::frame::BlockSize::Max4MB)
+        .block_mode(lz4_flex::frame::BlockMode::Independent)
+        .content_checksum(true)
+}
+
+/// brotli quality and base 2 logarithm of the window size, the defaults of the brotli command line tool
+#[cfg(feature = "brotli")]
+const BROTLI_QUALITY: u32 = 11;
+#[cfg(feature = "brotli")]
+const BROTLI_LGWIN: u32 = 22;
+
+/// bzip2 block size in units of 100 kB and the work factor of the fallback sorting algorithm,
+/// the defaults of the bzip2 command line tool
+#[cfg(feature = "bzip2")]
+const BZIP2_LEVEL: u32 = 9;
+#[cfg(feature = "bzip2")]
+const BZIP2_WORK_FACTOR: u32 = 30;
+
+/// zstd compression level if none is given, the default of the zstd command line tool, and the
+/// range of levels which don't need the large windows of `zstd --ultra`
+pub const ZSTD_DEFAULT_LEVEL: i32 = 3;
+pub const ZSTD_LEVELS: std::ops::RangeInclusive<i32> = 1..=19;
+
+/// size of the uncompressed input of each zstd frame, four times the largest window of the
+/// levels without `--ultra` like the jobs of `zstd -T`
+#[cfg(feature = "zstd")]
+pub const ZSTD_FRAME_SIZE: usize = 32 * 1024 * 1024;
+
+/// size of the uncompressed input of each frame of the seekable format, small enough that a
+/// single file can be extracted quickly
+#[cfg(feature = "zstd")]
+pub const ZSTD_SEEKABLE_FRAME_SIZE: usize = 1024 * 1024;
+
+/// magic numbers of the skippable frame which contains the seek table and of its footer, see
+/// the seekable format in the contrib directory of zstd
+#[cfg(feature = "zstd")]
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+#[cfg(feature = "zstd")]
+const ZSTD_SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+/// size of the seek table footer: number of frames, descriptor and magic number
+#[cfg(feature = "zstd")]
+const ZSTD_SEEK_TABLE_FOOTER: usize = 9;
+/// descriptor flag for seek table entries with checksums
+#[cfg(feature = "zstd")]
+const ZSTD_SEEK_TABLE_CHECKSUMS: u8 = 0x80;
+
+/// compresses a block into a zstd frame with all frame parameters pinned, the window size and
+/// the strategy follow from the level
+#[cfg(feature = "zstd")]
+fn zstd_frame(data: &[u8], level: i32) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), level)?;
+    encoder.include_checksum(true)?;
+    encoder.include_contentsize(false)?;
+    encoder.include_dictid(false)?;
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// A zstd compressor which splits the input into blocks of [`ZSTD_FRAME_SIZE`] bytes and
+/// compresses up to `threads` of them in parallel.
+///
+/// Every block is a complete frame, which `zstd -d` decompresses like a single frame. The frame
+/// boundaries only depend on the input, so the output is identical for any number of threads.
+/// [`ParallelZstdWriter::seekable`] writes blocks of [`ZSTD_SEEKABLE_FRAME_SIZE`] bytes and
+/// a seek table at the end, which `zstd -d` skips.
+#[cfg(feature = "zstd")]
+pub struct ParallelZstdWriter<W: Write> {
+    out: W,
+    level: i32,
+    threads: usize,
+    frame_size: usize,
+    /// full blocks which are not compressed yet
+    pending: Vec<Vec<u8>>,
+    current: Vec<u8>,
+    frames_written: bool,
+    /// compressed size, uncompressed size and checksum of each frame for the seekable format
+    seek_table: Option<Vec<(u32, u32, u32)>>,
+}
+
+#[cfg(feature = "zstd")]
+impl<W: Write> ParallelZstdWriter<W> {
+    pub fn new(
+        out: W,
+        level: i32,
+        threads: usize,
+    ) -> Result<ParallelZstdWriter<W>, std::io::Error> {
+        Self::_new(out, level, threads, ZSTD_FRAME_SIZE, None)
+    }
+
+    /// writes the seekable format with a seek table at the end
+    pub fn seekable(
+        out: W,
+        level: i32,
+        threads: usize,
+    ) -> Result<ParallelZstdWriter<W>, std::io::Error> {
+        Self::_new(
+            out,
+            level,
+            threads,
+            ZSTD_SEEKABLE_FRAME_SIZE,
+            Some(Vec::new()),
+        )
+    }
+
+    fn _new(
+        out: W,
+        level: i32,
+        threads: usize,
+        frame_size: usize,
+        seek_table: Option<Vec<(u32, u32, u32)>>,
+    ) -> Result<ParallelZstdWriter<W>, std::io::Error> {
+        if !ZSTD_LEVELS.contains(&level) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("zstd level {} is not between 1 and 19", level),
+            ));
+        }
+        Ok(ParallelZstdWriter {
+            out,
+            level,
+            threads: std::cmp::max(threads, 1),
+            frame_size,
+            pending: Vec::new(),
+            current: Vec::new(),
+            frames_written: false,
+            seek_table,
+        })
+    }
+
+    /// writes a compressed frame and adds it to the seek table. The checksum of the seek table
+    /// is the content checksum at the end of the frame.
+    fn _write_frame(&mut self, frame: &[u8], size: usize) -> Result<(), std::io::Error> {
+        self.out.write_all(frame)?;
+        self.frames_written = true;
+        if let Some(seek_table) = self.seek_table.as_mut() {
+            let checksum = u32::from_le_bytes(frame[frame.len() - 4..].try_into().unwrap());
+            seek_table.push((frame.len() as u32, size as u32, checksum));
+        }
+        Ok(())
+    }
+
+    /// compresses all pending blocks and writes them in order
+    fn _compress_pending(&mut self) -> Result<(), std::io::Error> {
+        let level = self.level;
+        let compressed: Vec<Result<Vec<u8>, std::io::Error>> = if self.threads == 1 {
+            self.pending.iter().map(|b| zstd_frame(b, level)).collect()
+        } else {
+            std::thread::scope(|s| {
+                let handles: Vec<_> = self
+                    .pending
+                    .iter()
+                    .map(|b| s.spawn(move || zstd_frame(b, level)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().expect("compression thread panicked"))
+                    .collect()
+            })
+        };
+        let pending = std::mem::take(&mut self.pending);
+        for (c, block) in compressed.into_iter().zip(pending) {
+            self._write_frame(&c?, block.len())?;
+        }
+        Ok(())
+    }
+
+    /// compresses the remaining input, writes the seek table and returns the underlying writer.
+    /// Empty input results in a single empty frame.
+    pub fn finish(mut self) -> Result<W, std::io::Error> {
+        self._compress_pending()?;
+        if !self.current.is_empty() || !self.frames_written {
+            let frame = zstd_frame(&self.current, self.level)?;
+            self._write_frame(&frame, self.current.len())?;
+        }
+        if let Some(seek_table) = self.seek_table.take() {
+            let size = seek_table.len() * 12 + ZSTD_SEEK_TABLE_FOOTER;
+            let mut table = Vec::with_capacity(8 + size);
+            table.extend_from_slice(&ZSTD_SKIPPABLE_MAGIC.to_le_bytes());
+            table.extend_from_slice(&(size as u32).to_le_bytes());
+            for (compressed, size, checksum) in &seek_table {
+                table.extend_from_slice(&compressed.to_le_bytes());
+                table.extend_from_slice(&size.to_le_bytes());
+                table.extend_from_slice(&checksum.to_le_bytes());
+            }
+            table.extend_from_slice(&(seek_table.len() as u32).to_le_bytes());
+            table.push(ZSTD_SEEK_TABLE_CHECKSUMS);
+            table.extend_from_slice(&ZSTD_SEEKABLE_MAGIC.to_le_bytes());
+            self.out.write_all(&table)?;
+        }
+        Ok(self.out)
+    }
+}
+
+/// reads the seek table at the end of a file in the seekable zstd format and returns the offset
+/// of each frame in the file and in the decompressed data. The file may start with other data,
+/// e.g. a self-extracting stub.
+#[cfg(feature = "zstd")]
+pub fn read_seek_table<R: std::io::Read + std::io::Seek>(
+    file: &mut R,
+) -> Result<Vec<(u64, u64)>, std::io::Error> {
+    use std::io::SeekFrom;
+    let invalid = |what: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("no seekable zstd format: {}", what),
+        )
+    };
+    let end = file.seek(SeekFrom::End(0))?;
+    let mut footer = [0u8; ZSTD_SEEK_TABLE_FOOTER];
+    if end < 8 + ZSTD_SEEK_TABLE_FOOTER as u64 {
+        return Err(invalid("file too short"));
+    }
+    file.seek(SeekFrom::End(-(ZSTD_SEEK_TABLE_FOOTER as i64)))?;
+    file.read_exact(&mut footer)?;
+    if footer[5..9] != ZSTD_SEEKABLE_MAGIC.to_le_bytes() {
+        return Err(invalid("seek table magic number missing"));
+    }
+    let frames = u32::from_le_bytes(footer[0..4].try_into().unwrap()) as u64;
+    let entry_size = if footer[4] & ZSTD_SEEK_TABLE_CHECKSUMS != 0 {
+        12
+    } else {
+        8
+    };
+    let table_size = 8 + frames * entry_size + ZSTD_SEEK_TABLE_FOOTER as u64;
+    if table_size > end {
+        return Err(invalid("seek table larger than the file"));
+    }
+    file.seek(SeekFrom::End(-(table_size as i64)))?;
+    let mut table = vec![0u8; (table_size - ZSTD_SEEK_TABLE_FOOTER as u64) as usize];
+    file.read_exact(&mut table)?;
+    if table[0..4] != ZSTD_SKIPPABLE_MAGIC.to_le_bytes() {
+        return Err(invalid("skippable frame magic number missing"));
+    }
+    let sizes: Vec<(u64, u64)> = table[8..]
+        .chunks(entry_size as usize)
+        .map(|e| {
+            let compressed = u32::from_le_bytes(e[0..4].try_into().unwrap());
+            let size = u32::from_le_bytes(e[4..8].try_into().unwrap());
+            (compressed as u64, size as u64)
+        })
+        .collect();
+    let compressed: u64 = sizes.iter().map(|(c, _)| c).sum();
+    if compressed > end - table_size {
+        return Err(invalid("frames larger than the file"));
+    }
+    let mut offset = end - table_size - compressed;
+    let mut decompressed = 0;
+    let mut offsets = Vec::with_capacity(sizes.len());
+    for (compressed, size) in sizes {
+        offsets.push((offset, decompressed));
+        offset += compressed;
+        decompressed += size;
+    }
+    Ok(offsets)
+}
+
+#[cfg(feature = "zstd")]
+impl<W: Write> Write for ParallelZstdWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = std::cmp::min(buf.len(), self.frame_size - self.current.len());
+        self.current.extend_from_slice(&buf[..n]);
+        if self.current.len() == self.frame_size {
+            self.pending.push(std::mem::take(&mut self.current));
+            if self.pending.len() == self.threads {
+                self._compress_pending()?;
+            }
+        }
+        Ok(n)
+    }
+
+    /// only flushes the underlying writer, buffered input stays buffered to keep the frame
+    /// boundaries fixed
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// error for a compression or format whose cargo feature is disabled, unused if all features are
+/// enabled
+#[allow(dead_code)]
+pub(crate) fn disabled(name: &str, feature: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!(
+            "{} is not available, the cargo feature {:?} was disabled at compile time",
+            name, feature
+        ),
+    )
+}
+
+impl Compression {
+    /// returns the shell command which decompresses stdin to stdout, e.g. for
+    /// [`crate::sfx::self_extracting_stub`]
+    pub fn decompress_command(&self) -> &'static str {
+        match self {
+            Compression::None => "cat",
+            Compression::Lzip => "lzip -dc",
+            Compression::Lz4 => "lz4 -dc",
+            Compression::Brotli => "brotli -dc",
+            Compression::Bzip2 => "bzip2 -dc",
+            Compression::Gzip | Compression::GzipRsyncable | Compression::Zopfli => "gzip -dc",
+            Compression::Xz => "xz -dc",
+            Compression::Zstd(_) | Compression::ZstdSeekable(_) => "zstd -dc",
+        }
+    }
+
+    /// describes all settings which influence the compressed bytes as "key=value" pairs, e.g.
+    /// to compare the settings of two runs. The number of threads is not included because it
+    /// doesn't change the output.
+    #[allow(unreachable_patterns)]
+    pub fn parameters(&self) -> String {
+        match self {
+            Compression::None => "format=none".to_string(),
+            #[cfg(feature = "lzip")]
+            Compression::Lzip => format!(
+                "format=lzip level={} dict_size={} members=1 dictionary=none",
+                LZIP_PRESET,
+                lzma_rust2::LzipOptions::with_preset(LZIP_PRESET)
+                    .lzma_options
+                    .dict_size
+            ),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let info = lz4_frame_info();
+                format!(
+                    "format=lz4 block_size={:?} block_mode={:?} content_checksum={} dictionary=none",
+                    info.block_size, info.block_mode, info.content_checksum
+                )
+            }
+            #[cfg(feature = "brotli")]
+            Compression::Brotli => format!(
+                "format=brotli level={} window_bits={} dictionary=none",
+                BROTLI_QUALITY, BROTLI_LGWIN
+            ),
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => format!(
+                "format=bzip2 block_size={} work_factor={} dictionary=none",
+                BZIP2_LEVEL * 100_000,
+                BZIP2_WORK_FACTOR
+            ),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => format!(
+                "format=gzip level={} block_size={} dictionary=none",
+                crate::gzip::GZIP_LEVEL,
+                crate::gzip::GZIP_BLOCK_SIZE
+            ),
+            #[cfg(feature = "gzip")]
+            Compression::GzipRsyncable => format!(
+                "format=gzip level={} rsyncable=gear min_block_size={} max_block_size={} dictionary=none",
+                crate::gzip::GZIP_LEVEL,
+                crate::gzip::GZIP_RSYNCABLE_MIN_SIZE,
+                crate::gzip::GZIP_RSYNCABLE_MAX_SIZE
+            ),
+            #[cfg(feature = "zopfli")]
+            Compression::Zopfli => format!(
+                "format=gzip encoder=zopfli iterations={} block_size={} dictionary=none",
+                zopfli::Options::default().iteration_count,
+                crate::gzip::ZOPFLI_CHUNK_SIZE
+            ),
+            #[cfg(feature = "xz")]
+            Compression::Xz => {
+                let options = xz_options();
+                format!(
+                    "format=xz level={} block_size={} dict_size={} check=crc64 dictionary=none",
+                    XZ_PRESET, XZ_BLOCK_SIZE, options.lzma_options.dict_size
+                )
+            }
+            // the output also depends on the version of the bundled zstd library
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => format!(
+                "format=zstd level={} version={} frame_size={} checksum=yes content_size=no dictionary=none",
+                level,
+                zstd::zstd_safe::version_string(),
+                ZSTD_FRAME_SIZE
+            ),
+            #[cfg(feature = "zstd")]
+            Compression::ZstdSeekable(level) => format!(
+                "format=zstd-seekable level={} version={} frame_size={} checksum=yes content_size=no dictionary=none",
+                level,
+                zstd::zstd_safe::version_string(),
+                ZSTD_SEEKABLE_FRAME_SIZE
+            ),
+            // the writer fails for compressions which are disabled
+            _ => "format=unavailable".to_string(),
+        }
+    }
+
+    /// wraps the writer into the compressor. Compressors which support multiple threads use up
+    /// to `threads` threads, the output does not depend on it.
+    #[cfg_attr(
+        not(any(feature = "gzip", feature = "xz", feature = "zstd")),
+        allow(unused_variables)
+    )]
+    pub fn writer<W: Write>(
+        &self,
+        out: W,
+        threads: usize,
+    ) -> Result<CompressedOutput<W>, std::io::Error> {
+        match self {
+            Compression::None => Ok(CompressedOutput::Plain(out)),
+            #[cfg(feature = "lzip")]
+            Compression::Lzip => Ok(CompressedOutput::Lzip(Box::new(
+                lzma_rust2::LzipWriter::new(out, lzma_rust2::LzipOptions::with_preset(LZIP_PRESET)),
+            ))),
+            #[cfg(not(feature = "lzip"))]
+            Compression::Lzip => Err(disabled("lzip compression", "lzip")),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(CompressedOutput::Lz4(Box::new(
+                lz4_flex::frame::FrameEncoder::with_frame_info(lz4_frame_info(), out),
+            ))),
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => Err(disabled("LZ4 compression", "lz4")),
+            #[cfg(feature = "brotli")]
+            Compression::Brotli => Ok(CompressedOutput::Brotli(Box::new(
+                brotli::CompressorWriter::new(out, 64 * 1024, BROTLI_QUALITY, BROTLI_LGWIN),
+            ))),
+            #[cfg(not(feature = "brotli"))]
+            Compression::Brotli => Err(disabled("brotli compression", "brotli")),
+            // the encoder uses the default work factor
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => Ok(CompressedOutput::Bzip2(Box::new(
+                bzip2::write::BzEncoder::new(out, bzip2::Compression::new(BZIP2_LEVEL)),
+            ))),
+            #[cfg(not(feature = "bzip2"))]
+            Compression::Bzip2 => Err(disabled("bzip2 compression", "bzip2")),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Ok(CompressedOutput::Gzip(Box::new(
+                crate::gzip::ParallelGzipWriter::new(out, threads),
+            ))),
+            #[cfg(feature = "gzip")]
+            Compression::GzipRsyncable => Ok(CompressedOutput::Gzip(Box::new(
+                crate::gzip::ParallelGzipWriter::rsyncable(out, threads),
+            ))),
+            #[cfg(not(feature = "gzip"))]
+            Compression::Gzip | Compression::GzipRsyncable => {
+                Err(disabled("gzip compression", "gzip"))
+            }
+            #[cfg(feature = "zopfli")]
+            Compression::Zopfli => Ok(CompressedOutput::Zopfli(Box::new(
+                crate::gzip::ZopfliGzipWriter::new(out)?,
+            ))),
+            #[cfg(not(feature = "zopfli"))]
+            Compression::Zopfli => Err(disabled("zopfli compression", "zopfli")),
+            #[cfg(feature = "xz")]
+            Compression::Xz => {
+                // the blocks are compressed independently, so the output does not depend on the
+                // number of threads
+                let threads = std::cmp::max(threads, 1) as u32;
+                Ok(CompressedOutput::Xz(Box::new(lzma_rust2::XzWriterMt::new(
+                    out,
+                    xz_options(),
+                    threads,
+                )?)))
+            }
+            #[cfg(not(feature = "xz"))]
+            Compression::Xz => Err(disabled("xz compression", "xz")),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Ok(CompressedOutput::Zstd(Box::new(
+                ParallelZstdWriter::new(out, *level, threads)?,
+            ))),
+            #[cfg(feature = "zstd")]
+            Compression::ZstdSeekable(level) => Ok(CompressedOutput::Zstd(Box::new(
+                ParallelZstdWriter::seekable(out, *level, threads)?,
+            ))),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd(_) | Compression::ZstdSeekable(_) => {
+                Err(disabled("zstd compression", "zstd"))
+            }
+        }
+    }
+}
+
+/// A writer which compresses everything written to it, pipes it through an external program or
+/// splits it into a chunk store.
+/// [`CompressedOutput::finish`] has to be called at the end to write the trailer of the
+/// compressed stream. Flushing before that may change the compressed bytes.
+pub enum CompressedOutput<W: Write> {
+    Plain(W),
+    Program(Box<crate::program::ProgramWriter<W>>),
+    #[cfg(feature = "chunk")]
+    Chunked(Box<crate::chunk::ChunkStoreWriter<W>>),
+    #[cfg(feature = "lzip")]
+    Lzip(Box<lzma_rust2::LzipWriter<W>>),
+    #[cfg(feature = "lz4")]
+    Lz4(Box<lz4_flex::frame::FrameEncoder<W>>),
+    #[cfg(feature = "brotli")]
+    Brotli(Box<brotli::CompressorWriter<W>>),
+    #[cfg(feature = "bzip2")]
+    Bzip2(Box<bzip2::write::BzEncoder<W>>),
+    #[cfg(feature = "gzip")]
+    Gzip(Box<crate::gzip::ParallelGzipWriter<W>>),
+    #[cfg(feature = "zopfli")]
+    Zopfli(Box<crate::gzip::ZopfliGzipWriter<W>>),
+    #[cfg(feature = "xz")]
+    Xz(Box<lzma_rust2::XzWriterMt<W>>),
+    #[cfg(feature = "zstd")]
+    Zstd(Box<ParallelZstdWriter<W>>),
+}
+
+impl<W: Write> CompressedOutput<W> {
+    /// finishes the compressed stream and returns the underlying writer
+    pub fn finish(self) -> Result<W, std::io::Error> {
+        let mut out = self.finish_stream()?;
+        out.flush()?;
+        Ok(out)
+    }
+
+    fn finish_stream(self) -> Result<W, std::io::Error> {
+        match self {
+            CompressedOutput::Plain(out) => Ok(out),
+            CompressedOutput::Program(out) => out.finish(),
+            #[cfg(feature = "chunk")]
+            CompressedOutput::Chunked(out) => out.finish(),
+            #[cfg(feature = "lzip")]
+            CompressedOutput::Lzip(out) => out.finish(),
+            #[cfg(feature = "lz4")]
+            CompressedOutput::Lz4(out) => Ok(out.finish()?),
+            // writes the end of the stream, but errors of the final write are only visible when
+            // the underlying writer is flushed
+            #[cfg(feature = "brotli")]
+            CompressedOutput::Brotli(out) => Ok(out.into_inner()),
+            #[cfg(feature = "bzip2")]
+            CompressedOutput::Bzip2(out) => out.finish(),
+            #[cfg(feature = "gzip")]
+            CompressedOutput::Gzip(out) => out.finish(),
+            #[cfg(feature = "zopfli")]
+            CompressedOutput::Zopfli(out) => out.finish(),
+            #[cfg(feature = "xz")]
+            CompressedOutput::Xz(out) => out.finish(),
+            #[cfg(feature = "zstd")]
+            CompressedOutput::Zstd(out) => out.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CompressedOutput::Plain(out) => out.write(buf),
+            CompressedOutput::Program(out) => out.write(buf),
+            #[cfg(feature = "chunk")]
+            CompressedOutput::Chunked(out) => out.write(buf),
+            #[cfg(feature = "lzip")]
+            CompressedOutput::Lzip(out) => out.write(buf),
+            #[cfg(feature = "lz4")]
+            CompressedOutput::Lz4(out) => out.write(buf),
+            #[cfg(feature = "brotli")]
+            CompressedOutput::Brotli(out) => out.write(buf),
+            #[cfg(feature = "bzip2")]
+            CompressedOutput::Bzip2(out) => out.write(buf),
+            #[cfg(feature = "gzip")]
+            CompressedOutput::Gzip(out) => out.write(buf),
+            #[cfg(feature = "zopfli")]
+            CompressedOutput::Zopfli(out) => out.write(buf),
+            #[cfg(feature = "xz")]
+            CompressedOutput::Xz(out) => out.write(buf),
+            #[cfg(feature = "zstd")]
+            CompressedOutput::Zstd(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CompressedOutput::Plain(out) => out.flush(),
+            CompressedOutput::Program(out) => out.flush(),
+            #[cfg(feature = "chunk")]
+            CompressedOutput::Chunked(out) => out.flush(),
+            #[cfg(feature = "lzip")]
+            CompressedOutput::Lzip(out) => out.flush(),
+            #[cfg(feature = "lz4")]
+            CompressedOutput::Lz4(out) => out.flush(),
+            #[cfg(feature = "brotli")]
+            CompressedOutput::Brotli(out) => out.flush(),
+            #[cfg(feature = "bzip2")]
+            CompressedOutput::Bzip2(out) => out.flush(),
+            #[cfg(feature = "gzip")]
+            CompressedOutput::Gzip(out) => out.flush(),
+            #[cfg(feature = "zopfli")]
+            CompressedOutput::Zopfli(out) => out.flush(),
+            #[cfg(feature = "xz")]
+            CompressedOutput::Xz(out) => out.flush(),
+            #[cfg(feature = "zstd")]
+            CompressedOutput::Zstd(out) => out.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn compressed(compression: Compression, content: &[u8], threads: usize) -> Vec<u8> {
+        let mut out = compression.writer(Vec::new(), threads).unwrap();
+        out.write_all(content).unwrap();
+        out.finish().unwrap()
+    }
+
+    /// decompresses with the reader of the library of each format
+    fn decompressed(compression: Compression, data: &[u8]) -> Vec<u8> {
+        let mut reader: Box<dyn Read + '_> = match compression {
+            Compression::None => Box::new(data),
+            #[cfg(feature = "lzip")]
+            Compression::Lzip => Box::new(lzma_rust2::LzipReader::new(data)),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(data)),
+            #[cfg(feature = "brotli")]
+            Compression::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => Box::new(bzip2::read::BzDecoder::new(data)),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip | Compression::GzipRsyncable | Compression::Zopfli => {
+                Box::new(flate2::read::MultiGzDecoder::new(data))
+            }
+            #[cfg(feature = "xz")]
+            Compression::Xz => Box::new(lzma_rust2::XzReader::new(data, false)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) | Compression::ZstdSeekable(_) => {
+                Box::new(zstd::stream::read::Decoder::new(data).unwrap())
+            }
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("{:?} is disabled", compression),
+        };
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn compressed_output_is_decompressed_again() {
+        // more than one block of gzip, partly compressible
+        let content: Vec<u8> = (0..140_000u32)
+            .map(|i| (i % 251) as u8 ^ (i / 4096) as u8 ^ ((i * 7) >> 11) as u8)
+            .collect();
+        let compressions = [
+            Compression::None,
+            Compression::Lzip,
+            Compression::Lz4,
+            Compression::Brotli,
+            Compression::Bzip2,
+            Compression::Gzip,
+            Compression::GzipRsyncable,
+            Compression::Zopfli,
+            Compression::Xz,
+            Compression::Zstd(ZSTD_DEFAULT_LEVEL),
+            Compression::ZstdSeekable(ZSTD_DEFAULT_LEVEL),
+        ];
+        for compression in compressions {
+            if compression.writer(Vec::new(), 1).is_err() {
+                // disabled at compile time
+                continue;
+            }
+            // the slowest ones get less
+            let content = match compression {
+                Compression::Zopfli => &content[..100],
+                Compression::Brotli => &content[..10_000],
+                _ => &content[..],
+            };
+            let data = compressed(compression, content, 1);
+            assert_eq!(
+                decompressed(compression, &data),
+                content,
+                "{:?}",
+                compression
+            );
+            // the output of the multi-threaded ones doesn't depend on the number of threads
+            if matches!(
+                compression,
+                Compression::Gzip
+                    | Compression::GzipRsyncable
+                    | Compression::Xz
+                    | Compression::Zstd(_)
+                    | Compression::ZstdSeekable(_)
+            ) {
+                assert_eq!(
+                    compressed(compression, content, 3),
+                    data,
+                    "{:?}",
+                    compression
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,270 @@
+#[cfg(feature = "sha512")]
+use crate::hash::{FileHasher, HashOptions};
+use std::io::{Read, Write};
+
+use crate::tar::TarStats;
+
+/// magic of the "new" portable ASCII format without checksums, as used for the Linux initramfs
+const NEWC_MAGIC: &[u8; 6] = b"070701";
+
+/// the archive is padded with zeros to a multiple of this size after the trailer, like GNU cpio
+const CPIO_BLOCK_SIZE: u64 = 512;
+
+/// returns the number of zero bytes to align length to 4 bytes
+fn padding4(length: u64) -> usize {
+    ((4 - length % 4) % 4) as usize
+}
+
+/// Writes deterministic cpio archives in the newc format.
+///
+/// Inode numbers are counted up from 1 in the order of the entries, like `cpio --reproducible`,
+/// because extractors treat entries with the same inode number and more than one link as
+/// hardlinks. Device numbers and modification times are zero, owner and group are 0 (root).
+/// Directories have mode 0755 and files 0644, like in the tar formats. Names are stored without
+/// trailing slashes. The statistics use the fields of [`TarStats`], the names and their padding
+/// count as header bytes.
+pub struct CpioOutput<W: Write> {
+    out_cpio: W,
+    /// the inode number of the next entry
+    next_inode: u32,
+    pub stats: TarStats,
+}
+
+impl<W: Write> CpioOutput<W> {
+    pub fn new(out_cpio: W) -> CpioOutput<W> {
+        CpioOutput {
+            out_cpio,
+            next_inode: 1,
+            stats: TarStats::default(),
+        }
+    }
+
+    /// writes the 110 byte header followed by the name, padded to 4 bytes
+    fn _write_header(
+        &mut self,
+        inode: u32,
+        name: &[u8],
+        mode: u32,
+        nlink: u32,
+        mtime: u32,
+        size: u64,
+    ) -> Result<(), std::io::Error> {
+        let filesize = u32::try_from(size).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{:?} has {} bytes, cpio newc only supports files up to 4 GiB",
+                    String::from_utf8_lossy(name),
+                    size
+                ),
+            )
+        })?;
+        // the name size includes the terminating NUL
+        let namesize = name.len() as u32 + 1;
+        // c_ino, c_mode, c_uid, c_gid, c_nlink, c_mtime, c_filesize, c_devmajor, c_devminor,
+        // c_rdevmajor, c_rdevminor, c_namesize and c_check, which is always zero for newc
+        let fields = [
+            inode, mode, 0, 0, nlink, mtime, filesize, 0, 0, 0, 0, namesize, 0,
+        ];
+        let mut header = Vec::with_capacity(110 + name.len() + 4);
+        header.extend_from_slice(NEWC_MAGIC);
+        for field in fields {
+            header.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        header.extend_from_slice(name);
+        header.push(0);
+        let padding = padding4(header.len() as u64);
+        header.extend_from_slice(&[0u8; 4][..padding]);
+        self.stats.header_bytes += header.len() as u64;
+        self.out_cpio.write_all(&header)
+    }
+
+    /// writes the header of a directory or file with the next inode number
+    fn _write_entry_header(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        nlink: u32,
+        mtime: u32,
+        size: u64,
+    ) -> Result<(), std::io::Error> {
+        let inode = self.next_inode;
+        self.next_inode = inode.checked_add(1).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cpio newc only supports 2^32 - 1 entries",
+            )
+        })?;
+        self._write_header(inode, name, mode, nlink, mtime, size)
+    }
+
+    pub fn cpio_write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error> {
+        self.cpio_write_dir_with_metadata(name, 0o755, None)
+    }
+
+    /// writes a directory with the given permissions instead of 0755 and, if given, a
+    /// modification time instead of zero, e.g. for the main directory
+    pub fn cpio_write_dir_with_metadata(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        mtime: Option<u64>,
+    ) -> Result<(), std::io::Error> {
+        let name = name.strip_suffix(b"/").unwrap_or(name);
+        let mtime = u32::try_from(mtime.unwrap_or(0)).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cpio newc only supports modification times up to 2106",
+            )
+        })?;
+        self._write_entry_header(name, 0o040000 | (mode & 0o7777), 2, mtime, 0)?;
+        self.stats.directories += 1;
+        Ok(())
+    }
+
+    pub fn cpio_write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut impl Write, HashOptions)>,
+        in_filedescriptor: &mut impl Read,
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self._write_entry_header(name, 0o100644, 1, 0, *size)?;
+        self.stats.files += 1;
+
+        let mut already_read = 0u64;
+        let mut buffer = [0u8; 64 * 1024];
+        #[cfg(feature = "sha512")]
+        let mut hasher = out_hash
+            .as_ref()
+            .map(|(_, options)| FileHasher::new(options.algorithm));
+        loop {
+            let n = in_filedescriptor.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            };
+            already_read += n as u64;
+            self.out_cpio.write_all(&buffer[0..n])?;
+            #[cfg(feature = "sha512")]
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[0..n]);
+            };
+        }
+        if already_read != *size {
+            panic!("size while reading different from stat");
+        }
+        let padding = padding4(already_read);
+        self.out_cpio.write_all(&[0u8; 4][..padding])?;
+        self.stats.content_bytes += already_read;
+        self.stats.padding_bytes += padding as u64;
+        #[cfg(feature = "sha512")]
+        if let (Some((out_hash, options)), Some(hasher)) = (out_hash.as_mut(), hasher) {
+            crate::hash::write_hash_line(out_hash, *options, hasher, name)?;
+        }
+        Ok(())
+    }
+
+    /// writes the "TRAILER!!!" entry and pads the archive to a multiple of 512 bytes
+    pub fn cpio_end_marker(&mut self) -> Result<(), std::io::Error> {
+        let before = self.stats.total_bytes();
+        self._write_header(0, b"TRAILER!!!", 0, 1, 0, 0)?;
+        let trailer = self.stats.total_bytes() - before;
+        self.stats.header_bytes -= trailer;
+        let padding = (CPIO_BLOCK_SIZE - (before + trailer) % CPIO_BLOCK_SIZE) % CPIO_BLOCK_SIZE;
+        self.out_cpio.write_all(&vec![0u8; padding as usize])?;
+        self.stats.end_marker_bytes += trailer + padding;
+        Ok(())
+    }
+
+    /// returns the underlying writer, usually after the end marker was written
+    pub fn into_inner(self) -> W {
+        self.out_cpio
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[derive(Debug, PartialEq)]
+    struct Entry {
+        inode: u32,
+        mode: u32,
+        nlink: u32,
+        name: Vec<u8>,
+        content: Vec<u8>,
+    }
+
+    fn entry(inode: u32, mode: u32, nlink: u32, name: &[u8], content: &[u8]) -> Entry {
+        Entry {
+            inode,
+            mode,
+            nlink,
+            name: name.to_vec(),
+            content: content.to_vec(),
+        }
+    }
+
+    /// reads the entries like an extractor
+    fn parse(cpio: &[u8]) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            assert_eq!(&cpio[offset..offset + 6], NEWC_MAGIC);
+            let field: Vec<u32> = (0..13)
+                .map(|i| {
+                    let hex = &cpio[offset + 6 + 8 * i..offset + 14 + 8 * i];
+                    u32::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).unwrap()
+                })
+                .collect();
+            let (namesize, filesize) = (field[11] as usize, field[6] as usize);
+            let name = cpio[offset + 110..offset + 110 + namesize - 1].to_vec();
+            offset += 110 + namesize;
+            offset += padding4(offset as u64);
+            let content = cpio[offset..offset + filesize].to_vec();
+            offset += filesize;
+            offset += padding4(offset as u64);
+            let end = name == b"TRAILER!!!";
+            entries.push(entry(field[0], field[1], field[4], &name, &content));
+            if end {
+                assert_eq!(cpio.len() as u64 % CPIO_BLOCK_SIZE, 0);
+                assert!(cpio[offset..].iter().all(|b| *b == 0));
+                return entries;
+            }
+        }
+    }
+
+    #[test]
+    fn nested_directories_round_trip() {
+        let mut out = CpioOutput::new(Vec::new());
+        out.cpio_write_dir_with_metadata(b"main/", 0o700, Some(1))
+            .unwrap();
+        for name in [&b"main/a/"[..], b"main/a/b/", b"main/c/"] {
+            out.cpio_write_dir(name).unwrap();
+        }
+        for (name, content) in [(&b"main/a/b/f"[..], &b"file"[..]), (b"main/c/g", b"")] {
+            out.cpio_write_file(
+                #[cfg(feature = "sha512")]
+                None::<(&mut Vec<u8>, HashOptions)>,
+                &mut Cursor::new(content),
+                &(content.len() as u64),
+                name,
+            )
+            .unwrap();
+        }
+        out.cpio_end_marker().unwrap();
+        assert_eq!(
+            parse(&out.into_inner()),
+            [
+                entry(1, 0o040700, 2, b"main", b""),
+                entry(2, 0o040755, 2, b"main/a", b""),
+                entry(3, 0o040755, 2, b"main/a/b", b""),
+                entry(4, 0o040755, 2, b"main/c", b""),
+                entry(5, 0o100644, 1, b"main/a/b/f", b"file"),
+                entry(6, 0o100644, 1, b"main/c/g", b""),
+                entry(0, 0, 1, b"TRAILER!!!", b""),
+            ]
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{
+    create_archive, json_escape, output_dirs, output_files, panic_message, validate_options,
+    DeterministicTarOpt,
+};
+use std::io::Write;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// converts a JSON job request of the daemon into command line arguments
+fn daemon_job_args(request: &str) -> Result<Vec<String>, String> {
+    let request: serde_json::Value =
+        serde_json::from_str(request).map_err(|e| format!("invalid JSON: {}", e))?;
+    let field = |key: &str| -> Result<Option<String>, String> {
+        match request.get(key) {
+            None => Ok(None),
+            Some(serde_json::Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(format!("\"{}\" must be a string", key)),
+        }
+    };
+    let input = field("input")?.ok_or_else(|| "\"input\" is missing".to_string())?;
+    let output = field("output")?.ok_or_else(|| "\"output\" is missing".to_string())?;
+    let mut args = vec![
+        "deterministic-tar".to_string(),
+        input,
+        "-o".to_string(),
+        output,
+    ];
+    match request.get("options") {
+        None => {}
+        Some(serde_json::Value::Array(options)) => {
+            for o in options {
+                match o {
+                    serde_json::Value::String(s) => args.push(s.clone()),
+                    _ => return Err("\"options\" must only contain strings".to_string()),
+                }
+            }
+        }
+        Some(_) => return Err("\"options\" must be an array".to_string()),
+    }
+    Ok(args)
+}
+
+/// returns true if path, which might not exist yet, is inside of the canonical directory dir: its
+/// directory must exist and it must not be a symlink
+fn is_inside(path: &std::path::Path, dir: &std::path::Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => return false,
+    };
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    let is_symlink = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink());
+    !is_symlink
+        && parent
+            .canonicalize()
+            .is_ok_and(|parent| parent.join(name).starts_with(dir))
+}
+
+/// fails if a job of the daemon cannot run in it: jobs must not run programs and must write all
+/// outputs to files below output_dir, which is canonical
+fn daemon_job_check(opt: &DeterministicTarOpt, output_dir: &std::path::Path) -> Result<(), String> {
+    let mut outputs = output_files(opt);
+    outputs.extend(opt.sealed_output.iter().cloned());
+    if opt.daemon
+        || opt.suggest_excludes
+        || opt.oci_layer
+        || opt.sandbox
+        || outputs.iter().any(|o| o == "-")
+    {
+        return Err("jobs must write all outputs to files and cannot use --daemon, --suggest-excludes, --oci-layer or --sandbox".to_string());
+    }
+    if !opt.add_command.is_empty()
+        || opt.use_compress_program.is_some()
+        || opt.sign_with_gpg.is_some()
+        || opt.sign_ssh.is_some()
+        || opt.output_template.is_some()
+    {
+        return Err("jobs cannot use --add-command, --use-compress-program, --sign-with-gpg, --sign-ssh or --output-template".to_string());
+    }
+    let mut outputs: Vec<PathBuf> = outputs.into_iter().map(PathBuf::from).collect();
+    outputs.extend(output_dirs(opt));
+    outputs.extend(opt.output_dir_hardlinks.iter().cloned());
+    match outputs.iter().find(|output| !is_inside(output, output_dir)) {
+        Some(output) => Err(format!(
+            "{:?} is not inside of --daemon-output-dir {:?} (its directory must exist and it must not be a symlink)",
+            output, output_dir
+        )),
+        None => Ok(()),
+    }
+}
+
+/// runs a single job of the daemon and reports progress and result to the client
+fn daemon_job(
+    request: &str,
+    output_dir: &std::path::Path,
+    client: &mut impl Write,
+) -> std::io::Result<()> {
+    let (opt, args) = match daemon_job_args(request).and_then(|args| {
+        let opt = DeterministicTarOpt::from_iter_safe(&args).map_err(|e| e.message)?;
+        validate_options(&opt)?;
+        daemon_job_check(&opt, output_dir)?;
+        Ok((opt, args))
+    }) {
+        Ok(job) => job,
+        Err(e) => return writeln!(client, "{{\"error\": \"{}\"}}", json_escape(&e)),
+    };
+    // errors are reported by panics, which must only abort the job but not the daemon
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        create_archive(&opt, &args[1..], &mut |entries| {
+            if entries % 1000 == 0 {
+                // a client which went away is noticed when the result is sent
+                let _ = writeln!(client, "{{\"progress\": {{\"entries\": {}}}}}", entries);
+            }
+        })
+    }));
+    match result {
+        Ok(stats) => writeln!(
+            client,
+            "{{\"done\": {{\"entries\": {}, \"total_bytes\": {}}}}}",
+            stats.entries(),
+            stats.total_bytes()
+        ),
+        Err(e) => writeln!(
+            client,
+            "{{\"error\": \"{}\"}}",
+            json_escape(&panic_message(e.as_ref()))
+        ),
+    }
+}
+
+pub fn daemon_main(listen: &std::path::Path, output_dir: &std::path::Path) {
+    use std::io::BufRead;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+
+    // remove a stale socket of a previous daemon, but never any other file
+    if let Ok(meta) = std::fs::symlink_metadata(listen) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(listen).unwrap();
+        }
+    }
+    let output_dir =
+        std::sync::Arc::new(output_dir.canonicalize().unwrap_or_else(|e| {
+            panic!("could not open --daemon-output-dir {:?}: {}", output_dir, e)
+        }));
+    // jobs run with the permissions of the daemon, so only its owner may connect: the socket is
+    // created with mode 0600. No other thread runs yet, which could create files meanwhile.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(listen);
+    unsafe { libc::umask(umask) };
+    let listener = listener.unwrap_or_else(|_| panic!("could not listen on {:?}", listen));
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(_) => continue,
+        };
+        let output_dir = output_dir.clone();
+        std::thread::spawn(move || {
+            let mut writer = client.try_clone().unwrap();
+            for line in std::io::BufReader::new(client).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => return,
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if daemon_job(&line, &output_dir, &mut writer).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a fresh, canonical directory for the outputs of the jobs
+    fn output_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "deterministic-tar-daemon-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    fn check(output_dir: &std::path::Path, options: &[&str]) -> Result<(), String> {
+        let output = output_dir.join("out.tar");
+        let mut args = vec!["deterministic-tar", ".", "-o", output.to_str().unwrap()];
+        args.extend_from_slice(options);
+        daemon_job_check(&DeterministicTarOpt::from_iter(args), output_dir)
+    }
+
+    #[test]
+    fn requests_are_converted_to_arguments() {
+        assert_eq!(
+            daemon_job_args(r#"{"input": "in", "output": "out.tar", "options": ["-z"]}"#),
+            Ok(vec![
+                "deterministic-tar".to_string(),
+                "in".to_string(),
+                "-o".to_string(),
+                "out.tar".to_string(),
+                "-z".to_string()
+            ])
+        );
+        for invalid in [
+            "",
+            "{",
+            r#"{"output": "out.tar"}"#,
+            r#"{"input": "in"}"#,
+            r#"{"input": 1, "output": "out.tar"}"#,
+            r#"{"input": "in", "output": "out.tar", "options": "-z"}"#,
+            r#"{"input": "in", "output": "out.tar", "options": [1]}"#,
+        ] {
+            assert!(daemon_job_args(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn jobs_must_stay_inside_of_the_output_dir() {
+        let dir = output_dir("inside");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::os::unix::fs::symlink(std::env::temp_dir(), dir.join("link")).unwrap();
+        std::os::unix::fs::symlink("elsewhere", dir.join("dangling")).unwrap();
+        assert!(is_inside(&dir.join("out.tar"), &dir));
+        assert!(is_inside(&dir.join("sub/out.tar"), &dir));
+        assert!(is_inside(&dir.join("sub/../out.tar"), &dir));
+        assert!(!is_inside(&dir.join("../out.tar"), &dir));
+        assert!(!is_inside(&dir.join("missing/out.tar"), &dir));
+        assert!(!is_inside(&dir.join("link/out.tar"), &dir));
+        assert!(!is_inside(&dir.join("dangling"), &dir));
+        assert!(!is_inside(&dir, &dir.join("sub")));
+
+        assert_eq!(check(&dir, &[]), Ok(()));
+        assert_eq!(
+            check(
+                &dir,
+                &["--output-hash", dir.join("sub/hashes").to_str().unwrap()]
+            ),
+            Ok(())
+        );
+        assert!(check(&dir, &["--output-hash", "hashes"]).is_err());
+        assert!(check(
+            &dir,
+            &["--output-hash", dir.join("link/hashes").to_str().unwrap()]
+        )
+        .is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn jobs_cannot_run_programs_or_write_to_stdout() {
+        let dir = output_dir("programs");
+        for options in [
+            &["--add-command", "x=true"][..],
+            &["--use-compress-program", "gzip"],
+            &["--sign-with-gpg", "key"],
+            &["--sign-ssh", "key"],
+            &["--sandbox"],
+            &["--suggest-excludes"],
+            &["--output-hash", "-"],
+        ] {
+            assert!(check(&dir, options).is_err(), "{:?}", options);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_jobs_are_reported_to_the_client() {
+        let dir = output_dir("report");
+        let mut client = Vec::new();
+        daemon_job(r#"{"input": "."}"#, &dir, &mut client).unwrap();
+        assert_eq!(client, b"{\"error\": \"\\\"output\\\" is missing\"}\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{digest_of_file, report_stdout};
+use deterministic_tar::hash::{FileHasher, HashAlgorithm};
+use deterministic_tar::reader::{ParseMode, ReaderOptions, TarReader};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "deterministic-tar diff",
+    about = "Compare two tar archives entry by entry, e.g. when a reproducible build suddenly produces a different archive. Added, removed and changed entries (type, size, content, link target, permissions, owner and modification time) are printed, followed by a summary. The exit code is 1 if the archives differ."
+)]
+pub struct DiffOpt {
+    /// old tar archive
+    #[structopt(parse(from_os_str))]
+    old: PathBuf,
+
+    /// new tar archive
+    #[structopt(parse(from_os_str))]
+    new: PathBuf,
+
+    /// accept archives which are malformed but still readable, like verify --lenient
+    #[structopt(long)]
+    lenient: bool,
+}
+
+/// what the diff subcommand compares of an entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct DiffEntry {
+    typeflag: u8,
+    size: u64,
+    digest: String,
+    linkname: Vec<u8>,
+    mode: u64,
+    owner: (Vec<u8>, Vec<u8>, u64, u64),
+    mtime: i64,
+}
+
+/// reads all entries of an archive for the diff subcommand, returns them by name and the names
+/// in the order of the archive
+fn diff_entries(
+    archive: &std::path::Path,
+    lenient: bool,
+) -> (BTreeMap<Vec<u8>, DiffEntry>, Vec<Vec<u8>>) {
+    let in_tar =
+        File::open(archive).unwrap_or_else(|_| panic!("could not open file {:?}", archive));
+    let reader_options = ReaderOptions {
+        mode: if lenient {
+            ParseMode::Lenient
+        } else {
+            ParseMode::Strict
+        },
+        ..ReaderOptions::default()
+    };
+    let mut reader = TarReader::with_options(BufReader::new(in_tar), reader_options);
+    let mut entries = BTreeMap::new();
+    let mut order = vec![];
+    while let Some(entry) = reader
+        .next_entry()
+        .unwrap_or_else(|e| panic!("could not read {:?}: {}", archive, e))
+    {
+        let mut hasher = FileHasher::new(HashAlgorithm::Sha512);
+        reader
+            .read_content(&mut hasher)
+            .unwrap_or_else(|e| panic!("could not read {:?}: {}", archive, e));
+        let diff_entry = DiffEntry {
+            // old archives use NUL for regular files
+            typeflag: if entry.typeflag == 0 {
+                b'0'
+            } else {
+                entry.typeflag
+            },
+            size: entry.size,
+            digest: hasher.hex_digest(),
+            linkname: entry.linkname,
+            mode: entry.mode,
+            owner: (entry.uname, entry.gname, entry.uid, entry.gid),
+            mtime: entry.mtime,
+        };
+        // later entries replace earlier ones with the same name like when the archive is extracted
+        if entries.insert(entry.name.clone(), diff_entry).is_none() {
+            order.push(entry.name);
+        }
+    }
+    (entries, order)
+}
+
+/// returns all differences of two entries with the same name
+fn compare_diff_entries(old: &DiffEntry, new: &DiffEntry) -> Vec<String> {
+    let mut reasons = vec![];
+    if old.typeflag != new.typeflag {
+        reasons.push(format!(
+            "type {:?} -> {:?}",
+            old.typeflag as char, new.typeflag as char
+        ));
+    }
+    if old.size != new.size {
+        reasons.push(format!("size {} -> {}", old.size, new.size));
+    } else if old.digest != new.digest {
+        reasons.push("content".to_string());
+    }
+    if old.linkname != new.linkname {
+        reasons.push(format!(
+            "link target {:?} -> {:?}",
+            String::from_utf8_lossy(&old.linkname),
+            String::from_utf8_lossy(&new.linkname)
+        ));
+    }
+    if old.mode != new.mode {
+        reasons.push(format!("mode {:o} -> {:o}", old.mode, new.mode));
+    }
+    if old.owner != new.owner {
+        let owner = |(uname, gname, uid, gid): &(Vec<u8>, Vec<u8>, u64, u64)| {
+            format!(
+                "{}/{} ({}/{})",
+                String::from_utf8_lossy(uname),
+                String::from_utf8_lossy(gname),
+                uid,
+                gid
+            )
+        };
+        reasons.push(format!(
+            "owner {} -> {}",
+            owner(&old.owner),
+            owner(&new.owner)
+        ));
+    }
+    if old.mtime != new.mtime {
+        reasons.push(format!("mtime {} -> {}", old.mtime, new.mtime));
+    }
+    reasons
+}
+
+pub fn diff_main(opt: DiffOpt) {
+    let (old, old_order) = diff_entries(&opt.old, opt.lenient);
+    let (new, new_order) = diff_entries(&opt.new, opt.lenient);
+    let mut stdout = report_stdout();
+    let (mut added, mut removed, mut changed) = (0u64, 0u64, 0u64);
+    let names: BTreeSet<&Vec<u8>> = old.keys().chain(new.keys()).collect();
+    for name in names {
+        let display = String::from_utf8_lossy(name);
+        match (old.get(name), new.get(name)) {
+            (Some(_), None) => {
+                writeln!(stdout, "REMOVED\t{}", display).unwrap();
+                removed += 1;
+            }
+            (None, Some(_)) => {
+                writeln!(stdout, "ADDED\t{}", display).unwrap();
+                added += 1;
+            }
+            (Some(a), Some(b)) => {
+                let reasons = compare_diff_entries(a, b);
+                if !reasons.is_empty() {
+                    writeln!(stdout, "CHANGED\t{}\t{}", display, reasons.join(", ")).unwrap();
+                    changed += 1;
+                }
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    // the same entries in a different order also change the archive
+    let old_common = old_order.iter().filter(|name| new.contains_key(*name));
+    let new_common = new_order.iter().filter(|name| old.contains_key(*name));
+    let reordered = old_common.zip(new_common).find(|(a, b)| a != b);
+    if let Some((name, _)) = reordered {
+        writeln!(
+            stdout,
+            "ORDER\t{}\tthe entries are in a different order from here on",
+            String::from_utf8_lossy(name)
+        )
+        .unwrap();
+    }
+    let differs = added + removed + changed > 0 || reordered.is_some();
+    let same_bytes = !differs
+        && digest_of_file(&opt.old, HashAlgorithm::Sha512).unwrap()
+            == digest_of_file(&opt.new, HashAlgorithm::Sha512).unwrap();
+    if !differs && !same_bytes {
+        writeln!(
+            stdout,
+            "ENCODING\tthe entries are the same, but the headers, padding or end marker differ"
+        )
+        .unwrap();
+    }
+    writeln!(
+        stdout,
+        "{} entries added, {} removed, {} changed",
+        added, removed, changed
+    )
+    .unwrap();
+    if !same_bytes {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,111 @@
+use sha2::{Digest, Sha512};
+use std::fs::{File, FileTimes, Permissions};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// returns the SHA512 of everything read from input
+fn sha512_of(input: &mut impl Read) -> Result<Vec<u8>, std::io::Error> {
+    let mut hasher = Sha512::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Materializes the archived tree as a directory, rsnapshot-style: a file whose content is
+/// identical to the file with the same name in the previous snapshot is a hardlink to it, all
+/// other files are copies. Files are compared by size and SHA512.
+///
+/// Like in the archive, files have mode 0644, directories 0755 and all modification times are
+/// the epoch, so the snapshots don't depend on the metadata of the input either.
+pub struct HardlinkFarm {
+    dir: PathBuf,
+    link_dest: Option<PathBuf>,
+    /// number of files which were linked to the previous snapshot and which were copied
+    pub linked: u64,
+    pub copied: u64,
+}
+
+impl HardlinkFarm {
+    /// creates the directory of the snapshot, which must not exist yet
+    pub fn new(dir: &Path, link_dest: Option<&Path>) -> Result<HardlinkFarm, std::io::Error> {
+        std::fs::create_dir(dir)?;
+        Ok(HardlinkFarm {
+            dir: dir.to_path_buf(),
+            link_dest: link_dest.map(|p| p.to_path_buf()),
+            linked: 0,
+            copied: 0,
+        })
+    }
+
+    /// sets the fixed permissions and modification time of a file or directory
+    fn _normalize(path: &Path, mode: u32) -> Result<(), std::io::Error> {
+        std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+        File::open(path)?.set_times(FileTimes::new().set_modified(SystemTime::UNIX_EPOCH))
+    }
+
+    /// creates a directory, the name is relative to the snapshot
+    pub fn add_dir(&mut self, name: &Path) -> Result<(), std::io::Error> {
+        let path = self.dir.join(name);
+        if !path.is_dir() {
+            std::fs::create_dir(&path)?;
+        }
+        Ok(())
+    }
+
+    /// adds a file with the given content, which is read once or twice from the start.
+    pub fn add_file(
+        &mut self,
+        name: &Path,
+        content: &mut (impl Read + std::io::Seek),
+        size: u64,
+    ) -> Result<(), std::io::Error> {
+        let path = self.dir.join(name);
+        if let Some(previous) = self.link_dest.as_ref().map(|d| d.join(name)) {
+            let same_size = std::fs::symlink_metadata(&previous)
+                .map(|m| m.is_file() && m.len() == size)
+                .unwrap_or(false);
+            if same_size && sha512_of(content)? == sha512_of(&mut File::open(&previous)?)? {
+                std::fs::hard_link(&previous, &path)?;
+                self.linked += 1;
+                return Ok(());
+            }
+            content.seek(std::io::SeekFrom::Start(0))?;
+        }
+        let mut file = File::create(&path)?;
+        std::io::copy(content, &mut file)?;
+        file.flush()?;
+        Self::_normalize(&path, 0o644)?;
+        self.copied += 1;
+        Ok(())
+    }
+
+    /// creates a symlink to the target, which is stored as it is. Its modification time is the
+    /// time it was created, because it cannot be set without following the link.
+    pub fn add_symlink(&mut self, name: &Path, target: &Path) -> Result<(), std::io::Error> {
+        std::os::unix::fs::symlink(target, self.dir.join(name))
+    }
+
+    /// sets the permissions and modification times of all directories, after all files were
+    /// added because adding a file changes the modification time of its directory
+    pub fn finish(self) -> Result<(), std::io::Error> {
+        let mut directories = vec![self.dir.clone()];
+        while let Some(dir) = directories.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    directories.push(entry.path());
+                }
+            }
+            Self::_normalize(&dir, 0o755)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+/// A gitignore-style glob, e.g. "*.o", "**/node_modules", "/build" or "target/debug/", which is
+/// matched against the path of an entry below the walked path (e.g. "src/main.o"). Like in
+/// .gitignore files, a glob without "/" (except at the end) matches in every directory, other
+/// globs are relative to the walked path and a trailing "/" only matches directories. "*" and "?"
+/// don't match "/", "**" matches any number of directories and "\" escapes the next character.
+/// A leading "!" negates the glob: the entries which it matches are included again.
+#[derive(Clone, Debug)]
+pub struct Glob {
+    pattern: String,
+    regex: Regex,
+    dir_only: bool,
+    negated: bool,
+}
+
+impl Glob {
+    /// returns true if the glob starts with "!" and includes the entries which it matches
+    pub fn is_negated(&self) -> bool {
+        self.negated
+    }
+
+    /// returns true if the glob only matches directories, because it ends with "/"
+    pub fn dir_only(&self) -> bool {
+        self.dir_only
+    }
+
+    /// returns true if the glob matches the path below the walked path, which is a directory if
+    /// `is_dir` is true
+    pub fn is_match(&self, relpath: &str, is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && self.regex.is_match(relpath)
+    }
+}
+
+impl std::str::FromStr for Glob {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Glob, String> {
+        let negated = s.starts_with('!');
+        let body = s.strip_prefix('!').unwrap_or(s);
+        let dir_only = body.ends_with('/');
+        let body = body.trim_end_matches('/');
+        let anchored = body.contains('/');
+        let body = body.strip_prefix('/').unwrap_or(body);
+        if body.is_empty() {
+            return Err(format!("glob {:?} matches nothing", s));
+        }
+        let prefix = if anchored { "" } else { "(?:.*/)?" };
+        let regex = Regex::new(&format!("^{}{}$", prefix, glob_to_regex(body)))
+            .map_err(|e| format!("invalid glob {:?}: {}", s, e))?;
+        Ok(Glob {
+            pattern: s.to_string(),
+            regex,
+            dir_only,
+            negated,
+        })
+    }
+}
+
+impl std::fmt::Display for Glob {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+/// translates the wildcards of a glob without the leading and trailing "/" into a regular
+/// expression, which is not anchored yet
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') && (i == 0 || chars[i - 1] == '/') => {
+                match chars.get(i + 2) {
+                    // "dir/**" matches everything inside of dir
+                    None => out.push_str(".*"),
+                    // "**/" matches zero or more directories
+                    Some('/') => {
+                        out.push_str("(?:.*/)?");
+                        i += 1;
+                    }
+                    // other "**" are like "*"
+                    Some(_) => out.push_str("[^/]*"),
+                }
+                i += 2;
+            }
+            '*' => {
+                out.push_str("[^/]*");
+                // "a**b" is the same as "a*b"
+                while chars.get(i + 1) == Some(&'*') {
+                    i += 1;
+                }
+                i += 1;
+            }
+            '?' => {
+                out.push_str("[^/]");
+                i += 1;
+            }
+            '\\' if i + 1 < chars.len() => {
+                out.push_str(&regex::quote(&chars[i + 1].to_string()));
+                i += 2;
+            }
+            '[' => match class_to_regex(&chars[i + 1..]) {
+                Some((class, len)) => {
+                    out.push_str(&class);
+                    i += len + 1;
+                }
+                // without a closing "]", it is a literal "["
+                None => {
+                    out.push_str("\\[");
+                    i += 1;
+                }
+            },
+            c => {
+                out.push_str(&regex::quote(&c.to_string()));
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// translates a character class like "[a-z]" or "[!0-9]" (given without the "[") into a regular
+/// expression, together with the number of characters it used up including the "]"
+fn class_to_regex(chars: &[char]) -> Option<(String, usize)> {
+    let negated = matches!(chars.first(), Some('!') | Some('^'));
+    let start = usize::from(negated);
+    // a "]" directly after the "[" (or "[!") is part of the class
+    let end = start + 1 + chars.get(start + 1..)?.iter().position(|&c| c == ']')?;
+    let members = &chars[start..end];
+    let mut class = String::from(if negated { "[^/" } else { "[" });
+    for (i, &c) in members.iter().enumerate() {
+        if c == '-' && i > 0 && i + 1 < members.len() {
+            class.push('-');
+        } else {
+            class.push_str(&regex::quote(&c.to_string()));
+        }
+    }
+    class.push(']');
+    Some((class, end + 1))
+}
+
+/// returns the lines of a file with one glob per line, together with their number: like in
+/// .gitignore files, empty lines and lines starting with "#" are skipped and trailing whitespace
+/// is removed
+pub fn glob_lines(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(number, line)| (number + 1, line.trim_end()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// The rules of a .gitignore file (or .git/info/exclude), which apply to the entries below its
+/// directory. The last rule which matches an entry decides: a glob excludes it, a negated glob
+/// includes it again.
+#[derive(Clone, Debug)]
+pub struct Gitignore {
+    dir: PathBuf,
+    rules: Vec<Glob>,
+}
+
+impl Gitignore {
+    /// returns the existing files with rules for the entries of a directory, in the order of
+    /// their precedence: .git/info/exclude of a repository before .gitignore
+    pub fn files(dir: &Path) -> Vec<PathBuf> {
+        [dir.join(".git/info/exclude"), dir.join(".gitignore")]
+            .into_iter()
+            .filter(|file| file.is_file())
+            .collect()
+    }
+
+    /// reads the rules of a file for the entries below dir. Invalid globs are skipped, like by
+    /// git.
+    pub fn read(file: &Path, dir: &Path) -> Result<Gitignore, std::io::Error> {
+        let content = std::fs::read(file)?;
+        let content = String::from_utf8_lossy(&content);
+        let rules = glob_lines(&content)
+            .filter_map(|(_, line)| line.parse().ok())
+            .collect();
+        Ok(Gitignore {
+            dir: dir.to_path_buf(),
+            rules,
+        })
+    }
+
+    /// the directory whose entries the rules apply to
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// returns the last rule which matches the path (below the directory of the rules), or None
+    /// if no rule matches
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Option<&Glob> {
+        let relpath = path.strip_prefix(&self.dir).ok()?.to_string_lossy();
+        self.rules
+            .iter()
+            .rev()
+            .find(|glob| glob.is_match(&relpath, is_dir))
+    }
+}
+
+/// returns the directories whose rules apply to the entries of dir: the root of the git
+/// repository which contains it and all directories below down to dir itself, or only dir if it
+/// is not inside of a repository
+pub fn repository_dirs(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for d in dir.ancestors() {
+        dirs.push(d.to_path_buf());
+        if d.join(".git").exists() {
+            dirs.reverse();
+            return dirs;
+        }
+    }
+    vec![dir.to_path_buf()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_like_gitignore() {
+        // glob, path below the walked path, whether it is a directory, expected match
+        let cases = [
+            ("*.o", "main.o", false, true),
+            ("*.o", "src/main.o", false, true),
+            ("*.o", "main.c", false, false),
+            ("*.o", "main.o/x", false, false),
+            ("/build", "build", true, true),
+            ("/build", "src/build", true, false),
+            ("src/*.rs", "src/main.rs", false, true),
+            ("src/*.rs", "src/bin/main.rs", false, false),
+            ("src/*.rs", "lib/src/main.rs", false, false),
+            ("?.txt", "a.txt", false, true),
+            ("?.txt", "ab.txt", false, false),
+            ("a?b", "a/b", false, false),
+            // "**"
+            ("**/node_modules", "node_modules", true, true),
+            ("**/node_modules", "a/b/node_modules", true, true),
+            ("a/**/b", "a/b", false, true),
+            ("a/**/b", "a/x/y/b", false, true),
+            ("a/**/b", "ab", false, false),
+            ("a/**", "a/x/y", false, true),
+            ("a/**", "a", true, false),
+            ("x**y", "x/y", false, false),
+            ("x**y", "xzzy", false, true),
+            // character classes
+            ("[a-c].txt", "b.txt", false, true),
+            ("[a-c].txt", "d.txt", false, false),
+            ("[!a-c].txt", "d.txt", false, true),
+            ("[!a-c].txt", "a.txt", false, false),
+            ("[^a-c].txt", "d.txt", false, true),
+            ("[!a]", "/", false, false),
+            ("[]]", "]", false, true),
+            ("[a-]", "-", false, true),
+            ("[abc", "[abc", false, true),
+            // escapes and regex characters are literal
+            ("\\*.txt", "*.txt", false, true),
+            ("\\*.txt", "a.txt", false, false),
+            ("a+b.(c)", "a+b.(c)", false, true),
+            ("a+b.(c)", "aab.xc", false, false),
+            // a trailing "/" only matches directories
+            ("target/", "target", true, true),
+            ("target/", "target", false, false),
+            ("target/", "a/target", true, true),
+            ("target/debug/", "target/debug", true, true),
+            ("target/debug/", "a/target/debug", true, false),
+        ];
+        for (glob, path, is_dir, expected) in cases {
+            let parsed: Glob = glob.parse().unwrap();
+            assert_eq!(
+                parsed.is_match(path, is_dir),
+                expected,
+                "{:?} on {:?} (directory: {})",
+                glob,
+                path,
+                is_dir
+            );
+        }
+    }
+
+    #[test]
+    fn globs_are_parsed() {
+        // glob, negated, directories only
+        let cases = [
+            ("*.o", false, false),
+            ("!*.o", true, false),
+            ("target/", false, true),
+            ("!target/", true, true),
+            ("\\!a", false, false),
+        ];
+        for (glob, negated, dir_only) in cases {
+            let parsed: Glob = glob.parse().unwrap();
+            assert_eq!(parsed.is_negated(), negated, "{:?}", glob);
+            assert_eq!(parsed.dir_only(), dir_only, "{:?}", glob);
+            assert_eq!(parsed.to_string(), glob);
+        }
+        // the pattern of a negated glob is matched like any other
+        let negated: Glob = "!important.o".parse().unwrap();
+        assert!(negated.is_match("src/important.o", false));
+        for glob in ["", "!", "/", "!/", "//"] {
+            assert!(glob.parse::<Glob>().is_err(), "{:?}", glob);
+        }
+    }
+
+    #[test]
+    fn last_matching_rule_decides() {
+        let rules = glob_lines("# objects\n*.o\n\n!keep.o  \nkeep.o/\n")
+            .map(|(_, line)| line.parse().unwrap())
+            .collect();
+        let gitignore = Gitignore {
+            dir: PathBuf::from("/repo"),
+            rules,
+        };
+        let matched = |path: &str, is_dir: bool| {
+            gitignore
+                .matched(Path::new(path), is_dir)
+                .map(|glob| glob.to_string())
+        };
+        assert_eq!(matched("/repo/a/main.o", false).as_deref(), Some("*.o"));
+        assert_eq!(matched("/repo/a/keep.o", false).as_deref(), Some("!keep.o"));
+        assert_eq!(matched("/repo/keep.o", true).as_deref(), Some("keep.o/"));
+        assert_eq!(matched("/repo/main.c", false), None);
+        // only the entries below the directory of the rules
+        assert_eq!(matched("/other/main.o", false), None);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::cdc::GearChunker;
+use flate2::{Compress, Crc, FlushCompress, Status};
+use std::io::Write;
+
+/// size of the uncompressed blocks which are compressed independently, like the default of pigz
+pub const GZIP_BLOCK_SIZE: usize = 128 * 1024;
+
+/// with [`ParallelGzipWriter::rsyncable`], blocks end at content-defined boundaries: where the
+/// lowest 16 bits of the rolling hash are zero, so blocks have an average size of about 128 KiB
+/// like the fixed blocks, but not before the minimum and at the latest at the maximum size
+pub const GZIP_RSYNCABLE_MIN_SIZE: usize = 64 * 1024;
+pub const GZIP_RSYNCABLE_MAX_SIZE: usize = 512 * 1024;
+const GZIP_RSYNCABLE_MASK: u64 = (1 << 16) - 1;
+
+/// deflate compression level, like the default of gzip and pigz
+pub const GZIP_LEVEL: u32 = 6;
+
+/// gzip header without file name and with a modification time of zero, OS is "unknown"
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+
+/// A gzip compressor which splits the input into blocks of [`GZIP_BLOCK_SIZE`] bytes and
+/// compresses up to `threads` of them in parallel.
+///
+/// Every block is compressed without the content of the previous block as dictionary and ends
+/// with a sync flush, so the block boundaries only depend on the input and the output is
+/// identical for any number of threads.
+///
+/// [`ParallelGzipWriter::rsyncable`] ends the blocks at content-defined boundaries instead, like
+/// `gzip --rsyncable`. A change of the input then only changes the compressed blocks around it,
+/// so rsync and other delta transfers of the compressed archive stay small.
+pub struct ParallelGzipWriter<W: Write> {
+    out: W,
+    threads: usize,
+    chunker: Option<GearChunker>,
+    /// full blocks which are not compressed yet
+    pending: Vec<Vec<u8>>,
+    current: Vec<u8>,
+    crc: Crc,
+    header_written: bool,
+}
+
+/// compresses a block to raw deflate. All but the last block end with a sync flush, so they
+/// end on a byte boundary and can be concatenated.
+fn deflate_block(data: &[u8], last: bool) -> Vec<u8> {
+    let mut compress = Compress::new(flate2::Compression::new(GZIP_LEVEL), false);
+    let flush = if last {
+        FlushCompress::Finish
+    } else {
+        FlushCompress::Sync
+    };
+    let mut out = Vec::with_capacity(data.len() + data.len() / 16 + 64);
+    loop {
+        let input = &data[compress.total_in() as usize..];
+        let status = compress
+            .compress_vec(input, &mut out, flush)
+            .expect("deflate failed");
+        // the block is complete if the output buffer had space left after consuming all input
+        let consumed = compress.total_in() as usize == data.len();
+        if status == Status::StreamEnd || (!last && consumed && out.len() < out.capacity()) {
+            return out;
+        }
+        out.reserve(64 * 1024);
+    }
+}
+
+impl<W: Write> ParallelGzipWriter<W> {
+    pub fn new(out: W, threads: usize) -> ParallelGzipWriter<W> {
+        ParallelGzipWriter {
+            out,
+            threads: std::cmp::max(threads, 1),
+            chunker: None,
+            pending: Vec::new(),
+            current: Vec::with_capacity(GZIP_BLOCK_SIZE),
+            crc: Crc::new(),
+            header_written: false,
+        }
+    }
+
+    /// ends the blocks at content-defined boundaries
+    pub fn rsyncable(out: W, threads: usize) -> ParallelGzipWriter<W> {
+        ParallelGzipWriter {
+            chunker: Some(GearChunker::new(
+                GZIP_RSYNCABLE_MIN_SIZE,
+                GZIP_RSYNCABLE_MAX_SIZE,
+                GZIP_RSYNCABLE_MASK,
+            )),
+            ..ParallelGzipWriter::new(out, threads)
+        }
+    }
+
+    fn _write_header(&mut self) -> Result<(), std::io::Error> {
+        if !self.header_written {
+            self.out.write_all(&GZIP_HEADER)?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    /// compresses all pending blocks and writes them in order
+    fn _compress_pending(&mut self) -> Result<(), std::io::Error> {
+        self._write_header()?;
+        let compressed: Vec<Vec<u8>> = if self.threads == 1 {
+            self.pending
+                .iter()
+                .map(|b| deflate_block(b, false))
+                .collect()
+        } else {
+            std::thread::scope(|s| {
+                let handles: Vec<_> = self
+                    .pending
+                    .iter()
+                    .map(|b| s.spawn(move || deflate_block(b, false)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().expect("compression thread panicked"))
+                    .collect()
+            })
+        };
+        for c in compressed {
+            self.out.write_all(&c)?;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// compresses the remaining input, writes the gzip trailer and returns the underlying writer
+    pub fn finish(mut self) -> Result<W, std::io::Error> {
+        self._compress_pending()?;
+        self.out.write_all(&deflate_block(&self.current, true))?;
+        self.out.write_all(&self.crc.sum().to_le_bytes())?;
+        self.out.write_all(&self.crc.amount().to_le_bytes())?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for ParallelGzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let (n, full) = match self.chunker.as_mut() {
+            Some(chunker) => match chunker.next_boundary(buf) {
+                Some(n) => (n, true),
+                None => (buf.len(), false),
+            },
+            None => {
+                let n = std::cmp::min(buf.len(), GZIP_BLOCK_SIZE - self.current.len());
+                (n, self.current.len() + n == GZIP_BLOCK_SIZE)
+            }
+        };
+        self.current.extend_from_slice(&buf[..n]);
+        self.crc.update(&buf[..n]);
+        if full {
+            let block = std::mem::replace(&mut self.current, Vec::with_capacity(GZIP_BLOCK_SIZE));
+            self.pending.push(block);
+            if self.pending.len() == self.threads {
+                self._compress_pending()?;
+            }
+        }
+        Ok(n)
+    }
+
+    /// only flushes the underlying writer, buffered input stays buffered to keep the block
+    /// boundaries fixed
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// size of the chunks which are passed to zopfli, each chunk can refer to the previous 32 KiB
+#[cfg(feature = "zopfli")]
+pub const ZOPFLI_CHUNK_SIZE: usize = 1000 * 1000;
+
+/// A gzip compressor using zopfli with its default options, which results in the smallest
+/// output but is very slow. It always uses a single thread.
+///
+/// Zopfli compresses each write separately, so the input is buffered into chunks of
+/// [`ZOPFLI_CHUNK_SIZE`] bytes to make the output independent of the sizes of the writes.
+#[cfg(feature = "zopfli")]
+pub struct ZopfliGzipWriter<W: Write> {
+    encoder: zopfli::DeflateEncoder<W>,
+    current: Vec<u8>,
+    crc: Crc,
+}
+
+#[cfg(feature = "zopfli")]
+impl<W: Write> ZopfliGzipWriter<W> {
+    pub fn new(mut out: W) -> Result<ZopfliGzipWriter<W>, std::io::Error> {
+        out.write_all(&GZIP_HEADER)?;
+        Ok(ZopfliGzipWriter {
+            encoder: zopfli::DeflateEncoder::new(
+                zopfli::Options::default(),
+                zopfli::BlockType::Dynamic,
+                out,
+            ),
+            current: Vec::with_capacity(ZOPFLI_CHUNK_SIZE),
+            crc: Crc::new(),
+        })
+    }
+
+    /// compresses the remaining input, writes the gzip trailer and returns the underlying writer
+    pub fn finish(mut self) -> Result<W, std::io::Error> {
+        if !self.current.is_empty() {
+            self.encoder.write_all(&self.current)?;
+        }
+        let mut out = self.encoder.finish()?;
+        out.write_all(&self.crc.sum().to_le_bytes())?;
+        out.write_all(&self.crc.amount().to_le_bytes())?;
+        Ok(out)
+    }
+}
+
+#[cfg(feature = "zopfli")]
+impl<W: Write> Write for ZopfliGzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = std::cmp::min(buf.len(), ZOPFLI_CHUNK_SIZE - self.current.len());
+        self.current.extend_from_slice(&buf[..n]);
+        self.crc.update(&buf[..n]);
+        if self.current.len() == ZOPFLI_CHUNK_SIZE {
+            self.encoder.write_all(&self.current)?;
+            self.current.clear();
+        }
+        Ok(n)
+    }
+
+    /// only flushes the underlying writer, buffered input stays buffered to keep the chunk
+    /// boundaries fixed
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.encoder.flush()
+    }
+}
@@ -0,0 +1,466 @@
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// Hash algorithm of the lines "<hex digest>  <name>" which the archive writers write for each
+/// file, like sha256sum or sha512sum.
+///
+/// XXH3 is much faster, but not cryptographic, so it is only suitable to detect changes. Its
+/// digests are written like `xxhsum -H3` does, i.e. with the prefix "XXH3_". CRC32 is the
+/// checksum of zip archives, written as 8 hex digits like `unzip -v` shows it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    Sha256,
+    #[default]
+    Sha512,
+    #[cfg(feature = "xxh3")]
+    Xxh3,
+    #[cfg(feature = "crc32")]
+    Crc32,
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<HashAlgorithm, String> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            #[cfg(feature = "xxh3")]
+            "xxh3" => Ok(HashAlgorithm::Xxh3),
+            #[cfg(not(feature = "xxh3"))]
+            "xxh3" => Err(crate::compress::disabled("the xxh3 hash", "xxh3").to_string()),
+            #[cfg(feature = "crc32")]
+            "crc32" => Ok(HashAlgorithm::Crc32),
+            #[cfg(not(feature = "crc32"))]
+            "crc32" => Err(crate::compress::disabled("the crc32 checksum", "crc32").to_string()),
+            _ => Err(format!(
+                "unknown hash algorithm {:?}, expected sha256, sha512, xxh3 or crc32",
+                s
+            )),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    /// returns the algorithm of a hex digest by its length and prefix, e.g. of a line of a
+    /// manifest
+    pub fn of_hex_digest(digest: &str) -> Option<HashAlgorithm> {
+        match digest.len() {
+            #[cfg(feature = "xxh3")]
+            21 if digest.starts_with("XXH3_") => Some(HashAlgorithm::Xxh3),
+            #[cfg(feature = "crc32")]
+            8 => Some(HashAlgorithm::Crc32),
+            64 => Some(HashAlgorithm::Sha256),
+            128 => Some(HashAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    /// returns the name of the algorithm in lines of the BSD format, e.g. "SHA512"
+    pub fn tag(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha512 => "SHA512",
+            #[cfg(feature = "xxh3")]
+            HashAlgorithm::Xxh3 => "XXH3",
+            #[cfg(feature = "crc32")]
+            HashAlgorithm::Crc32 => "CRC32",
+        }
+    }
+
+    /// returns whether different content practically never has the same digest, so the digest
+    /// can be used instead of comparing the content
+    pub fn is_cryptographic(&self) -> bool {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Sha512 => true,
+            #[cfg(feature = "xxh3")]
+            HashAlgorithm::Xxh3 => false,
+            #[cfg(feature = "crc32")]
+            HashAlgorithm::Crc32 => false,
+        }
+    }
+
+    /// returns the hex digest of all data which can be read
+    pub fn hex_digest_of(&self, input: &mut impl std::io::Read) -> Result<String, std::io::Error> {
+        let mut hasher = FileHasher::new(*self);
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = input.read(&mut buffer)?;
+            if n == 0 {
+                return Ok(hasher.hex_digest());
+            }
+            hasher.update(&buffer[..n]);
+        }
+    }
+}
+
+/// Format of the hash lines: "<hex digest>  <name>" like sha512sum (`Gnu`) or
+/// "SHA512 (<name>) = <hex digest>" like `sha512sum --tag` and the FreeBSD tools (`Bsd`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum HashFormat {
+    #[default]
+    Gnu,
+    Bsd,
+}
+
+impl std::str::FromStr for HashFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<HashFormat, String> {
+        match s {
+            "gnu" => Ok(HashFormat::Gnu),
+            "bsd" => Ok(HashFormat::Bsd),
+            _ => Err(format!("unknown hash format {:?}, expected gnu or bsd", s)),
+        }
+    }
+}
+
+/// algorithm and format of the hash lines which the archive writers write for each file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct HashOptions {
+    pub algorithm: HashAlgorithm,
+    pub format: HashFormat,
+}
+
+/// computes the digest of a file with the selected algorithm
+pub enum FileHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    #[cfg(feature = "xxh3")]
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+    #[cfg(feature = "crc32")]
+    Crc32(flate2::Crc),
+}
+
+impl FileHasher {
+    pub fn new(algorithm: HashAlgorithm) -> FileHasher {
+        match algorithm {
+            HashAlgorithm::Sha256 => FileHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => FileHasher::Sha512(Sha512::new()),
+            #[cfg(feature = "xxh3")]
+            HashAlgorithm::Xxh3 => FileHasher::Xxh3(Box::default()),
+            #[cfg(feature = "crc32")]
+            HashAlgorithm::Crc32 => FileHasher::Crc32(flate2::Crc::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Sha256(hasher) => hasher.update(data),
+            FileHasher::Sha512(hasher) => hasher.update(data),
+            #[cfg(feature = "xxh3")]
+            FileHasher::Xxh3(hasher) => hasher.update(data),
+            #[cfg(feature = "crc32")]
+            FileHasher::Crc32(crc) => crc.update(data),
+        }
+    }
+
+    pub fn hex_digest(self) -> String {
+        match self {
+            FileHasher::Sha256(hasher) => hex::encode(hasher.finalize()),
+            FileHasher::Sha512(hasher) => hex::encode(hasher.finalize()),
+            #[cfg(feature = "xxh3")]
+            FileHasher::Xxh3(hasher) => format!("XXH3_{:016x}", hasher.digest()),
+            #[cfg(feature = "crc32")]
+            FileHasher::Crc32(crc) => format!("{:08x}", crc.sum()),
+        }
+    }
+}
+
+impl Write for FileHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// writes the line of a file with the digest of `hasher`
+pub(crate) fn write_hash_line(
+    out_hash: &mut impl Write,
+    options: HashOptions,
+    hasher: FileHasher,
+    tarname: &[u8],
+) -> Result<(), std::io::Error> {
+    write_digest_line(out_hash, options, &hasher.hex_digest(), tarname)
+}
+
+/// writes the line of a file with the given digest, as returned by
+/// [`HashAlgorithm::hex_digest_of`]
+pub fn write_digest_line(
+    out_hash: &mut impl Write,
+    options: HashOptions,
+    digest: &str,
+    tarname: &[u8],
+) -> Result<(), std::io::Error> {
+    match options.format {
+        HashFormat::Gnu => {
+            out_hash.write_all(digest.as_bytes())?;
+            out_hash.write_all(b"  ")?;
+            out_hash.write_all(tarname)?;
+        }
+        HashFormat::Bsd => {
+            write!(out_hash, "{} (", options.algorithm.tag())?;
+            out_hash.write_all(tarname)?;
+            // the tag already names the algorithm, like in the output of "xxhsum --tag"
+            let digest = digest.strip_prefix("XXH3_").unwrap_or(digest);
+            write!(out_hash, ") = {}", digest)?;
+        }
+    }
+    out_hash.write_all(b"\n")
+}
+
+/// returns the digest, as returned by [`HashAlgorithm::hex_digest_of`], and the name of a line
+/// in either format, without the line break
+pub fn parse_hash_line(line: &[u8]) -> Option<(String, &[u8])> {
+    let find = |data: &[u8], pattern: &[u8]| data.windows(pattern.len()).position(|w| w == pattern);
+    // a hex digest never starts with a tag, but the name may contain two spaces
+    if let Some(open) = find(line, b" (") {
+        let rest = &line[open + 2..];
+        let close = rest.windows(4).rposition(|w| w == b") = ");
+        if let Some(close) = close {
+            let (name, digest) = (
+                &rest[..close],
+                std::str::from_utf8(&rest[close + 4..]).ok()?,
+            );
+            match &line[..open] {
+                b"SHA256" | b"SHA512" => return Some((digest.to_string(), name)),
+                #[cfg(feature = "xxh3")]
+                b"XXH3" => return Some((format!("XXH3_{}", digest), name)),
+                #[cfg(feature = "crc32")]
+                b"CRC32" => return Some((digest.to_string(), name)),
+                _ => {}
+            }
+        }
+    }
+    let separator = find(line, b"  ")?;
+    let digest = std::str::from_utf8(&line[..separator]).ok()?;
+    Some((digest.to_string(), &line[separator + 2..]))
+}
+
+/// returns the root of a Merkle tree over the lines "<hex digest>  <name>" of the files, sorted by
+/// name, as a compact fingerprint of the whole tree.
+///
+/// The tree is built like in RFC 6962 (Certificate Transparency): a leaf is the hash of a zero
+/// byte followed by the line, an inner node the hash of a one byte followed by the hex digests of
+/// its children, whose left subtree contains the largest power of two of leaves which is smaller
+/// than the number of leaves. An empty tree has the hash of no data.
+pub fn merkle_root(algorithm: HashAlgorithm, mut files: Vec<(Vec<u8>, String)>) -> String {
+    files.sort();
+    let leaves: Vec<String> = files
+        .iter()
+        .map(|(name, digest)| {
+            let mut hasher = FileHasher::new(algorithm);
+            hasher.update(b"\x00");
+            hasher.update(digest.as_bytes());
+            hasher.update(b"  ");
+            hasher.update(name);
+            hasher.hex_digest()
+        })
+        .collect();
+    merkle_subtree(algorithm, &leaves)
+}
+
+/// returns the digest of each directory and its name, sorted by name. It is the digest of the
+/// lines "<hex digest>  <name>" of its children sorted by name, where the names of
+/// subdirectories end with "/", i.e. it changes if anything in the subtree changes.
+///
+/// `files` are the names and digests of the files, `dirs` the names of all directories, so empty
+/// directories are included, too. Names are separated by "/" and the parents of all names are
+/// directories.
+pub fn directory_digests(
+    algorithm: HashAlgorithm,
+    files: &[(Vec<u8>, String)],
+    dirs: &[Vec<u8>],
+) -> Vec<(Vec<u8>, String)> {
+    let split = |name: &[u8]| {
+        let name = name.strip_suffix(b"/").unwrap_or(name).to_vec();
+        let pos = name.iter().rposition(|b| *b == b'/')?;
+        Some((name[..pos].to_vec(), name[pos + 1..].to_vec()))
+    };
+    // the children of each directory, including all parents
+    let mut children: BTreeMap<Vec<u8>, Vec<(Vec<u8>, String)>> = BTreeMap::new();
+    for dir in dirs {
+        let mut dir = dir.strip_suffix(b"/").unwrap_or(dir).to_vec();
+        children.entry(dir.clone()).or_default();
+        while let Some((parent, _)) = split(&dir) {
+            children.entry(parent.clone()).or_default();
+            dir = parent;
+        }
+    }
+    for (name, digest) in files {
+        if let Some((mut parent, child)) = split(name) {
+            children
+                .entry(parent.clone())
+                .or_default()
+                .push((child, digest.clone()));
+            while let Some((grandparent, _)) = split(&parent) {
+                children.entry(grandparent.clone()).or_default();
+                parent = grandparent;
+            }
+        }
+    }
+    // the subdirectories are done before their parents
+    let mut order: Vec<Vec<u8>> = children.keys().cloned().collect();
+    order.sort_by_key(|dir| std::cmp::Reverse(dir.iter().filter(|b| **b == b'/').count()));
+    let mut digests = Vec::new();
+    for dir in order {
+        let mut lines = children.remove(&dir).unwrap();
+        lines.sort();
+        let mut hasher = FileHasher::new(algorithm);
+        for (name, digest) in &lines {
+            hasher.update(digest.as_bytes());
+            hasher.update(b"  ");
+            hasher.update(name);
+            hasher.update(b"\n");
+        }
+        let digest = hasher.hex_digest();
+        if let Some((parent, mut child)) = split(&dir) {
+            child.push(b'/');
+            children
+                .get_mut(&parent)
+                .unwrap()
+                .push((child, digest.clone()));
+        }
+        digests.push((dir, digest));
+    }
+    digests.sort();
+    digests
+}
+
+fn merkle_subtree(algorithm: HashAlgorithm, leaves: &[String]) -> String {
+    match leaves.len() {
+        0 => FileHasher::new(algorithm).hex_digest(),
+        1 => leaves[0].clone(),
+        n => {
+            // the largest power of two which is smaller than n
+            let split = 1 << (usize::BITS - 1 - (n - 1).leading_zeros());
+            let mut hasher = FileHasher::new(algorithm);
+            hasher.update(b"\x01");
+            hasher.update(merkle_subtree(algorithm, &leaves[..split]).as_bytes());
+            hasher.update(merkle_subtree(algorithm, &leaves[split..]).as_bytes());
+            hasher.hex_digest()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(algorithm: HashAlgorithm, data: &[u8]) -> String {
+        algorithm.hex_digest_of(&mut &data[..]).unwrap()
+    }
+
+    #[test]
+    fn hash_lines_are_parsed_again() {
+        let algorithms = [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512,
+            #[cfg(feature = "xxh3")]
+            HashAlgorithm::Xxh3,
+            #[cfg(feature = "crc32")]
+            HashAlgorithm::Crc32,
+        ];
+        let names: [&[u8]; 4] = [b"a/b", b"two  spaces", b"x (y) = z", b"\xff"];
+        for algorithm in algorithms {
+            let digest = digest(algorithm, b"content");
+            assert_eq!(HashAlgorithm::of_hex_digest(&digest), Some(algorithm));
+            for format in [HashFormat::Gnu, HashFormat::Bsd] {
+                for name in names {
+                    let mut line = Vec::new();
+                    let options = HashOptions { algorithm, format };
+                    write_digest_line(&mut line, options, &digest, name).unwrap();
+                    let line = line.strip_suffix(b"\n").unwrap();
+                    assert_eq!(
+                        parse_hash_line(line),
+                        Some((digest.clone(), name)),
+                        "{:?}",
+                        String::from_utf8_lossy(line)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hash_lines_are_like_the_coreutils() {
+        let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(digest(HashAlgorithm::Sha256, b"abc"), sha256);
+        let line = |format| {
+            let mut line = Vec::new();
+            let algorithm = HashAlgorithm::Sha256;
+            write_digest_line(&mut line, HashOptions { algorithm, format }, sha256, b"a").unwrap();
+            String::from_utf8(line).unwrap()
+        };
+        assert_eq!(line(HashFormat::Gnu), format!("{}  a\n", sha256));
+        assert_eq!(line(HashFormat::Bsd), format!("SHA256 (a) = {}\n", sha256));
+        #[cfg(feature = "xxh3")]
+        {
+            let mut line = Vec::new();
+            let options = HashOptions {
+                algorithm: HashAlgorithm::Xxh3,
+                format: HashFormat::Bsd,
+            };
+            write_digest_line(&mut line, options, "XXH3_0123456789abcdef", b"a").unwrap();
+            assert_eq!(line, b"XXH3 (a) = 0123456789abcdef\n");
+        }
+        for line in [&b"no separator"[..], b"MD5 (a) = 00", b"\xff\xfe  a"] {
+            assert_eq!(parse_hash_line(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn merkle_root_is_built_like_rfc_6962() {
+        let algorithm = HashAlgorithm::Sha256;
+        let files: Vec<(Vec<u8>, String)> = ["c", "a", "b"]
+            .iter()
+            .map(|name| (name.as_bytes().to_vec(), digest(algorithm, name.as_bytes())))
+            .collect();
+        let leaf = |i: usize| {
+            let (name, file) = &files[i];
+            digest(algorithm, &[b"\x00", file.as_bytes(), b"  ", name].concat())
+        };
+        let node = |left: &str, right: &str| {
+            digest(
+                algorithm,
+                &[b"\x01", left.as_bytes(), right.as_bytes()].concat(),
+            )
+        };
+        // sorted by name: a and b on the left, c on the right
+        let expected = node(&node(&leaf(1), &leaf(2)), &leaf(0));
+        assert_eq!(merkle_root(algorithm, files.clone()), expected);
+        let mut reversed = files.clone();
+        reversed.reverse();
+        assert_eq!(merkle_root(algorithm, reversed), expected);
+        assert_eq!(merkle_root(algorithm, vec![]), digest(algorithm, b""));
+        assert_eq!(merkle_root(algorithm, files[..1].to_vec()), leaf(0));
+    }
+
+    #[test]
+    fn directory_digests_cover_the_subtree() {
+        let algorithm = HashAlgorithm::Sha256;
+        let files = vec![
+            (b"r/a/f".to_vec(), digest(algorithm, b"f")),
+            (b"r/g".to_vec(), digest(algorithm, b"g")),
+        ];
+        let dirs = vec![b"r/".to_vec(), b"r/a/".to_vec(), b"r/empty/".to_vec()];
+        let digests = directory_digests(algorithm, &files, &dirs);
+        let names: Vec<&[u8]> = digests.iter().map(|(name, _)| &name[..]).collect();
+        assert_eq!(names, [&b"r"[..], b"r/a", b"r/empty"]);
+        let empty = digest(algorithm, b"");
+        let a = digest(algorithm, format!("{}  f\n", files[0].1).as_bytes());
+        assert_eq!(digests[1].1, a);
+        assert_eq!(digests[2].1, empty);
+        let r = format!("{}  a/\n{}  empty/\n{}  g\n", a, empty, files[1].1);
+        assert_eq!(digests[0].1, digest(algorithm, r.as_bytes()));
+        // a change deep inside changes all parents
+        let mut changed = files.clone();
+        changed[0].1 = digest(algorithm, b"changed");
+        let changed = directory_digests(algorithm, &changed, &dirs);
+        assert_ne!(changed[0].1, digests[0].1);
+        assert_eq!(changed[2].1, digests[2].1);
+    }
+}
@@ -0,0 +1,68 @@
+//! Library behind the `deterministic-tar` command line tool.
+//!
+//! The tar writer ([`tar::TarOutput`]), the cpio and ar writers ([`cpio::CpioOutput`], [`ar::ArOutput`]), the tar reader ([`reader::TarReader`]), the directory
+//! walker ([`walk::DirWalkIterator`]) and the external compressor ([`program::ProgramWriter`]) have no mandatory dependencies. The writers of all
+//! archive formats implement [`archive::ArchiveWriter`]. Optional functionality is behind cargo features:
+//!
+//! * `regex`: exclude or include names matching regular expressions or globs while walking
+//!   ([`glob::Glob`])
+//! * `sha512`: write SHA512 or SHA256 hashes of the archived files ([`hash::HashAlgorithm`]) and
+//!   hardlink snapshots ([`farm::HardlinkFarm`])
+//! * `xxh3`: write fast, non-cryptographic XXH3 hashes of the archived files
+//! * `crc32`: write CRC32 checksums of the archived files, like in zip archives
+//! * `brotli`: brotli compression of the archive ([`compress::Compression`])
+//! * `bzip2`: bzip2 compression of the archive ([`compress::Compression`])
+//! * `gzip`: multi-threaded and rsyncable gzip compression of the archive ([`gzip::ParallelGzipWriter`])
+//! * `zopfli`: gzip compression with zopfli ([`gzip::ZopfliGzipWriter`])
+//! * `lz4`: LZ4 compression of the archive ([`compress::Compression`])
+//! * `xz`: multi-threaded xz compression of the archive ([`compress::Compression`])
+//! * `oci`: digests of OCI image layers ([`oci::layer_digests`])
+//! * `minisign`: minisign signatures of the hash lists ([`minisign::SecretKey`])
+//! * `lzip`: lzip compression of the archive ([`compress::Compression`])
+//! * `zstd`: multi-threaded zstd compression of the archive ([`compress::ParallelZstdWriter`])
+//! * `zip`: zip archives instead of tar ([`zip::ZipOutput`])
+//! * `squashfs`: squashfs images instead of tar ([`squashfs::SquashfsOutput`])
+//! * `chunk`: split the archive into a content-addressed chunk store ([`chunk::ChunkStoreWriter`])
+//! * `sparse`: detect holes of sparse files ([`sparse::sparse_map`])
+//! * `xattr`: read extended attributes of files on Linux, e.g. capabilities or SELinux contexts
+//!   ([`xattr::get_xattr`])
+//! * `sandbox`: restrict the file system access of the process with Landlock on Linux
+//!   ([`sandbox::Sandbox`])
+//! * `cli`: the `deterministic-tar` binary (implies all of the above)
+
+pub mod ar;
+pub mod archive;
+pub mod cdc;
+#[cfg(feature = "chunk")]
+pub mod chunk;
+pub mod compress;
+pub mod cpio;
+#[cfg(feature = "sha512")]
+pub mod farm;
+#[cfg(feature = "regex")]
+pub mod glob;
+#[cfg(feature = "gzip")]
+pub mod gzip;
+#[cfg(feature = "sha512")]
+pub mod hash;
+#[cfg(feature = "minisign")]
+pub mod minisign;
+#[cfg(feature = "oci")]
+pub mod oci;
+pub mod prefetch;
+pub mod program;
+pub mod reader;
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+pub mod sandbox;
+pub mod sfx;
+#[cfg(feature = "sparse")]
+pub mod sparse;
+#[cfg(feature = "squashfs")]
+pub mod squashfs;
+pub mod suggest;
+pub mod tar;
+pub mod walk;
+#[cfg(all(feature = "xattr", target_os = "linux"))]
+pub mod xattr;
+#[cfg(feature = "zip")]
+pub mod zip;
@@ -1,14 +1,144 @@
-// use hex::encode;
+use deterministic_tar::archive::{
+    self, squashfs_parameters, ArchiveFormat, ArchiveWriter, HardlinkPolicy, NameEncoding,
+    ZipMethod,
+};
+use deterministic_tar::chunk::ChunkStoreWriter;
+#[cfg(feature = "zstd")]
+use deterministic_tar::compress::read_seek_table;
+use deterministic_tar::compress::{CompressedOutput, Compression, ZSTD_LEVELS};
+use deterministic_tar::farm::HardlinkFarm;
+use deterministic_tar::glob::{glob_lines, repository_dirs, Gitignore, Glob};
+use deterministic_tar::hash::{
+    directory_digests, merkle_root, parse_hash_line, write_digest_line, FileHasher, HashAlgorithm,
+    HashFormat, HashOptions,
+};
+#[cfg(feature = "minisign")]
+use deterministic_tar::minisign::SecretKey;
+#[cfg(feature = "oci")]
+use deterministic_tar::oci::layer_digests;
+use deterministic_tar::prefetch::InodeOrderReader;
+use deterministic_tar::program::ProgramWriter;
+use deterministic_tar::reader::invalid_data;
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+use deterministic_tar::sandbox::Sandbox;
+use deterministic_tar::sfx::self_extracting_stub;
+use deterministic_tar::sparse::{has_holes, sparse_map, sparse_map_of};
+use deterministic_tar::suggest::suggest_excludes;
+use deterministic_tar::tar::{HeaderFormat, StrictUstarFormat, TarFormat, TarStats};
+use deterministic_tar::walk::{
+    escape_unsafe_name, unsafe_name_reason, DanglingSymlinkPolicy, DirWalkItem, DirWalkIterator,
+    DirWalkOptions, DirWalkType, ExclusionReason, SpecialFile, SpecialFilePolicy, SymlinkPolicy,
+    UnsafeNamePolicy,
+};
+#[cfg(all(feature = "xattr", target_os = "linux"))]
+use deterministic_tar::xattr::get_xattr;
 use regex::Regex;
 use sha2::{Digest, Sha512};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Read, Write};
-use std::os::unix::fs::MetadataExt;
+use std::io::{BufReader, Read, Seek, Write};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+mod check;
+mod check_hashes;
+#[cfg(feature = "daemon")]
+mod daemon;
+mod diff;
+mod profile;
+mod repack;
+mod verify;
+
+use check::{check_main, CheckOpt};
+use check_hashes::{check_hashes_main, verify_extracted_main, CheckHashesOpt, VerifyExtractedOpt};
+#[cfg(feature = "daemon")]
+use daemon::daemon_main;
+use diff::{diff_main, DiffOpt};
+use profile::{profile_main, ProfileOpt};
+use repack::{repack_main, RepackOpt};
+use verify::{verify_main, VerifyOpt};
+
 fn parse_regex(src: &str) -> Result<Regex, regex::Error> {
-    Ok(Regex::new(src)?)
+    Regex::new(src)
+}
+
+/// parses a number of bytes with an optional suffix K, M or G (powers of 1024), e.g. "100M"
+fn parse_size(src: &str) -> Result<u64, String> {
+    let (number, factor) = match src.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&src[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&src[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&src[..i], 1 << 30),
+        _ => (src, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+        .ok_or_else(|| {
+            format!(
+                "invalid size {:?}, expected e.g. 4096, 64K, 100M or 2G",
+                src
+            )
+        })
+}
+
+#[derive(Debug, Clone)]
+struct AddCommand {
+    name: String,
+    command: String,
+}
+
+fn parse_add_command(src: &str) -> Result<AddCommand, String> {
+    let (name, command) = src
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=COMMAND, got {:?}", src))?;
+    if name.is_empty()
+        || name.starts_with('/')
+        || name.ends_with('/')
+        || name
+            .split('/')
+            .any(|c| c.is_empty() || c == "." || c == "..")
+    {
+        return Err(format!("invalid entry name {:?}", name));
+    }
+    Ok(AddCommand {
+        name: name.to_string(),
+        command: command.to_string(),
+    })
+}
+
+/// parses a --pax-global argument of the form KEY=VALUE
+fn parse_pax_global(src: &str) -> Result<(String, String), String> {
+    match src.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {:?}", src)),
+    }
+}
+
+/// parses the octal permissions of --root-mode
+fn parse_mode(src: &str) -> Result<u32, String> {
+    match u32::from_str_radix(src, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!(
+            "expected octal permissions like 0755, got {:?}",
+            src
+        )),
+    }
+}
+
+/// runs the command with "sh -c" and returns its complete standard output
+fn run_add_command(c: &AddCommand) -> Vec<u8> {
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(&c.command)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .unwrap_or_else(|_| panic!("could not run command {:?}", &c.command));
+    if !output.status.success() {
+        panic!("command {:?} failed with {}", &c.command, output.status);
+    }
+    output.stdout
 }
 
 #[derive(Debug, Clone, StructOpt)]
@@ -18,322 +148,441 @@ fn parse_regex(src: &str) -> Result<Regex, regex::Error> {
 )]
 struct DeterministicTarOpt {
     /// Input directory (or single file)
-    #[structopt(parse(from_os_str))]
-    input: PathBuf,
+    #[structopt(parse(from_os_str), required_unless = "daemon")]
+    input: Option<PathBuf>,
 
     /// where to write the tar output to, use "-" for stdout
     #[structopt(short, long, default_value = "-")]
     output_tar: String,
 
-    /// optionally, you can get the list of SHA512 hashes of included files. It will be written to the filename or you can use "-" for stdout.
+    /// instead of --output-tar, write the archive to a file named after its content, e.g. 'backup-{tree_hash:.12}.tar'. The placeholders are "{tree_hash}" (the SHA512 of the written, possibly compressed archive), "{date}" (the day of SOURCE_DATE_EPOCH as YYYY-MM-DD in UTC) and "{name}" (the main directory name, "/" replaced by "-"), "{placeholder:.N}" keeps the first N characters. The archive is written to a temporary file in the same directory, renamed when it is complete and the final name is written to stdout. Placeholders are only allowed in the file name. Cannot be combined with --split-by-top-dir, --skip-if-unchanged or --oci-layer.
+    #[structopt(long)]
+    output_template: Option<String>,
+
+    /// don't write an archive, only walk the input and hash the files for --output-hash, --output-root-hash, --output-dir-hash, --expected-root-hash or --dedup-report. The hashes are the same as when the archive is written, but nothing is serialized or compressed, which is much faster. Cannot be combined with the options of the archive itself, e.g. --output-tar or --compress.
+    #[structopt(long)]
+    no_tar: bool,
+
+    /// optionally, you can get the list of SHA512 (or with --hash-algorithm SHA256 or XXH3) hashes of included files. It will be written to the filename or you can use "-" for stdout.
     #[structopt(long)]
     output_hash: Option<String>,
 
+    /// algorithm of --output-hash, "sha512", "sha256", "xxh3" or "crc32". The lines have the format of sha512sum, sha256sum or "xxhsum -H3", so they can be checked with "sha256sum -c" after extracting the archive. xxh3 is much faster, but not cryptographic, so it only detects changes. crc32 is the checksum of zip archives, as 8 hex digits, to compare with the CRCs of zip central directories or legacy tools. Both cannot be combined with --dedup-content.
+    #[structopt(long, default_value = "sha512")]
+    hash_algorithm: HashAlgorithm,
+
+    /// format of the lines of --output-hash: "gnu" writes "<digest>  <name>" like sha512sum, "bsd" writes "SHA512 (<name>) = <digest>" like "sha512sum --tag" and the FreeBSD tools
+    #[structopt(long, default_value = "gnu")]
+    hash_format: HashFormat,
+
+    /// optionally, write the hash of the archive, as it is written (i.e. compressed), with --hash-algorithm in the format of --hash-format, so a huge archive doesn't have to be read again, also if it is written to stdout. It will be written to the filename or you can use "-" for stdout. Cannot be combined with --split-by-top-dir, whose --split-manifest contains the hashes of the archives.
+    #[structopt(long)]
+    output_archive_hash: Option<String>,
+
+    /// fail with a non-zero exit code if the hash of the archive, as in --output-archive-hash, differs from this hex digest, e.g. to catch reproducibility regressions in CI. The outputs are written anyway. Cannot be combined with --split-by-top-dir.
+    #[structopt(long)]
+    expected_archive_hash: Option<String>,
+
+    /// optionally, write the root of a Merkle tree over the lines of --output-hash sorted by name, a single digest of --hash-algorithm which changes if any file name or content changes, e.g. to store a fingerprint of the tree in a database. The files are hashed even without --output-hash. It will be written to the filename or you can use "-" for stdout.
+    #[structopt(long)]
+    output_root_hash: Option<String>,
+
+    /// fail with a non-zero exit code if the root hash, as in --output-root-hash, differs from this hex digest. Unlike --expected-archive-hash, it only depends on the names and contents of the files. The outputs are written anyway.
+    #[structopt(long)]
+    expected_root_hash: Option<String>,
+
+    /// sign the hash lists with this gpg key, e.g. for release artifacts: when everything was written, "gpg --batch --yes --local-user KEYID --armor --detach-sign" creates a detached signature "<file>.asc" next to --output-hash, --output-archive-hash and the --split-manifest, which must be files. Unlike the archive, the signatures contain the time of signing.
+    #[structopt(long)]
+    sign_with_gpg: Option<String>,
+
+    /// sign the same files as --sign-with-gpg with this minisign secret key file, without gpg: a signature "<file>.minisig" is written, which can be checked with "minisign -V -p <public key> -m <file>". The timestamp in its trusted comment is SOURCE_DATE_EPOCH if it is set, so the signatures are deterministic, too.
+    #[structopt(long, parse(from_os_str))]
+    sign_minisign: Option<PathBuf>,
+
+    /// file whose first line is the password of an encrypted --sign-minisign key. Keys created with "minisign -G -W" don't need one.
+    #[structopt(long, parse(from_os_str))]
+    minisign_password_file: Option<PathBuf>,
+
+    /// sign the same files as --sign-with-gpg with this SSH private key, e.g. to verify them with an allowed_signers file: "ssh-keygen -Y sign -f KEYFILE -n <--sign-namespace>" writes "<file>.sig". Encrypted keys need a running ssh-agent, because ssh-keygen cannot ask for the passphrase.
+    #[structopt(long, parse(from_os_str))]
+    sign_ssh: Option<PathBuf>,
+
+    /// namespace of --sign-ssh, which has to be given to "ssh-keygen -Y verify -n", e.g. "file" or "release@example.com"
+    #[structopt(long)]
+    sign_namespace: Option<String>,
+
+    /// encrypt the archive to this age recipient ("age1..."), can be given multiple times. It can be decrypted with "age -d -i <identity file>". The hash lists are not encrypted and --output-archive-hash, --expected-archive-hash, --skip-if-unchanged and the report use the hash of the unencrypted archive, because the encryption is randomized. Cannot be combined with --split-by-top-dir, --chunk-store, --oci-layer, --self-extracting or --output-frame-index.
+    #[structopt(long)]
+    encrypt_age: Vec<String>,
+
+    /// encrypt the archive with the passphrase in the first line of this file, in the format of "age -p": the key is derived with scrypt and the archive is encrypted in chunks with ChaCha20-Poly1305, so it is streamed like without encryption. It is decrypted with "age -d" or "deterministic-tar verify --decrypt-passphrase". The hashes refer to the unencrypted archive, like with --encrypt-age, which cannot be given together with it.
+    #[structopt(long, parse(from_os_str))]
+    encrypt_passphrase: Option<PathBuf>,
+
+    /// instead of --output-tar, write a single container for backups: an uncompressed ustar archive with the cleartext hash list "MANIFEST" (the lines of --output-hash for all files and a last line with the hash of the unencrypted archive named "archive"), its signatures "MANIFEST.asc", "MANIFEST.minisig" and "MANIFEST.sig" and the encrypted archive "archive.age", in this order. The names and hashes can be verified without decrypting, e.g. with "tar -xf <file> MANIFEST MANIFEST.minisig". Needs --encrypt-age or --encrypt-passphrase and --sign-with-gpg, --sign-minisign or --sign-ssh. The encrypted archive is written to a temporary directory next to it first. Cannot be combined with --output-tar, --output-template, --no-tar or --sandbox. It will be written to the filename or you can use "-" for stdout.
+    #[structopt(long)]
+    sealed_output: Option<String>,
+
+    /// append the lines of --output-hash as the last file with this name in the main directory, e.g. "SHA512SUMS", so the archive contains the data to verify its files. The files are hashed even without --output-hash. The manifest doesn't list itself. Cannot be combined with --split-by-top-dir.
+    #[structopt(long)]
+    embed_hash_manifest: Option<String>,
+
+    /// optionally, write a digest of --hash-algorithm for each directory in the format of --hash-format, with a trailing "/" after the name. It is the digest of the sorted lines "<digest>  <name>" of its files and subdirectories, so comparing the lists of two runs shows which subtrees changed. The files are hashed even without --output-hash. It will be written to the filename or you can use "-" for stdout.
+    #[structopt(long)]
+    output_dir_hash: Option<String>,
+
     /// (optional) name if you want to rename base directory or (in case of single-file tar) the main file
     #[structopt(short, long)]
     main_dir_name: Option<String>,
 
+    /// permissions (octal) of the main directory entry instead of 0755, e.g. 0700. All other directories keep 0755. In --split-by-top-dir mode, this applies to the top-level directories. Only for tar, cpio-newc and squashfs.
+    #[structopt(long, parse(try_from_str = parse_mode))]
+    root_mode: Option<u32>,
+
+    /// modification time (seconds since the epoch) of the main directory entry instead of zero, e.g. for consumers which invalidate caches based on it. All other entries keep zero. In --split-by-top-dir mode, this applies to the top-level directories. Only for tar, cpio-newc and squashfs.
+    #[structopt(long)]
+    root_mtime: Option<u64>,
+
     /// list of regular expressions. If the regular expression matches the file or directory basename, then this file or directory (including potential subdirectories and files) will not be included into the archive.
     #[structopt(short, long, parse(try_from_str = parse_regex))]
     ignored_names: Vec<Regex>,
 
+    /// list of regular expressions. If the regular expression matches the path of a file or directory below the input directory (with "/" as separator, e.g. "target/debug"), then it (including potential subdirectories and files) will not be included into the archive. Use "^target/debug$" to exclude only this directory and not e.g. "src/target/debug".
+    #[structopt(long, parse(try_from_str = parse_regex))]
+    ignored_paths: Vec<Regex>,
+
+    /// list of gitignore-style globs, e.g. "*.o", "**/node_modules", "/build" or "cache/". Files and directories which match one of them (including potential subdirectories and files) will not be included into the archive. A glob without "/" matches the basename in every directory, others are relative to the input directory and a trailing "/" only matches directories. A glob with a leading "!" includes the entries which it matches again, also below an excluded directory, e.g. --exclude target/ --exclude '!target/package/*.crate'. The last glob which matches an entry or its directory decides, the globs of --exclude-from come after the ones of --exclude.
+    #[structopt(long)]
+    exclude: Vec<Glob>,
+
+    /// files with one --exclude glob per line, e.g. a list of exclusions which is kept in the repository. Like in .gitignore files, empty lines and lines starting with "#" are skipped (use "\#" for a glob starting with "#") and trailing whitespace is removed.
+    #[structopt(long, parse(from_os_str))]
+    exclude_from: Vec<PathBuf>,
+
+    /// exclude ".git" and everything which git ignores: the entries which match the rules of the .gitignore files in the input directory, in its parents up to the root of the git repository and in .git/info/exclude. Rules can be negated with "!" like in git. The global excludes file of git (core.excludesFile) is not used, so the archive doesn't depend on the configuration of the user.
+    #[structopt(long)]
+    respect_gitignore: bool,
+
+    /// list of regular expressions. Symlinks whose target (as stored in the link, e.g. "/proc/self/fd" or an absolute path of the build machine) matches one of them are excluded instead of being replaced by their target.
+    #[structopt(long, parse(try_from_str = parse_regex))]
+    exclude_link_target: Vec<Regex>,
+
+    /// list of regular expressions. If given, only files and symlinks whose path below the input directory (e.g. "src/main.rs") matches one of them are included, e.g. --include '\.rs$' --include '(^|/)Cargo\.[^/]*$'. Directories are always walked, --empty-dirs-ignored leaves out the ones without directly included entries, but a directory whose subdirectories are all left out is still kept. --ignored-names, --ignored-paths, --exclude and --respect-gitignore are applied first.
+    #[structopt(long, parse(try_from_str = parse_regex))]
+    include: Vec<Regex>,
+
+    /// write files and directories with this basename before all other entries of their directory, in the order given, e.g. --first debian-binary for .deb packages or --first mimetype for EPUB files. Can be given multiple times.
+    #[structopt(long)]
+    first: Vec<String>,
+
     /// if enabled, empty directories containing no or only ignored files are excluded. The default is to include them.
     #[structopt(short, long)]
     empty_dirs_ignored: bool,
 
-    /// program should stop if it encounters an symlink. The default behaviour is to replace all symlinks with the "actual" content of the files/dirs behind the symlinks. Please note that this program will never put actual symlinks into the tar file, it will always duplicate the content of the actual file where the symlink points to!
+    /// write an empty file with this name, e.g. ".keep", into each directory without included entries, so tools which drop empty directories (e.g. git or some unpackers) keep them. Symlinks to directories are written as empty directories, so they get one, too. Cannot be combined with --empty-dirs-ignored.
+    #[structopt(long)]
+    empty_dir_placeholder: Option<String>,
+
+    /// program should stop if it encounters an symlink. The default behaviour is to replace all symlinks with the "actual" content of the files/dirs behind the symlinks. Please note that this program will never put actual symlinks into the tar file (except for dangling ones with --dangling-symlinks store-as-symlink), it will always duplicate the content of the actual file where the symlink points to!
     #[structopt(short, long)]
     symlinks_should_abort: bool,
 
+    /// what to do with symlinks whose resolved target is outside of the input, e.g. a link to "/etc/shadow" in an untrusted tree: "follow" them like all other symlinks, fail with "inside-only" or exclude them with "skip-outside", which prints a warning and lists them as "symlink-outside" in --output-excluded
+    #[structopt(long, default_value = "follow")]
+    symlink_policy: SymlinkPolicy,
+
+    /// what to do with symlinks which cannot be resolved, because their target does not exist or they are part of a loop: fail with "error", exclude them with "skip" (listed as "dangling-symlink" in --output-excluded) or store them as symlink entries with the target as it is with "store-as-symlink", which is only available for the tar formats
+    #[structopt(long, default_value = "error")]
+    dangling_symlinks: DanglingSymlinkPolicy,
+
+    /// what to do with fifos, sockets and device nodes (or symlinks to them): fail with "error", exclude them with "skip" (listed as "special-file" in --output-excluded) or store fifos and character and block devices as entries without content with "store", which is only available for the tar formats except v7 and not with --output-dir-hardlinks or --oci-layer. Devices keep their major and minor number, all other metadata is the same as for files. Sockets cannot be archived, they are always skipped.
+    #[structopt(long, default_value = "error")]
+    special_files: SpecialFilePolicy,
+
+    /// what to do with names which are "..", contain a newline or another control character, e.g. in untrusted trees: "keep" them, fail with "error", exclude the entries with "skip" (listed as "unsafe-name" in --output-excluded) or percent-escape control characters and "%" of such names with "escape", e.g. "a%0Ab" for a name with a newline
+    #[structopt(long, default_value = "keep")]
+    unsafe_names: UnsafeNamePolicy,
+
     /// ignore files and directories where the basename starts with a dot. This is equivalent to -i '^[.].*'
     #[structopt(short, long)]
     dot_files_excluded: bool,
-}
 
-#[derive(Clone, Debug)]
-enum DirWalkType {
-    Directory,
-    File,
-    SymlinkToFile(PathBuf),
-    SymlinkToDirectory(PathBuf),
+    /// don't archive the content of directories on another file system than the given directory, e.g. /proc, /sys or NFS mounts when archiving / or the root file system of a container. Like with GNU tar, the mount points themselves are archived without content, but as their content is unknown, they are never left out by --empty-dirs-ignored and get no --empty-dir-placeholder.
+    #[structopt(long)]
+    one_file_system: bool,
+
+    /// ignore directories which contain a CACHEDIR.TAG file starting with "Signature: 8a477f597d28d172789f06886806bc55" (see https://bford.info/cachedir/), e.g. the caches of cargo, ccache or pip
+    #[structopt(long)]
+    exclude_caches: bool,
+
+    /// with --exclude-caches: keep the cache directories with only their CACHEDIR.TAG instead of ignoring them completely, so extracting the archive recreates them as marked caches
+    #[structopt(long)]
+    keep_cachedir_tag: bool,
+
+    /// ignore files (and symlinks to files) which are larger than this number of bytes, e.g. 100M for giant scratch files. K, M and G are powers of 1024.
+    #[structopt(long, parse(try_from_str = parse_size))]
+    exclude_larger_than: Option<u64>,
+
+    /// ignore files (and symlinks to files) which are smaller than this number of bytes, e.g. 1 for empty placeholder files. K, M and G are powers of 1024.
+    #[structopt(long, parse(try_from_str = parse_size))]
+    exclude_smaller_than: Option<u64>,
+
+    /// only archive the entries up to this many levels below the input directory, e.g. 1 for its direct entries. The directories at the last level are archived without content, which is not read at all, so they are never left out by --empty-dirs-ignored and get no --empty-dir-placeholder.
+    #[structopt(long)]
+    max_depth: Option<usize>,
+
+    /// ignore files and symlinks which are less than this many levels below the input directory, e.g. 2 to leave out the files directly in it. Directories are always walked and archived, unless --empty-dirs-ignored is given.
+    #[structopt(long)]
+    min_depth: Option<usize>,
+
+    /// optionally, write the list of all files and directories which were not included into the archive together with the rule which excluded them. Each line has the format "<rule><TAB><name>". It will be written to the filename or you can use "-" for stdout.
+    #[structopt(long)]
+    output_excluded: Option<String>,
+
+    /// optionally, write statistics about the archive (entry counts, header, padding and longlink overhead) after it has been written. If the archive is compressed, a zip archive or a squashfs image, all settings which influence the output bytes are included as "compression: ...", "zip: ..." and "squashfs: ...", so the settings of two runs can be compared. With --output-hash, --dedup-content or --dedup-report, the number of files with content which was archived before and their bytes are included as "duplicate_files" and "duplicate_bytes". It will be written to the filename or you can use "-" for stdout.
+    #[structopt(long)]
+    output_stats: Option<String>,
+
+    /// optionally, write a JSON report after the run: "status" ("ok" or "error" together with the "error" message), the "arguments", the "stats" as in --output-stats, the "duplicates" (files and bytes, with --output-hash, --dedup-content or --dedup-report), the "compression", "zip" and "squashfs" settings, the written "archives" with their SHA512, the "excluded" entries with their rule, "warnings" (e.g. followed symlinks), the "started" time in seconds since the epoch and the "duration_seconds". The report is also written if the run fails. It will be written to the filename or you can use "-" for stdout.
+    #[structopt(long)]
+    output_report: Option<String>,
+
+    /// add a file with the standard output of a command to the archive, e.g. --add-command 'VERSION=git describe'. The name is relative to the main directory, the command is run with "sh -c". These files are added after all other entries, ordered by name.
+    #[structopt(long, parse(try_from_str = parse_add_command))]
+    add_command: Vec<AddCommand>,
+
+    /// instead of a single archive, write one archive per top-level file or directory. The "{}" in this template is replaced by its name, e.g. "out/{}.tar". Each archive is identical to running this program on that file or directory alone. --output-tar and --main-dir-name are not used in this mode.
+    #[structopt(long)]
+    split_by_top_dir: Option<String>,
+
+    /// flavour of the archive, one of gnu, pax, ustar, ustar-strict, v7, cpio-newc, zip, ar or squashfs: "gnu" stores long names in GNU LongLink entries, "pax" stores them in POSIX pax extended headers, "ustar" splits them into the ustar prefix and name field and aborts if that is not possible (device numbers which are too large still use the GNU base-256 encoding), "ustar-strict" is the same, but fails for such device numbers, see --strict-ustar, "v7" writes pre-POSIX headers without magic and owner names and aborts for names longer than 99 bytes, "cpio-newc" writes a cpio archive in the newc format with inode numbers counted from 1 and zero device and modification time fields, e.g. for a Linux initramfs, "zip" writes a zip archive with fixed timestamps and permissions, a central directory sorted by name and zip64 extensions only where needed, see --zip-method, "ar" writes a GNU ar archive of the files in the main directory (without subdirectories, names up to 15 bytes), e.g. for .deb packages together with --first debian-binary, "squashfs" writes a squashfs image compressed with gzip level 9 in blocks of 128 KiB with zero timestamps and owners, e.g. for firmware root filesystems
+    #[structopt(long, default_value = "gnu")]
+    format: ArchiveFormat,
+
+    /// the same as --format ustar-strict, only with --format ustar: fail if a device of --special-files store has a major or minor number which does not fit into the seven octal digits of the header, naming every such device and number, instead of storing it in the GNU base-256 encoding, which old tar implementations cannot read. The owner ids are always zero, so they always fit.
+    #[structopt(long)]
+    strict_ustar: bool,
+
+    /// compress the archive with fixed settings, so the compressed output is deterministic, too: "none", "gz" (gzip with level 6 in independent blocks of 128 KiB, e.g. for .tar.gz), "gzip-rsyncable" (like gz, but the blocks end at content-defined boundaries, so small changes only change a few blocks for rsync), "xz" (like "xz -6" in independent blocks of 24 MiB, e.g. for .tar.xz), "lz" (lzip, like "lzip -6", e.g. for .tar.lz), "lz4" (LZ4 frame format with 4 MiB independent blocks, e.g. for .tar.lz4) "br" (brotli with quality 11 and a 4 MiB window, e.g. for .tar.br), "bzip2" (900 kB blocks like "bzip2 -9", e.g. for .tar.bz2), "zstd" (level 3 or --compress-level in independent frames of 32 MiB with a content checksum, e.g. for .tar.zst) or "zstd-seekable" (like zstd in frames of 1 MiB with a seek table, see --output-frame-index). In --split-by-top-dir mode, each archive is compressed and the manifest contains the hashes of the compressed files.
+    #[structopt(long, default_value = "none")]
+    compress: Compression,
+
+    /// compress the archive with an external program instead of --compress, like "tar --use-compress-program", e.g. "zstd -19 -T0". The command is run with "sh -c", gets the archive on its standard input and its standard output is written to --output-tar. The program fails the run if it exits with an error. The output is only deterministic if the program is. Cannot be combined with --compress, --chunk-store, --oci-layer or --self-extracting.
+    #[structopt(long)]
+    use_compress_program: Option<String>,
+
+    /// level of --compress zstd from 1 (fastest) to 19 (smallest), the default is 3 like the zstd tool. The other compressions have fixed levels.
+    #[structopt(long)]
+    compress_level: Option<i32>,
+
+    /// optionally, write an index of the entries of an archive written with --compress zstd-seekable, so single files can be extracted without decompressing everything. Each line has the format "<frame offset><TAB><offset in frame><TAB><name>": the byte offset of the zstd frame in the output file, where decompression starts, and the number of decompressed bytes before the tar header of the entry. It will be written to the filename or you can use "-" for stdout. Only for the tar formats, needs --output-tar and cannot be combined with --split-by-top-dir.
+    #[structopt(long)]
+    output_frame_index: Option<String>,
+
+    /// use zopfli for --compress gz, which results in the smallest gzip output but is about 100 times slower. It always uses a single thread.
+    #[structopt(long)]
+    zopfli: bool,
+
+    /// how files are stored with --format zip: "deflate" (level 6, files which don't get smaller are stored) or "store" (uncompressed)
+    #[structopt(long, default_value = "deflate")]
+    zip_method: ZipMethod,
+
+    /// with --format zip: gitignore-style globs (like --exclude) of files which are stored uncompressed instead of with --zip-method, e.g. "*.jpg" or "*.gz" for content which is already compressed, which saves deflating it once. A glob with a leading "!" compresses the files which it matches again, the last glob which matches a file decides.
+    #[structopt(long)]
+    store_only: Vec<Glob>,
+
+    /// how the names are encoded in the archive: "utf8" (names which are not valid UTF-8 are rejected), "latin1" (ISO 8859-1 for legacy systems, UTF-8 names are transcoded and rejected if they contain other characters, other names are kept as they are) or "raw" (the bytes of the file system). zip archives only mark utf8 names as UTF-8, --format pax and --oci-layer only support utf8.
+    #[structopt(long, default_value = "utf8")]
+    name_encoding: NameEncoding,
+
+    /// number of threads for --compress gz, gzip-rsyncable, xz and zstd, the default is the number of CPUs. The output is identical for any number of threads.
+    #[structopt(long)]
+    compress_threads: Option<usize>,
+
+    /// write a self-extracting archive: a fixed shell script of 512 bytes followed by the (compressed) tar archive, which is unchanged. Run it with "sh ./archive.sh [directory]" to extract it with tail, tar and the decompressor. The output file is made executable. Only for the tar formats and cannot be combined with --chunk-store or --oci-layer.
+    #[structopt(long)]
+    self_extracting: bool,
+
+    /// only write the archive if it differs from a previous one, e.g. in nightly jobs: the SHA512 of the (compressed) output is computed first without writing anything and if it equals this hex digest, nothing is written (except --output-report). Instead of a digest, a file in the format of sha512sum can be given, e.g. "out.tar.sha512", the line for --output-tar (or its only line) is used; if it doesn't exist or is empty, the archive is written. The input is read twice if the archive changed. Cannot be combined with --split-by-top-dir, --chunk-store or --oci-layer.
+    #[structopt(long)]
+    skip_if_unchanged: Option<String>,
+
+    /// instead of the archive, write an index of content-defined chunks of the archive to --output-tar and store the chunks in this directory, named by their SHA512/256. Chunks which already exist are not written again, so similar archives share most chunks. Cannot be combined with --compress.
+    #[structopt(long, parse(from_os_str))]
+    chunk_store: Option<PathBuf>,
+
+    /// number of zero blocks of 512 bytes which mark the end of the archive, at least 2. The default of 10 blocks is the layout of earlier versions; GNU tar writes 2 blocks and pads them to a full record instead, like --eof-blocks 2 --blocking-factor 20.
+    #[structopt(long, default_value = "10")]
+    eof_blocks: u64,
+
+    /// pad the archive after the end marker with zero blocks to a multiple of this number of 512 byte blocks. GNU tar uses 20, i.e. records of 10240 bytes.
+    #[structopt(long, default_value = "1")]
+    blocking_factor: u64,
+
+    /// store files with zero blocks as sparse files: GNU sparse entries with --format gnu, GNU sparse 1.0 entries with --format pax. The holes only depend on the content (all zero blocks of 512 bytes), not on the allocation on disk, so the archive stays deterministic. Not available with --format ustar and v7.
+    #[structopt(long)]
+    sparse: bool,
+
+    /// store the capabilities of files (the extended attribute "security.capability", e.g. cap_net_bind_service of a server binary) as "SCHILY.xattr.security.capability" records of pax extended headers, which GNU tar restores with "--xattrs --xattrs-include=security.capability" when it runs as root. Other extended attributes are not stored. Only for --format pax (and --oci-layer), on Linux, and cannot be combined with --dedup-content.
+    #[structopt(long)]
+    xattr_capabilities: bool,
+
+    /// store the SELinux context of files and directories (the extended attribute "security.selinux") as "SCHILY.xattr.security.selinux" records of pax extended headers, e.g. for archives which populate root file systems on SELinux-enforcing systems. GNU tar restores them with "--xattrs --xattrs-include=security.selinux". With --xattr-capabilities, the records are sorted by name. Only for --format pax (and --oci-layer), on Linux, and cannot be combined with --dedup-content.
+    #[structopt(long)]
+    selinux: bool,
+
+    /// write a pax global header with this record at the start of the archive, e.g. --pax-global 'build.id=1234'. Can be given multiple times, the records are sorted by key. Not available with --format v7.
+    #[structopt(long, parse(try_from_str = parse_pax_global))]
+    pax_global: Vec<(String, String)>,
+
+    /// instead of writing an archive, analyze the input (artifact directories and files, large files, duplicate content) and print suggested --ignored-names patterns together with the number of bytes they would save
+    #[structopt(long)]
+    suggest_excludes: bool,
+
+    /// read the content of files in inode order instead of alphabetical order, which is faster on spinning disks. The archive is still written in alphabetical order and is identical. Up to --inode-order-buffer bytes of file content are held in memory.
+    #[structopt(long)]
+    inode_order_read: bool,
+
+    /// check that the input didn't change while it was archived, e.g. on busy network file systems: each directory is listed again after its content was archived and each file is stat'ed again after it was read. If entries appeared or disappeared or a file changed, the program aborts instead of writing an archive of an inconsistent view. Cannot be combined with --inode-order-read.
+    #[structopt(long)]
+    paranoid: bool,
+
+    /// after the archive was written, archive the input a second time without writing anything and abort if the SHA512 of the (compressed) output differs, i.e. if the input changed in between. The input is read twice. The archive is written anyway. Cannot be combined with --split-by-top-dir, --chunk-store or --oci-layer.
+    #[structopt(long)]
+    self_check: bool,
+
+    /// store files whose content was archived before as hardlink entries to the first file with the same content (compared by the hash of --hash-algorithm), which are extracted as regular files. Only available for the tar formats and not with --split-by-top-dir. Empty files are always stored as files.
+    #[structopt(long, visible_alias = "dedup-as-hardlinks")]
+    dedup_content: bool,
+
+    /// optionally, write the groups of files with the same content (compared by the hash of --hash-algorithm, which must be cryptographic) as lines of --output-hash: only files whose content occurs more than once are listed, the lines of each group follow each other and the groups are sorted by the name of their first file. Empty files are not listed. It will be written to the filename or you can use "-" for stdout.
+    #[structopt(long)]
+    dedup_report: Option<String>,
+
+    /// what to do with files which have more than one name in the input (a link count above 1): store the content for each name with "copy" or, like GNU tar, store the names after the first one as hardlink entries to it with "keep". Symlinks to such files count as names of them. "keep" is only available for the tar formats and not with --split-by-top-dir.
+    #[structopt(long, default_value = "copy")]
+    hardlinks: HardlinkPolicy,
+
+    /// abort as soon as more than this number of entries was found, e.g. if a cache with millions of files was included by accident. Entries excluded by name are not counted.
+    #[structopt(long)]
+    max_entries: Option<usize>,
+
+    /// abort as soon as a directory with more than this number of entries is found. Entries excluded by name are not counted.
+    #[structopt(long)]
+    max_entries_per_dir: Option<usize>,
+
+    /// write an OCI image layer: implies --format pax and --compress gz, the entries are the content of the input directory without a main directory (and without "./"). After the layer was written to --output-tar, its diff_id (SHA256 of the tar), digest (SHA256 of the .tar.gz) and size are written to stdout.
+    #[structopt(long)]
+    oci_layer: bool,
+
+    /// number of bytes of file content which are buffered for --inode-order-read
+    #[structopt(long, default_value = "67108864")]
+    inode_order_buffer: u64,
+
+    /// run as a daemon which accepts jobs on the unix socket given with --listen, which only the owner can connect to. Each connection sends one JSON object per line, e.g. {"input": "/src", "output": "/out/src.tar", "options": ["--format", "pax"]}, and receives progress and result as JSON lines. Jobs can only write below --daemon-output-dir and cannot run programs.
+    #[structopt(long, requires_all = &["listen", "daemon-output-dir"])]
+    daemon: bool,
+
+    /// path of the unix socket for --daemon
+    #[structopt(long, parse(from_os_str))]
+    listen: Option<PathBuf>,
+
+    /// the directory below which the jobs of --daemon write all their outputs, e.g. the archive and --output-hash. The directory of each output must exist and outputs must not be symlinks.
+    #[structopt(long, parse(from_os_str))]
+    daemon_output_dir: Option<PathBuf>,
+
+    /// where to write the SHA512 hashes of the archives written by --split-by-top-dir, use "-" for stdout
+    #[structopt(long, default_value = "-")]
+    split_manifest: String,
+
+    /// in addition to the archive, write the archived files and directories into this directory, which must not exist yet. With --link-dest, files are hardlinks to identical files of a previous snapshot. Like in the archive, files have mode 0644, directories 0755 and all modification times are zero. Use --output-tar /dev/null to only write the directory.
+    #[structopt(long, parse(from_os_str))]
+    output_dir_hardlinks: Option<PathBuf>,
+
+    /// previous snapshot for --output-dir-hardlinks, e.g. the directory written by the last run. Files which have the same name, size and SHA512 as in this directory are hardlinked instead of copied, like rsnapshot does.
+    #[structopt(long, parse(from_os_str))]
+    link_dest: Option<PathBuf>,
+
+    /// before archiving begins, restrict the process with Landlock (Linux 5.13 or newer) to reading the input and writing the output files, e.g. when archiving untrusted directories. Output files which don't exist are created empty first. Symlinks which point outside of the input cannot be followed. It fails if Landlock is not available and cannot be combined with --daemon, --output-template, --use-compress-program, --add-command, --sign-with-gpg, --sign-ssh, --output-dir-hardlinks or --link-dest.
+    #[structopt(long)]
+    sandbox: bool,
 }
 
-#[derive(Clone, Debug)]
-struct DirWalkItem {
-    abspath: PathBuf,
-    relpath: PathBuf,
-    typ: DirWalkType,
-    size: Option<u64>,
+/// The standard output of the reports of the subcommands: if it is closed early, e.g. by "| head",
+/// the program exits quietly with the status of a process killed by SIGPIPE instead of panicking.
+struct ReportStdout(std::io::StdoutLock<'static>);
+
+fn report_stdout() -> ReportStdout {
+    ReportStdout(std::io::stdout().lock())
 }
 
-#[derive(Clone, Debug)]
-struct DirWalkIterator {
-    empty_dirs_ignored: bool,
-    symlinks_should_abort: bool,
-    ignored_filenames: Vec<Regex>,
-    remaining: Vec<PathBuf>,
-    basedir: PathBuf,
+fn exit_on_broken_pipe(e: std::io::Error) -> std::io::Error {
+    if e.kind() == std::io::ErrorKind::BrokenPipe {
+        std::process::exit(128 + 13);
+    }
+    e
 }
 
-impl DirWalkIterator {
-    fn new(
-        basedir: &PathBuf,
-        remaining: &Vec<PathBuf>,
-        ignored_filenames: &Vec<Regex>,
-        empty_dirs_ignored: &bool,
-        symlinks_should_abort: &bool,
-    ) -> DirWalkIterator {
-        DirWalkIterator {
-            empty_dirs_ignored: empty_dirs_ignored.clone(),
-            symlinks_should_abort: symlinks_should_abort.clone(),
-            ignored_filenames: ignored_filenames.clone(),
-            remaining: remaining.clone(),
-            basedir: basedir.clone(),
-        }
+impl Write for ReportStdout {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf).map_err(exit_on_broken_pipe)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush().map_err(exit_on_broken_pipe)
     }
 }
 
-fn is_allowed_name(p: &PathBuf, i: &Vec<Regex>) -> bool {
-    let p = p
-        .file_name()
-        .unwrap()
-        .to_str()
-        .expect(format!("cannot convert PathBuf {:?} to string", &p).as_str());
-    // now check if we match any "ignored_filenames regex"
-    !i.iter().any(|regex| regex.is_match(p))
-}
-
-impl Iterator for DirWalkIterator {
-    type Item = DirWalkItem;
-    fn next(&mut self) -> Option<DirWalkItem> {
-        if let Some(r) = self.remaining.pop() {
-            let sym_meta =
-                std::fs::symlink_metadata(&r).expect(format!("stat for {:?} failed", &r).as_str());
-            let abspath = r.clone();
-            let relpath = r
-                .clone()
-                .strip_prefix(&self.basedir)
-                .expect("could not strip prefix")
-                .to_path_buf();
-            //dbg!(&relpath, &abspath);
-            if sym_meta.is_symlink() {
-                if self.symlinks_should_abort {
-                    panic!("Found symlink at {:?}, aborting.", &abspath);
-                };
-                let resolved_path = r
-                    .canonicalize()
-                    .expect(format!("error resolving symlink {:?}", &r).as_str());
-                let resolved_meta = std::fs::symlink_metadata(&resolved_path)
-                    .expect(format!("stat for {:?} failed", &resolved_path).as_str());
-                if resolved_meta.is_dir() {
-                    return Some(DirWalkItem {
-                        relpath: relpath,
-                        abspath: abspath,
-                        typ: DirWalkType::SymlinkToDirectory(resolved_path),
-                        size: Some(resolved_meta.size()),
-                    });
-                } else if resolved_meta.is_file() {
-                    return Some(DirWalkItem {
-                        relpath: relpath,
-                        abspath: abspath,
-                        typ: DirWalkType::SymlinkToFile(resolved_path),
-                        size: Some(resolved_meta.size()),
-                    });
-                } else {
-                    unreachable!("");
-                }
-            }
-            if sym_meta.is_file() {
-                return Some(DirWalkItem {
-                    relpath: relpath,
-                    abspath: abspath,
-                    typ: DirWalkType::File,
-                    size: Some(sym_meta.size()),
-                });
-            }
-            if sym_meta.is_dir() {
-                let mut subs: Vec<PathBuf> = r
-                    .read_dir()
-                    .expect(format!("can't read directory {:?}", &r).as_str())
-                    .map(|i| i.expect("intermittent i/o error").path())
-                    .filter(|d| {
-                        is_allowed_name(
-                            &d.strip_prefix(&self.basedir)
-                                .expect("could not strip prefix")
-                                .to_path_buf(),
-                            &self.ignored_filenames,
-                        )
-                    })
-                    .collect();
-                // if the directory is empty and we shouldn't include empty directories, then we proceed with empty dir
-                if subs.is_empty() && self.empty_dirs_ignored {
-                    return self.next();
-                }
-                // sort in reverse alphabetically order
-                subs.sort_by(|a, b| b.cmp(a));
-                self.remaining.append(&mut subs);
-                return Some(DirWalkItem {
-                    relpath: relpath,
-                    abspath: abspath,
-                    typ: DirWalkType::Directory,
-                    size: None,
-                });
-            }
-            unreachable!("Neither symlink, file nor dir!");
-        } else {
-            // nothing left
-            None
-        }
+/// writes the line of --output-hash of a file, adds it to the files of --output-root-hash and
+/// --output-dir-hash and the manifest of --embed-hash-manifest and returns its digest. Entries without content (e.g.
+/// directories) have no line.
+fn add_hash_line(
+    hash_line: &[u8],
+    output_hash: Option<&mut Box<dyn Write>>,
+    hashed_files: Option<&mut Vec<(Vec<u8>, String)>>,
+    embedded_manifest: Option<&mut Vec<u8>>,
+) -> Option<String> {
+    let (digest, name) = parse_hash_line(hash_line.strip_suffix(b"\n")?).unwrap();
+    if let Some(out) = output_hash {
+        out.write_all(hash_line).unwrap();
+    }
+    if let Some(manifest) = embedded_manifest {
+        manifest.extend_from_slice(hash_line);
     }
+    if let Some(files) = hashed_files {
+        files.push((name.to_vec(), digest.clone()));
+    }
+    Some(digest)
 }
 
-struct TarOutput {}
-impl TarOutput {
-    fn _tar_fix_header_checksum(header: &mut Vec<u8>) {
-        let mut sum = 0u64;
-        drop(
-            header
-                .iter()
-                .map(|i| {
-                    sum += *i as u64;
-                })
-                .collect::<Vec<_>>(),
-        );
-        // checksum is now correct
-        header[148..156].clone_from_slice(format!("{:06o}\x00 ", sum).as_bytes());
-    }
-
-    fn tar_write_dir(out_tar: &mut impl Write, tarname: &[u8]) -> Result<(), std::io::Error> {
-        if tarname.len() > 100 {
-            // first create a longlink
-            let mut header: Vec<u8> = vec![0u8; 512];
-            header[0..13].clone_from_slice(b"././@LongLink");
-            header[100..108].clone_from_slice(b"0000755\x00"); // File mode (octal)
-            header[108..116].clone_from_slice(b"0000000\x00"); // Owner's numeric user ID (octal), here we use 0 for "root"
-            header[116..124].clone_from_slice(b"0000000\x00"); // Group's numeric user ID (octal), here we use 0 for "root"
-            header[124..136].clone_from_slice(format!("{:011o}\x00", tarname.len()).as_bytes()); // longlink name length bytes (octal), zero for a directory
-            header[148..156].clone_from_slice(b"        "); // checksum: eight spaces, will be replaced later
-            header[156] = b'L'; // magic value for "LongLink"
-            header[257..265].clone_from_slice(b"ustar  \x00"); // magic string for ustar format extension, version 00
-            header[265..269].clone_from_slice(b"root"); // Owner user name
-            header[297..301].clone_from_slice(b"root"); // Owner group name
-            TarOutput::_tar_fix_header_checksum(&mut header);
-            out_tar.write_all(&header)?;
-
-            // now, write LongLink entry padded to 512 bytes
-            let padding = ((512 - (tarname.len() % 512)) % 512) as usize;
-            out_tar.write_all(tarname)?;
-            out_tar.write_all(&[0u8; 512][..padding])?;
-        }
-
-        let mut header: Vec<u8> = vec![0u8; 512];
-        header[0..std::cmp::min(tarname.len(), 100)]
-            .clone_from_slice(&tarname[..std::cmp::min(tarname.len(), 100)]);
-        header[100..108].clone_from_slice(b"0000755\x00"); // File mode (octal)
-        header[108..116].clone_from_slice(b"0000000\x00"); // Owner's numeric user ID (octal), here we use 0 for "root"
-        header[116..124].clone_from_slice(b"0000000\x00"); // Group's numeric user ID (octal), here we use 0 for "root"
-        header[124..136].clone_from_slice(b"00000000000\x00"); // File size in bytes (octal), zero for a directory
-        header[148..156].clone_from_slice(b"        "); // checksum: eight spaces, will be replaced later
-        header[156] = b'5';
-        header[257..265].clone_from_slice(b"ustar  \x00"); // magic string for ustar format extension, version 00
-        header[265..269].clone_from_slice(b"root"); // Owner user name
-        header[297..301].clone_from_slice(b"root"); // Owner group name
-        TarOutput::_tar_fix_header_checksum(&mut header);
-        out_tar.write_all(&header)
-    }
-
-    fn tar_write_file(
-        out_tar: &mut impl Write,
-        out_hash: Option<&mut impl Write>,
-        in_filedescriptor: &mut BufReader<File>,
-        size: &u64,
-        tarname: &[u8],
-    ) -> Result<(), std::io::Error> {
-        if tarname.len() > 100 {
-            // first create a longlink
-            let mut header: Vec<u8> = vec![0u8; 512];
-            header[0..13].clone_from_slice(b"././@LongLink");
-            header[100..108].clone_from_slice(b"0000644\x00"); // File mode (octal)
-            header[108..116].clone_from_slice(b"0000000\x00"); // Owner's numeric user ID (octal), here we use 0 for "root"
-            header[116..124].clone_from_slice(b"0000000\x00"); // Group's numeric user ID (octal), here we use 0 for "root"
-            header[124..136].clone_from_slice(format!("{:011o}\x00", tarname.len()).as_bytes()); // longlink name length bytes (octal), zero for a directory
-            header[148..156].clone_from_slice(b"        "); // checksum: eight spaces, will be replaced later
-            header[156] = b'L'; // magic value for "LongLink"
-            header[257..265].clone_from_slice(b"ustar  \x00"); // magic string for ustar format extension, version 00
-            header[265..269].clone_from_slice(b"root"); // Owner user name
-            header[297..301].clone_from_slice(b"root"); // Owner group name
-            TarOutput::_tar_fix_header_checksum(&mut header);
-            out_tar.write_all(&header)?;
-
-            // now, write LongLink padded to 512 bytes
-            out_tar.write_all(tarname)?;
-            let padding = if tarname.len() % 512 == 0 {
-                0
-            } else {
-                512 - (tarname.len() % 512)
-            };
-            out_tar.write_all(&[0u8; 512][..padding])?;
-        }
-        let mut header: Vec<u8> = vec![0u8; 512];
-        header[0..std::cmp::min(tarname.len(), 100)]
-            .clone_from_slice(&tarname[..std::cmp::min(tarname.len(), 100)]);
-        header[100..108].clone_from_slice(b"0000644\x00"); // File mode (octal)
-        header[108..116].clone_from_slice(b"0000000\x00"); // Owner's numeric user ID (octal), here we use 0 for "root"
-        header[116..124].clone_from_slice(b"0000000\x00"); // Group's numeric user ID (octal), here we use 0 for "root"
-        header[124..136].clone_from_slice(format!("{:011o}\x00", size).as_bytes()); // File size in bytes (octal), zero for a directory
-        header[148..156].clone_from_slice(b"        "); // checksum: eight spaces, will be replaced later
-        header[156] = b'0'; // magic value for "normal file"
-        header[257..265].clone_from_slice(b"ustar  \x00"); // magic string for ustar format extension, version 00
-        header[265..269].clone_from_slice(b"root"); // Owner user name
-        header[297..301].clone_from_slice(b"root"); // Owner group name
-        TarOutput::_tar_fix_header_checksum(&mut header);
-
-        out_tar.write_all(&header)?;
-
-        // // now we have to write the file in 512 bytes block and pad it with zero bytes on end
-        let mut already_read = 0u64;
-        let mut buffer = [0; 512];
-        let mut sha512_hasher = Sha512::new();
-        loop {
-            let n = in_filedescriptor.read(&mut buffer)?;
-            if n == 0 {
-                break;
-            };
-            already_read += n as u64;
-            out_tar
-                .write_all(&buffer[0..n])
-                .expect("could not write to tarfile");
-            if out_hash.is_some() {
-                sha512_hasher.update(&buffer[0..n]);
-            };
-        }
-        if already_read != *size {
-            panic!("size while reading different from stat");
-        }
-        let padding = ((512 - (already_read % 512)) % 512) as usize;
-        out_tar.write_all(&[0u8; 512][..padding])?;
-        if out_hash.is_some() {
-            let digest = sha512_hasher.finalize();
-            let out_hash = out_hash.unwrap();
-            out_hash.write_all(hex::encode(&digest).as_bytes())?;
-            out_hash.write_all(b"  ")?;
-            out_hash.write_all(tarname)?;
-            out_hash.write_all(b"\n")?;
-        }
-        Ok(())
+/// returns the algorithm and format of --output-hash
+fn hash_options(opt: &DeterministicTarOpt) -> HashOptions {
+    HashOptions {
+        algorithm: opt.hash_algorithm,
+        format: opt.hash_format,
     }
+}
 
-    fn tar_end_marker(out_tar: &mut impl Write) -> Result<(), std::io::Error> {
-        // tar archives ends with 2 blocks of zeros, each 512 bytes
-        // actually, gnu tar creates 10 empty blocks but 2 blocks are strictly spoken already sufficient
-        out_tar.write_all(&[0u8; 10 * 512])
+/// returns the hex encoded digest of a regular file
+fn digest_of_file(
+    path: &std::path::Path,
+    algorithm: HashAlgorithm,
+) -> Result<String, std::io::Error> {
+    if !std::fs::metadata(path)?.is_file() {
+        return Err(invalid_data("not a regular file".to_string()));
     }
+    algorithm.hex_digest_of(&mut File::open(path)?)
+}
+
+/// percent-escapes the unsafe components of a name for --unsafe-names escape
+fn escape_unsafe_components(name: &std::path::Path) -> PathBuf {
+    name.iter().map(escape_unsafe_name).collect()
 }
 
 fn validate_main_dir_name(m: &Option<String>) -> Option<PathBuf> {
     match m {
         Some(s) => {
-            if s.starts_with("/") || s.ends_with("/") {
+            if s.starts_with('/') || s.ends_with('/') {
                 panic!("main dir name must not start or end with /");
             } else {
                 let mut p = PathBuf::new();
@@ -345,89 +594,2395 @@ fn validate_main_dir_name(m: &Option<String>) -> Option<PathBuf> {
     }
 }
 
-fn main() {
-    // command line argument parsing
-    let opt = DeterministicTarOpt::from_args();
+/// forwards everything to the inner writer and computes the SHA512 hash of it, unless it was
+/// created with `passthrough`, and the hash of --output-archive-hash
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Option<Sha512>,
+    archive_hasher: Option<FileHasher>,
+}
 
-    let mut ignored_names = opt.ignored_names.clone();
-    if opt.dot_files_excluded {
-        ignored_names.push(Regex::new(r"^[.].*$").unwrap());
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Some(Sha512::new()),
+            archive_hasher: None,
+        }
     }
-    let input = opt
-        .input
-        .canonicalize()
-        .expect("error getting absolute path of input file/directory");
 
-    // prepare output streams
-    let mut stdout_used: usize = 0;
-    let mut output_tar: Box<dyn Write> = if opt.output_tar == String::from("-") {
-        stdout_used += 1;
-        Box::new(std::io::stdout())
-    } else {
-        Box::new(
-            std::fs::File::create(&opt.output_tar)
-                .expect(format!("could not open file {:?}", &opt.output_tar).as_str()),
-        )
-    };
-    let mut output_hash: Option<Box<dyn Write>> = if opt.output_hash == Some(String::from("-")) {
-        stdout_used += 1;
-        Some(Box::new(std::io::stdout()))
-    } else {
-        if opt.output_hash == None {
-            None
-        } else {
-            let filename = opt.output_hash.unwrap();
-            Some(Box::new(std::fs::File::create(&filename).expect(
-                format!("could not open file {:?}", &filename).as_str(),
-            )))
+    fn passthrough(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: None,
+            archive_hasher: None,
         }
-    };
-    if stdout_used > 1 {
-        panic!("Stdout used for more than one argument!");
     }
 
-    let parent = input
-        .parent()
-        .expect("input directory has no parent!")
-        .to_path_buf();
-    let main_dir_name =
-        validate_main_dir_name(&opt.main_dir_name).unwrap_or(input.file_name().unwrap().into());
-    let remaining = vec![input.clone()];
+    /// additionally computes the hash of everything written with the given algorithm
+    fn with_archive_hash(mut self, algorithm: HashAlgorithm) -> HashingWriter<W> {
+        self.archive_hasher = Some(FileHasher::new(algorithm));
+        self
+    }
 
-    // now, iterate through all files
-    for d in DirWalkIterator::new(
-        &parent,
-        &remaining,
-        &ignored_names,
-        &opt.empty_dirs_ignored,
-        &opt.symlinks_should_abort,
-    ) {
-        let mut tarname = main_dir_name.clone();
-        for p in d.relpath.iter().skip(1) {
-            tarname.push(p);
+    /// returns the hex encoded SHA512 of everything written
+    fn digest(self) -> Option<String> {
+        self.digests().0
+    }
+
+    /// returns the hex encoded SHA512 and the hash of `with_archive_hash` of everything written
+    fn digests(self) -> (Option<String>, Option<String>) {
+        (
+            self.hasher.map(|h| hex::encode(h.finalize())),
+            self.archive_hasher.map(|h| h.hex_digest()),
+        )
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..n]);
         }
-        match d.typ {
-            DirWalkType::Directory | DirWalkType::SymlinkToDirectory(_) => {
-                // create trailing slash at end
-                tarname.push("");
-                TarOutput::tar_write_dir(&mut output_tar, tarname.to_str().unwrap().as_bytes())
+        if let Some(hasher) = self.archive_hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl HashingWriter<OutputFile> {
+    /// finishes the encryption of --encrypt-age and returns the digests of the unencrypted archive
+    fn finish_output(self) -> std::io::Result<(Option<String>, Option<String>)> {
+        let HashingWriter {
+            inner,
+            hasher,
+            archive_hasher,
+        } = self;
+        inner.finish()?;
+        Ok((
+            hasher.map(|h| hex::encode(h.finalize())),
+            archive_hasher.map(|h| h.hex_digest()),
+        ))
+    }
+}
+
+/// writes the end marker of a split archive and adds it to the manifest
+fn finish_split_archive(
+    opt: &DeterministicTarOpt,
+    (filename, mut out): (String, ArchiveOutput<CompressedOutput<HashingWriter<File>>>),
+    manifest: &mut impl Write,
+    stats: &mut TarStats,
+    report: &mut Report,
+) {
+    out.finish(opt.eof_blocks, opt.blocking_factor).unwrap();
+    stats.add(out.stats());
+    let digest = out.into_inner().finish().unwrap().digest().unwrap();
+    writeln!(manifest, "{}  {}", digest, filename).unwrap();
+    report.archives.push((filename, digest));
+}
+
+/// what happened during a run, for --output-report
+#[derive(Default)]
+struct Report {
+    /// name and rule of the excluded entries
+    excluded: Vec<(String, String)>,
+    warnings: Vec<String>,
+    /// filename and SHA512 of the written archives
+    archives: Vec<(String, String)>,
+    /// with --skip-if-unchanged, whether writing was skipped
+    skipped: Option<bool>,
+    /// number of files and bytes with content which was archived before, if it was hashed
+    duplicates: Option<(u64, u64)>,
+}
+
+/// Content which was archived before, for the duplicate statistics, --dedup-content and
+/// --dedup-report.
+#[derive(Default)]
+struct Duplicates {
+    /// SHA512 of the content and the name of the first file with it
+    first: HashMap<String, Vec<u8>>,
+    /// for --dedup-report, the names of the later files with the same content as the first one
+    later: Option<HashMap<String, Vec<Vec<u8>>>>,
+    files: u64,
+    bytes: u64,
+}
+
+impl Duplicates {
+    /// returns the name of the first file with the same content if there was one
+    fn add(&mut self, digest: String, size: u64, name: &[u8]) -> Option<Vec<u8>> {
+        match self.first.entry(digest) {
+            std::collections::hash_map::Entry::Occupied(first) => {
+                self.files += 1;
+                self.bytes += size;
+                if let Some(later) = self.later.as_mut() {
+                    later
+                        .entry(first.key().clone())
+                        .or_default()
+                        .push(name.to_vec());
+                }
+                Some(first.get().clone())
+            }
+            std::collections::hash_map::Entry::Vacant(first) => {
+                first.insert(name.to_vec());
+                None
             }
-            DirWalkType::File => TarOutput::tar_write_file(
-                &mut output_tar,
-                output_hash.as_mut(),
-                &mut BufReader::new(std::fs::File::open(&d.abspath).unwrap()),
-                &d.size.unwrap(),
-                tarname.to_str().unwrap().as_bytes(),
-            ),
-            DirWalkType::SymlinkToFile(resolved_path) => TarOutput::tar_write_file(
-                &mut output_tar,
-                output_hash.as_mut(),
-                &mut BufReader::new(std::fs::File::open(resolved_path).unwrap()),
-                &d.size.unwrap(),
-                tarname.to_str().unwrap().as_bytes(),
-            ),
         }
-        .unwrap();
     }
-    TarOutput::tar_end_marker(&mut output_tar).unwrap();
+
+    /// returns the groups of --dedup-report with their digest and names, sorted by the name of
+    /// their first file
+    fn groups(&self) -> Vec<(&str, Vec<&[u8]>)> {
+        let mut groups: Vec<(&str, Vec<&[u8]>)> = self
+            .later
+            .iter()
+            .flatten()
+            .map(|(digest, later)| {
+                let mut names = vec![self.first[digest].as_slice()];
+                names.extend(later.iter().map(Vec::as_slice));
+                (digest.as_str(), names)
+            })
+            .collect();
+        groups.sort_by(|a, b| a.1[0].cmp(b.1[0]));
+        groups
+    }
+}
+
+/// the day of SOURCE_DATE_EPOCH as YYYY-MM-DD in UTC
+fn source_date() -> Result<String, String> {
+    let epoch = std::env::var("SOURCE_DATE_EPOCH")
+        .map_err(|_| "{date} needs SOURCE_DATE_EPOCH to be set".to_string())?;
+    let seconds: i64 = epoch
+        .trim()
+        .parse()
+        .map_err(|_| format!("SOURCE_DATE_EPOCH {:?} is not a number of seconds", epoch))?;
+    // days to civil date in the proleptic Gregorian calendar, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = seconds.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    Ok(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+/// expands the placeholders of --output-template, "{tree_hash}", "{date}" and "{name}", each
+/// optionally shortened to its first N characters by e.g. "{tree_hash:.12}"
+fn expand_output_template(template: &str, tree_hash: &str, name: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed \"{{\" in {:?}", template))?
+            + start;
+        let placeholder = &rest[start + 1..end];
+        let (key, length) = match placeholder.split_once(":.") {
+            Some((key, length)) => match length.parse::<usize>() {
+                Ok(length) => (key, length),
+                Err(_) => return Err(format!("invalid length in {{{}}}", placeholder)),
+            },
+            None => (placeholder, usize::MAX),
+        };
+        let value = match key {
+            "tree_hash" => tree_hash.to_string(),
+            "date" => source_date()?,
+            "name" => name.replace('/', "-"),
+            _ => {
+                return Err(format!(
+                    "unknown placeholder {{{}}}, expected tree_hash, date or name",
+                    key
+                ))
+            }
+        };
+        expanded.extend(value.chars().take(length));
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// The temporary directory of --sealed-output next to the container (in the current directory for
+/// stdout), which contains the encrypted archive, the manifest and its signatures until the
+/// container is written. It is removed when this is dropped, also if the program panics.
+struct SealedDir {
+    path: String,
+}
+
+impl SealedDir {
+    fn create(sealed: &str) -> SealedDir {
+        let dir = std::path::Path::new(sealed)
+            .parent()
+            .unwrap_or(std::path::Path::new(""));
+        let temp = dir.join(format!(".deterministic-tar-{}.sealed", std::process::id()));
+        let path = temp.to_str().unwrap().to_string();
+        std::fs::create_dir(&path)
+            .unwrap_or_else(|e| panic!("could not create directory {:?}: {}", path, e));
+        SealedDir { path }
+    }
+}
+
+impl Drop for SealedDir {
+    fn drop(&mut self) {
+        // nothing can be done about an error here, the directory is only left over
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// the temporary file of --output-template in the directory of the final file, so it can be renamed
+fn output_template_temp(template: &str) -> String {
+    let dir = std::path::Path::new(template)
+        .parent()
+        .unwrap_or(std::path::Path::new(""));
+    let temp = dir.join(format!(".deterministic-tar-{}.tmp", std::process::id()));
+    temp.to_str().unwrap().to_string()
+}
+
+/// returns the SHA512 given to --skip-if-unchanged, either directly or in a file in the format of
+/// sha512sum, or None if the file doesn't exist or is empty
+fn previous_digest(previous: &str, output_tar: &str) -> Option<String> {
+    let is_digest = |s: &str| s.len() == 128 && s.bytes().all(|b| b.is_ascii_hexdigit());
+    if is_digest(previous) {
+        return Some(previous.to_ascii_lowercase());
+    }
+    let content = match std::fs::read_to_string(previous) {
+        // an empty file was created by --sandbox before the first run
+        Ok(content) if content.is_empty() => return None,
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(_) => panic!("could not read --skip-if-unchanged file {:?}", previous),
+    };
+    // lines are "<digest>  <filename>", binary mode of sha512sum marks the filename with "*"
+    let lines: Vec<(&str, &str)> = content
+        .lines()
+        .filter_map(|l| l.split_once(' '))
+        .map(|(digest, name)| (digest, name.trim_start_matches([' ', '*'])))
+        .collect();
+    let line = match lines.as_slice() {
+        [line] => line,
+        _ => lines
+            .iter()
+            .find(|(_, name)| *name == output_tar)
+            .unwrap_or_else(|| {
+                panic!(
+                    "--skip-if-unchanged file {:?} has no line for {:?}",
+                    previous, output_tar
+                )
+            }),
+    };
+    if !is_digest(line.0) {
+        panic!("--skip-if-unchanged file {:?} contains no SHA512", previous);
+    }
+    Some(line.0.to_ascii_lowercase())
+}
+
+/// writes a hardlink entry of --dedup-content and its line of --output-hash
+fn write_hardlink_item<W: Write>(
+    out: &mut dyn ArchiveWriter<Inner = W>,
+    output_hash: Option<&mut Vec<u8>>,
+    tarname: &std::path::Path,
+    (digest, target): &(String, Vec<u8>),
+    opt: &DeterministicTarOpt,
+) -> Result<(), std::io::Error> {
+    let name = opt.name_encoding.encode(tarname.as_os_str())?;
+    out.write_hardlink(&name, target)?;
+    if let Some(out_hash) = output_hash {
+        write_digest_line(out_hash, hash_options(opt), digest, &name)?;
+    }
+    Ok(())
+}
+
+/// returns true if the file is stored uncompressed because of --store-only: the last glob which
+/// matches its path below the input decides
+fn is_store_only(opt: &DeterministicTarOpt, d: &DirWalkItem) -> bool {
+    // a single file has no path below the input, its name is matched instead
+    let below: PathBuf = d.relpath.iter().skip(1).collect();
+    let path = if below.as_os_str().is_empty() {
+        d.relpath.to_string_lossy().to_string()
+    } else {
+        below.to_string_lossy().to_string()
+    };
+    opt.store_only
+        .iter()
+        .rev()
+        .find(|glob| glob.is_match(&path, false))
+        .is_some_and(|glob| !glob.is_negated())
+}
+
+fn write_walk_item<W: Write>(
+    out: &mut dyn ArchiveWriter<Inner = W>,
+    output_hash: Option<&mut Vec<u8>>,
+    d: &DirWalkItem,
+    content: Option<Vec<u8>>,
+    mut tarname: PathBuf,
+    opt: &DeterministicTarOpt,
+    dir_metadata: Option<(u32, Option<u64>)>,
+) -> Result<(), std::io::Error> {
+    if let DirWalkType::Directory | DirWalkType::SymlinkToDirectory = d.typ {
+        // create trailing slash at end
+        tarname.push("");
+    }
+    let name = opt.name_encoding.encode(tarname.as_os_str())?;
+    // symlinks and special files have no content, so they have no line in the hash list
+    match &d.typ {
+        DirWalkType::DanglingSymlink(target) => {
+            let target = opt.name_encoding.encode(target.as_os_str())?;
+            return out.write_symlink(&name, &target);
+        }
+        DirWalkType::Special(special) => {
+            let (typeflag, device) = special_typeflag(*special);
+            return out.write_special(&name, typeflag, device);
+        }
+        _ => {}
+    }
+    if matches!(d.typ, DirWalkType::File | DirWalkType::SymlinkToFile(_)) && is_store_only(opt, d) {
+        out.store_next_file()?;
+    }
+    let output_hash = output_hash.map(|out_hash| (out_hash as &mut dyn Write, hash_options(opt)));
+    #[cfg(all(feature = "xattr", target_os = "linux"))]
+    {
+        // symlinks to directories are followed by getxattr, like the ones to files
+        let path = match &d.typ {
+            DirWalkType::SymlinkToFile(resolved_path) => resolved_path,
+            _ => &d.abspath,
+        };
+        // sorted by name, so the records are always in the same order
+        let attributes = [
+            ("security.capability", opt.xattr_capabilities),
+            ("security.selinux", opt.selinux),
+        ];
+        for (attribute, _) in attributes.iter().filter(|(_, enabled)| *enabled) {
+            if let Some(value) = get_xattr(path, attribute)? {
+                out.add_pax_record(&format!("SCHILY.xattr.{}", attribute), &value)?;
+            }
+        }
+    }
+    if let Some(content) = content {
+        // the content was already read by --inode-order-read
+        if opt.sparse {
+            let map = sparse_map_of(&content);
+            if has_holes(&map, content.len() as u64) {
+                return out.write_sparse_file(
+                    output_hash,
+                    &mut std::io::Cursor::new(&content),
+                    &map,
+                    &(content.len() as u64),
+                    &name,
+                );
+            }
+        }
+        return out.write_file(
+            output_hash,
+            &mut std::io::Cursor::new(&content),
+            &d.size.unwrap(),
+            &name,
+        );
+    }
+    let path = match &d.typ {
+        DirWalkType::Directory | DirWalkType::SymlinkToDirectory => {
+            match dir_metadata {
+                Some((mode, mtime)) => out.write_dir_with_metadata(&name, mode, mtime)?,
+                None => out.write_dir(&name)?,
+            };
+            return match opt.empty_dir_placeholder.as_ref().filter(|_| d.empty) {
+                Some(placeholder) => {
+                    let name = opt
+                        .name_encoding
+                        .encode(tarname.join(placeholder).as_os_str())?;
+                    out.write_file(output_hash, &mut std::io::empty(), &0, &name)
+                }
+                None => Ok(()),
+            };
+        }
+        DirWalkType::File => &d.abspath,
+        DirWalkType::SymlinkToFile(resolved_path) => resolved_path,
+        DirWalkType::DanglingSymlink(_) | DirWalkType::Special(_) | DirWalkType::Excluded(_) => {
+            unreachable!("symlinks, special files and excluded entries were handled before")
+        }
+    };
+    let size = d.size.unwrap();
+    let file = std::fs::File::open(path).unwrap();
+    if opt.sparse {
+        let map = sparse_map(&file, size)?;
+        if has_holes(&map, size) {
+            return out.write_sparse_file(
+                output_hash,
+                &mut BufReader::new(file),
+                &map,
+                &size,
+                &name,
+            );
+        }
+    }
+    out.write_file(output_hash, &mut BufReader::new(file), &size, &name)
+}
+
+/// returns the tar typeflag and the major and minor device number of a special file of
+/// --special-files store, which are zero for fifos
+fn special_typeflag(special: SpecialFile) -> (u8, (u32, u32)) {
+    match special {
+        SpecialFile::CharDevice(major, minor) => (b'3', (major, minor)),
+        SpecialFile::BlockDevice(major, minor) => (b'4', (major, minor)),
+        SpecialFile::Fifo => (b'6', (0, 0)),
+        SpecialFile::Socket => unreachable!("sockets are always excluded"),
+    }
+}
+
+/// writes the hash line of an entry for --no-tar, the same as write_walk_item
+fn hash_walk_item(
+    output_hash: Option<&mut Vec<u8>>,
+    d: &DirWalkItem,
+    content: Option<Vec<u8>>,
+    tarname: &std::path::Path,
+    opt: &DeterministicTarOpt,
+) -> Result<(), std::io::Error> {
+    let Some(out) = output_hash else {
+        return Ok(());
+    };
+    let digest = match (&d.typ, content) {
+        (DirWalkType::DanglingSymlink(_) | DirWalkType::Special(_), _) => return Ok(()),
+        (DirWalkType::Directory | DirWalkType::SymlinkToDirectory, _) => {
+            return match opt.empty_dir_placeholder.as_ref().filter(|_| d.empty) {
+                Some(placeholder) => {
+                    let name = opt
+                        .name_encoding
+                        .encode(tarname.join(placeholder).as_os_str())?;
+                    let digest = opt.hash_algorithm.hex_digest_of(&mut std::io::empty())?;
+                    write_digest_line(out, hash_options(opt), &digest, &name)
+                }
+                None => Ok(()),
+            };
+        }
+        // the content was already read by --inode-order-read
+        (_, Some(content)) => opt.hash_algorithm.hex_digest_of(&mut content.as_slice())?,
+        (DirWalkType::File, None) => digest_of_file(&d.abspath, opt.hash_algorithm)?,
+        (DirWalkType::SymlinkToFile(resolved_path), None) => {
+            digest_of_file(resolved_path, opt.hash_algorithm)?
+        }
+        (DirWalkType::Excluded(_), None) => unreachable!("excluded entries are not written"),
+    };
+    let name = opt.name_encoding.encode(tarname.as_os_str())?;
+    write_digest_line(out, hash_options(opt), &digest, &name)
+}
+
+/// returns the compression given by --compress, --zopfli and --oci-layer
+fn compression(opt: &DeterministicTarOpt) -> Compression {
+    match opt.compress {
+        _ if opt.oci_layer && opt.zopfli => Compression::Zopfli,
+        _ if opt.oci_layer => Compression::Gzip,
+        Compression::Gzip if opt.zopfli => Compression::Zopfli,
+        Compression::Zstd(level) => Compression::Zstd(opt.compress_level.unwrap_or(level)),
+        Compression::ZstdSeekable(level) => {
+            Compression::ZstdSeekable(opt.compress_level.unwrap_or(level))
+        }
+        compress => compress,
+    }
+}
+
+/// writes --output-frame-index for the entries at the given offsets of the uncompressed archive,
+/// using the seek table of the written archive
+#[cfg(feature = "zstd")]
+fn write_frame_index(
+    out: &mut impl Write,
+    archive: &str,
+    entries: &[(u64, String)],
+) -> Result<(), std::io::Error> {
+    let mut file =
+        File::open(archive).unwrap_or_else(|_| panic!("could not open file {:?}", archive));
+    let frames = read_seek_table(&mut file)?;
+    for (offset, name) in entries {
+        // the last frame which starts before the entry
+        let frame = frames.partition_point(|(_, start)| start <= offset) - 1;
+        let (frame_offset, start) = frames[frame];
+        writeln!(out, "{}\t{}\t{}", frame_offset, offset - start, name)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn write_frame_index(
+    _out: &mut impl Write,
+    _archive: &str,
+    _entries: &[(u64, String)],
+) -> Result<(), std::io::Error> {
+    panic!("--output-frame-index is not available, it was disabled at compile time");
+}
+
+/// the settings of the compression for --output-stats and --output-report, if it is compressed
+fn compression_parameters(opt: &DeterministicTarOpt) -> Option<String> {
+    match &opt.use_compress_program {
+        Some(command) => Some(format!("format=program command={:?}", command)),
+        None if compression(opt) != Compression::None => Some(compression(opt).parameters()),
+        None => None,
+    }
+}
+
+/// returns the format given by --format, --zip-method and --oci-layer
+fn archive_format(opt: &DeterministicTarOpt) -> ArchiveFormat {
+    match opt.format {
+        _ if opt.oci_layer => ArchiveFormat::Tar(TarFormat::Pax),
+        ArchiveFormat::Tar(TarFormat::Ustar) if opt.strict_ustar => {
+            ArchiveFormat::Tar(TarFormat::UstarStrict)
+        }
+        ArchiveFormat::Zip(_) => ArchiveFormat::Zip(opt.zip_method),
+        format => format,
+    }
+}
+
+/// returns device, inode, size, modification and change time of a file for --paranoid, None for
+/// directories
+fn file_state(d: &DirWalkItem) -> Option<(u64, u64, u64, i64, i64, i64, i64)> {
+    use std::os::unix::fs::MetadataExt;
+    let path = match &d.typ {
+        DirWalkType::File => &d.abspath,
+        DirWalkType::SymlinkToFile(resolved_path) => resolved_path,
+        _ => return None,
+    };
+    let m = std::fs::metadata(path).unwrap_or_else(|_| panic!("stat for {:?} failed", path));
+    Some((
+        m.dev(),
+        m.ino(),
+        m.size(),
+        m.mtime(),
+        m.mtime_nsec(),
+        m.ctime(),
+        m.ctime_nsec(),
+    ))
+}
+
+/// adds a file, directory or symlink of the walk to the --output-dir-hardlinks snapshot
+fn add_walk_item_to_farm(
+    farm: &mut HardlinkFarm,
+    d: &DirWalkItem,
+    content: Option<&Vec<u8>>,
+    name: &std::path::Path,
+) -> Result<(), std::io::Error> {
+    let size = d.size.unwrap_or(0);
+    match (&d.typ, content) {
+        (DirWalkType::Directory | DirWalkType::SymlinkToDirectory, _) => farm.add_dir(name),
+        (DirWalkType::DanglingSymlink(target), _) => farm.add_symlink(name, target),
+        (DirWalkType::Special(_), _) => unreachable!("special files cannot be added"),
+        (_, Some(content)) => farm.add_file(name, &mut std::io::Cursor::new(content), size),
+        (DirWalkType::File, None) => farm.add_file(name, &mut File::open(&d.abspath)?, size),
+        (DirWalkType::SymlinkToFile(resolved_path), None) => {
+            farm.add_file(name, &mut File::open(resolved_path)?, size)
+        }
+        (DirWalkType::Excluded(_), None) => unreachable!("excluded entries are not written"),
+    }
+}
+
+/// the writer of the archive in the format of --format, see [`archive::with_format`]
+type ArchiveOutput<W> = Box<dyn ArchiveWriter<Inner = W>>;
+
+/// creates the (compressed) tar output and writes the pax global header, if there is one
+fn new_tar_output<W: Write + 'static>(
+    opt: &DeterministicTarOpt,
+    mut out: W,
+) -> ArchiveOutput<CompressedOutput<W>> {
+    if opt.self_extracting {
+        out.write_all(&self_extracting_stub(compression(opt)))
+            .unwrap();
+    }
+    let threads = opt
+        .compress_threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let out = match (&opt.chunk_store, &opt.use_compress_program) {
+        (Some(store), _) => CompressedOutput::Chunked(Box::new(
+            ChunkStoreWriter::new(out, store)
+                .unwrap_or_else(|_| panic!("could not create chunk store {:?}", store)),
+        )),
+        (None, Some(command)) => CompressedOutput::Program(Box::new(
+            ProgramWriter::new(out, command)
+                .unwrap_or_else(|e| panic!("could not run {:?}: {}", command, e)),
+        )),
+        (None, None) => compression(opt).writer(out, threads).unwrap(),
+    };
+    let mut out = archive::with_format(out, archive_format(opt)).unwrap();
+    out.set_name_encoding(opt.name_encoding);
+    if !opt.pax_global.is_empty() {
+        let mut records = opt.pax_global.clone();
+        records.sort();
+        out.write_pax_global(&records).unwrap();
+    }
+    out
+}
+
+/// sets the mode of an output file of --self-extracting to 0755, but not of stdout or devices
+fn make_executable(filename: &str) {
+    use std::os::unix::fs::PermissionsExt;
+    if std::fs::metadata(filename).is_ok_and(|m| m.is_file()) {
+        std::fs::set_permissions(filename, std::fs::Permissions::from_mode(0o755))
+            .unwrap_or_else(|_| panic!("could not make {:?} executable", filename));
+    }
+}
+
+/// the archive file, which is encrypted with --encrypt-age or --encrypt-passphrase
+enum OutputFile {
+    Plain(Box<dyn Write>),
+    #[cfg(feature = "age")]
+    Age(age::stream::StreamWriter<Box<dyn Write>>),
+}
+
+impl OutputFile {
+    #[cfg(feature = "age")]
+    fn encrypt(out: Box<dyn Write>, encryptor: age::Encryptor) -> OutputFile {
+        OutputFile::Age(
+            encryptor
+                .wrap_output(out)
+                .unwrap_or_else(|e| panic!("could not write the age header: {}", e)),
+        )
+    }
+
+    /// writes the last chunk of an encrypted archive and flushes it
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            OutputFile::Plain(mut out) => out.flush(),
+            #[cfg(feature = "age")]
+            OutputFile::Age(out) => out.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputFile::Plain(out) => out.write(buf),
+            #[cfg(feature = "age")]
+            OutputFile::Age(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputFile::Plain(out) => out.flush(),
+            #[cfg(feature = "age")]
+            OutputFile::Age(out) => out.flush(),
+        }
+    }
+}
+
+/// the archive which is read, which is decrypted with --decrypt-passphrase
+enum InputFile {
+    Plain(BufReader<File>),
+    #[cfg(feature = "age")]
+    Age(age::stream::StreamReader<BufReader<File>>),
+}
+
+impl InputFile {
+    #[cfg(feature = "age")]
+    fn decrypt_passphrase(
+        input: BufReader<File>,
+        passphrase: age::secrecy::SecretString,
+    ) -> InputFile {
+        let identity = age::scrypt::Identity::new(passphrase);
+        let decryptor = age::Decryptor::new(input)
+            .unwrap_or_else(|e| panic!("the archive is not encrypted with age: {}", e));
+        if !decryptor.is_scrypt() {
+            panic!("the archive is not encrypted with a passphrase");
+        }
+        InputFile::Age(
+            decryptor
+                .decrypt(std::iter::once(&identity as &dyn age::Identity))
+                .unwrap_or_else(|e| panic!("could not decrypt the archive: {}", e)),
+        )
+    }
+}
+
+impl Read for InputFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            InputFile::Plain(input) => input.read(buf),
+            #[cfg(feature = "age")]
+            InputFile::Age(input) => input.read(buf),
+        }
+    }
+}
+
+impl Seek for InputFile {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        match self {
+            InputFile::Plain(input) => input.seek(pos),
+            #[cfg(feature = "age")]
+            InputFile::Age(input) => input.seek(pos),
+        }
+    }
+}
+
+fn open_output(filename: &str, stdout_used: &mut usize) -> Box<dyn Write> {
+    if filename == "-" {
+        *stdout_used += 1;
+        Box::new(std::io::stdout())
+    } else {
+        Box::new(
+            std::fs::File::create(filename)
+                .unwrap_or_else(|_| panic!("could not open file {:?}", filename)),
+        )
+    }
+}
+
+fn main() {
+    // subcommands are given as first argument, everything else is the default "create" mode
+    let args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    if let Some("verify") = args.get(1).and_then(|a| a.to_str()) {
+        return verify_main(VerifyOpt::from_iter(&args[1..]));
+    }
+    if let Some("verify-extracted") = args.get(1).and_then(|a| a.to_str()) {
+        return verify_extracted_main(VerifyExtractedOpt::from_iter(&args[1..]));
+    }
+    if let Some("check") = args.get(1).and_then(|a| a.to_str()) {
+        return check_main(CheckOpt::from_iter(&args[1..]));
+    }
+    if let Some("diff") = args.get(1).and_then(|a| a.to_str()) {
+        return diff_main(DiffOpt::from_iter(&args[1..]));
+    }
+    if let Some("repack") = args.get(1).and_then(|a| a.to_str()) {
+        return repack_main(RepackOpt::from_iter(&args[1..]));
+    }
+    if let Some("check-hashes") = args.get(1).and_then(|a| a.to_str()) {
+        return check_hashes_main(CheckHashesOpt::from_iter(&args[1..]));
+    }
+    if let Some("profile") = args.get(1).and_then(|a| a.to_str()) {
+        return profile_main(ProfileOpt::from_iter(&args[1..]));
+    }
+
+    // command line argument parsing
+    let opt = DeterministicTarOpt::from_args();
+    if let Err(e) = validate_options(&opt) {
+        structopt::clap::Error::with_description(&e, structopt::clap::ErrorKind::ArgumentConflict)
+            .exit();
+    }
+    if opt.sandbox {
+        #[cfg(all(feature = "sandbox", target_os = "linux"))]
+        enter_sandbox(&opt);
+        #[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+        panic!("--sandbox is not available, it was disabled at compile time or this is not Linux");
+    }
+    if opt.daemon {
+        #[cfg(feature = "daemon")]
+        return daemon_main(
+            opt.listen.as_ref().unwrap(),
+            opt.daemon_output_dir.as_ref().unwrap(),
+        );
+        #[cfg(not(feature = "daemon"))]
+        panic!(
+            "--daemon --listen {:?} --daemon-output-dir {:?} is not available, it was disabled at compile time",
+            opt.listen, opt.daemon_output_dir
+        );
+    }
+    let args: Vec<String> = args[1..]
+        .iter()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect();
+    create_archive(&opt, &args, &mut |_| {});
+}
+
+/// returns the files which are written (or "-" for stdout), except for the ones of
+/// --output-template, --sealed-output and the signatures of gpg and ssh-keygen
+fn output_files(opt: &DeterministicTarOpt) -> Vec<String> {
+    let mut writes: Vec<String> = [
+        &opt.output_hash,
+        &opt.output_excluded,
+        &opt.output_stats,
+        &opt.dedup_report,
+        &opt.output_report,
+        &opt.output_archive_hash,
+        &opt.output_root_hash,
+        &opt.output_dir_hash,
+        &opt.output_frame_index,
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .collect();
+    match &opt.split_by_top_dir {
+        Some(_) => writes.push(opt.split_manifest.clone()),
+        None if !opt.no_tar => writes.push(opt.output_tar.clone()),
+        None => {}
+    }
+    if opt.sign_minisign.is_some() {
+        writes.extend(signed_outputs(opt).iter().map(|f| format!("{}.minisig", f)));
+    }
+    writes
+}
+
+/// returns the directories in which new files are created: the chunks and the archives of
+/// --split-by-top-dir, whose directory might contain "{}"
+fn output_dirs(opt: &DeterministicTarOpt) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = opt.chunk_store.iter().cloned().collect();
+    if let Some(template) = &opt.split_by_top_dir {
+        let dir = std::path::Path::new(template)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(std::path::Path::new("."));
+        dirs.push(dir.to_path_buf());
+    }
+    dirs
+}
+
+/// restricts the process with --sandbox to reading the input and writing the outputs. It must be
+/// called before any thread is started.
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+fn enter_sandbox(opt: &DeterministicTarOpt) {
+    if opt.daemon
+        || opt.output_template.is_some()
+        || opt.use_compress_program.is_some()
+        || !opt.add_command.is_empty()
+        || opt.sign_with_gpg.is_some()
+        || opt.sign_ssh.is_some()
+        || opt.output_dir_hardlinks.is_some()
+        || opt.link_dest.is_some()
+        || opt.sealed_output.is_some()
+    {
+        panic!("--sandbox cannot be combined with --daemon, --output-template, --use-compress-program, --add-command, --sign-with-gpg, --sign-ssh, --output-dir-hardlinks, --link-dest or --sealed-output");
+    }
+    let mut sandbox = Sandbox::new()
+        .unwrap_or_else(|e| panic!("--sandbox needs Landlock, which is not available: {}", e));
+    let mut reads: Vec<PathBuf> = opt.input.iter().cloned().collect();
+    reads.extend(opt.sign_minisign.iter().cloned());
+    reads.extend(opt.minisign_password_file.iter().cloned());
+    reads.extend(opt.encrypt_passphrase.iter().cloned());
+    reads.extend(opt.exclude_from.iter().cloned());
+    if opt.respect_gitignore {
+        // the rules of the parent directories are outside of the input
+        if let Some(input) = opt.input.as_ref().and_then(|i| i.canonicalize().ok()) {
+            reads.extend(
+                repository_dirs(&input)
+                    .iter()
+                    .flat_map(|d| Gitignore::files(d)),
+            );
+        }
+    }
+    if let Some(previous) = &opt.skip_if_unchanged {
+        // it is either a digest or a file, which might not exist yet
+        if std::path::Path::new(previous).exists() {
+            reads.push(PathBuf::from(previous));
+        }
+    }
+    for path in reads {
+        sandbox
+            .allow_read(&path)
+            .unwrap_or_else(|e| panic!("--sandbox could not allow reading {:?}: {}", path, e));
+    }
+    for filename in output_files(opt).iter().filter(|f| *f != "-") {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filename)
+            .unwrap_or_else(|_| panic!("could not open file {:?}", filename));
+        sandbox
+            .allow_write(std::path::Path::new(filename))
+            .unwrap_or_else(|e| panic!("--sandbox could not allow writing {:?}: {}", filename, e));
+    }
+    let dirs = output_dirs(opt);
+    if let (Some(_), Some(dir)) = (&opt.split_by_top_dir, dirs.last()) {
+        if dir.to_string_lossy().contains("{}") {
+            panic!("--sandbox needs a fixed directory in --split-by-top-dir");
+        }
+    }
+    for dir in dirs {
+        std::fs::create_dir_all(&dir)
+            .unwrap_or_else(|_| panic!("could not create directory {:?}", dir));
+        sandbox
+            .allow_write(&dir)
+            .unwrap_or_else(|e| panic!("--sandbox could not allow writing {:?}: {}", dir, e));
+    }
+    sandbox
+        .restrict_self()
+        .unwrap_or_else(|e| panic!("--sandbox could not restrict the process: {}", e));
+}
+
+/// returns the message of a panic, which is how errors are reported
+fn panic_message(e: &(dyn std::any::Any + Send)) -> String {
+    e.downcast_ref::<String>()
+        .cloned()
+        .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "unknown error".to_string())
+}
+
+/// writes the JSON document of --output-report, stats is None if the run failed with error
+fn write_report(
+    out: &mut impl Write,
+    opt: &DeterministicTarOpt,
+    args: &[String],
+    report: &Report,
+    result: Result<&TarStats, &str>,
+    started: std::time::SystemTime,
+) -> std::io::Result<()> {
+    let string = |s: &str| format!("\"{}\"", json_escape(s));
+    let list = |items: Vec<String>| format!("[{}]", items.join(", "));
+    writeln!(out, "{{")?;
+    match result {
+        Ok(_) => writeln!(out, "  \"status\": \"ok\",")?,
+        Err(e) => writeln!(out, "  \"status\": \"error\",\n  \"error\": {},", string(e))?,
+    }
+    let args = args.iter().map(|a| string(a)).collect();
+    writeln!(out, "  \"arguments\": {},", list(args))?;
+    if let Ok(stats) = result {
+        // the same counters as --output-stats
+        let counters = stats
+            .to_string()
+            .lines()
+            .map(|l| format!("\"{}", l.replacen(": ", "\": ", 1)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(out, "  \"stats\": {{{}}},", counters)?;
+    }
+    if let Some((files, bytes)) = report.duplicates {
+        writeln!(
+            out,
+            "  \"duplicates\": {{\"files\": {}, \"bytes\": {}}},",
+            files, bytes
+        )?;
+    }
+    if let Some(parameters) = compression_parameters(opt) {
+        writeln!(out, "  \"compression\": {},", string(&parameters))?;
+    }
+    if let ArchiveFormat::Zip(method) = archive_format(opt) {
+        writeln!(out, "  \"zip\": {},", string(&method.parameters()))?;
+    }
+    if archive_format(opt) == ArchiveFormat::Squashfs {
+        writeln!(out, "  \"squashfs\": {},", string(&squashfs_parameters()))?;
+    }
+    let archives = report
+        .archives
+        .iter()
+        .map(|(file, sha512)| {
+            format!(
+                "{{\"file\": {}, \"sha512\": {}}}",
+                string(file),
+                string(sha512)
+            )
+        })
+        .collect();
+    writeln!(out, "  \"archives\": {},", list(archives))?;
+    let excluded = report
+        .excluded
+        .iter()
+        .map(|(name, rule)| format!("{{\"name\": {}, \"rule\": {}}}", string(name), string(rule)))
+        .collect();
+    writeln!(out, "  \"excluded\": {},", list(excluded))?;
+    let warnings = report.warnings.iter().map(|w| string(w)).collect();
+    writeln!(out, "  \"warnings\": {},", list(warnings))?;
+    if let Some(skipped) = report.skipped {
+        writeln!(out, "  \"skipped\": {},", skipped)?;
+    }
+    let started_seconds = started
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    writeln!(out, "  \"started\": {},", started_seconds)?;
+    let duration = started.elapsed().map(|d| d.as_secs_f64()).unwrap_or(0.0);
+    writeln!(out, "  \"duration_seconds\": {:.3}", duration)?;
+    writeln!(out, "}}")?;
+    out.flush()
+}
+
+/// creates the archive(s) as given by the options, calls progress with the number of entries
+/// after each entry and returns the statistics. args are the command line arguments for the
+/// report, which is also written if the run fails.
+fn create_archive(
+    opt: &DeterministicTarOpt,
+    args: &[String],
+    progress: &mut dyn FnMut(u64),
+) -> TarStats {
+    let filename = match &opt.output_report {
+        Some(filename) => filename,
+        None => return create_archive_with_report(opt, &mut Report::default(), progress, false),
+    };
+    let started = std::time::SystemTime::now();
+    let mut report = Report::default();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        create_archive_with_report(opt, &mut report, progress, false)
+    }));
+    let message = result.as_ref().err().map(|e| panic_message(e.as_ref()));
+    let mut out = open_output(filename, &mut 0);
+    let result_for_report = match (&result, &message) {
+        (Ok(stats), _) => Ok(stats),
+        (Err(_), message) => Err(message.as_deref().unwrap()),
+    };
+    write_report(&mut out, opt, args, &report, result_for_report, started).unwrap();
+    match result {
+        Ok(stats) => stats,
+        Err(e) => std::panic::resume_unwind(e),
+    }
+}
+
+/// returns the files which are signed by --sign-with-gpg, --sign-minisign and --sign-ssh
+fn signed_outputs(opt: &DeterministicTarOpt) -> Vec<&str> {
+    let split_manifest = opt
+        .split_by_top_dir
+        .as_ref()
+        .map(|_| opt.split_manifest.as_str());
+    [
+        opt.output_hash.as_deref(),
+        opt.output_archive_hash.as_deref(),
+        split_manifest,
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// creates the detached ASCII armored signature "<filename>.asc" with gpg
+fn sign_with_gpg(key: &str, filename: &str) -> Result<(), std::io::Error> {
+    let status = std::process::Command::new("gpg")
+        .args([
+            "--batch",
+            "--yes",
+            "--local-user",
+            key,
+            "--armor",
+            "--detach-sign",
+        ])
+        .arg("--output")
+        .arg(format!("{}.asc", filename))
+        .arg(filename)
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!(
+            "gpg failed with {} for {:?}",
+            status, filename
+        )))
+    }
+}
+
+/// creates the SSH signature "<filename>.sig" with "ssh-keygen -Y sign". The file is passed on
+/// stdin, so ssh-keygen doesn't ask whether an existing signature should be overwritten.
+fn sign_with_ssh(
+    key: &std::path::Path,
+    namespace: &str,
+    filename: &str,
+) -> Result<(), std::io::Error> {
+    let output = std::process::Command::new("ssh-keygen")
+        .args(["-Y", "sign", "-n", namespace, "-f"])
+        .arg(key)
+        .stdin(File::open(filename)?)
+        .stderr(std::process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "ssh-keygen failed with {} for {:?}",
+            output.status, filename
+        )));
+    }
+    std::fs::write(format!("{}.sig", filename), output.stdout)
+}
+
+/// returns the first line of a password file, without the line break
+#[cfg(any(feature = "minisign", feature = "age"))]
+fn read_first_line(path: &std::path::Path) -> String {
+    let content =
+        std::fs::read_to_string(path).unwrap_or_else(|_| panic!("could not read file {:?}", path));
+    content.lines().next().unwrap_or("").to_string()
+}
+
+/// returns the globs of --exclude followed by the ones of the --exclude-from files
+fn excluded_globs(exclude: &[Glob], exclude_from: &[PathBuf]) -> Vec<Glob> {
+    let mut globs = exclude.to_vec();
+    for path in exclude_from {
+        let content = std::fs::read_to_string(path)
+            .unwrap_or_else(|_| panic!("could not read file {:?}", path));
+        for (number, line) in glob_lines(&content) {
+            let glob = line
+                .parse()
+                .unwrap_or_else(|e| panic!("line {} of {:?}: {}", number, path, e));
+            globs.push(glob);
+        }
+    }
+    globs
+}
+
+/// reads the passphrase of --encrypt-passphrase or --decrypt-passphrase
+#[cfg(feature = "age")]
+fn read_passphrase(path: &std::path::Path) -> age::secrecy::SecretString {
+    let passphrase = read_first_line(path);
+    if passphrase.is_empty() {
+        panic!("the passphrase in {:?} is empty", path);
+    }
+    passphrase.into()
+}
+
+/// reads the secret key of --sign-minisign and decrypts it with --minisign-password-file
+#[cfg(feature = "minisign")]
+fn read_minisign_key(path: &std::path::Path, password_file: Option<&std::path::Path>) -> SecretKey {
+    let content =
+        std::fs::read_to_string(path).unwrap_or_else(|_| panic!("could not read file {:?}", path));
+    let password = password_file.map(read_first_line);
+    SecretKey::parse(&content, password.as_deref())
+        .unwrap_or_else(|e| panic!("--sign-minisign {:?}: {}", path, e))
+}
+
+/// writes the minisign signature "<filename>.minisig" of a file
+#[cfg(feature = "minisign")]
+fn write_minisig(key: &SecretKey, filename: &str) -> Result<(), std::io::Error> {
+    // like the archive, the signature is reproducible with SOURCE_DATE_EPOCH
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<i64>().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64)
+        });
+    let basename = std::path::Path::new(filename)
+        .file_name()
+        .map_or_else(|| filename.into(), |name| name.to_string_lossy());
+    let trusted_comment = format!("timestamp:{}\tfile:{}\thashed", timestamp, basename);
+    let signature = key.sign(&mut File::open(filename)?, &trusted_comment)?;
+    std::fs::write(format!("{}.minisig", filename), signature)
+}
+
+/// returns the options for a dry run which creates the same archive, but only its digest.
+/// --output-report is only written by the caller
+fn dry_run_options(opt: &DeterministicTarOpt) -> DeterministicTarOpt {
+    let mut dry = opt.clone();
+    dry.skip_if_unchanged = None;
+    dry.self_check = false;
+    dry.output_template = None;
+    dry.output_hash = None;
+    dry.output_excluded = None;
+    dry.output_stats = None;
+    dry.dedup_report = None;
+    dry.output_archive_hash = None;
+    dry.output_root_hash = None;
+    dry.output_dir_hash = None;
+    dry.output_dir_hardlinks = None;
+    dry.output_frame_index = None;
+    dry.sign_with_gpg = None;
+    dry.sign_minisign = None;
+    dry.sign_ssh = None;
+    dry.encrypt_age = Vec::new();
+    dry.encrypt_passphrase = None;
+    dry.sealed_output = None;
+    dry.link_dest = None;
+    dry
+}
+
+/// checks the options of the default mode which cannot be combined or are not available, before
+/// anything is read or written
+fn validate_options(opt: &DeterministicTarOpt) -> Result<(), String> {
+    if opt.self_check
+        && (opt.split_by_top_dir.is_some() || opt.chunk_store.is_some() || opt.oci_layer)
+    {
+        return Err(
+            "--self-check cannot be combined with --split-by-top-dir, --chunk-store or --oci-layer"
+                .to_string(),
+        );
+    }
+    if opt.skip_if_unchanged.is_some()
+        && (opt.split_by_top_dir.is_some() || opt.chunk_store.is_some() || opt.oci_layer)
+    {
+        return Err("--skip-if-unchanged cannot be combined with --split-by-top-dir, --chunk-store or --oci-layer".to_string());
+    }
+    if let (Some(min), Some(max)) = (opt.min_depth, opt.max_depth) {
+        if min > max {
+            return Err(format!(
+                "--min-depth {} cannot be larger than --max-depth {}",
+                min, max
+            ));
+        }
+    }
+    if opt.zopfli && opt.compress != Compression::Gzip && !opt.oci_layer {
+        return Err("--zopfli can only be used with --compress gz or --oci-layer".to_string());
+    }
+    if !opt.store_only.is_empty() && !matches!(archive_format(opt), ArchiveFormat::Zip(_)) {
+        return Err("--store-only is only available for --format zip".to_string());
+    }
+    if opt.strict_ustar && archive_format(opt) != ArchiveFormat::Tar(TarFormat::UstarStrict) {
+        return Err("--strict-ustar is only available for --format ustar".to_string());
+    }
+    if let Some(level) = opt.compress_level {
+        if !matches!(
+            opt.compress,
+            Compression::Zstd(_) | Compression::ZstdSeekable(_)
+        ) || opt.oci_layer
+        {
+            return Err(
+                "--compress-level can only be used with --compress zstd or zstd-seekable"
+                    .to_string(),
+            );
+        }
+        if !ZSTD_LEVELS.contains(&level) {
+            return Err("--compress-level must be between 1 and 19".to_string());
+        }
+    }
+    if opt.use_compress_program.is_some()
+        && (opt.compress != Compression::None
+            || opt.chunk_store.is_some()
+            || opt.oci_layer
+            || opt.self_extracting)
+    {
+        return Err("--use-compress-program cannot be combined with --compress, --chunk-store, --oci-layer or --self-extracting".to_string());
+    }
+    if opt.output_frame_index.is_some() {
+        if !matches!(compression(opt), Compression::ZstdSeekable(_))
+            || opt.use_compress_program.is_some()
+            || opt.chunk_store.is_some()
+        {
+            return Err(
+                "--output-frame-index can only be used with --compress zstd-seekable".to_string(),
+            );
+        }
+        if !matches!(archive_format(opt), ArchiveFormat::Tar(_)) {
+            return Err("--output-frame-index is only available for the tar formats".to_string());
+        }
+        if (opt.output_tar == "-" && opt.output_template.is_none())
+            || opt.split_by_top_dir.is_some()
+        {
+            return Err("--output-frame-index needs --output-tar and cannot be combined with --split-by-top-dir, the index is read from the written file".to_string());
+        }
+    }
+    if (opt.output_archive_hash.is_some() || opt.expected_archive_hash.is_some())
+        && opt.split_by_top_dir.is_some()
+    {
+        return Err("--output-archive-hash and --expected-archive-hash cannot be combined with --split-by-top-dir, use --split-manifest".to_string());
+    }
+    if opt.chunk_store.is_some() && opt.compress != Compression::None {
+        return Err(
+            "--chunk-store cannot be combined with --compress, the chunks would not be shared"
+                .to_string(),
+        );
+    }
+    if opt.oci_layer {
+        if opt.output_tar == "-" {
+            return Err(
+                "--oci-layer needs --output-tar, the digests are written to stdout".to_string(),
+            );
+        }
+        if opt.split_by_top_dir.is_some() || opt.chunk_store.is_some() {
+            return Err(
+                "--oci-layer cannot be combined with --split-by-top-dir or --chunk-store"
+                    .to_string(),
+            );
+        }
+        if !opt.pax_global.is_empty() {
+            return Err("--oci-layer cannot be combined with --pax-global".to_string());
+        }
+    }
+    if opt.no_tar {
+        if opt.output_tar != "-"
+            || opt.output_template.is_some()
+            || opt.split_by_top_dir.is_some()
+            || opt.chunk_store.is_some()
+            || opt.oci_layer
+            || opt.self_extracting
+            || opt.skip_if_unchanged.is_some()
+            || opt.self_check
+            || opt.embed_hash_manifest.is_some()
+            || opt.output_archive_hash.is_some()
+            || opt.expected_archive_hash.is_some()
+            || opt.output_frame_index.is_some()
+            || opt.output_stats.is_some()
+            || opt.use_compress_program.is_some()
+            || opt.compress != Compression::None
+            || !opt.encrypt_age.is_empty()
+            || opt.encrypt_passphrase.is_some()
+        {
+            return Err("--no-tar cannot be combined with options for the archive: --output-tar, --output-template, --split-by-top-dir, --chunk-store, --oci-layer, --self-extracting, --skip-if-unchanged, --self-check, --embed-hash-manifest, --output-archive-hash, --expected-archive-hash, --output-frame-index, --output-stats, --use-compress-program, --compress, --encrypt-age or --encrypt-passphrase".to_string());
+        }
+        if opt.output_hash.is_none()
+            && opt.output_root_hash.is_none()
+            && opt.output_dir_hash.is_none()
+            && opt.expected_root_hash.is_none()
+            && opt.dedup_report.is_none()
+        {
+            return Err("--no-tar needs --output-hash, --output-root-hash, --output-dir-hash, --expected-root-hash or --dedup-report".to_string());
+        }
+    }
+    if opt.sign_with_gpg.is_some() || opt.sign_minisign.is_some() || opt.sign_ssh.is_some() {
+        let signed = signed_outputs(opt);
+        if (signed.is_empty() && opt.sealed_output.is_none()) || signed.contains(&"-") {
+            return Err("--sign-with-gpg, --sign-minisign and --sign-ssh need --output-hash, --output-archive-hash, --split-manifest or --sealed-output and the lists must be files, not stdout".to_string());
+        }
+    }
+    if opt.sign_ssh.is_some() != opt.sign_namespace.is_some() {
+        return Err("--sign-ssh and --sign-namespace must be given together".to_string());
+    }
+    #[cfg(not(feature = "minisign"))]
+    if opt.sign_minisign.is_some() || opt.minisign_password_file.is_some() {
+        return Err(
+            "--sign-minisign is not available, it was disabled at compile time".to_string(),
+        );
+    }
+    if !opt.encrypt_age.is_empty() && opt.encrypt_passphrase.is_some() {
+        return Err("--encrypt-age cannot be combined with --encrypt-passphrase".to_string());
+    }
+    if opt.sealed_output.is_some() {
+        if opt.encrypt_age.is_empty() && opt.encrypt_passphrase.is_none() {
+            return Err("--sealed-output needs --encrypt-age or --encrypt-passphrase".to_string());
+        }
+        if opt.sign_with_gpg.is_none() && opt.sign_minisign.is_none() && opt.sign_ssh.is_none() {
+            return Err(
+                "--sealed-output needs --sign-with-gpg, --sign-minisign or --sign-ssh".to_string(),
+            );
+        }
+        if opt.output_tar != "-" || opt.output_template.is_some() || opt.no_tar {
+            return Err("--sealed-output cannot be combined with --output-tar, --output-template or --no-tar".to_string());
+        }
+    }
+    if (!opt.encrypt_age.is_empty() || opt.encrypt_passphrase.is_some())
+        && (opt.split_by_top_dir.is_some()
+            || opt.chunk_store.is_some()
+            || opt.oci_layer
+            || opt.self_extracting
+            || opt.output_frame_index.is_some())
+    {
+        return Err("--encrypt-age and --encrypt-passphrase cannot be combined with --split-by-top-dir, --chunk-store, --oci-layer, --self-extracting or --output-frame-index".to_string());
+    }
+    #[cfg(not(feature = "age"))]
+    if !opt.encrypt_age.is_empty() || opt.encrypt_passphrase.is_some() {
+        return Err("--encrypt-age and --encrypt-passphrase are not available, they were disabled at compile time".to_string());
+    }
+    if opt.paranoid && opt.inode_order_read {
+        return Err("--paranoid cannot be combined with --inode-order-read".to_string());
+    }
+    if opt.self_extracting
+        && (!matches!(archive_format(opt), ArchiveFormat::Tar(_))
+            || opt.chunk_store.is_some()
+            || opt.oci_layer)
+    {
+        return Err("--self-extracting is only available for the tar formats and cannot be combined with --chunk-store or --oci-layer".to_string());
+    }
+    if opt.dedup_content
+        && (!matches!(archive_format(opt), ArchiveFormat::Tar(_)) || opt.split_by_top_dir.is_some())
+    {
+        return Err("--dedup-content is only available for the tar formats and cannot be combined with --split-by-top-dir".to_string());
+    }
+    if opt.hardlinks == HardlinkPolicy::Keep
+        && (!matches!(archive_format(opt), ArchiveFormat::Tar(_)) || opt.split_by_top_dir.is_some())
+    {
+        return Err("--hardlinks keep is only available for the tar formats and cannot be combined with --split-by-top-dir".to_string());
+    }
+    if opt.dangling_symlinks == DanglingSymlinkPolicy::StoreAsSymlink
+        && !matches!(archive_format(opt), ArchiveFormat::Tar(_))
+    {
+        return Err(
+            "--dangling-symlinks store-as-symlink is only available for the tar formats"
+                .to_string(),
+        );
+    }
+    if opt.special_files == SpecialFilePolicy::Store
+        && (!matches!(archive_format(opt), ArchiveFormat::Tar(_))
+            || archive_format(opt) == ArchiveFormat::Tar(TarFormat::V7)
+            || opt.output_dir_hardlinks.is_some()
+            || opt.oci_layer)
+    {
+        return Err("--special-files store is only available for the tar formats except v7 and cannot be combined with --output-dir-hardlinks or --oci-layer".to_string());
+    }
+    if opt.dedup_content && !opt.hash_algorithm.is_cryptographic() {
+        return Err(format!(
+            "--dedup-content needs a cryptographic --hash-algorithm, files with the same {} may differ",
+            opt.hash_algorithm.tag()
+        ));
+    }
+    if opt.dedup_report.is_some() && !opt.hash_algorithm.is_cryptographic() {
+        return Err(format!(
+            "--dedup-report needs a cryptographic --hash-algorithm, files with the same {} may differ",
+            opt.hash_algorithm.tag()
+        ));
+    }
+    if opt.keep_cachedir_tag && !opt.exclude_caches {
+        return Err("--keep-cachedir-tag can only be used with --exclude-caches".to_string());
+    }
+    if let Some(placeholder) = &opt.empty_dir_placeholder {
+        if opt.empty_dirs_ignored {
+            return Err(
+                "--empty-dir-placeholder cannot be combined with --empty-dirs-ignored".to_string(),
+            );
+        }
+        if placeholder.is_empty()
+            || placeholder.contains('/')
+            || placeholder == "."
+            || placeholder == ".."
+        {
+            return Err(
+                "--empty-dir-placeholder must be a file name without \"/\", e.g. .keep".to_string(),
+            );
+        }
+    }
+    if let Some(name) = &opt.embed_hash_manifest {
+        if opt.split_by_top_dir.is_some() {
+            return Err(
+                "--embed-hash-manifest cannot be combined with --split-by-top-dir".to_string(),
+            );
+        }
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(
+                "--embed-hash-manifest must be a file name without \"/\", e.g. SHA512SUMS"
+                    .to_string(),
+            );
+        }
+    }
+    if let Some(template) = &opt.output_template {
+        if opt.output_tar != "-" {
+            return Err("--output-template cannot be combined with --output-tar".to_string());
+        }
+        if opt.split_by_top_dir.is_some() || opt.skip_if_unchanged.is_some() || opt.oci_layer {
+            return Err("--output-template cannot be combined with --split-by-top-dir, --skip-if-unchanged or --oci-layer".to_string());
+        }
+        let dir = std::path::Path::new(template).parent();
+        if dir.is_some_and(|dir| dir.to_string_lossy().contains('{')) {
+            return Err("--output-template only allows placeholders in the file name".to_string());
+        }
+    }
+    if !archive_format(opt).supports_name_encoding(opt.name_encoding) {
+        return Err("--name-encoding latin1 or raw cannot be combined with --format pax or --oci-layer, pax headers are always UTF-8".to_string());
+    }
+    if opt.root_mode.is_some() || opt.root_mtime.is_some() {
+        if opt.oci_layer {
+            return Err(
+                "--root-mode and --root-mtime cannot be combined with --oci-layer".to_string(),
+            );
+        }
+        if let ArchiveFormat::Zip(_) | ArchiveFormat::Ar = archive_format(opt) {
+            return Err(
+                "--root-mode and --root-mtime are not available with --format zip or ar"
+                    .to_string(),
+            );
+        }
+    }
+    if opt.eof_blocks < 2 {
+        return Err("--eof-blocks must be at least 2".to_string());
+    }
+    if opt.blocking_factor == 0 {
+        return Err("--blocking-factor must be at least 1".to_string());
+    }
+    if opt.sparse && !opt.format.supports_sparse() {
+        return Err("--sparse can only be used with --format gnu or pax".to_string());
+    }
+    if opt.xattr_capabilities || opt.selinux {
+        let option = if opt.xattr_capabilities {
+            "--xattr-capabilities"
+        } else {
+            "--selinux"
+        };
+        if archive_format(opt) != ArchiveFormat::Tar(TarFormat::Pax) {
+            return Err(format!(
+                "{} can only be used with --format pax or --oci-layer",
+                option
+            ));
+        }
+        // a hardlink would give all files with the same content the attributes of the first
+        if opt.dedup_content {
+            return Err(format!(
+                "{} cannot be combined with --dedup-content",
+                option
+            ));
+        }
+        #[cfg(not(all(feature = "xattr", target_os = "linux")))]
+        return Err(format!(
+            "{} is not available, it was disabled at compile time or this is not Linux",
+            option
+        ));
+    }
+    if !opt.pax_global.is_empty() {
+        if !opt.format.supports_pax_global() {
+            return Err(
+                "--pax-global cannot be used with --format v7, cpio-newc, zip or ar".to_string(),
+            );
+        }
+        let mut keys: Vec<&String> = opt.pax_global.iter().map(|(key, _)| key).collect();
+        keys.sort();
+        if let Some(w) = keys.windows(2).find(|w| w[0] == w[1]) {
+            return Err(format!("--pax-global key {:?} given more than once", w[0]));
+        }
+    }
+    if let Some(template) = &opt.split_by_top_dir {
+        if !template.contains("{}") {
+            return Err("--split-by-top-dir template must contain \"{}\"".to_string());
+        }
+        if !opt.add_command.is_empty() {
+            return Err("--add-command cannot be combined with --split-by-top-dir".to_string());
+        }
+    }
+    if opt.link_dest.is_some() && opt.output_dir_hardlinks.is_none() {
+        return Err("--link-dest can only be used with --output-dir-hardlinks".to_string());
+    }
+    Ok(())
+}
+
+/// with dry_run, nothing is written and the digest of the archive is added to the report
+fn create_archive_with_report(
+    opt: &DeterministicTarOpt,
+    report: &mut Report,
+    progress: &mut dyn FnMut(u64),
+    dry_run: bool,
+) -> TarStats {
+    if let Some(previous) = &opt.skip_if_unchanged {
+        if let Some(expected) = previous_digest(previous, &opt.output_tar) {
+            let dry = dry_run_options(opt);
+            let mut dry_report = Report::default();
+            let stats = create_archive_with_report(&dry, &mut dry_report, &mut |_| {}, true);
+            if dry_report.archives[0].1 == expected {
+                eprintln!("{:?} is unchanged, it was not written", opt.output_tar);
+                // the excluded entries and warnings are the same as if it was written
+                *report = dry_report;
+                report.skipped = Some(true);
+                return stats;
+            }
+        }
+        report.skipped = Some(false);
+    }
+    let input = opt
+        .input
+        .as_ref()
+        .unwrap()
+        .canonicalize()
+        .expect("error getting absolute path of input file/directory");
+
+    let parent = input
+        .parent()
+        .expect("input directory has no parent!")
+        .to_path_buf();
+    let main_dir_name = validate_main_dir_name(&opt.main_dir_name)
+        .unwrap_or_else(|| input.file_name().unwrap().into());
+    // the walker only checks the names below the input, in split mode they are the top-level names
+    if let (UnsafeNamePolicy::Error | UnsafeNamePolicy::Skip, Some(reason)) = (
+        opt.unsafe_names,
+        unsafe_name_reason(main_dir_name.as_os_str()),
+    ) {
+        if opt.split_by_top_dir.is_none() && !opt.oci_layer {
+            panic!(
+                "the name of the input {:?} {}, it can be changed with --main-dir-name",
+                main_dir_name, reason
+            );
+        }
+    }
+    let remaining = vec![input.clone()];
+
+    let walk_options = DirWalkOptions {
+        empty_dirs_ignored: opt.empty_dirs_ignored,
+        symlinks_should_abort: opt.symlinks_should_abort,
+        symlink_policy: opt.symlink_policy,
+        dangling_symlinks: opt.dangling_symlinks,
+        special_files: opt.special_files,
+        unsafe_names: opt.unsafe_names,
+        dot_files_excluded: opt.dot_files_excluded,
+        one_file_system: opt.one_file_system,
+        exclude_caches: opt.exclude_caches,
+        keep_cachedir_tag: opt.keep_cachedir_tag,
+        exclude_larger_than: opt.exclude_larger_than,
+        exclude_smaller_than: opt.exclude_smaller_than,
+        max_depth: opt.max_depth,
+        min_depth: opt.min_depth,
+        ignored_filenames: opt.ignored_names.clone(),
+        ignored_paths: opt.ignored_paths.clone(),
+        excluded_globs: excluded_globs(&opt.exclude, &opt.exclude_from),
+        respect_gitignore: opt.respect_gitignore,
+        excluded_link_targets: opt.exclude_link_target.clone(),
+        included_paths: opt.include.clone(),
+        first_names: opt.first.clone(),
+        paranoid: opt.paranoid,
+        max_entries: opt.max_entries,
+        max_entries_per_dir: opt.max_entries_per_dir,
+    };
+    if opt.suggest_excludes {
+        // the suggestions are most useful for exactly the inputs which exceed the limits
+        let walk_options = DirWalkOptions {
+            max_entries: None,
+            max_entries_per_dir: None,
+            ..walk_options
+        };
+        for s in suggest_excludes(DirWalkIterator::new(&parent, &remaining, &walk_options)) {
+            println!("{}", s);
+        }
+        return TarStats::default();
+    }
+
+    // the key is read first, so a wrong password doesn't waste a whole run
+    #[cfg(feature = "minisign")]
+    let minisign_key = opt
+        .sign_minisign
+        .as_ref()
+        .map(|path| read_minisign_key(path, opt.minisign_password_file.as_deref()));
+    #[cfg(feature = "age")]
+    let mut age_encryptor = if let Some(path) = &opt.encrypt_passphrase {
+        Some(age::Encryptor::with_user_passphrase(read_passphrase(path)))
+    } else if !opt.encrypt_age.is_empty() {
+        let recipients: Vec<age::x25519::Recipient> = opt
+            .encrypt_age
+            .iter()
+            .map(|r| {
+                r.parse()
+                    .unwrap_or_else(|e| panic!("invalid age recipient {:?}: {}", r, e))
+            })
+            .collect();
+        Some(
+            age::Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+                .unwrap_or_else(|e| panic!("could not encrypt to the age recipients: {}", e)),
+        )
+    } else {
+        None
+    };
+    if let Some(name) = &opt.embed_hash_manifest {
+        if !input.is_dir() {
+            panic!("--embed-hash-manifest needs a directory as input");
+        }
+        if input.join(name).exists() || opt.add_command.iter().any(|c| &c.name == name) {
+            panic!(
+                "--embed-hash-manifest {:?} would duplicate an existing file",
+                name
+            );
+        }
+    }
+    if let Some(template) = &opt.output_template {
+        // all placeholders are checked before anything is written
+        let name = main_dir_name.to_string_lossy();
+        if let Err(e) = expand_output_template(template, &"0".repeat(128), &name) {
+            panic!("--output-template: {}", e);
+        }
+    }
+    // mode and modification time of the main directory entry
+    let root_metadata = match (opt.root_mode, opt.root_mtime) {
+        (None, None) => None,
+        (mode, mtime) => {
+            if !input.is_dir() {
+                panic!("--root-mode and --root-mtime need a directory as input");
+            }
+            Some((mode.unwrap_or(0o755), mtime))
+        }
+    };
+
+    // prepare output streams
+    let mut stdout_used: usize = 0;
+    // with --output-template, the name is only known after the archive was written
+    // with --sealed-output, the encrypted archive is written to a temporary directory first
+    let sealed_dir = opt
+        .sealed_output
+        .as_ref()
+        .map(|sealed| SealedDir::create(sealed));
+    let output_path = match (&opt.output_template, &sealed_dir) {
+        (Some(template), _) => output_template_temp(template),
+        (None, Some(dir)) => format!("{}/archive.age", dir.path),
+        (None, None) => opt.output_tar.clone(),
+    };
+    let mut output_tar = match opt.split_by_top_dir {
+        _ if opt.no_tar => None,
+        Some(_) => None,
+        None => {
+            let out: Box<dyn Write> = if dry_run {
+                Box::new(std::io::sink())
+            } else {
+                open_output(&output_path, &mut stdout_used)
+            };
+            #[cfg(feature = "age")]
+            let out = match age_encryptor.take() {
+                Some(encryptor) => OutputFile::encrypt(out, encryptor),
+                None => OutputFile::Plain(out),
+            };
+            #[cfg(not(feature = "age"))]
+            let out = OutputFile::Plain(out);
+            if opt.self_extracting && !dry_run {
+                make_executable(&output_path);
+            }
+            // the digest is only needed for the report, --skip-if-unchanged, --self-check and
+            // --output-template
+            let out = if opt.output_report.is_some()
+                || opt.output_template.is_some()
+                || opt.self_check
+                || dry_run
+            {
+                HashingWriter::new(out)
+            } else {
+                HashingWriter::passthrough(out)
+            };
+            let out = if opt.output_archive_hash.is_some()
+                || opt.expected_archive_hash.is_some()
+                || opt.sealed_output.is_some()
+            {
+                out.with_archive_hash(opt.hash_algorithm)
+            } else {
+                out
+            };
+            Some(new_tar_output(opt, out))
+        }
+    };
+    let mut split_manifest = opt
+        .split_by_top_dir
+        .as_ref()
+        .map(|_| open_output(&opt.split_manifest, &mut stdout_used));
+    let mut output_hash = opt
+        .output_hash
+        .as_ref()
+        .map(|filename| open_output(filename, &mut stdout_used));
+    let mut output_excluded = opt
+        .output_excluded
+        .as_ref()
+        .map(|filename| open_output(filename, &mut stdout_used));
+    let mut output_stats = opt
+        .output_stats
+        .as_ref()
+        .map(|filename| open_output(filename, &mut stdout_used));
+    let mut output_root_hash = opt
+        .output_root_hash
+        .as_ref()
+        .map(|filename| open_output(filename, &mut stdout_used));
+    let mut output_dir_hash = opt
+        .output_dir_hash
+        .as_ref()
+        .map(|filename| open_output(filename, &mut stdout_used));
+    // names and digests of all files for --output-root-hash and --output-dir-hash
+    let mut hashed_files = (output_root_hash.is_some()
+        || output_dir_hash.is_some()
+        || opt.expected_root_hash.is_some()
+        || opt.sealed_output.is_some())
+    .then(Vec::new);
+    // names of all directories for --output-dir-hash
+    let mut hashed_dirs = output_dir_hash.as_ref().map(|_| Vec::new());
+    let mut embedded_manifest = opt.embed_hash_manifest.as_ref().map(|_| Vec::new());
+    let mut dedup_report = opt
+        .dedup_report
+        .as_ref()
+        .map(|filename| open_output(filename, &mut stdout_used));
+    let hashed = output_hash.is_some()
+        || hashed_files.is_some()
+        || embedded_manifest.is_some()
+        || dedup_report.is_some();
+    let mut output_archive_hash = opt
+        .output_archive_hash
+        .as_ref()
+        .map(|filename| open_output(filename, &mut stdout_used));
+    let mut output_frame_index = opt
+        .output_frame_index
+        .as_ref()
+        .map(|filename| open_output(filename, &mut stdout_used));
+    // offset in the uncompressed archive and name of each entry for --output-frame-index
+    let mut frame_index: Vec<(u64, String)> = Vec::new();
+    if opt.oci_layer || opt.output_template.is_some() {
+        // for the digests or the name of the archive
+        stdout_used += 1;
+    }
+    if opt.output_report.as_deref() == Some("-") {
+        // it is opened after the archive was written
+        stdout_used += 1;
+    }
+    if stdout_used > 1 {
+        panic!("Stdout used for more than one argument!");
+    }
+
+    let mut farm = opt.output_dir_hardlinks.as_ref().map(|dir| {
+        HardlinkFarm::new(dir, opt.link_dest.as_deref())
+            .unwrap_or_else(|_| panic!("could not create directory {:?}", dir))
+    });
+
+    // archive which is currently written in --split-by-top-dir mode, together with its filename
+    let mut split_current: Option<(String, ArchiveOutput<CompressedOutput<HashingWriter<File>>>)> =
+        None;
+    let mut split_stats = TarStats::default();
+
+    // now, iterate through all files
+    let mut entries = 0u64;
+    let mut duplicates = Duplicates {
+        later: dedup_report.as_ref().map(|_| HashMap::new()),
+        ..Duplicates::default()
+    };
+    // with --hardlinks keep, the name of the first entry of each file with more than one name, by
+    // device and inode
+    let mut first_links: HashMap<(u64, u64), Vec<u8>> = HashMap::new();
+    // the digests of files with more than one name which were hashed before, by device and inode,
+    // so the other names don't have to be read again to hash them
+    let mut inode_digests: HashMap<(u64, u64), String> = HashMap::new();
+    // with --format ustar-strict, the devices which don't fit into the header, reported after the
+    // walk
+    let strict_ustar = archive_format(opt) == ArchiveFormat::Tar(TarFormat::UstarStrict);
+    let mut strict_ustar_errors = Vec::new();
+    let walk = DirWalkIterator::new(&parent, &remaining, &walk_options);
+    let walk: Box<dyn Iterator<Item = _>> = if opt.inode_order_read {
+        Box::new(InodeOrderReader::new(walk, opt.inode_order_buffer))
+    } else {
+        Box::new(walk.map(|d| (d, None)))
+    };
+    for (d, content) in walk {
+        // in split mode, each top-level file or directory is the main entry of its own archive,
+        // OCI layers contain the content of the input directory without a main entry
+        let mut tarname = if opt.split_by_top_dir.is_some() || opt.oci_layer {
+            PathBuf::new()
+        } else {
+            main_dir_name.clone()
+        };
+        for p in d.relpath.iter().skip(1) {
+            tarname.push(p);
+        }
+        // the skipped names are escaped as well, so they cannot break --output-excluded
+        if opt.unsafe_names == UnsafeNamePolicy::Escape
+            || matches!(d.typ, DirWalkType::Excluded(ExclusionReason::UnsafeName))
+        {
+            tarname = escape_unsafe_components(&tarname);
+        }
+        if let DirWalkType::Excluded(reason) = &d.typ {
+            if let ExclusionReason::SymlinkOutside = reason {
+                let warning = format!(
+                    "symlink {:?} points outside of the input, it was skipped",
+                    tarname.to_string_lossy()
+                );
+                // the dry run of --skip-if-unchanged and --self-check walks the same input
+                if !dry_run {
+                    eprintln!("WARNING: {}", warning);
+                }
+                report.warnings.push(warning);
+            }
+            if let Some(out) = output_excluded.as_mut() {
+                writeln!(out, "{}\t{}", reason, tarname.to_string_lossy()).unwrap();
+            }
+            if opt.output_report.is_some() {
+                let name = tarname.to_string_lossy().to_string();
+                report.excluded.push((name, reason.to_string()));
+            }
+            continue;
+        }
+        if opt.oci_layer && tarname.as_os_str().is_empty() {
+            if !d.abspath.is_dir() {
+                panic!("--oci-layer needs a directory as input");
+            }
+            continue;
+        }
+        if opt.output_report.is_some() {
+            if let DirWalkType::SymlinkToFile(_) | DirWalkType::SymlinkToDirectory = d.typ {
+                report.warnings.push(format!(
+                    "symlink {:?} was replaced by its target",
+                    tarname.to_string_lossy()
+                ));
+            }
+        }
+        let state_before = if opt.paranoid { file_state(&d) } else { None };
+        // the main directory, in split mode the top-level directories
+        let is_root = tarname.components().count() == 1;
+        if let Some(farm) = farm.as_mut() {
+            add_walk_item_to_farm(farm, &d, content.as_ref(), &tarname).unwrap();
+            if let Some(placeholder) = opt.empty_dir_placeholder.as_ref().filter(|_| d.empty) {
+                farm.add_file(&tarname.join(placeholder), &mut std::io::empty(), 0)
+                    .unwrap();
+            }
+        }
+        // the size of files with content, whose digest is counted for the duplicate statistics
+        let size = d.size.filter(|size| {
+            *size > 0 && matches!(d.typ, DirWalkType::File | DirWalkType::SymlinkToFile(_))
+        });
+        let cached_digest = d.inode.and_then(|inode| inode_digests.get(&inode).cloned());
+        // with --hardlinks keep, the name of the first entry of the same file
+        let inode = d.inode.filter(|_| opt.hardlinks == HardlinkPolicy::Keep);
+        let first_link = inode.and_then(|inode| first_links.get(&inode).cloned());
+        // with --dedup-content, the digest and the name of the first file with the same content
+        let hardlink = match size {
+            Some(size) if opt.dedup_content => {
+                let digest = match (&content, &cached_digest) {
+                    (_, Some(digest)) => digest.clone(),
+                    (Some(content), None) => opt
+                        .hash_algorithm
+                        .hex_digest_of(&mut content.as_slice())
+                        .unwrap(),
+                    (None, None) => digest_of_file(&d.abspath, opt.hash_algorithm)
+                        .unwrap_or_else(|e| panic!("could not read {:?}: {}", d.abspath, e)),
+                };
+                if let Some(inode) = d.inode {
+                    inode_digests.insert(inode, digest.clone());
+                }
+                let name = opt.name_encoding.encode(tarname.as_os_str()).unwrap();
+                duplicates
+                    .add(digest.clone(), size, &name)
+                    .map(|target| (digest, target))
+            }
+            _ => None,
+        };
+        let hardlink = first_link
+            .clone()
+            .map(|target| (cached_digest.clone().unwrap_or_default(), target))
+            .or(hardlink);
+        let link_name = inode.map(|_| opt.name_encoding.encode(tarname.as_os_str()).unwrap());
+        let report_name = dedup_report
+            .as_ref()
+            .map(|_| opt.name_encoding.encode(tarname.as_os_str()).unwrap());
+        if let (Some(out), Some(_)) = (&output_tar, &output_frame_index) {
+            let mut name = tarname.to_string_lossy().to_string();
+            if let DirWalkType::Directory | DirWalkType::SymlinkToDirectory = d.typ {
+                name.push('/');
+            }
+            frame_index.push((out.stats().total_bytes(), name));
+        }
+        // in split mode, the input directory itself is not archived
+        if let (Some(dirs), DirWalkType::Directory | DirWalkType::SymlinkToDirectory, false) =
+            (hashed_dirs.as_mut(), &d.typ, tarname.as_os_str().is_empty())
+        {
+            dirs.push(opt.name_encoding.encode(tarname.as_os_str()).unwrap());
+        }
+        if let (true, DirWalkType::Special(special)) = (strict_ustar, &d.typ) {
+            let name = opt.name_encoding.encode(tarname.as_os_str()).unwrap();
+            let (_, device) = special_typeflag(*special);
+            if let Err(e) = StrictUstarFormat.encode_device(&name, device) {
+                strict_ustar_errors.push(e.to_string());
+                continue;
+            }
+        }
+        // the hash line of each entry is buffered to count duplicate content
+        let mut hash_line = hashed.then(Vec::new);
+        // another name of the same file was hashed before, so its content is only copied and its
+        // hash line gets the cached digest
+        let reused_digest = cached_digest
+            .as_ref()
+            .filter(|_| hardlink.is_none() && hash_line.is_some())
+            .map(|digest| {
+                (
+                    digest,
+                    opt.name_encoding.encode(tarname.as_os_str()).unwrap(),
+                )
+            });
+        let item_hash_line = hash_line.as_mut().filter(|_| reused_digest.is_none());
+        match (&mut output_tar, &opt.split_by_top_dir) {
+            (Some(out), _) if hardlink.is_some() => write_hardlink_item(
+                out.as_mut(),
+                hash_line.as_mut(),
+                &tarname,
+                hardlink.as_ref().unwrap(),
+                opt,
+            ),
+            (Some(out), _) => write_walk_item(
+                out.as_mut(),
+                item_hash_line,
+                &d,
+                content,
+                tarname,
+                opt,
+                root_metadata.filter(|_| is_root),
+            ),
+            (None, Some(template)) => {
+                let component = match tarname.iter().next() {
+                    Some(component) => component.to_string_lossy(),
+                    // the main directory itself is not part of any split archive
+                    None => continue,
+                };
+                let filename = template.replace("{}", &component);
+                if split_current.as_ref().map(|(f, _)| f) != Some(&filename) {
+                    if let Some(previous) = split_current.take() {
+                        finish_split_archive(
+                            opt,
+                            previous,
+                            split_manifest.as_mut().unwrap(),
+                            &mut split_stats,
+                            report,
+                        );
+                    }
+                    let file = File::create(&filename)
+                        .unwrap_or_else(|_| panic!("could not open file {:?}", &filename));
+                    if opt.self_extracting {
+                        make_executable(&filename);
+                    }
+                    split_current = Some((filename, new_tar_output(opt, HashingWriter::new(file))));
+                }
+                write_walk_item(
+                    split_current.as_mut().unwrap().1.as_mut(),
+                    item_hash_line,
+                    &d,
+                    content,
+                    tarname,
+                    opt,
+                    root_metadata.filter(|_| is_root),
+                )
+            }
+            (None, None) => hash_walk_item(item_hash_line, &d, content, &tarname, opt),
+        }
+        .unwrap();
+        if let (Some(hash_line), Some((digest, name))) = (hash_line.as_mut(), &reused_digest) {
+            write_digest_line(hash_line, hash_options(opt), digest, name).unwrap();
+        }
+        let digest = hash_line.and_then(|hash_line| {
+            add_hash_line(
+                &hash_line,
+                output_hash.as_mut(),
+                hashed_files.as_mut(),
+                embedded_manifest.as_mut(),
+            )
+        });
+        if let (Some(digest), Some(size), false) = (&digest, size, opt.dedup_content) {
+            // the name is only needed for --dedup-content and --dedup-report
+            duplicates.add(digest.clone(), size, &report_name.unwrap_or_default());
+        }
+        if let (Some(inode), Some(digest)) = (d.inode, digest) {
+            inode_digests.entry(inode).or_insert(digest);
+        }
+        if let (Some(inode), Some(name), None) = (inode, link_name, first_link) {
+            first_links.insert(inode, name);
+        }
+        if state_before.is_some() && file_state(&d) != state_before {
+            panic!("--paranoid: {:?} changed while it was archived", d.abspath);
+        }
+        entries += 1;
+        progress(entries);
+    }
+    if !strict_ustar_errors.is_empty() {
+        panic!(
+            "--format ustar-strict: {} devices cannot be stored:\n{}",
+            strict_ustar_errors.len(),
+            strict_ustar_errors.join("\n")
+        );
+    }
+    if let Some(previous) = split_current.take() {
+        finish_split_archive(
+            opt,
+            previous,
+            split_manifest.as_mut().unwrap(),
+            &mut split_stats,
+            report,
+        );
+    }
+
+    let mut add_commands = opt.add_command.clone();
+    add_commands.sort_by(|a, b| a.name.cmp(&b.name));
+    for c in add_commands {
+        if !input.is_dir() {
+            panic!("--add-command can only be used if the input is a directory");
+        }
+        if input.join(&c.name).exists() {
+            panic!(
+                "--add-command {:?} would duplicate an existing file",
+                &c.name
+            );
+        }
+        let content = run_add_command(&c);
+        let tarname = if opt.oci_layer {
+            PathBuf::from(&c.name)
+        } else {
+            main_dir_name.join(&c.name)
+        };
+        if let (Some(out), Some(_)) = (&output_tar, &output_frame_index) {
+            let name = tarname.to_string_lossy().to_string();
+            frame_index.push((out.stats().total_bytes(), name));
+        }
+        let mut hash_line = hashed.then(Vec::new);
+        let name = opt.name_encoding.encode(tarname.as_os_str()).unwrap();
+        match output_tar.as_mut() {
+            Some(out) => out.write_file(
+                hash_line
+                    .as_mut()
+                    .map(|out| (out as &mut dyn Write, hash_options(opt))),
+                &mut std::io::Cursor::new(&content),
+                &(content.len() as u64),
+                &name,
+            ),
+            // --no-tar
+            None => hash_line.as_mut().map_or(Ok(()), |out| {
+                let digest = opt.hash_algorithm.hex_digest_of(&mut content.as_slice())?;
+                write_digest_line(out, hash_options(opt), &digest, &name)
+            }),
+        }
+        .unwrap();
+        if let Some(hash_line) = hash_line {
+            add_hash_line(
+                &hash_line,
+                output_hash.as_mut(),
+                hashed_files.as_mut(),
+                embedded_manifest.as_mut(),
+            );
+        }
+        if let Some(farm) = farm.as_mut() {
+            farm.add_file(
+                &tarname,
+                &mut std::io::Cursor::new(&content),
+                content.len() as u64,
+            )
+            .unwrap();
+        }
+        entries += 1;
+        progress(entries);
+    }
+    if let (Some(name), Some(manifest)) = (&opt.embed_hash_manifest, embedded_manifest) {
+        let tarname = if opt.oci_layer {
+            PathBuf::from(name)
+        } else {
+            main_dir_name.join(name)
+        };
+        if let (Some(out), Some(_)) = (&output_tar, &output_frame_index) {
+            let name = tarname.to_string_lossy().to_string();
+            frame_index.push((out.stats().total_bytes(), name));
+        }
+        output_tar
+            .as_mut()
+            .unwrap()
+            .write_file(
+                // the manifest doesn't list itself
+                None,
+                &mut std::io::Cursor::new(&manifest),
+                &(manifest.len() as u64),
+                &opt.name_encoding.encode(tarname.as_os_str()).unwrap(),
+            )
+            .unwrap();
+        if let Some(farm) = farm.as_mut() {
+            farm.add_file(
+                &tarname,
+                &mut std::io::Cursor::new(&manifest),
+                manifest.len() as u64,
+            )
+            .unwrap();
+        }
+        entries += 1;
+        progress(entries);
+    }
+    if let Some(farm) = farm {
+        farm.finish().unwrap();
+    }
+    // the file which was written, after --output-template renamed it, or the --sealed-output
+    // container which contains it
+    let mut written = opt
+        .sealed_output
+        .clone()
+        .unwrap_or_else(|| output_path.clone());
+    let mut archive_digest = None;
+    let stats = match output_tar {
+        Some(mut output_tar) => {
+            output_tar
+                .finish(opt.eof_blocks, opt.blocking_factor)
+                .unwrap();
+            let stats = output_tar.stats().clone();
+            let (digest, digest_of_archive_hash) = output_tar
+                .into_inner()
+                .finish()
+                .unwrap()
+                .finish_output()
+                .unwrap();
+            archive_digest = digest_of_archive_hash;
+            if let Some(digest) = digest {
+                let filename = match &opt.output_template {
+                    Some(template) => {
+                        let name = main_dir_name.to_string_lossy();
+                        let filename = expand_output_template(template, &digest, &name).unwrap();
+                        std::fs::rename(&output_path, &filename).unwrap_or_else(|e| {
+                            panic!(
+                                "could not rename {:?} to {:?}: {}",
+                                output_path, filename, e
+                            )
+                        });
+                        println!("{}", filename);
+                        written = filename.clone();
+                        filename
+                    }
+                    None => written.clone(),
+                };
+                report.archives.push((filename, digest));
+            }
+            stats
+        }
+        None => split_stats,
+    };
+    if let Some(out) = output_frame_index.as_mut() {
+        write_frame_index(out, &written, &frame_index).unwrap();
+    }
+    if let (Some(out), Some(files), Some(dirs)) =
+        (output_dir_hash.as_mut(), hashed_files.as_ref(), hashed_dirs)
+    {
+        for (mut name, digest) in directory_digests(opt.hash_algorithm, files, &dirs) {
+            name.push(b'/');
+            write_digest_line(out, hash_options(opt), &digest, &name).unwrap();
+        }
+    }
+    // the manifest of --sealed-output lists the files and the unencrypted archive
+    let sealed_manifest = hashed_files
+        .as_ref()
+        .filter(|_| opt.sealed_output.is_some())
+        .map(|files| {
+            let mut manifest = Vec::new();
+            for (name, digest) in files {
+                write_digest_line(&mut manifest, hash_options(opt), digest, name).unwrap();
+            }
+            if let Some(digest) = &archive_digest {
+                write_digest_line(&mut manifest, hash_options(opt), digest, b"archive").unwrap();
+            }
+            manifest
+        });
+    let root_digest = hashed_files.map(|files| merkle_root(opt.hash_algorithm, files));
+    if let (Some(out), Some(digest)) = (output_root_hash.as_mut(), &root_digest) {
+        writeln!(out, "{}", digest).unwrap();
+    }
+    if let (Some(out), Some(digest)) = (output_archive_hash.as_mut(), &archive_digest) {
+        write_digest_line(out, hash_options(opt), digest, written.as_bytes()).unwrap();
+    }
+    // duplicate content can only be counted if it was hashed
+    if opt.output_hash.is_some() || opt.dedup_content || opt.dedup_report.is_some() {
+        report.duplicates = Some((duplicates.files, duplicates.bytes));
+    }
+    if let Some(out) = dedup_report.as_mut() {
+        for (digest, names) in duplicates.groups() {
+            for name in names {
+                write_digest_line(out, hash_options(opt), digest, name).unwrap();
+            }
+        }
+    }
+    if let Some(out) = output_stats.as_mut() {
+        write!(out, "{}", stats).unwrap();
+        if let Some((files, bytes)) = report.duplicates {
+            writeln!(out, "duplicate_files: {}", files).unwrap();
+            writeln!(out, "duplicate_bytes: {}", bytes).unwrap();
+        }
+        if let Some(parameters) = compression_parameters(opt) {
+            writeln!(out, "compression: {}", parameters).unwrap();
+        }
+        if let ArchiveFormat::Zip(method) = archive_format(opt) {
+            write!(out, "zip: {}", method.parameters()).unwrap();
+            if !opt.store_only.is_empty() {
+                let globs: Vec<String> = opt.store_only.iter().map(|g| g.to_string()).collect();
+                write!(out, " store_only={}", globs.join(",")).unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+        if archive_format(opt) == ArchiveFormat::Squashfs {
+            writeln!(out, "squashfs: {}", squashfs_parameters()).unwrap();
+        }
+    }
+    if opt.oci_layer {
+        #[cfg(feature = "oci")]
+        {
+            let digests = layer_digests(std::path::Path::new(&opt.output_tar)).unwrap();
+            println!("diff_id: {}", digests.diff_id);
+            println!("digest: {}", digests.digest);
+            println!("size: {}", digests.size);
+        }
+        #[cfg(not(feature = "oci"))]
+        panic!("--oci-layer is not available, it was disabled at compile time");
+    }
+    // the outputs are complete, so the new digests can be recorded if the change was intended
+    for (option, expected, digest) in [
+        (
+            "--expected-archive-hash",
+            &opt.expected_archive_hash,
+            &archive_digest,
+        ),
+        (
+            "--expected-root-hash",
+            &opt.expected_root_hash,
+            &root_digest,
+        ),
+    ] {
+        if let (Some(expected), Some(digest)) = (expected, digest) {
+            if !expected.eq_ignore_ascii_case(digest) {
+                panic!(
+                    "{}: the digest is {} instead of {}",
+                    option, digest, expected
+                );
+            }
+        }
+    }
+    if opt.self_check {
+        // any difference means that the input changed in between or the output is not deterministic
+        let mut dry = dry_run_options(opt);
+        dry.expected_archive_hash = None;
+        dry.expected_root_hash = None;
+        let mut dry_report = Report::default();
+        create_archive_with_report(&dry, &mut dry_report, &mut |_| {}, true);
+        let digest = &report.archives.last().unwrap().1;
+        if &dry_report.archives[0].1 != digest {
+            panic!(
+                "--self-check: archiving the input again produced a different archive, the input changed while {:?} was written or the output is not deterministic",
+                written
+            );
+        }
+    }
+    if opt.sign_with_gpg.is_some() || opt.sign_minisign.is_some() || opt.sign_ssh.is_some() {
+        // the signed files are read again, so they have to be complete
+        drop(output_hash);
+        drop(output_archive_hash);
+        drop(split_manifest);
+        for filename in signed_outputs(opt) {
+            sign_file(
+                opt,
+                filename,
+                #[cfg(feature = "minisign")]
+                minisign_key.as_ref(),
+            );
+        }
+    }
+    if let (Some(sealed), Some(dir), Some(manifest)) =
+        (&opt.sealed_output, &sealed_dir, sealed_manifest)
+    {
+        let manifest_path = format!("{}/MANIFEST", dir.path);
+        std::fs::write(&manifest_path, manifest).unwrap();
+        sign_file(
+            opt,
+            &manifest_path,
+            #[cfg(feature = "minisign")]
+            minisign_key.as_ref(),
+        );
+        write_sealed_output(opt, open_output(sealed, &mut stdout_used), &dir.path)
+            .unwrap_or_else(|e| panic!("could not write --sealed-output {:?}: {}", sealed, e));
+    }
+    stats
+}
+
+/// signs a file with the keys of --sign-with-gpg, --sign-ssh and --sign-minisign
+fn sign_file(
+    opt: &DeterministicTarOpt,
+    filename: &str,
+    #[cfg(feature = "minisign")] minisign_key: Option<&SecretKey>,
+) {
+    if let Some(key) = &opt.sign_with_gpg {
+        sign_with_gpg(key, filename).unwrap_or_else(|e| panic!("--sign-with-gpg: {}", e));
+    }
+    if let (Some(key), Some(namespace)) = (&opt.sign_ssh, &opt.sign_namespace) {
+        sign_with_ssh(key, namespace, filename).unwrap_or_else(|e| panic!("--sign-ssh: {}", e));
+    }
+    #[cfg(feature = "minisign")]
+    if let Some(key) = minisign_key {
+        write_minisig(key, filename).unwrap_or_else(|e| panic!("--sign-minisign: {}", e));
+    }
+}
+
+/// the members of the --sealed-output container in this order, the signatures only exist for the
+/// signing options which were given
+const SEALED_MEMBERS: [&str; 5] = [
+    "MANIFEST",
+    "MANIFEST.asc",
+    "MANIFEST.minisig",
+    "MANIFEST.sig",
+    "archive.age",
+];
+
+/// writes the container of --sealed-output with the manifest, its signatures and the encrypted
+/// archive, which were written to the temporary directory
+fn write_sealed_output(
+    opt: &DeterministicTarOpt,
+    out: Box<dyn Write>,
+    dir: &str,
+) -> Result<(), std::io::Error> {
+    let mut container = archive::with_format(out, ArchiveFormat::Tar(TarFormat::Ustar))?;
+    for name in SEALED_MEMBERS {
+        let path = format!("{}/{}", dir, name);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let size = file.metadata()?.len();
+        container.write_file(
+            #[cfg(feature = "sha512")]
+            None,
+            &mut file,
+            &size,
+            name.as_bytes(),
+        )?;
+    }
+    container.finish(opt.eof_blocks, opt.blocking_factor)?;
+    container.into_inner().flush()
+}
+
+/// escapes a string for use inside a JSON string literal
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
 }
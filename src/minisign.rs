@@ -0,0 +1,116 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Blake2b512, Digest};
+use ed25519_dalek::{Signer, SigningKey};
+use std::io::Read;
+
+use crate::reader::invalid_data;
+
+/// size of the decoded second line of a secret key file: signature, key derivation and checksum
+/// algorithm, salt, ops and memory limit of scrypt and the (encrypted) key id, key and checksum
+const SECRET_KEY_SIZE: usize = 2 + 2 + 2 + 32 + 8 + 8 + 104;
+
+/// parameters of scrypt for the ops and memory limit of a secret key, like `pickparams` of
+/// libsodium, which minisign uses
+fn scrypt_params(opslimit: u64, memlimit: u64) -> Result<scrypt::Params, std::io::Error> {
+    let opslimit = opslimit.max(32768);
+    let r = 8u32;
+    let log_n = |max_n: u64| (1..63).find(|n| 1u64 << n > max_n / 2).unwrap_or(63);
+    let (log_n, p) = if opslimit < memlimit / 32 {
+        (log_n(opslimit / (r as u64 * 4)), 1)
+    } else {
+        let log_n = log_n(memlimit / (r as u64 * 128));
+        let max_rp = ((opslimit / 4) >> log_n).min(0x3fffffff);
+        (log_n, max_rp as u32 / r)
+    };
+    // the length is only used for password hash strings, the key stream is longer
+    scrypt::Params::new(log_n, r, p, 64)
+        .map_err(|_| invalid_data("invalid scrypt parameters in secret key".to_string()))
+}
+
+/// A secret key of minisign (<https://jedisct1.github.io/minisign/>), as created by
+/// `minisign -G`, which signs files with Ed25519.
+pub struct SecretKey {
+    key_id: [u8; 8],
+    signing_key: SigningKey,
+}
+
+impl SecretKey {
+    /// parses the content of a secret key file. Encrypted keys need the password, keys created
+    /// with `minisign -G -W` are not encrypted.
+    pub fn parse(content: &str, password: Option<&str>) -> Result<SecretKey, std::io::Error> {
+        let data = content
+            .lines()
+            .nth(1)
+            .and_then(|line| STANDARD.decode(line.trim()).ok())
+            .filter(|data| data.len() == SECRET_KEY_SIZE)
+            .ok_or_else(|| invalid_data("not a minisign secret key".to_string()))?;
+        if &data[0..2] != b"Ed" || &data[4..6] != b"B2" {
+            return Err(invalid_data(
+                "unsupported signature or checksum algorithm in secret key".to_string(),
+            ));
+        }
+        let mut keynum = data[54..].to_vec();
+        match &data[2..4] {
+            b"\0\0" => {}
+            b"Sc" => {
+                let password = password.ok_or_else(|| {
+                    invalid_data("the secret key is encrypted, it needs a password".to_string())
+                })?;
+                let opslimit = u64::from_le_bytes(data[38..46].try_into().unwrap());
+                let memlimit = u64::from_le_bytes(data[46..54].try_into().unwrap());
+                let params = scrypt_params(opslimit, memlimit)?;
+                let mut stream = vec![0u8; keynum.len()];
+                scrypt::scrypt(password.as_bytes(), &data[6..38], &params, &mut stream)
+                    .map_err(|_| invalid_data("scrypt failed".to_string()))?;
+                keynum.iter_mut().zip(stream).for_each(|(k, s)| *k ^= s);
+            }
+            _ => {
+                return Err(invalid_data(
+                    "unsupported key derivation algorithm in secret key".to_string(),
+                ))
+            }
+        }
+        let (key_id, rest) = keynum.split_at(8);
+        let (keypair, checksum) = rest.split_at(64);
+        let mut hasher = Blake2b::<U32>::new();
+        hasher.update(b"Ed");
+        hasher.update(key_id);
+        hasher.update(keypair);
+        if hasher.finalize().as_slice() != checksum {
+            return Err(invalid_data(
+                "wrong password or corrupt secret key".to_string(),
+            ));
+        }
+        let signing_key = SigningKey::from_keypair_bytes(keypair.try_into().unwrap())
+            .map_err(|_| invalid_data("corrupt secret key".to_string()))?;
+        Ok(SecretKey {
+            key_id: key_id.try_into().unwrap(),
+            signing_key,
+        })
+    }
+
+    /// returns the content of a ".minisig" file with the signature of the BLAKE2b-512 hash of
+    /// data, like `minisign -S`. Ed25519 signatures are deterministic, so the same data and
+    /// trusted comment always give the same file.
+    pub fn sign(
+        &self,
+        data: &mut impl Read,
+        trusted_comment: &str,
+    ) -> Result<String, std::io::Error> {
+        let mut hasher = Blake2b512::new();
+        std::io::copy(data, &mut hasher)?;
+        let signature = self.signing_key.sign(&hasher.finalize()).to_bytes();
+        let global_signature = self
+            .signing_key
+            .sign(&[&signature[..], trusted_comment.as_bytes()].concat())
+            .to_bytes();
+        Ok(format!(
+            "untrusted comment: signature from deterministic-tar\n{}\ntrusted comment: {}\n{}\n",
+            STANDARD.encode([&b"ED"[..], &self.key_id, &signature].concat()),
+            trusted_comment,
+            STANDARD.encode(global_signature)
+        ))
+    }
+}
@@ -0,0 +1,43 @@
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::Path;
+
+/// The identifiers of a gzip compressed OCI image layer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerDigests {
+    /// "sha256:<hex>" of the uncompressed tar, used in the image config
+    pub diff_id: String,
+    /// "sha256:<hex>" of the compressed layer, used in the image manifest
+    pub digest: String,
+    /// size of the compressed layer in bytes
+    pub size: u64,
+}
+
+/// hashes everything read from input and returns the digest together with the number of bytes
+fn sha256_of(input: &mut impl Read) -> Result<(String, u64), std::io::Error> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        size += n as u64;
+    }
+    Ok((format!("sha256:{}", hex::encode(hasher.finalize())), size))
+}
+
+/// Reads a written .tar.gz layer back and computes its digests.
+pub fn layer_digests(path: &Path) -> Result<LayerDigests, std::io::Error> {
+    let (digest, size) = sha256_of(&mut std::fs::File::open(path)?)?;
+    let (diff_id, _) = sha256_of(&mut flate2::read::MultiGzDecoder::new(
+        std::io::BufReader::new(std::fs::File::open(path)?),
+    ))?;
+    Ok(LayerDigests {
+        diff_id,
+        digest,
+        size,
+    })
+}
@@ -0,0 +1,94 @@
+use crate::walk::{DirWalkItem, DirWalkType};
+use std::collections::VecDeque;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+
+/// maximum number of entries which are buffered at once, regardless of their size
+const MAX_BUFFERED_ENTRIES: usize = 65536;
+
+/// Reads the content of upcoming files in inode order, which reduces seeks on spinning disks,
+/// and yields them in the original (alphabetical) order of the wrapped walk.
+///
+/// Entries are buffered until `buffer_size` bytes of file content are collected. Files which are
+/// larger than the buffer are not read ahead and yielded without content.
+pub struct InodeOrderReader<I: Iterator<Item = DirWalkItem>> {
+    walk: I,
+    buffer_size: u64,
+    buffered: VecDeque<(DirWalkItem, Option<Vec<u8>>)>,
+}
+
+impl<I: Iterator<Item = DirWalkItem>> InodeOrderReader<I> {
+    pub fn new(walk: I, buffer_size: u64) -> InodeOrderReader<I> {
+        InodeOrderReader {
+            walk,
+            buffer_size,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    fn _fill(&mut self) {
+        let mut buffered_bytes = 0u64;
+        let mut batch = Vec::new();
+        while buffered_bytes < self.buffer_size && batch.len() < MAX_BUFFERED_ENTRIES {
+            let d = match self.walk.next() {
+                Some(d) => d,
+                None => break,
+            };
+            let fits = match d.typ {
+                DirWalkType::File | DirWalkType::SymlinkToFile(_) => {
+                    d.size.unwrap() <= self.buffer_size - buffered_bytes
+                }
+                _ => false,
+            };
+            if fits {
+                buffered_bytes += d.size.unwrap();
+            }
+            batch.push((d, fits));
+        }
+
+        // read all buffered files ordered by device and inode
+        let mut contents: Vec<Option<Vec<u8>>> = vec![None; batch.len()];
+        let mut order: Vec<(u64, u64, usize)> = batch
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, fits))| *fits)
+            .map(|(i, (d, _))| {
+                let meta = std::fs::metadata(_content_path(d))
+                    .unwrap_or_else(|_| panic!("stat for {:?} failed", _content_path(d)));
+                (meta.dev(), meta.ino(), i)
+            })
+            .collect();
+        order.sort();
+        for (_, _, i) in order {
+            let d = &batch[i].0;
+            let mut content = Vec::with_capacity(d.size.unwrap() as usize);
+            std::fs::File::open(_content_path(d))
+                .unwrap_or_else(|_| panic!("could not open file {:?}", _content_path(d)))
+                .read_to_end(&mut content)
+                .expect("intermittent i/o error");
+            if content.len() as u64 != d.size.unwrap() {
+                panic!("size while reading different from stat");
+            }
+            contents[i] = Some(content);
+        }
+        self.buffered
+            .extend(batch.into_iter().map(|(d, _)| d).zip(contents));
+    }
+}
+
+fn _content_path(d: &DirWalkItem) -> &std::path::Path {
+    match &d.typ {
+        DirWalkType::SymlinkToFile(resolved_path) => resolved_path,
+        _ => &d.abspath,
+    }
+}
+
+impl<I: Iterator<Item = DirWalkItem>> Iterator for InodeOrderReader<I> {
+    type Item = (DirWalkItem, Option<Vec<u8>>);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffered.is_empty() {
+            self._fill();
+        }
+        self.buffered.pop_front()
+    }
+}
@@ -0,0 +1,143 @@
+use crate::report_stdout;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "deterministic-tar profile",
+    about = "Walk a directory twice back-to-back and report what makes archives of it differ between runs: entries which appeared, disappeared or changed between the walks, directories whose listing order changed and names which are not valid UTF-8."
+)]
+pub struct ProfileOpt {
+    /// directory which should be checked
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+}
+
+/// type, size and modification time of an entry for the profile subcommand
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ProfileEntry {
+    kind: &'static str,
+    size: u64,
+    mtime: (i64, i64),
+}
+
+/// walks dir without following symlinks and returns all entries by their path relative to dir,
+/// together with the names of each directory in the order the file system listed them
+#[allow(clippy::type_complexity)]
+fn profile_walk(
+    dir: &std::path::Path,
+) -> (
+    BTreeMap<PathBuf, ProfileEntry>,
+    BTreeMap<PathBuf, Vec<std::ffi::OsString>>,
+) {
+    use std::os::unix::fs::MetadataExt;
+    let mut entries = BTreeMap::new();
+    let mut listings = BTreeMap::new();
+    let mut remaining = vec![PathBuf::new()];
+    while let Some(relpath) = remaining.pop() {
+        let path = dir.join(&relpath);
+        // entries can disappear while they are walked, which is reported as such
+        let Ok(meta) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        let kind = match meta.file_type() {
+            t if t.is_dir() => "directory",
+            t if t.is_file() => "file",
+            t if t.is_symlink() => "symlink",
+            _ => "special file",
+        };
+        let entry = ProfileEntry {
+            kind,
+            size: if meta.is_file() { meta.size() } else { 0 },
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+        };
+        entries.insert(relpath.clone(), entry);
+        if meta.is_dir() {
+            let Ok(listing) = path.read_dir() else {
+                continue;
+            };
+            let names: Vec<std::ffi::OsString> = listing
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name())
+                .collect();
+            remaining.extend(names.iter().map(|name| relpath.join(name)));
+            listings.insert(relpath, names);
+        }
+    }
+    (entries, listings)
+}
+
+pub fn profile_main(opt: ProfileOpt) {
+    if !opt.dir.is_dir() {
+        panic!("{:?} is not a directory", &opt.dir);
+    }
+    let (first, first_listings) = profile_walk(&opt.dir);
+    let (second, second_listings) = profile_walk(&opt.dir);
+
+    let mut stdout = report_stdout();
+    let mut report = |kind: &str, name: &std::path::Path, reason: &str| {
+        let name = std::path::Path::new(".").join(name);
+        writeln!(stdout, "{}\t{}\t{}", kind, name.to_string_lossy(), reason).unwrap();
+    };
+    let mut problems = 0u64;
+    let names: BTreeSet<&PathBuf> = first.keys().chain(second.keys()).collect();
+    for name in names {
+        let problem = match (first.get(name), second.get(name)) {
+            (Some(_), None) => Some(("DISAPPEARED", "removed while it was walked".to_string())),
+            (None, Some(_)) => Some(("APPEARED", "created while it was walked".to_string())),
+            (Some(a), Some(b)) if a.kind != b.kind => {
+                Some(("CHANGED", format!("{} became {}", a.kind, b.kind)))
+            }
+            (Some(a), Some(b)) if a.size != b.size => Some((
+                "CHANGED",
+                format!("size changed from {} to {} bytes", a.size, b.size),
+            )),
+            (Some(a), Some(b)) if a.mtime != b.mtime => Some((
+                "CHANGED",
+                "modification time changed, it is being written".to_string(),
+            )),
+            _ if name.file_name().is_some_and(|n| n.to_str().is_none()) => Some((
+                "NON-UTF-8",
+                "name is not valid UTF-8, see --name-encoding".to_string(),
+            )),
+            _ => None,
+        };
+        if let Some((kind, reason)) = problem {
+            report(kind, name, &reason);
+            problems += 1;
+        }
+    }
+    // the order is harmless for deterministic-tar, which sorts the entries, but it changes the
+    // archives of tools which don't
+    let mut reordered = 0u64;
+    for (dir, names) in &first_listings {
+        let Some(again) = second_listings.get(dir) else {
+            continue;
+        };
+        let mut sorted = names.clone();
+        let mut sorted_again = again.clone();
+        sorted.sort();
+        sorted_again.sort();
+        if names != again && sorted == sorted_again {
+            report(
+                "ORDER",
+                dir,
+                "listed in a different order, which is harmless because entries are sorted",
+            );
+            reordered += 1;
+        }
+    }
+    writeln!(
+        stdout,
+        "{} entries walked twice, {} problems, {} directories listed in a different order",
+        first.len(),
+        problems,
+        reordered
+    )
+    .unwrap();
+    if problems > 0 {
+        std::process::exit(1);
+    }
+}
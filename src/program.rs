@@ -0,0 +1,121 @@
+use std::io::{Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{Receiver, TryRecvError};
+
+/// size of the reads from the standard output of the program
+const READ_SIZE: usize = 64 * 1024;
+
+/// A writer which pipes everything through an external program, e.g. "zstd -19 -T0" like
+/// `tar --use-compress-program`, and writes its standard output to the inner writer.
+///
+/// The program is run with `sh -c`. Its output is read by a separate thread, so the program
+/// never blocks on a full pipe, and written to the inner writer whenever new input is written,
+/// so the inner writer doesn't need to be `Send`. Whether the output is deterministic depends on
+/// the program.
+pub struct ProgramWriter<W: Write> {
+    out: W,
+    command: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    output: Receiver<std::io::Result<Vec<u8>>>,
+}
+
+impl<W: Write> ProgramWriter<W> {
+    pub fn new(out: W, command: &str) -> Result<ProgramWriter<W>, std::io::Error> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().unwrap();
+        let (sender, output) = std::sync::mpsc::channel();
+        std::thread::spawn(move || loop {
+            let mut buffer = vec![0; READ_SIZE];
+            match stdout.read(&mut buffer) {
+                Ok(0) => return,
+                Ok(n) => {
+                    buffer.truncate(n);
+                    if sender.send(Ok(buffer)).is_err() {
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            }
+        });
+        Ok(ProgramWriter {
+            out,
+            command: command.to_string(),
+            child,
+            stdin,
+            output,
+        })
+    }
+
+    /// writes the output which the program produced so far, or all of it if `wait` is set
+    fn _forward_output(&mut self, wait: bool) -> Result<(), std::io::Error> {
+        loop {
+            let received = if wait {
+                self.output.recv().map_err(|_| TryRecvError::Disconnected)
+            } else {
+                self.output.try_recv()
+            };
+            match received {
+                Ok(data) => self.out.write_all(&data?)?,
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+
+    /// waits for the program and returns an error if it failed
+    fn _wait(&mut self) -> Result<(), std::io::Error> {
+        let status = self.child.wait()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(std::io::Error::other(format!(
+                "compress program {:?} failed with {}",
+                self.command, status
+            )))
+        }
+    }
+
+    /// closes the standard input of the program, writes its remaining output and returns the
+    /// underlying writer. Fails if the program exits with an error.
+    pub fn finish(mut self) -> Result<W, std::io::Error> {
+        drop(self.stdin.take());
+        self._forward_output(true)?;
+        self._wait()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for ProgramWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self._forward_output(false)?;
+        let stdin = match self.stdin.as_mut() {
+            Some(stdin) => stdin,
+            None => return Err(std::io::ErrorKind::BrokenPipe.into()),
+        };
+        match stdin.write(buf) {
+            // the program exited before it read all input, its exit status is more helpful
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                drop(self.stdin.take());
+                self._wait()?;
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    /// only flushes the underlying writer, the program decides when it writes its output
+    fn flush(&mut self) -> std::io::Result<()> {
+        self._forward_output(false)?;
+        self.out.flush()
+    }
+}
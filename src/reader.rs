@@ -0,0 +1,780 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// An entry of an existing archive, as returned by [`TarReader::next_entry`].
+#[derive(Clone, Debug)]
+pub struct TarEntry {
+    pub name: Vec<u8>,
+    pub typeflag: u8,
+    pub size: u64,
+    /// offset of the (first) header of this entry inside the archive
+    pub offset: u64,
+    /// target of a hardlink or symlink, empty for other entries
+    pub linkname: Vec<u8>,
+    /// whether the content is a GNU sparse map followed by the data, either 'S' or PAX sparse
+    pub sparse: bool,
+    /// permission bits, owner and modification time, from the header or PAX records
+    pub mode: u64,
+    pub uid: u64,
+    pub gid: u64,
+    pub mtime: i64,
+    pub uname: Vec<u8>,
+    pub gname: Vec<u8>,
+    /// keywords of the local and global PAX headers before this entry
+    pub pax_keywords: Vec<Vec<u8>>,
+}
+
+/// How tolerant the reader is about archives which are malformed but still readable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseMode {
+    /// every deviation from the format is an error
+    Strict,
+    /// accept wrong (signed) checksums, unknown magic values, non-zero padding, repeated
+    /// extended headers and a missing end-of-archive marker
+    Lenient,
+}
+
+/// Options for [`TarReader::with_options`].
+#[derive(Clone, Debug)]
+pub struct ReaderOptions {
+    pub mode: ParseMode,
+    /// maximum size of a GNU LongLink or PAX extended header payload, which is held in memory
+    pub max_extended_header_size: u64,
+}
+
+impl Default for ReaderOptions {
+    fn default() -> ReaderOptions {
+        ReaderOptions {
+            mode: ParseMode::Strict,
+            max_extended_header_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Streaming reader for (GNU) tar archives which needs constant memory.
+pub struct TarReader<R: Read + Seek> {
+    pub in_tar: R,
+    pub position: u64,
+    options: ReaderOptions,
+    // content and padding bytes of the current entry which were not consumed yet
+    remaining_content: u64,
+    remaining_padding: u64,
+    // length of the archive, determined when content is skipped for the first time
+    archive_len: Option<u64>,
+    finished: bool,
+}
+
+pub fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// parses a numeric header field, either octal or GNU base-256
+pub fn parse_octal(field: &[u8]) -> Result<u64, std::io::Error> {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        // GNU base-256: big endian, the highest bit of the first byte is only the marker
+        let mut value = (field[0] & 0x7f) as u64;
+        for b in &field[1..] {
+            value = value
+                .checked_mul(256)
+                .and_then(|v| v.checked_add(*b as u64))
+                .ok_or_else(|| invalid_data("base-256 field overflows".to_string()))?;
+        }
+        return Ok(value);
+    }
+    let s = std::str::from_utf8(field)
+        .map_err(|_| invalid_data(format!("non-ascii numeric field {:?}", field)))?;
+    let s = s.trim_matches(|c| c == '\x00' || c == ' ');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(s, 8).map_err(|_| invalid_data(format!("invalid octal field {:?}", s)))
+}
+
+fn header_name(header: &[u8; 512]) -> &[u8] {
+    header[..100].split(|b| *b == 0).next().unwrap()
+}
+
+/// returns a NUL terminated string field of a header
+fn header_string(field: &[u8]) -> Vec<u8> {
+    field.split(|b| *b == 0).next().unwrap().to_vec()
+}
+
+/// the PAX records which the reader needs, all other keys are ignored
+#[derive(Default)]
+struct PaxRecords {
+    path: Option<Vec<u8>>,
+    linkpath: Option<Vec<u8>>,
+    size: Option<u64>,
+    uid: Option<u64>,
+    gid: Option<u64>,
+    /// whole seconds, the fraction is ignored
+    mtime: Option<i64>,
+    uname: Option<Vec<u8>>,
+    gname: Option<Vec<u8>>,
+    sparse: bool,
+    /// keys of all records, in the order of the header
+    keywords: Vec<Vec<u8>>,
+}
+
+impl PaxRecords {
+    /// returns the records of self, and those of `earlier` which self does not contain
+    fn or(self, mut earlier: PaxRecords) -> PaxRecords {
+        earlier.keywords.extend(self.keywords);
+        PaxRecords {
+            path: self.path.or(earlier.path),
+            linkpath: self.linkpath.or(earlier.linkpath),
+            size: self.size.or(earlier.size),
+            uid: self.uid.or(earlier.uid),
+            gid: self.gid.or(earlier.gid),
+            mtime: self.mtime.or(earlier.mtime),
+            uname: self.uname.or(earlier.uname),
+            gname: self.gname.or(earlier.gname),
+            sparse: self.sparse || earlier.sparse,
+            keywords: earlier.keywords,
+        }
+    }
+}
+
+fn parse_pax_number<T: std::str::FromStr>(key: &[u8], value: &[u8]) -> Result<T, std::io::Error> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| {
+            invalid_data(format!(
+                "invalid PAX {} record",
+                String::from_utf8_lossy(key)
+            ))
+        })
+}
+
+/// parses the records of a PAX extended header. The name of a GNU sparse file
+/// ("GNU.sparse.name") takes precedence over "path".
+fn parse_pax_records(mut records: &[u8]) -> Result<PaxRecords, std::io::Error> {
+    let mut pax = PaxRecords::default();
+    let mut sparse_name = None;
+    while !records.is_empty() {
+        // each record is "<length> <key>=<value>\n", where length includes itself
+        let space = records
+            .iter()
+            .position(|b| *b == b' ')
+            .ok_or_else(|| invalid_data("PAX record without length".to_string()))?;
+        let len: usize = std::str::from_utf8(&records[..space])
+            .ok()
+            .and_then(|l| l.parse().ok())
+            .ok_or_else(|| invalid_data("invalid PAX record length".to_string()))?;
+        if len <= space + 1 || len > records.len() || records[len - 1] != b'\n' {
+            return Err(invalid_data("PAX record length out of bounds".to_string()));
+        }
+        let record = &records[space + 1..len - 1];
+        let eq = record
+            .iter()
+            .position(|b| *b == b'=')
+            .ok_or_else(|| invalid_data("PAX record without '='".to_string()))?;
+        let (key, value) = (&record[..eq], &record[eq + 1..]);
+        pax.keywords.push(key.to_vec());
+        match key {
+            b"path" => pax.path = Some(value.to_vec()),
+            b"GNU.sparse.name" => sparse_name = Some(value.to_vec()),
+            b"linkpath" => pax.linkpath = Some(value.to_vec()),
+            _ if key.starts_with(b"GNU.sparse.") => pax.sparse = true,
+            b"size" => pax.size = Some(parse_pax_number(key, value)?),
+            b"uid" => pax.uid = Some(parse_pax_number(key, value)?),
+            b"gid" => pax.gid = Some(parse_pax_number(key, value)?),
+            b"mtime" => {
+                let seconds = value.split(|b| *b == b'.').next().unwrap();
+                pax.mtime = Some(parse_pax_number(key, seconds)?)
+            }
+            b"uname" => pax.uname = Some(value.to_vec()),
+            b"gname" => pax.gname = Some(value.to_vec()),
+            _ => {}
+        }
+        records = &records[len..];
+    }
+    pax.sparse |= sparse_name.is_some();
+    pax.path = sparse_name.or(pax.path);
+    Ok(pax)
+}
+
+impl<R: Read + Seek> TarReader<R> {
+    pub fn new(in_tar: R) -> TarReader<R> {
+        TarReader::with_options(in_tar, ReaderOptions::default())
+    }
+
+    pub fn with_options(in_tar: R, options: ReaderOptions) -> TarReader<R> {
+        TarReader {
+            in_tar,
+            position: 0,
+            options,
+            remaining_content: 0,
+            remaining_padding: 0,
+            archive_len: None,
+            finished: false,
+        }
+    }
+
+    fn _strict(&self) -> bool {
+        self.options.mode == ParseMode::Strict
+    }
+
+    fn _truncated(&self, e: std::io::Error) -> std::io::Error {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => {
+                invalid_data(format!("archive truncated at offset {}", self.position))
+            }
+            _ => e,
+        }
+    }
+
+    /// reads a block, returns false on a clean end of file right at the block boundary
+    fn _read_block(&mut self, block: &mut [u8; 512]) -> Result<bool, std::io::Error> {
+        let mut filled = 0;
+        while filled < 512 {
+            match self.in_tar.read(&mut block[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if filled == 0 {
+            return Ok(false);
+        }
+        if filled < 512 {
+            return Err(invalid_data(format!(
+                "archive truncated inside header at offset {}",
+                self.position
+            )));
+        }
+        self.position += 512;
+        Ok(true)
+    }
+
+    fn _read_header(&mut self) -> Result<Option<[u8; 512]>, std::io::Error> {
+        let mut header = [0u8; 512];
+        if !self._read_block(&mut header)? {
+            if self._strict() {
+                return Err(invalid_data(format!(
+                    "missing end-of-archive marker at offset {}",
+                    self.position
+                )));
+            }
+            return Ok(None);
+        }
+        if header.iter().all(|b| *b == 0) {
+            // end of archive is marked by (at least) two blocks of zeros
+            let found_second = self._read_block(&mut header)?;
+            if !found_second || header.iter().any(|b| *b != 0) {
+                if self._strict() {
+                    return Err(invalid_data(format!(
+                        "single zero block at offset {}",
+                        self.position - 512 * (found_second as u64 + 1)
+                    )));
+                }
+                if found_second {
+                    return self._check_header(header).map(Some);
+                }
+            }
+            return Ok(None);
+        }
+        self._check_header(header).map(Some)
+    }
+
+    fn _check_header(&self, header: [u8; 512]) -> Result<[u8; 512], std::io::Error> {
+        let offset = self.position - 512;
+        let expected = parse_octal(&header[148..156])?;
+        let checksum_bytes = || {
+            header[..148]
+                .iter()
+                .chain(b"        ".iter())
+                .chain(header[156..].iter())
+        };
+        let sum: u64 = checksum_bytes().map(|i| *i as u64).sum();
+        // some historic implementations summed up signed bytes
+        let signed_sum: i64 = checksum_bytes().map(|i| *i as i8 as i64).sum();
+        if sum != expected && (self._strict() || signed_sum != expected as i64) {
+            return Err(invalid_data(format!(
+                "header checksum mismatch at offset {}",
+                offset
+            )));
+        }
+        if self._strict()
+            && &header[257..263] != b"ustar\x00"
+            && &header[257..265] != b"ustar  \x00"
+            && header[257..265] != [0u8; 8]
+        {
+            return Err(invalid_data(format!(
+                "unknown header magic at offset {}",
+                offset
+            )));
+        }
+        Ok(header)
+    }
+
+    fn _read_extended_header(&mut self, header: &[u8; 512]) -> Result<Vec<u8>, std::io::Error> {
+        let size = parse_octal(&header[124..136])?;
+        if size > self.options.max_extended_header_size {
+            return Err(invalid_data(format!(
+                "extended header at offset {} has {} bytes, limit is {}",
+                self.position - 512,
+                size,
+                self.options.max_extended_header_size
+            )));
+        }
+        let mut content = vec![0u8; size as usize];
+        self.in_tar
+            .read_exact(&mut content)
+            .map_err(|e| self._truncated(e))?;
+        self.position += size;
+        self.remaining_content = 0;
+        self.remaining_padding = (512 - (size % 512)) % 512;
+        self._read_padding()?;
+        Ok(content)
+    }
+
+    fn _read_padding(&mut self) -> Result<(), std::io::Error> {
+        let mut padding = [0u8; 512];
+        let padding = &mut padding[..self.remaining_padding as usize];
+        let result = self.in_tar.read_exact(padding);
+        if let Err(e) = result {
+            return Err(self._truncated(e));
+        }
+        self.position += self.remaining_padding;
+        self.remaining_padding = 0;
+        if self._strict() && padding.iter().any(|b| *b != 0) {
+            return Err(invalid_data(format!(
+                "non-zero padding before offset {}",
+                self.position
+            )));
+        }
+        Ok(())
+    }
+
+    /// returns the next entry of the archive, skipping the unread content of the previous one
+    pub fn next_entry(&mut self) -> Result<Option<TarEntry>, std::io::Error> {
+        if self.finished {
+            return Ok(None);
+        }
+        self.skip_content()?;
+        let offset = self.position;
+        let mut longname = None;
+        let mut pax = PaxRecords::default();
+        let mut longlink = None;
+        let header = loop {
+            let header = match self._read_header()? {
+                Some(header) => header,
+                None if longname.is_none()
+                    && pax.path.is_none()
+                    && pax.size.is_none()
+                    && longlink.is_none() =>
+                {
+                    self.finished = true;
+                    return Ok(None);
+                }
+                None => {
+                    return Err(invalid_data(format!(
+                        "extended header at offset {} without entry",
+                        offset
+                    )))
+                }
+            };
+            match header[156] {
+                b'L' => {
+                    // GNU LongLink: the content of this entry is the name of the following entry
+                    if longname.is_some() && self._strict() {
+                        return Err(invalid_data(format!(
+                            "repeated LongLink at offset {}",
+                            self.position - 512
+                        )));
+                    }
+                    let mut name = self._read_extended_header(&header)?;
+                    while name.last() == Some(&0) {
+                        name.pop();
+                    }
+                    longname = Some(name);
+                }
+                b'K' => {
+                    // GNU LongLink of the target of a hardlink or symlink
+                    if longlink.is_some() && self._strict() {
+                        return Err(invalid_data(format!(
+                            "repeated LongLink at offset {}",
+                            self.position - 512
+                        )));
+                    }
+                    let mut target = self._read_extended_header(&header)?;
+                    while target.last() == Some(&0) {
+                        target.pop();
+                    }
+                    longlink = Some(target);
+                }
+                b'x' => {
+                    if (pax.path.is_some() || pax.size.is_some()) && self._strict() {
+                        return Err(invalid_data(format!(
+                            "repeated PAX header at offset {}",
+                            self.position - 512
+                        )));
+                    }
+                    let records = self._read_extended_header(&header)?;
+                    pax = parse_pax_records(&records)?.or(pax);
+                }
+                b'g' => {
+                    // global PAX headers are only checked and their keywords are kept
+                    let records = self._read_extended_header(&header)?;
+                    let keywords = parse_pax_records(&records)?.keywords;
+                    pax.keywords.extend(keywords);
+                }
+                _ => break header,
+            }
+        };
+        if header[156] == b'S' && header[482] != 0 {
+            // GNU sparse files store the rest of their map in extension blocks
+            let mut block = [0u8; 512];
+            loop {
+                if !self._read_block(&mut block)? {
+                    return Err(invalid_data(format!(
+                        "archive truncated inside sparse header of entry at offset {}",
+                        offset
+                    )));
+                }
+                if block[504] == 0 {
+                    break;
+                }
+            }
+        }
+        if let Some(longname) = &longname {
+            if !longname.starts_with(header_name(&header)) {
+                return Err(invalid_data(format!(
+                    "LongLink at offset {} does not match following header",
+                    offset
+                )));
+            }
+        }
+        let name = pax.path.or(longname).unwrap_or_else(|| {
+            // POSIX ustar stores the beginning of long names in the prefix field
+            let prefix = header[345..500].split(|b| *b == 0).next().unwrap();
+            if &header[257..263] == b"ustar\x00" && !prefix.is_empty() {
+                [prefix, b"/", header_name(&header)].concat()
+            } else {
+                header_name(&header).to_vec()
+            }
+        });
+        let linkname = pax
+            .linkpath
+            .or(longlink)
+            .unwrap_or_else(|| header_string(&header[157..257]));
+        let size = match pax.size {
+            Some(size) => size,
+            None => parse_octal(&header[124..136])?,
+        };
+        if size > i64::MAX as u64 - 512 {
+            return Err(invalid_data(format!(
+                "entry at offset {} has impossible size {}",
+                offset, size
+            )));
+        }
+        self.remaining_content = size;
+        self.remaining_padding = (512 - (size % 512)) % 512;
+        Ok(Some(TarEntry {
+            name,
+            typeflag: header[156],
+            size,
+            offset,
+            linkname,
+            sparse: header[156] == b'S' || pax.sparse,
+            mode: parse_octal(&header[100..108])?,
+            uid: pax.uid.map_or_else(|| parse_octal(&header[108..116]), Ok)?,
+            gid: pax.gid.map_or_else(|| parse_octal(&header[116..124]), Ok)?,
+            mtime: pax
+                .mtime
+                .map_or_else(|| parse_octal(&header[136..148]).map(|m| m as i64), Ok)?,
+            uname: pax
+                .uname
+                .unwrap_or_else(|| header_string(&header[265..297])),
+            gname: pax
+                .gname
+                .unwrap_or_else(|| header_string(&header[297..329])),
+            pax_keywords: pax.keywords,
+        }))
+    }
+
+    /// streams the content of the current entry to out and checks that the padding is zero
+    pub fn read_content(&mut self, out: &mut impl Write) -> Result<u64, std::io::Error> {
+        let mut buffer = [0u8; 64 * 1024];
+        let mut already_read = 0u64;
+        while self.remaining_content > 0 {
+            let n = std::cmp::min(self.remaining_content, buffer.len() as u64) as usize;
+            let result = self.in_tar.read_exact(&mut buffer[..n]);
+            if let Err(e) = result {
+                return Err(self._truncated(e));
+            }
+            out.write_all(&buffer[..n])?;
+            self.position += n as u64;
+            self.remaining_content -= n as u64;
+            already_read += n as u64;
+        }
+        self._read_padding()?;
+        Ok(already_read)
+    }
+
+    /// skips the content of the current entry without reading it
+    pub fn skip_content(&mut self) -> Result<(), std::io::Error> {
+        let skip = self.remaining_content + self.remaining_padding;
+        if skip > 0 {
+            if self.archive_len.is_none() {
+                let len = self.in_tar.seek(SeekFrom::End(0))?;
+                self.in_tar.seek(SeekFrom::Start(self.position))?;
+                self.archive_len = Some(len);
+            }
+            if self.position + skip > self.archive_len.unwrap() {
+                return Err(invalid_data(format!(
+                    "archive truncated inside entry content before offset {}",
+                    self.position + skip
+                )));
+            }
+            self.in_tar.seek(SeekFrom::Current(skip as i64))?;
+            self.position += skip;
+            self.remaining_content = 0;
+            self.remaining_padding = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "sha512")]
+    use crate::hash::HashOptions;
+    use crate::tar::{TarFormat, TarOutput};
+    use std::io::Cursor;
+
+    /// a ustar header with a correct checksum
+    fn header(name: &[u8], typeflag: u8, size: u64) -> [u8; 512] {
+        let mut header = [0u8; 512];
+        header[..name.len()].clone_from_slice(name);
+        header[100..108].clone_from_slice(b"0000644\x00");
+        header[124..136].clone_from_slice(format!("{:011o}\x00", size).as_bytes());
+        header[156] = typeflag;
+        header[257..265].clone_from_slice(b"ustar\x0000");
+        fix_checksum(&mut header);
+        header
+    }
+
+    fn fix_checksum(header: &mut [u8; 512]) {
+        header[148..156].clone_from_slice(b"        ");
+        let sum: u64 = header.iter().map(|b| *b as u64).sum();
+        header[148..156].clone_from_slice(format!("{:06o}\x00 ", sum).as_bytes());
+    }
+
+    /// an entry with its content padded to full blocks
+    fn entry(name: &[u8], typeflag: u8, content: &[u8]) -> Vec<u8> {
+        let mut entry = header(name, typeflag, content.len() as u64).to_vec();
+        entry.extend_from_slice(content);
+        entry.resize(entry.len().div_ceil(512) * 512, 0);
+        entry
+    }
+
+    /// a file and the end-of-archive marker
+    fn archive_with(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut archive = entries.concat();
+        archive.extend_from_slice(&entry(b"a", b'0', b"content"));
+        archive.extend_from_slice(&[0u8; 1024]);
+        archive
+    }
+
+    /// reads all entries and their content
+    fn read_all(archive: &[u8], mode: ParseMode) -> Result<Vec<TarEntry>, std::io::Error> {
+        let options = ReaderOptions {
+            mode,
+            ..ReaderOptions::default()
+        };
+        let mut reader = TarReader::with_options(Cursor::new(archive), options);
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry()? {
+            reader.read_content(&mut std::io::sink())?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn valid_archive_is_read() {
+        let archive = archive_with(&[entry(b"x", b'x', b"12 path=b/c\n")]);
+        let entries = read_all(&archive, ParseMode::Strict).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, b"b/c");
+        assert_eq!(entries[0].size, 7);
+    }
+
+    #[test]
+    fn truncated_archive_is_an_error() {
+        let archive = archive_with(&[]);
+        // inside the header, inside the content, inside the padding and before the marker
+        for len in [100, 512 + 3, 512 + 100, 1024] {
+            assert!(read_all(&archive[..len], ParseMode::Strict).is_err());
+        }
+        // the content is skipped instead of read
+        let mut reader = TarReader::new(Cursor::new(&archive[..600]));
+        reader.next_entry().unwrap();
+        assert!(reader.next_entry().is_err());
+        // without the marker, it is only accepted by the lenient mode
+        assert!(read_all(&archive[..1024], ParseMode::Lenient).is_ok());
+    }
+
+    #[test]
+    fn bad_checksum_is_an_error() {
+        let mut archive = archive_with(&[]);
+        archive[0] = b'b';
+        for mode in [ParseMode::Strict, ParseMode::Lenient] {
+            let e = read_all(&archive, mode).unwrap_err();
+            assert!(e.to_string().contains("checksum mismatch"), "{}", e);
+        }
+        let mut archive = archive_with(&[]);
+        archive[148..156].clone_from_slice(b"8888888\x00");
+        assert!(read_all(&archive, ParseMode::Lenient).is_err());
+    }
+
+    #[test]
+    fn malformed_pax_records_are_an_error() {
+        let records: [&[u8]; 8] = [
+            b"path=b\n",
+            b"x path=b\n",
+            b"0 path=b\n",
+            b"99 path=b\n",
+            b"8 path=b\n",
+            b"9 pathXb\n",
+            b"18446744073709551616 path=b\n",
+            b"11 size=-1\n",
+        ];
+        for record in records {
+            let archive = archive_with(&[entry(b"x", b'x', record)]);
+            for mode in [ParseMode::Strict, ParseMode::Lenient] {
+                assert!(
+                    read_all(&archive, mode).is_err(),
+                    "{:?}",
+                    String::from_utf8_lossy(record)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn impossible_sizes_are_an_error() {
+        // an extended header above the limit is not read into memory
+        let mut extended = header(b"x", b'x', 0);
+        extended[124..136].clone_from_slice(b"77777777777\x00");
+        fix_checksum(&mut extended);
+        assert!(read_all(&archive_with(&[extended.to_vec()]), ParseMode::Strict).is_err());
+        // base-256 sizes which overflow and sizes beyond the end of the archive
+        let mut file = header(b"f", b'0', 0);
+        file[124..136].clone_from_slice(&[0xff; 12]);
+        fix_checksum(&mut file);
+        assert!(read_all(&archive_with(&[file.to_vec()]), ParseMode::Strict).is_err());
+        let archive = archive_with(&[entry(b"x", b'x', b"28 size=9223372036854775807\n")]);
+        assert!(read_all(&archive, ParseMode::Strict).is_err());
+        let archive = archive_with(&[entry(b"x", b'x', b"19 size=1000000000\n")]);
+        let mut reader = TarReader::new(Cursor::new(&archive));
+        assert!(reader.next_entry().is_ok());
+        assert!(reader.next_entry().is_err());
+    }
+
+    #[test]
+    fn extended_header_without_entry_is_an_error() {
+        let mut archive = entry(b"x", b'x', b"12 path=b/c\n");
+        archive.extend_from_slice(&[0u8; 1024]);
+        assert!(read_all(&archive, ParseMode::Lenient).is_err());
+    }
+
+    #[test]
+    fn corrupted_bytes_never_panic() {
+        let archive = archive_with(&[
+            entry(b"x", b'x', b"12 path=b/c\n"),
+            entry(b"././@LongLink", b'L', b"d"),
+        ]);
+        // the blocks 1, 3 and 5 are the content of the entries
+        let headers = [0, 2, 4];
+        for position in 0..archive.len() - 1024 {
+            for value in [0x00, b'0', b'7', 0x80, 0xff] {
+                let mut corrupted = archive.clone();
+                corrupted[position] = value;
+                // keeps the checksum correct, so the parsing after it is reached, too
+                if headers.contains(&(position / 512)) {
+                    let block = position / 512 * 512;
+                    let mut header = [0u8; 512];
+                    header.clone_from_slice(&corrupted[block..block + 512]);
+                    if !(148..156).contains(&(position % 512)) {
+                        fix_checksum(&mut header);
+                    }
+                    corrupted[block..block + 512].clone_from_slice(&header);
+                }
+                for mode in [ParseMode::Strict, ParseMode::Lenient] {
+                    let _ = read_all(&corrupted, mode);
+                }
+            }
+        }
+    }
+
+    /// writes a directory, a file and a symlink below dir with the writer of the given format
+    fn written_with(format: TarFormat, dir: &[u8]) -> Vec<u8> {
+        let mut out = TarOutput::with_format(Vec::new(), format);
+        out.tar_write_dir(dir).unwrap();
+        out.tar_write_file(
+            #[cfg(feature = "sha512")]
+            None::<(&mut Vec<u8>, HashOptions)>,
+            &mut Cursor::new(b"content"),
+            &7,
+            &[dir, b"f"].concat(),
+        )
+        .unwrap();
+        out.tar_write_symlink(&[dir, b"s"].concat(), b"f").unwrap();
+        out.tar_end_marker().unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn archives_of_every_format_are_read_back() {
+        let short = b"d/".to_vec();
+        // the ustar prefix field holds it, gnu and pax use their extended headers
+        let long = [b"d/".as_slice(), &[b'l'; 120], b"/e/"].concat();
+        let cases = [
+            (TarFormat::Gnu, &long),
+            (TarFormat::Pax, &long),
+            (TarFormat::Ustar, &long),
+            (TarFormat::UstarStrict, &long),
+            (TarFormat::V7, &short),
+        ];
+        for (format, dir) in cases {
+            let archive = written_with(format, dir);
+            let mut reader = TarReader::new(Cursor::new(&archive));
+            let mut entries = Vec::new();
+            while let Some(entry) = reader.next_entry().unwrap() {
+                let mut content = Vec::new();
+                reader.read_content(&mut content).unwrap();
+                entries.push((entry, content));
+            }
+            let summary: Vec<_> = entries
+                .iter()
+                .map(|(e, content)| {
+                    (
+                        e.name.clone(),
+                        e.typeflag,
+                        e.linkname.clone(),
+                        content.clone(),
+                    )
+                })
+                .collect();
+            let file = [dir.as_slice(), b"f"].concat();
+            let symlink = [dir.as_slice(), b"s"].concat();
+            assert_eq!(
+                summary,
+                [
+                    (dir.clone(), b'5', vec![], vec![]),
+                    (file, b'0', vec![], b"content".to_vec()),
+                    (symlink, b'2', b"f".to_vec(), vec![]),
+                ],
+                "{:?}",
+                format
+            );
+            assert_eq!(entries[1].0.mode, 0o644, "{:?}", format);
+            assert_eq!(entries[1].0.mtime, 0, "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use crate::open_output;
+use deterministic_tar::archive::{self, ArchiveFormat};
+use deterministic_tar::compress::Compression;
+use deterministic_tar::reader::{invalid_data, ParseMode, ReaderOptions, TarReader};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "deterministic-tar repack",
+    about = "Rewrite an existing tar archive into the deterministic form which deterministic-tar would create from its extracted content: all metadata is dropped, the entries are sorted like the directory walk, missing parent directories are added, hardlinks and symlinks to files are replaced by the content of the file, symlinks to directories are kept and long names are written in the given format."
+)]
+pub struct RepackOpt {
+    /// tar archive which should be repacked, it has to be uncompressed
+    #[structopt(parse(from_os_str))]
+    archive: PathBuf,
+
+    /// output file, "-" is stdout
+    #[structopt(short, long, default_value = "-")]
+    output_tar: String,
+
+    /// flavour of the output like --format when creating an archive
+    #[structopt(long, default_value = "gnu")]
+    format: ArchiveFormat,
+
+    /// compression of the output like --compress when creating an archive
+    #[structopt(long, default_value = "none")]
+    compress: Compression,
+
+    /// accept archives which are malformed but still readable, like verify --lenient
+    #[structopt(long)]
+    lenient: bool,
+}
+
+/// an entry of the archive given to repack, files refer to their content inside the archive
+#[derive(Clone, Debug)]
+enum RepackEntry {
+    Directory,
+    File {
+        offset: u64,
+        size: u64,
+    },
+    /// components of the target, relative to the root of the archive, and the target as it is in
+    /// the archive. Only symlinks to directories are kept after [`repack_entries`].
+    Symlink(Vec<Vec<u8>>, Vec<u8>),
+}
+
+/// splits a name in an archive into its components without empty and "." components, None if
+/// it contains ".."
+fn repack_components(name: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut components = vec![];
+    for component in name.split(|b| *b == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => return None,
+            component => components.push(component.to_vec()),
+        }
+    }
+    Some(components)
+}
+
+/// resolves the target of a symlink in the directory `parent`, None if it leaves the archive
+fn repack_symlink_target(parent: &[Vec<u8>], target: &[u8]) -> Option<Vec<Vec<u8>>> {
+    if target.starts_with(b"/") {
+        return None;
+    }
+    let mut components = parent.to_vec();
+    for component in target.split(|b| *b == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => {
+                components.pop()?;
+            }
+            component => components.push(component.to_vec()),
+        }
+    }
+    Some(components)
+}
+
+/// The content of an entry inside the archive given to repack, read through its own file handle.
+struct EntryContent {
+    file: File,
+    start: u64,
+    size: u64,
+    position: u64,
+}
+
+impl EntryContent {
+    fn new(archive: &std::path::Path, start: u64, size: u64) -> std::io::Result<EntryContent> {
+        let mut file = File::open(archive)?;
+        file.seek(std::io::SeekFrom::Start(start))?;
+        Ok(EntryContent {
+            file,
+            start,
+            size,
+            position: 0,
+        })
+    }
+}
+
+impl Read for EntryContent {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.size.saturating_sub(self.position);
+        let n = std::cmp::min(remaining, buf.len() as u64) as usize;
+        let n = self.file.read(&mut buf[..n])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for EntryContent {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            std::io::SeekFrom::Start(p) => Some(p),
+            std::io::SeekFrom::End(p) => self.size.checked_add_signed(p),
+            std::io::SeekFrom::Current(p) => self.position.checked_add_signed(p),
+        }
+        .ok_or_else(|| invalid_data("seek before the start of the entry".to_string()))?;
+        self.file
+            .seek(std::io::SeekFrom::Start(self.start + position))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// reads all entries of the archive given to repack, later entries replace earlier ones with the
+/// same name like when the archive is extracted
+fn repack_entries(opt: &RepackOpt) -> BTreeMap<Vec<Vec<u8>>, RepackEntry> {
+    let in_tar = File::open(&opt.archive)
+        .unwrap_or_else(|_| panic!("could not open file {:?}", &opt.archive));
+    repack_entries_of(BufReader::new(in_tar), opt.lenient)
+        .unwrap_or_else(|e| panic!("could not read {:?}: {}", &opt.archive, e))
+}
+
+/// see [`repack_entries`], the errors are the ones of reading the archive
+fn repack_entries_of(
+    in_tar: impl Read + Seek,
+    lenient: bool,
+) -> std::io::Result<BTreeMap<Vec<Vec<u8>>, RepackEntry>> {
+    let reader_options = ReaderOptions {
+        mode: if lenient {
+            ParseMode::Lenient
+        } else {
+            ParseMode::Strict
+        },
+        ..ReaderOptions::default()
+    };
+    let mut reader = TarReader::with_options(in_tar, reader_options);
+    let mut entries = BTreeMap::new();
+    while let Some(entry) = reader.next_entry()? {
+        let display = String::from_utf8_lossy(&entry.name).to_string();
+        let name = repack_components(&entry.name)
+            .unwrap_or_else(|| panic!("entry {:?} contains \"..\"", display));
+        if name.is_empty() {
+            // the root directory, e.g. "./"
+            continue;
+        }
+        if entry.sparse {
+            panic!("sparse entry {:?} cannot be repacked", display);
+        }
+        let repacked = match entry.typeflag {
+            b'5' => RepackEntry::Directory,
+            // pre-POSIX archives mark directories with a trailing slash
+            b'0' | b'\0' if entry.name.ends_with(b"/") => RepackEntry::Directory,
+            b'0' | b'\0' | b'7' => RepackEntry::File {
+                offset: reader.position,
+                size: entry.size,
+            },
+            b'1' => {
+                let target = repack_components(&entry.linkname);
+                match target.and_then(|target| entries.get(&target)) {
+                    Some(file @ RepackEntry::File { .. }) => file.clone(),
+                    _ => panic!(
+                        "hardlink {:?} does not point to a regular file before it",
+                        display
+                    ),
+                }
+            }
+            b'2' => RepackEntry::Symlink(
+                repack_symlink_target(&name[..name.len() - 1], &entry.linkname)
+                    .unwrap_or_else(|| panic!("symlink {:?} points outside of the archive", display)),
+                entry.linkname.clone(),
+            ),
+            typeflag => panic!(
+                "entry {:?} has type {:?}, only directories, files, hardlinks and symlinks can be repacked",
+                display, typeflag as char
+            ),
+        };
+        entries.insert(name, repacked);
+    }
+
+    let names: Vec<_> = entries.keys().cloned().collect();
+    for name in names {
+        for i in 1..name.len() {
+            let parent = entries
+                .entry(name[..i].to_vec())
+                .or_insert(RepackEntry::Directory);
+            if !matches!(parent, RepackEntry::Directory) {
+                panic!(
+                    "{:?} is not a directory, but the archive contains entries inside of it",
+                    String::from_utf8_lossy(&name[..i].join(&b'/'))
+                );
+            }
+        }
+    }
+
+    // symlinks to files are replaced by the file like in the directory walk, symlinks to
+    // directories are kept as symlinks, so nothing is archived twice
+    let symlinks: Vec<_> = entries
+        .iter()
+        .filter_map(|(name, entry)| match entry {
+            RepackEntry::Symlink(target, _) => Some((name.clone(), target.clone())),
+            _ => None,
+        })
+        .collect();
+    for (name, mut target) in symlinks {
+        let mut hops = 0;
+        let resolved = loop {
+            // the first symlink on the way to the target, which can also be a directory
+            let symlink = (1..=target.len()).find_map(|i| match entries.get(&target[..i]) {
+                Some(RepackEntry::Symlink(next, _)) => Some((i, next)),
+                _ => None,
+            });
+            match (symlink, entries.get(&target)) {
+                (Some((i, next)), _) if hops < 40 => {
+                    target = [&next[..], &target[i..]].concat();
+                    hops += 1;
+                }
+                (None, Some(file @ RepackEntry::File { .. })) => break Some(file.clone()),
+                (None, Some(RepackEntry::Directory)) => break None,
+                _ => panic!(
+                    "symlink {:?} does not point to a regular file or directory in the archive",
+                    String::from_utf8_lossy(&name.join(&b'/'))
+                ),
+            }
+        };
+        if let Some(file) = resolved {
+            entries.insert(name, file);
+        }
+    }
+    Ok(entries)
+}
+
+pub fn repack_main(opt: RepackOpt) {
+    // the components sort like the directory walk: each directory right before its content
+    let entries = repack_entries(&opt);
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let out = open_output(&opt.output_tar, &mut 0);
+    let out = opt.compress.writer(out, threads).unwrap();
+    let mut out = archive::with_format(out, opt.format).unwrap();
+    for (name, entry) in &entries {
+        let mut name = name.join(&b'/');
+        match entry {
+            RepackEntry::Directory => {
+                name.push(b'/');
+                out.write_dir(&name)
+            }
+            RepackEntry::File { offset, size } => {
+                let mut content = EntryContent::new(&opt.archive, *offset, *size)
+                    .unwrap_or_else(|_| panic!("could not open file {:?}", &opt.archive));
+                out.write_file(None, &mut content, size, &name)
+            }
+            RepackEntry::Symlink(_, target) => out.write_symlink(&name, target),
+        }
+        .unwrap_or_else(|e| {
+            panic!(
+                "could not write {:?}: {}",
+                String::from_utf8_lossy(&name),
+                e
+            )
+        });
+    }
+    // the defaults of --eof-blocks and --blocking-factor
+    out.finish(10, 1).unwrap();
+    out.into_inner().finish().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deterministic_tar::hash::HashOptions;
+    use deterministic_tar::tar::TarOutput;
+    use std::io::Cursor;
+
+    fn components(name: &str) -> Vec<Vec<u8>> {
+        repack_components(name.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn repack_keeps_symlinks_to_directories() {
+        let mut out = TarOutput::new(Vec::new());
+        out.tar_write_dir(b"d/").unwrap();
+        out.tar_write_file(
+            None::<(&mut Vec<u8>, HashOptions)>,
+            &mut Cursor::new(b"content"),
+            &7,
+            b"d/f",
+        )
+        .unwrap();
+        out.tar_write_symlink(b"l", b"d").unwrap();
+        out.tar_write_symlink(b"m", b"./l/").unwrap();
+        out.tar_write_symlink(b"s", b"l/f").unwrap();
+        out.tar_end_marker().unwrap();
+        let entries = repack_entries_of(Cursor::new(out.into_inner()), false).unwrap();
+        let names: Vec<_> = entries.keys().cloned().collect();
+        assert_eq!(names, ["d", "d/f", "l", "m", "s"].map(components).to_vec());
+        assert!(matches!(
+            &entries[&components("l")],
+            RepackEntry::Symlink(target, linkname) if *target == components("d") && linkname == b"d"
+        ));
+        assert!(matches!(
+            &entries[&components("m")],
+            RepackEntry::Symlink(target, linkname) if *target == components("l") && linkname == b"./l/"
+        ));
+        // symlinks to files through a symlink to a directory are still replaced by the file
+        assert!(matches!(
+            (&entries[&components("s")], &entries[&components("d/f")]),
+            (RepackEntry::File { offset: a, size: 7 }, RepackEntry::File { offset: b, size: 7 }) if a == b
+        ));
+    }
+}
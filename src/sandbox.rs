@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+// access rights of the file system, see linux/landlock.h
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+/// the only access rights which can be allowed for a file instead of a directory
+const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_TRUNCATE
+    | ACCESS_FS_IOCTL_DEV;
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Restricts the file system access of the process with Landlock, which is available since
+/// Linux 5.13. Everything which is not allowed by a rule is denied after `restrict_self`, also
+/// for threads and child processes started later. Open files can still be used.
+pub struct Sandbox {
+    ruleset: OwnedFd,
+    handled: u64,
+}
+
+impl Sandbox {
+    /// creates a ruleset which handles all file system accesses known to the kernel. Fails if
+    /// Landlock is not available, e.g. because it is disabled in the kernel.
+    pub fn new() -> Result<Sandbox, std::io::Error> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // REFER was added in version 2, TRUNCATE in version 3 and IOCTL_DEV in version 5
+        let handled = match abi {
+            1 => ACCESS_FS_REFER - 1,
+            2 => ACCESS_FS_TRUNCATE - 1,
+            3 | 4 => ACCESS_FS_IOCTL_DEV - 1,
+            _ => (ACCESS_FS_IOCTL_DEV << 1) - 1,
+        };
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Sandbox {
+            ruleset: unsafe { OwnedFd::from_raw_fd(fd as i32) },
+            handled,
+        })
+    }
+
+    /// allows access to the file or directory and everything below it. Rights which only apply
+    /// to directories are dropped for files.
+    fn allow(&mut self, path: &Path, access: u64) -> Result<(), std::io::Error> {
+        let file = File::open(path)?;
+        let access = if file.metadata()?.is_dir() {
+            access
+        } else {
+            access & ACCESS_FILE
+        };
+        let attr = PathBeneathAttr {
+            allowed_access: access & self.handled,
+            parent_fd: file.as_raw_fd(),
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                self.ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// allows reading the file, or the directory with everything below it
+    pub fn allow_read(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        self.allow(path, ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR)
+    }
+
+    /// allows writing an existing file. For a directory, files and directories can also be
+    /// created, read and replaced below it.
+    pub fn allow_write(&mut self, path: &Path) -> Result<(), std::io::Error> {
+        self.allow(
+            path,
+            ACCESS_FS_READ_FILE
+                | ACCESS_FS_READ_DIR
+                | ACCESS_FS_WRITE_FILE
+                | ACCESS_FS_TRUNCATE
+                | ACCESS_FS_MAKE_REG
+                | ACCESS_FS_MAKE_DIR
+                | ACCESS_FS_REMOVE_FILE
+                | ACCESS_FS_REFER,
+        )
+    }
+
+    /// enforces the rules for the calling thread and all threads and child processes which it
+    /// starts afterwards, so it has to be called before other threads are started
+    pub fn restrict_self(self) -> Result<(), std::io::Error> {
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_restrict_self,
+                self.ruleset.as_raw_fd(),
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
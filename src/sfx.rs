@@ -0,0 +1,30 @@
+use crate::compress::Compression;
+
+/// size of the stub, the archive starts directly after it
+pub const SFX_STUB_SIZE: usize = 512;
+
+/// Returns the shell script which is written before a self-extracting tar archive.
+///
+/// The script only depends on the compression, it is padded with newlines to [`SFX_STUB_SIZE`]
+/// bytes so the archive starts at a fixed offset and the archive itself is unchanged. It is run
+/// as `sh ./archive.sh [directory]` and extracts into the given or the current directory with
+/// `tail`, the decompressor and `tar`.
+pub fn self_extracting_stub(compression: Compression) -> Vec<u8> {
+    let script = format!(
+        "#!/bin/sh\n\
+         # self-extracting tar archive written by deterministic-tar, it starts at byte {offset}\n\
+         # usage: sh ./this-file [directory], the default is the current directory\n\
+         set -e\n\
+         archive=$0\n\
+         case $archive in /*) ;; *) archive=$PWD/$archive ;; esac\n\
+         if [ $# -gt 0 ]; then mkdir -p \"$1\"; cd \"$1\"; fi\n\
+         tail -c +{offset} \"$archive\" | {decompress} | tar -xf -\n\
+         exit 0\n",
+        offset = SFX_STUB_SIZE + 1,
+        decompress = compression.decompress_command(),
+    );
+    let mut stub = script.into_bytes();
+    assert!(stub.len() <= SFX_STUB_SIZE, "self-extracting stub too long");
+    stub.resize(SFX_STUB_SIZE, b'\n');
+    stub
+}
@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+/// Data regions of a sparse file as (offset, length), sorted by offset. Everything between them
+/// is zero. If the file ends with a hole, the last region is (size, 0), like in GNU tar.
+pub type SparseMap = Vec<(u64, u64)>;
+
+/// granularity of holes, the tar block size
+const SPARSE_BLOCK: u64 = 512;
+
+/// adds the data regions of data, which starts at offset inside the file. Zero blocks are holes.
+fn scan_blocks(data: &[u8], offset: u64, map: &mut SparseMap) {
+    for (i, block) in data.chunks(SPARSE_BLOCK as usize).enumerate() {
+        if block.iter().all(|b| *b == 0) {
+            continue;
+        }
+        let start = offset + i as u64 * SPARSE_BLOCK;
+        match map.last_mut() {
+            Some((o, l)) if *o + *l == start => *l += block.len() as u64,
+            _ => map.push((start, block.len() as u64)),
+        }
+    }
+}
+
+fn finish_map(mut map: SparseMap, size: u64) -> SparseMap {
+    if map.last().is_none_or(|(o, l)| o + l != size) {
+        map.push((size, 0));
+    }
+    map
+}
+
+/// Computes the sparse map of content which is already in memory.
+pub fn sparse_map_of(content: &[u8]) -> SparseMap {
+    let mut map = Vec::new();
+    scan_blocks(content, 0, &mut map);
+    finish_map(map, content.len() as u64)
+}
+
+/// returns the next offset >= offset with the given lseek whence, None if there is none
+fn seek_data_or_hole(file: &File, offset: u64, whence: libc::c_int) -> Option<u64> {
+    let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if result < 0 {
+        None
+    } else {
+        Some(result as u64)
+    }
+}
+
+/// Computes the sparse map of a file with the given size.
+///
+/// The map only depends on the content: all zero blocks of 512 bytes are holes, whether they are
+/// allocated on disk or not. Holes reported by `SEEK_DATA`/`SEEK_HOLE` are just not read.
+/// Afterwards, the file position is at the start of the file.
+pub fn sparse_map(file: &File, size: u64) -> Result<SparseMap, std::io::Error> {
+    let mut map = Vec::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    // everything before offset is scanned already, it is always at a block boundary or at size
+    let mut offset = 0;
+    while offset < size {
+        // skip holes of the file system, without support for SEEK_DATA everything is data
+        let data_start = match seek_data_or_hole(file, offset, libc::SEEK_DATA) {
+            Some(data_start) => data_start,
+            None if std::io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) => break,
+            None => offset,
+        };
+        let data_end = seek_data_or_hole(file, data_start, libc::SEEK_HOLE).unwrap_or(size);
+        // the file system may report holes at any offset, but the map uses whole blocks
+        let mut pos = std::cmp::max(offset, data_start - data_start % SPARSE_BLOCK);
+        let end = std::cmp::min(size, data_end.div_ceil(SPARSE_BLOCK) * SPARSE_BLOCK);
+        while pos < end {
+            let n = std::cmp::min(buffer.len() as u64, end - pos) as usize;
+            file.read_exact_at(&mut buffer[..n], pos)?;
+            scan_blocks(&buffer[..n], pos, &mut map);
+            pos += n as u64;
+        }
+        offset = std::cmp::max(end, offset + SPARSE_BLOCK);
+    }
+    (&mut &*file).seek(SeekFrom::Start(0))?;
+    Ok(finish_map(map, size))
+}
+
+/// returns true if the map contains any hole
+pub fn has_holes(map: &SparseMap, size: u64) -> bool {
+    map.as_slice() != [(0, size)]
+}
@@ -0,0 +1,824 @@
+#[cfg(feature = "sha512")]
+use crate::hash::{FileHasher, HashOptions};
+use flate2::write::ZlibEncoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::archive::{SQUASHFS_BLOCK_SIZE, SQUASHFS_GZIP_LEVEL};
+use crate::tar::TarStats;
+
+const SQUASHFS_MAGIC: u32 = 0x73717368;
+const SUPERBLOCK_SIZE: u64 = 96;
+/// log2 of SQUASHFS_BLOCK_SIZE
+const BLOCK_LOG: u16 = 17;
+/// uncompressed size of metadata blocks (inodes, directories, fragment and id tables)
+const METADATA_SIZE: usize = 8192;
+/// the image is padded to a multiple of this, so it can be used as a block device
+const DEVICE_BLOCK_SIZE: u64 = 4096;
+
+const COMPRESSION_GZIP: u16 = 1;
+const FLAG_NO_XATTRS: u16 = 0x0200;
+/// size bits which mark blocks that are stored without compression
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+const INVALID_TABLE: u64 = u64::MAX;
+const INVALID_FRAGMENT: u32 = u32::MAX;
+const INVALID_XATTR: u32 = u32::MAX;
+
+/// inode types, directory entries always use the basic ones
+const BASIC_DIRECTORY: u16 = 1;
+const BASIC_FILE: u16 = 2;
+const EXTENDED_DIRECTORY: u16 = 8;
+const EXTENDED_FILE: u16 = 9;
+
+/// a directory header can cover at most this many entries
+const DIRECTORY_HEADER_ENTRIES: usize = 256;
+const MAX_NAME_LEN: usize = 256;
+
+fn invalid_name(name: &[u8], reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("{:?} {}", String::from_utf8_lossy(name), reason),
+    )
+}
+
+/// compresses with zlib, returns None if the result isn't smaller
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::new(SQUASHFS_GZIP_LEVEL));
+    encoder.write_all(data).unwrap();
+    let compressed = encoder.finish().unwrap();
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Collects a table which is stored in compressed metadata blocks.
+#[derive(Default)]
+struct MetadataWriter {
+    out: Vec<u8>,
+    block: Vec<u8>,
+    /// start of every metadata block, relative to the table
+    block_starts: Vec<u64>,
+}
+
+impl MetadataWriter {
+    /// returns the start of the metadata block of the next byte and its offset inside of it
+    fn position(&self) -> (u32, u16) {
+        (self.out.len() as u32, self.block.len() as u16)
+    }
+
+    fn write(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = (METADATA_SIZE - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.block.len() == METADATA_SIZE {
+                self._write_block();
+            }
+        }
+    }
+
+    fn _write_block(&mut self) {
+        if self.block.is_empty() {
+            return;
+        }
+        self.block_starts.push(self.out.len() as u64);
+        match compress(&self.block) {
+            Some(compressed) => {
+                self.out
+                    .extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+                self.out.extend_from_slice(&compressed);
+            }
+            None => {
+                let size = self.block.len() as u16 | METADATA_UNCOMPRESSED;
+                self.out.extend_from_slice(&size.to_le_bytes());
+                self.out.extend_from_slice(&self.block);
+            }
+        }
+        self.block.clear();
+    }
+
+    /// returns the table and the start of its metadata blocks
+    fn finish(mut self) -> (Vec<u8>, Vec<u64>) {
+        self._write_block();
+        (self.out, self.block_starts)
+    }
+}
+
+enum NodeKind {
+    Directory {
+        children: Vec<usize>,
+        mode: u16,
+        mtime: u32,
+    },
+    File {
+        /// absolute position of the first data block
+        start: u64,
+        size: u64,
+        /// (compressed) size of each full block
+        blocks: Vec<u32>,
+        /// index of the fragment block and offset inside of it for the tail of the file
+        fragment: Option<(u32, u32)>,
+    },
+}
+
+struct Node {
+    name: Vec<u8>,
+    parent: usize,
+    kind: NodeKind,
+}
+
+/// creates an unnamed temporary file in the temporary directory of the system
+fn temporary_file() -> Result<File, std::io::Error> {
+    let dir = std::env::temp_dir();
+    for i in 0.. {
+        let path = dir.join(format!(".deterministic-tar-{}-{}", std::process::id(), i));
+        match File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => {
+                std::fs::remove_file(&path)?;
+                return Ok(file);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Writes deterministic squashfs 4.0 images, e.g. for firmware root filesystems.
+///
+/// Data and metadata are compressed with zlib (the "gzip" compressor of squashfs) at level 9 in
+/// blocks of 128 KiB, the tails of files are packed into fragment blocks in the order of the
+/// files. All modification times (including the one of the image) are 0, the owner and group are
+/// 0, directories have mode 0755 and files 0644. Directory entries are sorted by name and inode
+/// numbers are allocated in that order, the content of a directory before the directory itself,
+/// so the root directory has the highest number. There are no extended attributes, no export
+/// table and duplicate files are stored twice. The image is padded to a multiple of 4 KiB.
+///
+/// Like for ar, the main directory is the root of the image. The superblock at the start can only
+/// be written after everything else, so the data blocks are buffered in an unnamed temporary file
+/// until [`SquashfsOutput::squashfs_end_marker`] writes the image.
+///
+/// The statistics use the fields of [`TarStats`]: the superblock, inode and directory table are
+/// header bytes, the data and fragment blocks are content and the fragment and id tables count as
+/// end marker.
+pub struct SquashfsOutput<W: Write> {
+    out_squashfs: W,
+    data: BufWriter<File>,
+    data_size: u64,
+    nodes: Vec<Node>,
+    /// index of the directories by their path inside of the image, without a trailing slash
+    directories: HashMap<Vec<u8>, usize>,
+    fragment: Vec<u8>,
+    /// position and size of the written fragment blocks
+    fragments: Vec<(u64, u32)>,
+    pub stats: TarStats,
+}
+
+impl<W: Write> SquashfsOutput<W> {
+    pub fn new(out_squashfs: W) -> Result<SquashfsOutput<W>, std::io::Error> {
+        Ok(SquashfsOutput {
+            out_squashfs,
+            data: BufWriter::new(temporary_file()?),
+            data_size: 0,
+            nodes: vec![Node {
+                name: Vec::new(),
+                parent: 0,
+                kind: NodeKind::Directory {
+                    children: Vec::new(),
+                    mode: 0o755,
+                    mtime: 0,
+                },
+            }],
+            directories: HashMap::from([(Vec::new(), 0)]),
+            fragment: Vec::new(),
+            fragments: Vec::new(),
+            stats: TarStats::default(),
+        })
+    }
+
+    /// adds a node to its parent directory and returns its index. The name is the path in the
+    /// archive, the first component is the main directory.
+    fn _add_node(&mut self, name: &[u8], kind: NodeKind) -> Result<usize, std::io::Error> {
+        let path = match name.iter().position(|b| *b == b'/') {
+            Some(slash) => &name[slash + 1..],
+            // a single file without main directory
+            None => name,
+        };
+        let (parent_path, basename) = match path.iter().rposition(|b| *b == b'/') {
+            Some(slash) => (&path[..slash], &path[slash + 1..]),
+            None => (&path[..0], path),
+        };
+        if basename.is_empty() || basename.len() > MAX_NAME_LEN {
+            return Err(invalid_name(name, "is not a valid name in squashfs"));
+        }
+        let parent = *self
+            .directories
+            .get(parent_path)
+            .ok_or_else(|| invalid_name(name, "is not inside of an already written directory"))?;
+        let index = self.nodes.len();
+        if let NodeKind::Directory { .. } = kind {
+            self.directories.insert(path.to_vec(), index);
+        }
+        self.nodes.push(Node {
+            name: basename.to_vec(),
+            parent,
+            kind,
+        });
+        match &mut self.nodes[parent].kind {
+            NodeKind::Directory { children, .. } => children.push(index),
+            NodeKind::File { .. } => unreachable!("directories only contain directories"),
+        }
+        Ok(index)
+    }
+
+    /// compresses a data or fragment block into the temporary file, returns its size field
+    fn _write_block(&mut self, block: &[u8]) -> Result<u32, std::io::Error> {
+        let (data, size) = match compress(block) {
+            Some(compressed) => {
+                let size = compressed.len() as u32;
+                (compressed, size)
+            }
+            None => (block.to_vec(), block.len() as u32 | DATA_UNCOMPRESSED),
+        };
+        self.data.write_all(&data)?;
+        self.data_size += data.len() as u64;
+        self.stats.content_bytes += data.len() as u64;
+        Ok(size)
+    }
+
+    /// writes the pending fragment block
+    fn _write_fragment(&mut self) -> Result<(), std::io::Error> {
+        if self.fragment.is_empty() {
+            return Ok(());
+        }
+        let start = SUPERBLOCK_SIZE + self.data_size;
+        let fragment = std::mem::take(&mut self.fragment);
+        let size = self._write_block(&fragment)?;
+        self.fragments.push((start, size));
+        Ok(())
+    }
+
+    /// adds a directory, the name ends with a slash. The main directory is the root of the image.
+    pub fn squashfs_write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error> {
+        self.squashfs_write_dir_with_metadata(name, 0o755, None)
+    }
+
+    /// adds a directory with the given permissions instead of 0755 and, if given, a modification
+    /// time instead of zero, e.g. for the main directory
+    pub fn squashfs_write_dir_with_metadata(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        mtime: Option<u64>,
+    ) -> Result<(), std::io::Error> {
+        let name = name.strip_suffix(b"/").unwrap_or(name);
+        let mtime = u32::try_from(mtime.unwrap_or(0)).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "squashfs only supports modification times up to 2106",
+            )
+        })?;
+        let mode = (mode & 0o7777) as u16;
+        if name.contains(&b'/') {
+            self._add_node(
+                name,
+                NodeKind::Directory {
+                    children: Vec::new(),
+                    mode,
+                    mtime,
+                },
+            )?;
+        } else if let NodeKind::Directory {
+            mode: root_mode,
+            mtime: root_mtime,
+            ..
+        } = &mut self.nodes[0].kind
+        {
+            // the root directory always exists, only its metadata is set
+            *root_mode = mode;
+            *root_mtime = mtime;
+        }
+        self.stats.directories += 1;
+        Ok(())
+    }
+
+    /// writes the full blocks of a file and adds its tail to the current fragment block
+    pub fn squashfs_write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut impl Write, HashOptions)>,
+        in_filedescriptor: &mut impl Read,
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let start = match size {
+            0 => 0,
+            _ => SUPERBLOCK_SIZE + self.data_size,
+        };
+        let mut blocks = Vec::new();
+        let mut fragment = None;
+        let mut already_read = 0u64;
+        let mut buffer = vec![0u8; SQUASHFS_BLOCK_SIZE as usize];
+        #[cfg(feature = "sha512")]
+        let mut hasher = out_hash
+            .as_ref()
+            .map(|(_, options)| FileHasher::new(options.algorithm));
+        loop {
+            let mut filled = 0;
+            while filled < buffer.len() {
+                let n = in_filedescriptor.read(&mut buffer[filled..])?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            if filled == 0 {
+                break;
+            }
+            already_read += filled as u64;
+            #[cfg(feature = "sha512")]
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[..filled]);
+            }
+            if filled < buffer.len() {
+                // the tail of the file
+                if self.fragment.len() + filled > buffer.len() {
+                    self._write_fragment()?;
+                }
+                fragment = Some((self.fragments.len() as u32, self.fragment.len() as u32));
+                self.fragment.extend_from_slice(&buffer[..filled]);
+                break;
+            }
+            blocks.push(self._write_block(&buffer)?);
+        }
+        if already_read != *size {
+            panic!("size while reading different from stat");
+        }
+        self._add_node(
+            name,
+            NodeKind::File {
+                start,
+                size: already_read,
+                blocks,
+                fragment,
+            },
+        )?;
+        self.stats.files += 1;
+        #[cfg(feature = "sha512")]
+        if let (Some((out_hash, options)), Some(hasher)) = (out_hash.as_mut(), hasher) {
+            crate::hash::write_hash_line(out_hash, *options, hasher, name)?;
+        }
+        Ok(())
+    }
+
+    /// returns the inode numbers in the order in which the inodes are written: the entries of a
+    /// directory sorted by name, each directory after its content
+    fn _inode_order(&mut self) -> Vec<usize> {
+        for i in 0..self.nodes.len() {
+            if let NodeKind::Directory { children, .. } = &self.nodes[i].kind {
+                let mut sorted = children.clone();
+                sorted.sort_by(|a, b| self.nodes[*a].name.cmp(&self.nodes[*b].name));
+                if let NodeKind::Directory { children, .. } = &mut self.nodes[i].kind {
+                    *children = sorted;
+                }
+            }
+        }
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![(0, false)];
+        while let Some((i, content_done)) = stack.pop() {
+            if content_done {
+                order.push(i);
+                continue;
+            }
+            stack.push((i, true));
+            if let NodeKind::Directory { children, .. } = &self.nodes[i].kind {
+                stack.extend(children.iter().rev().map(|c| (*c, false)));
+            }
+        }
+        order
+    }
+
+    /// writes the listing of a directory to the directory table, returns its size in bytes
+    fn _write_listing(
+        &self,
+        children: &[usize],
+        numbers: &[u32],
+        references: &[(u32, u16)],
+        directory_table: &mut MetadataWriter,
+    ) -> usize {
+        let mut listing = Vec::new();
+        let mut i = 0;
+        while i < children.len() {
+            // a header covers entries whose inodes are in the same metadata block
+            let (block, _) = references[children[i]];
+            let base = numbers[children[i]];
+            let run = children[i..]
+                .iter()
+                .take(DIRECTORY_HEADER_ENTRIES)
+                .take_while(|c| {
+                    references[**c].0 == block
+                        && i16::try_from(numbers[**c] as i64 - base as i64).is_ok()
+                })
+                .count();
+            listing.extend_from_slice(&(run as u32 - 1).to_le_bytes());
+            listing.extend_from_slice(&block.to_le_bytes());
+            listing.extend_from_slice(&base.to_le_bytes());
+            for c in &children[i..i + run] {
+                let node = &self.nodes[*c];
+                let typ = match node.kind {
+                    NodeKind::Directory { .. } => BASIC_DIRECTORY,
+                    NodeKind::File { .. } => BASIC_FILE,
+                };
+                listing.extend_from_slice(&references[*c].1.to_le_bytes());
+                listing
+                    .extend_from_slice(&((numbers[*c] as i64 - base as i64) as i16).to_le_bytes());
+                listing.extend_from_slice(&typ.to_le_bytes());
+                listing.extend_from_slice(&(node.name.len() as u16 - 1).to_le_bytes());
+                listing.extend_from_slice(&node.name);
+            }
+            i += run;
+        }
+        directory_table.write(&listing);
+        listing.len()
+    }
+
+    /// writes the inode and directory tables
+    fn _write_inodes(&mut self) -> (MetadataWriter, MetadataWriter, u64) {
+        let order = self._inode_order();
+        let mut numbers = vec![0u32; self.nodes.len()];
+        for (number, i) in order.iter().enumerate() {
+            numbers[*i] = number as u32 + 1;
+        }
+        let mut references = vec![(0u32, 0u16); self.nodes.len()];
+        let mut inode_table = MetadataWriter::default();
+        let mut directory_table = MetadataWriter::default();
+        for i in order {
+            let node = &self.nodes[i];
+            references[i] = inode_table.position();
+            let mut inode = Vec::new();
+            let header = |inode: &mut Vec<u8>, typ: u16, mode: u16, mtime: u32| {
+                inode.extend_from_slice(&typ.to_le_bytes());
+                inode.extend_from_slice(&mode.to_le_bytes());
+                inode.extend_from_slice(&0u16.to_le_bytes()); // index of the owner in the id table
+                inode.extend_from_slice(&0u16.to_le_bytes()); // index of the group in the id table
+                inode.extend_from_slice(&mtime.to_le_bytes());
+                inode.extend_from_slice(&numbers[i].to_le_bytes());
+            };
+            match &node.kind {
+                NodeKind::Directory {
+                    children,
+                    mode,
+                    mtime,
+                } => {
+                    let (start, offset) = directory_table.position();
+                    let size =
+                        self._write_listing(children, &numbers, &references, &mut directory_table)
+                            as u64
+                            + 3; // for "." and ".."
+                    let nlink = 2 + children
+                        .iter()
+                        .filter(|c| matches!(self.nodes[**c].kind, NodeKind::Directory { .. }))
+                        .count() as u32;
+                    // the parent of the root directory is one more than the number of inodes
+                    let parent = match i {
+                        0 => self.nodes.len() as u32 + 1,
+                        _ => numbers[node.parent],
+                    };
+                    if size <= u16::MAX as u64 {
+                        header(&mut inode, BASIC_DIRECTORY, *mode, *mtime);
+                        inode.extend_from_slice(&start.to_le_bytes());
+                        inode.extend_from_slice(&nlink.to_le_bytes());
+                        inode.extend_from_slice(&(size as u16).to_le_bytes());
+                        inode.extend_from_slice(&offset.to_le_bytes());
+                        inode.extend_from_slice(&parent.to_le_bytes());
+                    } else {
+                        header(&mut inode, EXTENDED_DIRECTORY, *mode, *mtime);
+                        inode.extend_from_slice(&nlink.to_le_bytes());
+                        inode.extend_from_slice(&(size as u32).to_le_bytes());
+                        inode.extend_from_slice(&start.to_le_bytes());
+                        inode.extend_from_slice(&parent.to_le_bytes());
+                        inode.extend_from_slice(&0u16.to_le_bytes()); // no directory index
+                        inode.extend_from_slice(&offset.to_le_bytes());
+                        inode.extend_from_slice(&INVALID_XATTR.to_le_bytes());
+                    }
+                }
+                NodeKind::File {
+                    start,
+                    size,
+                    blocks,
+                    fragment,
+                } => {
+                    let (fragment, fragment_offset) = fragment.unwrap_or((INVALID_FRAGMENT, 0));
+                    if *start <= u32::MAX as u64 && *size <= u32::MAX as u64 {
+                        header(&mut inode, BASIC_FILE, 0o644, 0);
+                        inode.extend_from_slice(&(*start as u32).to_le_bytes());
+                        inode.extend_from_slice(&fragment.to_le_bytes());
+                        inode.extend_from_slice(&fragment_offset.to_le_bytes());
+                        inode.extend_from_slice(&(*size as u32).to_le_bytes());
+                    } else {
+                        header(&mut inode, EXTENDED_FILE, 0o644, 0);
+                        inode.extend_from_slice(&start.to_le_bytes());
+                        inode.extend_from_slice(&size.to_le_bytes());
+                        inode.extend_from_slice(&0u64.to_le_bytes()); // no sparse blocks
+                        inode.extend_from_slice(&1u32.to_le_bytes()); // nlink
+                        inode.extend_from_slice(&fragment.to_le_bytes());
+                        inode.extend_from_slice(&fragment_offset.to_le_bytes());
+                        inode.extend_from_slice(&INVALID_XATTR.to_le_bytes());
+                    }
+                    for block in blocks {
+                        inode.extend_from_slice(&block.to_le_bytes());
+                    }
+                }
+            }
+            inode_table.write(&inode);
+        }
+        let (block, offset) = references[0];
+        let root = ((block as u64) << 16) | offset as u64;
+        (inode_table, directory_table, root)
+    }
+
+    /// writes the image: the superblock, the data blocks from the temporary file and all tables
+    pub fn squashfs_end_marker(&mut self) -> Result<(), std::io::Error> {
+        self._write_fragment()?;
+        let (inode_table, directory_table, root) = self._write_inodes();
+        let (inode_table, _) = inode_table.finish();
+        let (directory_table, _) = directory_table.finish();
+
+        let mut fragment_table = MetadataWriter::default();
+        for (start, size) in &self.fragments {
+            fragment_table.write(&start.to_le_bytes());
+            fragment_table.write(&size.to_le_bytes());
+            fragment_table.write(&0u32.to_le_bytes());
+        }
+        let (fragment_table, fragment_blocks) = fragment_table.finish();
+        let mut id_table = MetadataWriter::default();
+        id_table.write(&0u32.to_le_bytes()); // the only owner and group
+        let (id_table, id_blocks) = id_table.finish();
+
+        // the tables follow the data in this order, the indexes of the fragment and id table
+        // contain the absolute positions of their metadata blocks
+        let inode_table_start = SUPERBLOCK_SIZE + self.data_size;
+        let directory_table_start = inode_table_start + inode_table.len() as u64;
+        let fragment_blocks_start = directory_table_start + directory_table.len() as u64;
+        let fragment_table_start = fragment_blocks_start + fragment_table.len() as u64;
+        let id_blocks_start = fragment_table_start + 8 * fragment_blocks.len() as u64;
+        let id_table_start = id_blocks_start + id_table.len() as u64;
+        let bytes_used = id_table_start + 8 * id_blocks.len() as u64;
+
+        let mut superblock = Vec::with_capacity(SUPERBLOCK_SIZE as usize);
+        superblock.extend_from_slice(&SQUASHFS_MAGIC.to_le_bytes());
+        superblock.extend_from_slice(&(self.nodes.len() as u32).to_le_bytes());
+        superblock.extend_from_slice(&0u32.to_le_bytes()); // modification time
+        superblock.extend_from_slice(&SQUASHFS_BLOCK_SIZE.to_le_bytes());
+        superblock.extend_from_slice(&(self.fragments.len() as u32).to_le_bytes());
+        superblock.extend_from_slice(&COMPRESSION_GZIP.to_le_bytes());
+        superblock.extend_from_slice(&BLOCK_LOG.to_le_bytes());
+        superblock.extend_from_slice(&FLAG_NO_XATTRS.to_le_bytes());
+        superblock.extend_from_slice(&1u16.to_le_bytes()); // entries of the id table
+        superblock.extend_from_slice(&4u16.to_le_bytes()); // version 4.0
+        superblock.extend_from_slice(&0u16.to_le_bytes());
+        superblock.extend_from_slice(&root.to_le_bytes());
+        superblock.extend_from_slice(&bytes_used.to_le_bytes());
+        superblock.extend_from_slice(&id_table_start.to_le_bytes());
+        superblock.extend_from_slice(&INVALID_TABLE.to_le_bytes()); // extended attributes
+        superblock.extend_from_slice(&inode_table_start.to_le_bytes());
+        superblock.extend_from_slice(&directory_table_start.to_le_bytes());
+        superblock.extend_from_slice(&fragment_table_start.to_le_bytes());
+        superblock.extend_from_slice(&INVALID_TABLE.to_le_bytes()); // export table
+        self.out_squashfs.write_all(&superblock)?;
+
+        self.data.flush()?;
+        let data = self.data.get_mut();
+        data.seek(SeekFrom::Start(0))?;
+        let copied = std::io::copy(data, &mut self.out_squashfs)?;
+        if copied != self.data_size {
+            panic!("temporary file of the squashfs data was modified");
+        }
+        data.set_len(0)?;
+
+        self.out_squashfs.write_all(&inode_table)?;
+        self.out_squashfs.write_all(&directory_table)?;
+        self.out_squashfs.write_all(&fragment_table)?;
+        for block in fragment_blocks {
+            self.out_squashfs
+                .write_all(&(fragment_blocks_start + block).to_le_bytes())?;
+        }
+        self.out_squashfs.write_all(&id_table)?;
+        for block in id_blocks {
+            self.out_squashfs
+                .write_all(&(id_blocks_start + block).to_le_bytes())?;
+        }
+        let padding = (DEVICE_BLOCK_SIZE - bytes_used % DEVICE_BLOCK_SIZE) % DEVICE_BLOCK_SIZE;
+        self.out_squashfs.write_all(&vec![0u8; padding as usize])?;
+
+        self.stats.header_bytes += SUPERBLOCK_SIZE + (fragment_blocks_start - inode_table_start);
+        self.stats.end_marker_bytes += bytes_used - fragment_blocks_start;
+        self.stats.padding_bytes += padding;
+        Ok(())
+    }
+
+    /// returns the underlying writer, usually after the end marker was written
+    pub fn into_inner(self) -> W {
+        self.out_squashfs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Cursor;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    }
+
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        ZlibDecoder::new(data).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    /// reads len bytes of metadata, which start at offset inside of the metadata block at position
+    fn metadata(image: &[u8], mut position: usize, offset: usize, len: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        while out.len() < offset + len {
+            let header = u16_at(image, position);
+            let size = (header & !METADATA_UNCOMPRESSED) as usize;
+            let block = &image[position + 2..position + 2 + size];
+            match header & METADATA_UNCOMPRESSED {
+                0 => out.extend_from_slice(&decompress(block)),
+                _ => out.extend_from_slice(block),
+            }
+            position += 2 + size;
+        }
+        out[offset..offset + len].to_vec()
+    }
+
+    /// an entry of the image: path, whether it is a directory, mode, modification time and content
+    type Entry = (Vec<u8>, bool, u16, u32, Vec<u8>);
+
+    /// reads the inode of the reference (block and offset in the inode table) and everything below
+    fn read_inode(
+        image: &[u8],
+        path: Vec<u8>,
+        reference: (usize, usize),
+        entries: &mut Vec<Entry>,
+    ) {
+        let inode_table = u64_at(image, 64) as usize;
+        let directory_table = u64_at(image, 72) as usize;
+        let block_size = u32_at(image, 12) as u64;
+        let (block, offset) = reference;
+        let inode = metadata(image, inode_table + block, offset, 32);
+        let (typ, mode, mtime) = (u16_at(&inode, 0), u16_at(&inode, 2), u32_at(&inode, 8));
+        // the owner and group are the first entry of the id table
+        assert_eq!(inode[4..8], [0; 4]);
+        match typ {
+            BASIC_DIRECTORY => {
+                entries.push((path.clone(), true, mode, mtime, vec![]));
+                let start = u32_at(&inode, 16) as usize;
+                let size = u16_at(&inode, 24) as usize - 3;
+                let listing = metadata(
+                    image,
+                    directory_table + start,
+                    u16_at(&inode, 26) as usize,
+                    size,
+                );
+                let mut at = 0;
+                while at < listing.len() {
+                    let count = u32_at(&listing, at) + 1;
+                    let block = u32_at(&listing, at + 4) as usize;
+                    at += 12;
+                    for _ in 0..count {
+                        let offset = u16_at(&listing, at) as usize;
+                        let name_len = u16_at(&listing, at + 6) as usize + 1;
+                        let name = &listing[at + 8..at + 8 + name_len];
+                        let child = match path.is_empty() {
+                            true => name.to_vec(),
+                            false => [&path, &b"/"[..], name].concat(),
+                        };
+                        read_inode(image, child, (block, offset), entries);
+                        at += 8 + name_len;
+                    }
+                }
+            }
+            BASIC_FILE => {
+                let mut position = u32_at(&inode, 16) as usize;
+                let fragment = u32_at(&inode, 20);
+                let size = u32_at(&inode, 28) as u64;
+                let blocks = (size / block_size) as usize;
+                let inode = metadata(image, inode_table + block, offset, 32 + 4 * blocks);
+                let mut content = Vec::new();
+                for i in 0..blocks {
+                    let field = u32_at(&inode, 32 + 4 * i);
+                    let stored = (field & !DATA_UNCOMPRESSED) as usize;
+                    let data = &image[position..position + stored];
+                    match field & DATA_UNCOMPRESSED {
+                        0 => content.extend_from_slice(&decompress(data)),
+                        _ => content.extend_from_slice(data),
+                    }
+                    position += stored;
+                }
+                if !size.is_multiple_of(block_size) {
+                    // the fragment table holds 16 byte entries, its index the metadata blocks
+                    let index = u64_at(image, u64_at(image, 80) as usize) as usize;
+                    let entry = metadata(image, index, 16 * fragment as usize, 16);
+                    let (start, field) = (u64_at(&entry, 0) as usize, u32_at(&entry, 8));
+                    let stored = (field & !DATA_UNCOMPRESSED) as usize;
+                    let data = &image[start..start + stored];
+                    let fragment_block = match field & DATA_UNCOMPRESSED {
+                        0 => decompress(data),
+                        _ => data.to_vec(),
+                    };
+                    let tail = u32_at(&inode, 24) as usize;
+                    content.extend_from_slice(
+                        &fragment_block[tail..tail + (size % block_size) as usize],
+                    );
+                } else {
+                    assert_eq!(fragment, INVALID_FRAGMENT);
+                }
+                entries.push((path, false, mode, mtime, content));
+            }
+            _ => panic!("unexpected inode type {}", typ),
+        }
+    }
+
+    /// returns all entries of the image, the root directory first
+    fn read_back(image: &[u8]) -> Vec<Entry> {
+        assert_eq!(u32_at(image, 0), SQUASHFS_MAGIC);
+        assert_eq!(image.len() as u64 % DEVICE_BLOCK_SIZE, 0);
+        let root = u64_at(image, 32);
+        let mut entries = Vec::new();
+        read_inode(
+            image,
+            vec![],
+            ((root >> 16) as usize, (root & 0xffff) as usize),
+            &mut entries,
+        );
+        assert_eq!(entries.len() as u32, u32_at(image, 4));
+        entries
+    }
+
+    /// bytes of a linear congruential generator, which zlib can't compress
+    fn incompressible(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn images_are_read_back() {
+        let block = SQUASHFS_BLOCK_SIZE as usize;
+        let big: Vec<u8> = (0..block + 100).map(|i| (i % 7) as u8).collect();
+        let small = incompressible(300);
+        let full = incompressible(block);
+        let mut out = SquashfsOutput::new(Vec::new()).unwrap();
+        out.squashfs_write_dir_with_metadata(b"r/", 0o700, Some(5))
+            .unwrap();
+        out.squashfs_write_dir(b"r/a/").unwrap();
+        for (content, name) in [
+            (&full, &b"r/c"[..]),
+            (&vec![], b"r/b"),
+            (&big, b"r/a/big"),
+            (&small, b"r/a/small"),
+        ] {
+            out.squashfs_write_file(
+                #[cfg(feature = "sha512")]
+                None::<(&mut Vec<u8>, HashOptions)>,
+                &mut Cursor::new(content),
+                &(content.len() as u64),
+                name,
+            )
+            .unwrap();
+        }
+        out.squashfs_end_marker().unwrap();
+        let entries = read_back(&out.into_inner());
+        assert_eq!(
+            entries,
+            [
+                (b"".to_vec(), true, 0o700, 5, vec![]),
+                (b"a".to_vec(), true, 0o755, 0, vec![]),
+                (b"a/big".to_vec(), false, 0o644, 0, big),
+                (b"a/small".to_vec(), false, 0o644, 0, small),
+                (b"b".to_vec(), false, 0o644, 0, vec![]),
+                (b"c".to_vec(), false, 0o644, 0, full),
+            ]
+        );
+    }
+}
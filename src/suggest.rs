@@ -0,0 +1,200 @@
+use crate::walk::{DirWalkItem, DirWalkType};
+#[cfg(feature = "sha512")]
+use sha2::{Digest, Sha512};
+use std::collections::BTreeMap;
+#[cfg(feature = "sha512")]
+use std::collections::HashMap;
+#[cfg(feature = "sha512")]
+use std::io::Read;
+use std::path::PathBuf;
+
+/// directory names which usually contain generated files, caches or version control data
+const ARTIFACT_DIRECTORIES: &[&str] = &[
+    ".cache",
+    ".git",
+    ".gradle",
+    ".hg",
+    ".idea",
+    ".mypy_cache",
+    ".pytest_cache",
+    ".svn",
+    ".tox",
+    ".venv",
+    "__pycache__",
+    "build",
+    "dist",
+    "node_modules",
+    "target",
+    "venv",
+];
+
+/// file extensions of object files, logs and editor backups
+const ARTIFACT_EXTENSIONS: &[&str] = &[
+    "a", "bak", "class", "log", "o", "obj", "pyc", "pyo", "so", "swp", "tmp",
+];
+
+/// files at least this large are suggested individually
+const LARGE_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+/// A suggested exclusion together with the number of bytes it would save.
+#[derive(Clone, Debug)]
+pub struct Suggestion {
+    pub saved_bytes: u64,
+    /// regular expression for --ignored-names, if the suggestion can be expressed as one
+    pub pattern: Option<String>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Suggestion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.pattern {
+            Some(pattern) => write!(f, "{}\t-i '{}'\t{}", self.saved_bytes, pattern, self.reason),
+            None => write!(f, "{}\t-\t{}", self.saved_bytes, self.reason),
+        }
+    }
+}
+
+/// escapes all characters which have a special meaning in a regular expression
+fn regex_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Analyzes all entries of a walk and returns suggested exclusions, largest savings first.
+pub fn suggest_excludes(walk: impl Iterator<Item = DirWalkItem>) -> Vec<Suggestion> {
+    // bytes per artifact directory name, per artifact extension and per file size
+    let mut directories: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    let mut extensions: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    let mut large_files: Vec<(u64, PathBuf)> = Vec::new();
+    let mut by_size: BTreeMap<u64, Vec<DirWalkItem>> = BTreeMap::new();
+
+    for d in walk {
+        let size = match d.typ {
+            DirWalkType::File | DirWalkType::SymlinkToFile(_) => d.size.unwrap(),
+            _ => continue,
+        };
+        // attribute the file to the outermost artifact directory it is in
+        let directory = d
+            .relpath
+            .iter()
+            .skip(1)
+            .filter_map(|c| c.to_str())
+            .find_map(|c| ARTIFACT_DIRECTORIES.iter().find(|a| **a == c));
+        if let Some(directory) = directory {
+            let entry = directories.entry(directory).or_default();
+            entry.0 += size;
+            entry.1 += 1;
+            continue;
+        }
+        let extension = d
+            .relpath
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| ARTIFACT_EXTENSIONS.iter().find(|a| **a == e));
+        if let Some(extension) = extension {
+            let entry = extensions.entry(extension).or_default();
+            entry.0 += size;
+            entry.1 += 1;
+            continue;
+        }
+        if size >= LARGE_FILE_SIZE {
+            large_files.push((size, d.relpath.clone()));
+        }
+        if size > 0 {
+            by_size.entry(size).or_default().push(d);
+        }
+    }
+
+    let mut suggestions = Vec::new();
+    for (directory, (bytes, files)) in directories {
+        suggestions.push(Suggestion {
+            saved_bytes: bytes,
+            pattern: Some(format!("^{}$", regex_escape(directory))),
+            reason: format!("{} files in \"{}\" directories", files, directory),
+        });
+    }
+    for (extension, (bytes, files)) in extensions {
+        suggestions.push(Suggestion {
+            saved_bytes: bytes,
+            pattern: Some(format!("\\.{}$", regex_escape(extension))),
+            reason: format!("{} \"*.{}\" files", files, extension),
+        });
+    }
+    for (size, relpath) in large_files {
+        let basename = relpath.file_name().unwrap().to_str().unwrap();
+        suggestions.push(Suggestion {
+            saved_bytes: size,
+            pattern: Some(format!("^{}$", regex_escape(basename))),
+            reason: format!("large file {}", relpath.display()),
+        });
+    }
+    #[cfg(feature = "sha512")]
+    suggestions.extend(duplicate_suggestions(by_size));
+    #[cfg(not(feature = "sha512"))]
+    drop(by_size);
+
+    suggestions.sort_by(|a, b| {
+        b.saved_bytes
+            .cmp(&a.saved_bytes)
+            .then_with(|| a.reason.cmp(&b.reason))
+    });
+    suggestions
+}
+
+/// finds files with identical content among files of the same size
+#[cfg(feature = "sha512")]
+fn duplicate_suggestions(by_size: BTreeMap<u64, Vec<DirWalkItem>>) -> Vec<Suggestion> {
+    let mut suggestions = Vec::new();
+    for (size, items) in by_size {
+        if items.len() < 2 {
+            continue;
+        }
+        let mut by_hash: BTreeMap<Vec<u8>, Vec<PathBuf>> = BTreeMap::new();
+        // the digests of files with more than one name, so each of them is only read once
+        let mut by_inode: HashMap<(u64, u64), Vec<u8>> = HashMap::new();
+        for d in items {
+            if let Some(digest) = d.inode.and_then(|inode| by_inode.get(&inode)) {
+                by_hash.entry(digest.clone()).or_default().push(d.relpath);
+                continue;
+            }
+            let path = match &d.typ {
+                DirWalkType::SymlinkToFile(resolved_path) => resolved_path.clone(),
+                _ => d.abspath.clone(),
+            };
+            let mut file = std::fs::File::open(&path)
+                .unwrap_or_else(|_| panic!("could not open file {:?}", &path));
+            let mut hasher = Sha512::new();
+            let mut buffer = [0u8; 64 * 1024];
+            loop {
+                let n = file.read(&mut buffer).expect("intermittent i/o error");
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..n]);
+            }
+            let digest = hasher.finalize().to_vec();
+            if let Some(inode) = d.inode {
+                by_inode.insert(inode, digest.clone());
+            }
+            by_hash.entry(digest).or_default().push(d.relpath);
+        }
+        for (_, relpaths) in by_hash {
+            if relpaths.len() < 2 {
+                continue;
+            }
+            let names: Vec<String> = relpaths.iter().map(|p| p.display().to_string()).collect();
+            suggestions.push(Suggestion {
+                saved_bytes: size * (relpaths.len() as u64 - 1),
+                pattern: None,
+                reason: format!("duplicate content: {}", names.join(", ")),
+            });
+        }
+    }
+    suggestions
+}
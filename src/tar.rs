@@ -0,0 +1,1149 @@
+#[cfg(feature = "sha512")]
+use crate::hash::{write_hash_line, FileHasher, HashOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Counters about the entries and the overhead of a written archive.
+#[derive(Clone, Debug, Default)]
+pub struct TarStats {
+    pub directories: u64,
+    pub files: u64,
+    pub hardlinks: u64,
+    pub symlinks: u64,
+    pub special_files: u64,
+    pub longlinks: u64,
+    pub header_bytes: u64,
+    pub longlink_bytes: u64,
+    pub pax_headers: u64,
+    pub pax_bytes: u64,
+    pub content_bytes: u64,
+    pub padding_bytes: u64,
+    pub end_marker_bytes: u64,
+    pub sparse_files: u64,
+}
+
+impl TarStats {
+    /// the number of entries of all kinds
+    pub fn entries(&self) -> u64 {
+        self.directories + self.files + self.hardlinks + self.symlinks + self.special_files
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.header_bytes
+            + self.longlink_bytes
+            + self.pax_bytes
+            + self.content_bytes
+            + self.padding_bytes
+            + self.end_marker_bytes
+    }
+
+    pub fn overhead_bytes(&self) -> u64 {
+        self.total_bytes() - self.content_bytes
+    }
+
+    /// adds the counters of another archive, e.g. when writing multiple archives
+    pub fn add(&mut self, other: &TarStats) {
+        self.directories += other.directories;
+        self.files += other.files;
+        self.hardlinks += other.hardlinks;
+        self.symlinks += other.symlinks;
+        self.special_files += other.special_files;
+        self.longlinks += other.longlinks;
+        self.header_bytes += other.header_bytes;
+        self.longlink_bytes += other.longlink_bytes;
+        self.pax_headers += other.pax_headers;
+        self.pax_bytes += other.pax_bytes;
+        self.content_bytes += other.content_bytes;
+        self.padding_bytes += other.padding_bytes;
+        self.end_marker_bytes += other.end_marker_bytes;
+        self.sparse_files += other.sparse_files;
+    }
+}
+
+impl std::fmt::Display for TarStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "entries: {}", self.entries())?;
+        writeln!(f, "directories: {}", self.directories)?;
+        writeln!(f, "files: {}", self.files)?;
+        writeln!(f, "hardlinks: {}", self.hardlinks)?;
+        writeln!(f, "symlinks: {}", self.symlinks)?;
+        writeln!(f, "special_files: {}", self.special_files)?;
+        writeln!(f, "sparse_files: {}", self.sparse_files)?;
+        writeln!(f, "longlinks: {}", self.longlinks)?;
+        writeln!(f, "header_bytes: {}", self.header_bytes)?;
+        writeln!(f, "longlink_bytes: {}", self.longlink_bytes)?;
+        writeln!(f, "pax_headers: {}", self.pax_headers)?;
+        writeln!(f, "pax_bytes: {}", self.pax_bytes)?;
+        writeln!(f, "content_bytes: {}", self.content_bytes)?;
+        writeln!(f, "padding_bytes: {}", self.padding_bytes)?;
+        writeln!(f, "end_marker_bytes: {}", self.end_marker_bytes)?;
+        writeln!(f, "overhead_bytes: {}", self.overhead_bytes())?;
+        writeln!(f, "total_bytes: {}", self.total_bytes())
+    }
+}
+
+/// The flavour of tar archive which is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TarFormat {
+    /// GNU tar, long names are stored in "././@LongLink" entries
+    Gnu,
+    /// POSIX.1-2001 pax interchange format, long names are stored in extended headers
+    Pax,
+    /// POSIX.1-1988 ustar, long names are split into the prefix and name field and fail if they don't fit
+    Ustar,
+    /// like [`TarFormat::Ustar`], but device numbers which don't fit into the header fail instead
+    /// of using the GNU base-256 encoding, see [`StrictUstarFormat`]
+    UstarStrict,
+    /// pre-POSIX Unix V7, without magic and owner names; names longer than 99 bytes fail
+    V7,
+}
+
+impl TarFormat {
+    /// returns the implementation of the header details of this format
+    pub fn header_format(&self) -> Box<dyn HeaderFormat> {
+        match self {
+            TarFormat::Gnu => Box::new(GnuFormat),
+            TarFormat::Pax => Box::new(PaxFormat),
+            TarFormat::Ustar => Box::new(UstarFormat),
+            TarFormat::UstarStrict => Box::new(StrictUstarFormat),
+            TarFormat::V7 => Box::new(V7Format),
+        }
+    }
+}
+
+impl std::str::FromStr for TarFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<TarFormat, String> {
+        match s {
+            "gnu" => Ok(TarFormat::Gnu),
+            "pax" => Ok(TarFormat::Pax),
+            "ustar" => Ok(TarFormat::Ustar),
+            "ustar-strict" => Ok(TarFormat::UstarStrict),
+            "v7" => Ok(TarFormat::V7),
+            _ => Err(format!(
+                "unknown format {:?}, expected gnu, pax, ustar, ustar-strict or v7",
+                s
+            )),
+        }
+    }
+}
+
+/// An extra entry written before the actual entry, which stores a name or size that does not fit into the header.
+pub struct ExtendedHeader {
+    /// name of the extra entry itself
+    pub headername: Vec<u8>,
+    pub typeflag: u8,
+    pub content: Vec<u8>,
+}
+
+/// The format specific content of a header.
+pub struct EncodedHeader<'a> {
+    pub name: &'a [u8],
+    pub prefix: &'a [u8],
+    pub size: [u8; 12],
+    /// extra entry which has to be written first
+    pub extended: Option<ExtendedHeader>,
+}
+
+/// The format specific content of the device number fields of a header.
+pub struct EncodedDevice {
+    pub major: [u8; 8],
+    pub minor: [u8; 8],
+    /// pax records which have to be added to the extended header
+    pub records: Vec<u8>,
+}
+
+/// How a format stores sparse files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SparseFormat {
+    /// GNU 'S' entries, the map is stored in the header and in extension blocks after it
+    OldGnu,
+    /// GNU sparse format 1.0 for pax, the map is stored in front of the data
+    Pax,
+}
+
+/// The details in which the tar formats differ. Everything else of the 512 byte header is
+/// written by [`TarOutput`].
+pub trait HeaderFormat {
+    /// content of the magic and version field, or None if the format has neither magic nor owner names
+    fn magic(&self) -> Option<&'static [u8; 8]>;
+
+    /// returns the content of the name, prefix and size field of the header, and the extra entry
+    /// which has to be written first if the name or size does not fit
+    fn encode<'a>(&self, tarname: &'a [u8], size: u64)
+        -> Result<EncodedHeader<'a>, std::io::Error>;
+
+    /// how sparse files are stored, None if the format does not support them
+    fn sparse(&self) -> Option<SparseFormat> {
+        None
+    }
+
+    /// true if additional records can be stored in pax extended headers
+    fn pax_records(&self) -> bool {
+        false
+    }
+
+    /// returns the content of the devmajor and devminor field of the device tarname and the pax
+    /// records which have to be added to its extended header. By default, numbers which don't fit
+    /// into seven octal digits are stored in GNU base-256.
+    fn encode_device(
+        &self,
+        _tarname: &[u8],
+        (major, minor): (u32, u32),
+    ) -> Result<EncodedDevice, std::io::Error> {
+        Ok(EncodedDevice {
+            major: device_number(major),
+            minor: device_number(minor),
+            records: Vec::new(),
+        })
+    }
+
+    /// returns the content of the linkname field of the hardlink or symlink tarname and the extra
+    /// entry which
+    /// has to be written first if the target does not fit. By default, targets longer than 100
+    /// bytes fail.
+    fn encode_linkname<'a>(
+        &self,
+        _tarname: &[u8],
+        linkname: &'a [u8],
+    ) -> Result<(&'a [u8], Option<ExtendedHeader>), std::io::Error> {
+        if linkname.len() > 100 {
+            return Err(name_too_long(
+                linkname,
+                "is longer than 100 bytes and cannot be the target of a link",
+            ));
+        }
+        Ok((linkname, None))
+    }
+}
+
+/// largest size which fits into the 11 octal digits of the size field, which is 8 GiB - 1
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+/// largest device number which fits into the 7 octal digits of the devmajor and devminor field
+const MAX_OCTAL_DEVICE_NUMBER: u32 = 0o7777777;
+
+/// encodes the size as 11 octal digits, if possible
+fn octal_size(size: u64) -> Option<[u8; 12]> {
+    if size > MAX_OCTAL_SIZE {
+        return None;
+    }
+    let mut field = [0u8; 12];
+    field.clone_from_slice(format!("{:011o}\x00", size).as_bytes());
+    Some(field)
+}
+
+/// encodes a number as 11 octal digits or, if it is too large, as GNU base-256
+fn gnu_number(value: u64) -> [u8; 12] {
+    octal_size(value).unwrap_or_else(|| {
+        // base-256: the highest bit of the first byte is set, the rest is the big endian value
+        let mut field = [0u8; 12];
+        field[0] = 0x80;
+        field[4..12].clone_from_slice(&value.to_be_bytes());
+        field
+    })
+}
+
+/// encodes a device number as seven octal digits and a zero byte, if possible
+fn octal_device_number(number: u32) -> Option<[u8; 8]> {
+    if number > MAX_OCTAL_DEVICE_NUMBER {
+        return None;
+    }
+    let mut field = [0u8; 8];
+    field.clone_from_slice(format!("{:07o}\x00", number).as_bytes());
+    Some(field)
+}
+
+/// encodes a device number like the owner as seven octal digits or, if it is too large, as GNU
+/// base-256
+fn device_number(number: u32) -> [u8; 8] {
+    octal_device_number(number).unwrap_or_else(|| {
+        let mut field = [0u8; 8];
+        field[0] = 0x80;
+        field[4..8].clone_from_slice(&number.to_be_bytes());
+        field
+    })
+}
+
+/// deterministic name of a pax extended header, derived from the basename
+fn pax_header_name(tarname: &[u8]) -> Vec<u8> {
+    let basename = tarname
+        .split(|b| *b == b'/')
+        .rfind(|c| !c.is_empty())
+        .unwrap_or(b"");
+    let mut headername = b"PaxHeaders/".to_vec();
+    headername.extend_from_slice(basename);
+    headername.truncate(100);
+    headername
+}
+
+fn name_too_long(tarname: &[u8], reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("name {:?} {}", String::from_utf8_lossy(tarname), reason),
+    )
+}
+
+fn size_too_large(tarname: &[u8], size: u64, format: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!(
+            "size {} of {:?} is too large for the {} format, which is limited to {} bytes",
+            size,
+            String::from_utf8_lossy(tarname),
+            format,
+            MAX_OCTAL_SIZE
+        ),
+    )
+}
+
+/// GNU tar: long names are stored in "././@LongLink" entries, large sizes in base-256.
+pub struct GnuFormat;
+
+impl HeaderFormat for GnuFormat {
+    fn magic(&self) -> Option<&'static [u8; 8]> {
+        Some(b"ustar  \x00")
+    }
+
+    fn encode<'a>(
+        &self,
+        tarname: &'a [u8],
+        size: u64,
+    ) -> Result<EncodedHeader<'a>, std::io::Error> {
+        let size = gnu_number(size);
+        if tarname.len() <= 100 {
+            return Ok(EncodedHeader {
+                name: tarname,
+                prefix: b"",
+                size,
+                extended: None,
+            });
+        }
+        let longlink = ExtendedHeader {
+            headername: b"././@LongLink".to_vec(),
+            typeflag: b'L',
+            content: tarname.to_vec(),
+        };
+        Ok(EncodedHeader {
+            name: &tarname[..100],
+            prefix: b"",
+            size,
+            extended: Some(longlink),
+        })
+    }
+
+    fn sparse(&self) -> Option<SparseFormat> {
+        Some(SparseFormat::OldGnu)
+    }
+
+    fn encode_linkname<'a>(
+        &self,
+        _tarname: &[u8],
+        linkname: &'a [u8],
+    ) -> Result<(&'a [u8], Option<ExtendedHeader>), std::io::Error> {
+        if linkname.len() <= 100 {
+            return Ok((linkname, None));
+        }
+        let longlink = ExtendedHeader {
+            headername: b"././@LongLink".to_vec(),
+            typeflag: b'K',
+            content: linkname.to_vec(),
+        };
+        Ok((&linkname[..100], Some(longlink)))
+    }
+}
+
+/// POSIX pax: long names and large sizes are stored as records of an extended header.
+pub struct PaxFormat;
+
+impl HeaderFormat for PaxFormat {
+    fn magic(&self) -> Option<&'static [u8; 8]> {
+        Some(b"ustar\x0000")
+    }
+
+    fn encode<'a>(
+        &self,
+        tarname: &'a [u8],
+        size: u64,
+    ) -> Result<EncodedHeader<'a>, std::io::Error> {
+        // the records are always written in the same order: path, size
+        let mut records = Vec::new();
+        if tarname.len() > 100 {
+            records.extend(pax_record("path", tarname));
+        }
+        let octal = octal_size(size);
+        if octal.is_none() {
+            records.extend(pax_record("size", size.to_string().as_bytes()));
+        }
+        let name = &tarname[..std::cmp::min(tarname.len(), 100)];
+        // the size field of the header is ignored if the extended header contains the size
+        let size = octal.unwrap_or_else(|| octal_size(0).unwrap());
+        if records.is_empty() {
+            return Ok(EncodedHeader {
+                name,
+                prefix: b"",
+                size,
+                extended: None,
+            });
+        }
+        let extended = ExtendedHeader {
+            headername: pax_header_name(tarname),
+            typeflag: b'x',
+            content: records,
+        };
+        Ok(EncodedHeader {
+            name,
+            prefix: b"",
+            size,
+            extended: Some(extended),
+        })
+    }
+
+    fn sparse(&self) -> Option<SparseFormat> {
+        Some(SparseFormat::Pax)
+    }
+
+    fn pax_records(&self) -> bool {
+        true
+    }
+
+    fn encode_device(
+        &self,
+        _tarname: &[u8],
+        (major, minor): (u32, u32),
+    ) -> Result<EncodedDevice, std::io::Error> {
+        // readers which don't know the records (e.g. GNU tar) still get the numbers from the
+        // base-256 fields. Both are stored, as some readers (e.g. bsdtar) drop the minor number
+        // of the header if there is only a devmajor record.
+        let mut records = Vec::new();
+        if octal_device_number(major).is_none() || octal_device_number(minor).is_none() {
+            records.extend(pax_record("SCHILY.devmajor", major.to_string().as_bytes()));
+            records.extend(pax_record("SCHILY.devminor", minor.to_string().as_bytes()));
+        }
+        Ok(EncodedDevice {
+            major: device_number(major),
+            minor: device_number(minor),
+            records,
+        })
+    }
+
+    fn encode_linkname<'a>(
+        &self,
+        tarname: &[u8],
+        linkname: &'a [u8],
+    ) -> Result<(&'a [u8], Option<ExtendedHeader>), std::io::Error> {
+        if linkname.len() <= 100 {
+            return Ok((linkname, None));
+        }
+        let extended = ExtendedHeader {
+            headername: pax_header_name(tarname),
+            typeflag: b'x',
+            content: pax_record("linkpath", linkname),
+        };
+        Ok((&linkname[..100], Some(extended)))
+    }
+}
+
+/// POSIX ustar: long names are split into the prefix and name field, otherwise they fail. Device
+/// numbers which don't fit into the header use the GNU base-256 encoding, see
+/// [`StrictUstarFormat`].
+pub struct UstarFormat;
+
+impl HeaderFormat for UstarFormat {
+    fn magic(&self) -> Option<&'static [u8; 8]> {
+        Some(b"ustar\x0000")
+    }
+
+    fn encode<'a>(
+        &self,
+        tarname: &'a [u8],
+        size: u64,
+    ) -> Result<EncodedHeader<'a>, std::io::Error> {
+        let size = octal_size(size).ok_or_else(|| size_too_large(tarname, size, "ustar"))?;
+        if tarname.len() <= 100 {
+            return Ok(EncodedHeader {
+                name: tarname,
+                prefix: b"",
+                size,
+                extended: None,
+            });
+        }
+        // the name is split at a slash into prefix (max. 155 bytes) and name (max. 100 bytes),
+        // the slash itself is not stored. A trailing slash of a directory stays in the name.
+        let search_end = std::cmp::min(tarname.len() - 1, 156);
+        tarname[..search_end]
+            .iter()
+            .rposition(|b| *b == b'/')
+            .filter(|i| *i > 0 && tarname.len() - i - 1 <= 100)
+            .map(|i| EncodedHeader {
+                name: &tarname[i + 1..],
+                prefix: &tarname[..i],
+                size,
+                extended: None,
+            })
+            .ok_or_else(|| {
+                name_too_long(
+                    tarname,
+                    "does not fit into the ustar name and prefix fields",
+                )
+            })
+    }
+}
+
+/// POSIX ustar which refuses the GNU base-256 extension, so every header can be read by old tar
+/// implementations: device numbers which don't fit into the header fail like long names.
+pub struct StrictUstarFormat;
+
+impl HeaderFormat for StrictUstarFormat {
+    fn magic(&self) -> Option<&'static [u8; 8]> {
+        UstarFormat.magic()
+    }
+
+    fn encode<'a>(
+        &self,
+        tarname: &'a [u8],
+        size: u64,
+    ) -> Result<EncodedHeader<'a>, std::io::Error> {
+        UstarFormat.encode(tarname, size)
+    }
+
+    fn encode_device(
+        &self,
+        tarname: &[u8],
+        (major, minor): (u32, u32),
+    ) -> Result<EncodedDevice, std::io::Error> {
+        let field = |name: &str, number: u32| {
+            octal_device_number(number).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} {} of {:?} does not fit into the seven octal digits of the strict ustar format",
+                        name,
+                        number,
+                        String::from_utf8_lossy(tarname)
+                    ),
+                )
+            })
+        };
+        Ok(EncodedDevice {
+            major: field("devmajor", major)?,
+            minor: field("devminor", minor)?,
+            records: Vec::new(),
+        })
+    }
+}
+
+/// Unix V7: no magic, no owner names and at most 99 bytes for the name.
+pub struct V7Format;
+
+impl HeaderFormat for V7Format {
+    fn magic(&self) -> Option<&'static [u8; 8]> {
+        None
+    }
+
+    fn encode<'a>(
+        &self,
+        tarname: &'a [u8],
+        size: u64,
+    ) -> Result<EncodedHeader<'a>, std::io::Error> {
+        // the name field has to be terminated by a zero byte
+        if tarname.len() > 99 {
+            return Err(name_too_long(
+                tarname,
+                "is longer than 99 bytes, which is the limit of the v7 format",
+            ));
+        }
+        Ok(EncodedHeader {
+            name: tarname,
+            prefix: b"",
+            size: octal_size(size).ok_or_else(|| size_too_large(tarname, size, "v7"))?,
+            extended: None,
+        })
+    }
+
+    fn encode_linkname<'a>(
+        &self,
+        _tarname: &[u8],
+        linkname: &'a [u8],
+    ) -> Result<(&'a [u8], Option<ExtendedHeader>), std::io::Error> {
+        // like the name field, the linkname field has to be terminated by a zero byte
+        if linkname.len() > 99 {
+            return Err(name_too_long(
+                linkname,
+                "is longer than 99 bytes and cannot be the target of a link in the v7 format",
+            ));
+        }
+        Ok((linkname, None))
+    }
+}
+
+/// Creates a single pax extended header record "<length> <key>=<value>\n".
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    // the length includes the number of digits of the length itself
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    let mut record = format!("{} {}=", len, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+/// Writes deterministic tar archives in one of the supported formats.
+pub struct TarOutput<W: Write> {
+    out_tar: W,
+    format: Box<dyn HeaderFormat>,
+    // records of tar_add_pax_record for the next file or directory
+    pending_records: Vec<u8>,
+    pub stats: TarStats,
+}
+
+impl<W: Write> TarOutput<W> {
+    pub fn new(out_tar: W) -> TarOutput<W> {
+        TarOutput::with_format(out_tar, TarFormat::Gnu)
+    }
+
+    pub fn with_format(out_tar: W, format: TarFormat) -> TarOutput<W> {
+        TarOutput::with_header_format(out_tar, format.header_format())
+    }
+
+    /// uses a custom implementation of the header details
+    pub fn with_header_format(out_tar: W, format: Box<dyn HeaderFormat>) -> TarOutput<W> {
+        TarOutput {
+            out_tar,
+            format,
+            pending_records: Vec::new(),
+            stats: TarStats::default(),
+        }
+    }
+
+    fn _tar_fix_header_checksum(header: &mut [u8]) {
+        let sum: u64 = header.iter().map(|i| *i as u64).sum();
+        // checksum is now correct
+        header[148..156].clone_from_slice(format!("{:06o}\x00 ", sum).as_bytes());
+    }
+
+    /// writes a single 512 byte header
+    fn _write_header(
+        &mut self,
+        name: &[u8],
+        prefix: &[u8],
+        mode: &[u8; 8],
+        size: &[u8; 12],
+        typeflag: u8,
+    ) -> Result<(), std::io::Error> {
+        let header = self._header(name, prefix, mode, size, typeflag);
+        self._write_header_block(header)
+    }
+
+    /// creates a header without checksum
+    fn _header(
+        &self,
+        name: &[u8],
+        prefix: &[u8],
+        mode: &[u8; 8],
+        size: &[u8; 12],
+        typeflag: u8,
+    ) -> [u8; 512] {
+        let mut header = [0u8; 512];
+        header[0..name.len()].clone_from_slice(name);
+        header[100..108].clone_from_slice(mode); // File mode (octal)
+        header[108..116].clone_from_slice(b"0000000\x00"); // Owner's numeric user ID (octal), here we use 0 for "root"
+        header[116..124].clone_from_slice(b"0000000\x00"); // Group's numeric user ID (octal), here we use 0 for "root"
+        header[124..136].clone_from_slice(size); // File size in bytes (octal or base-256), zero for a directory
+        header[148..156].clone_from_slice(b"        "); // checksum: eight spaces, will be replaced later
+        header[156] = typeflag;
+        if let Some(magic) = self.format.magic() {
+            header[257..265].clone_from_slice(magic); // magic string for ustar format extension and version
+            header[265..269].clone_from_slice(b"root"); // Owner user name
+            header[297..301].clone_from_slice(b"root"); // Owner group name
+        }
+        header[345..345 + prefix.len()].clone_from_slice(prefix); // ustar filename prefix
+        header
+    }
+
+    /// computes the checksum of the header and writes it
+    fn _write_header_block(&mut self, mut header: [u8; 512]) -> Result<(), std::io::Error> {
+        Self::_tar_fix_header_checksum(&mut header);
+        self.stats.header_bytes += 512;
+        self.out_tar.write_all(&header)
+    }
+
+    /// writes the header of an entry, preceded by an extra entry for the name or size if necessary
+    fn _write_entry_header(
+        &mut self,
+        tarname: &[u8],
+        mode: &[u8; 8],
+        size: u64,
+        typeflag: u8,
+    ) -> Result<(), std::io::Error> {
+        let encoded = self.format.encode(tarname, size)?;
+        if let Some(extended) = self._with_pending_records(tarname, encoded.extended) {
+            self._write_extended_header(&extended, mode)?;
+        }
+        self._write_header(encoded.name, encoded.prefix, mode, &encoded.size, typeflag)
+    }
+
+    /// adds the records of tar_add_pax_record to the extended header of an entry, after path and
+    /// size, or creates one for them
+    fn _with_pending_records(
+        &mut self,
+        tarname: &[u8],
+        extended: Option<ExtendedHeader>,
+    ) -> Option<ExtendedHeader> {
+        match extended {
+            _ if self.pending_records.is_empty() => extended,
+            Some(mut extended) => {
+                extended.content.append(&mut self.pending_records);
+                Some(extended)
+            }
+            None => Some(ExtendedHeader {
+                headername: pax_header_name(tarname),
+                typeflag: b'x',
+                content: std::mem::take(&mut self.pending_records),
+            }),
+        }
+    }
+
+    /// writes an extra entry (LongLink or pax extended header) together with its content
+    fn _write_extended_header(
+        &mut self,
+        extended: &ExtendedHeader,
+        mode: &[u8; 8],
+    ) -> Result<(), std::io::Error> {
+        let content = &extended.content;
+        let content_size =
+            octal_size(content.len() as u64).expect("extended header larger than 8 GiB");
+        self._write_header(
+            &extended.headername,
+            b"",
+            mode,
+            &content_size,
+            extended.typeflag,
+        )?;
+        // now, write the content padded to 512 bytes
+        let padding = (512 - (content.len() % 512)) % 512;
+        self.out_tar.write_all(content)?;
+        self.out_tar.write_all(&[0u8; 512][..padding])?;
+        if let b'L' | b'K' = extended.typeflag {
+            self.stats.longlinks += 1;
+            self.stats.longlink_bytes += (content.len() + padding) as u64;
+        } else {
+            self.stats.pax_headers += 1;
+            self.stats.pax_bytes += (content.len() + padding) as u64;
+        }
+        Ok(())
+    }
+
+    /// writes a pax global header named "pax_global_header" with the given records, in the given
+    /// order. It has to be written before the first entry.
+    pub fn tar_write_pax_global(
+        &mut self,
+        records: &[(String, String)],
+    ) -> Result<(), std::io::Error> {
+        let mut content = Vec::new();
+        for (key, value) in records {
+            content.extend(pax_record(key, value.as_bytes()));
+        }
+        let global = ExtendedHeader {
+            headername: b"pax_global_header".to_vec(),
+            typeflag: b'g',
+            content,
+        };
+        self._write_extended_header(&global, b"0000644\x00")
+    }
+
+    /// adds a record to the pax extended header of the next file or directory, e.g. an extended
+    /// attribute "SCHILY.xattr.security.capability". The records are written in the order in which they
+    /// were added, after the records for the name and size. Only supported by the pax format.
+    pub fn tar_add_pax_record(&mut self, key: &str, value: &[u8]) -> Result<(), std::io::Error> {
+        if !self.format.pax_records() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("the tar format cannot store the pax record {:?}", key),
+            ));
+        }
+        self.pending_records.extend(pax_record(key, value));
+        Ok(())
+    }
+
+    pub fn tar_write_dir(&mut self, tarname: &[u8]) -> Result<(), std::io::Error> {
+        self.tar_write_dir_with_metadata(tarname, 0o755, None)
+    }
+
+    /// writes a directory with the given permissions instead of 0755 and, if given, a
+    /// modification time instead of the empty field, e.g. for the main directory
+    pub fn tar_write_dir_with_metadata(
+        &mut self,
+        tarname: &[u8],
+        mode: u32,
+        mtime: Option<u64>,
+    ) -> Result<(), std::io::Error> {
+        let mut mode_field = [0u8; 8];
+        mode_field.clone_from_slice(format!("{:07o}\x00", mode & 0o7777).as_bytes());
+        let encoded = self.format.encode(tarname, 0)?;
+        if let Some(extended) = self._with_pending_records(tarname, encoded.extended) {
+            self._write_extended_header(&extended, &mode_field)?;
+        }
+        let mut header = self._header(
+            encoded.name,
+            encoded.prefix,
+            &mode_field,
+            &encoded.size,
+            b'5',
+        );
+        if let Some(mtime) = mtime {
+            let mtime_field = octal_size(mtime).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("modification time {} does not fit into a tar header", mtime),
+                )
+            })?;
+            header[136..148].clone_from_slice(&mtime_field); // modification time (octal)
+        }
+        self._write_header_block(header)?;
+        self.stats.directories += 1;
+        Ok(())
+    }
+
+    /// writes a hardlink to linkname, an entry which was written before. Extracting it creates
+    /// the same content without storing it again.
+    pub fn tar_write_hardlink(
+        &mut self,
+        tarname: &[u8],
+        linkname: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self._write_link(tarname, linkname, b"0000644\x00", b'1')?;
+        self.stats.hardlinks += 1;
+        Ok(())
+    }
+
+    /// writes a symlink to linkname, which is stored as it is and doesn't have to exist, with
+    /// mode 0777 like GNU tar
+    pub fn tar_write_symlink(
+        &mut self,
+        tarname: &[u8],
+        linkname: &[u8],
+    ) -> Result<(), std::io::Error> {
+        self._write_link(tarname, linkname, b"0000777\x00", b'2')?;
+        self.stats.symlinks += 1;
+        Ok(())
+    }
+
+    /// writes a character device ('3'), block device ('4') or fifo ('6') without content. The
+    /// device numbers are only stored for devices, they stay zero for fifos.
+    pub fn tar_write_special(
+        &mut self,
+        tarname: &[u8],
+        typeflag: u8,
+        device: (u32, u32),
+    ) -> Result<(), std::io::Error> {
+        let mode = b"0000644\x00";
+        if self.format.magic().is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "special files cannot be stored in the v7 format",
+            ));
+        }
+        let encoded = self.format.encode(tarname, 0)?;
+        // the fields of fifos stay empty
+        let fields = if typeflag != b'6' {
+            let mut encoded = self.format.encode_device(tarname, device)?;
+            // the device records come before the ones of tar_add_pax_record
+            encoded.records.append(&mut self.pending_records);
+            self.pending_records = encoded.records;
+            Some((encoded.major, encoded.minor))
+        } else {
+            None
+        };
+        if let Some(extended) = self._with_pending_records(tarname, encoded.extended) {
+            self._write_extended_header(&extended, mode)?;
+        }
+        let mut header = self._header(encoded.name, encoded.prefix, mode, &encoded.size, typeflag);
+        if let Some((major, minor)) = fields {
+            header[329..337].clone_from_slice(&major); // devmajor
+            header[337..345].clone_from_slice(&minor); // devminor
+        }
+        self._write_header_block(header)?;
+        self.stats.special_files += 1;
+        Ok(())
+    }
+
+    fn _write_link(
+        &mut self,
+        tarname: &[u8],
+        linkname: &[u8],
+        mode: &[u8; 8],
+        typeflag: u8,
+    ) -> Result<(), std::io::Error> {
+        let (link_field, link_extended) = self.format.encode_linkname(tarname, linkname)?;
+        let encoded = self.format.encode(tarname, 0)?;
+        match (link_extended, encoded.extended) {
+            // a single pax extended header with the records path and linkpath
+            (Some(link), Some(mut name)) if link.typeflag == b'x' && name.typeflag == b'x' => {
+                name.content.extend_from_slice(&link.content);
+                self._write_extended_header(&name, mode)?;
+            }
+            (link, name) => {
+                // GNU tar writes the LongLink of the target before the one of the name
+                for extended in [link, name].iter().flatten() {
+                    self._write_extended_header(extended, mode)?;
+                }
+            }
+        }
+        let mut header = self._header(encoded.name, encoded.prefix, mode, &encoded.size, typeflag);
+        header[157..157 + link_field.len()].clone_from_slice(link_field); // name of the link target
+        self._write_header_block(header)?;
+        Ok(())
+    }
+
+    pub fn tar_write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut impl Write, HashOptions)>,
+        in_filedescriptor: &mut impl Read,
+        size: &u64,
+        tarname: &[u8],
+    ) -> Result<(), std::io::Error> {
+        // magic value '0' for "normal file"
+        self._write_entry_header(tarname, b"0000644\x00", *size, b'0')?;
+        self.stats.files += 1;
+
+        // // now we have to write the file in 512 bytes block and pad it with zero bytes on end
+        let mut already_read = 0u64;
+        let mut buffer = [0; 512];
+        #[cfg(feature = "sha512")]
+        let mut hasher = out_hash
+            .as_ref()
+            .map(|(_, options)| FileHasher::new(options.algorithm));
+        loop {
+            let n = in_filedescriptor.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            };
+            already_read += n as u64;
+            self.out_tar
+                .write_all(&buffer[0..n])
+                .expect("could not write to tarfile");
+            #[cfg(feature = "sha512")]
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[0..n]);
+            };
+        }
+        if already_read != *size {
+            panic!("size while reading different from stat");
+        }
+        let padding = ((512 - (already_read % 512)) % 512) as usize;
+        self.out_tar.write_all(&[0u8; 512][..padding])?;
+        self.stats.content_bytes += already_read;
+        self.stats.padding_bytes += padding as u64;
+        #[cfg(feature = "sha512")]
+        if let (Some((out_hash, options)), Some(hasher)) = (out_hash.as_mut(), hasher) {
+            write_hash_line(out_hash, *options, hasher, tarname)?;
+        }
+        Ok(())
+    }
+
+    /// writes a file which contains holes. Only the data regions of the map are read and stored,
+    /// the hash covers the whole content including the holes.
+    pub fn tar_write_sparse_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut impl Write, HashOptions)>,
+        in_filedescriptor: &mut (impl Read + Seek),
+        map: &[(u64, u64)],
+        size: &u64,
+        tarname: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let stored: u64 = map.iter().map(|(_, length)| length).sum();
+        let map_bytes = match self.format.sparse() {
+            Some(SparseFormat::OldGnu) => {
+                self._write_old_gnu_sparse_header(tarname, map, *size, stored)?;
+                0
+            }
+            Some(SparseFormat::Pax) => {
+                self._write_pax_sparse_header(tarname, map, *size, stored)?
+            }
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the archive format does not support sparse files",
+                ))
+            }
+        };
+        self.stats.files += 1;
+        self.stats.sparse_files += 1;
+
+        let mut buffer = [0u8; 64 * 1024];
+        #[cfg(feature = "sha512")]
+        let mut hasher = out_hash
+            .as_ref()
+            .map(|(_, options)| FileHasher::new(options.algorithm));
+        #[cfg(feature = "sha512")]
+        let mut hashed = 0u64;
+        for (offset, length) in map {
+            if offset + length > *size {
+                panic!("sparse map exceeds the size of the file");
+            }
+            #[cfg(feature = "sha512")]
+            if let Some(hasher) = hasher.as_mut() {
+                // the holes are hashed as zeros
+                while hashed < *offset {
+                    let n = std::cmp::min(offset - hashed, buffer.len() as u64) as usize;
+                    hasher.update(&[0u8; 64 * 1024][..n]);
+                    hashed += n as u64;
+                }
+                hashed = offset + length;
+            }
+            in_filedescriptor.seek(SeekFrom::Start(*offset))?;
+            let mut remaining = *length;
+            while remaining > 0 {
+                let n = std::cmp::min(remaining, buffer.len() as u64) as usize;
+                in_filedescriptor.read_exact(&mut buffer[..n])?;
+                self.out_tar.write_all(&buffer[..n])?;
+                #[cfg(feature = "sha512")]
+                if let Some(hasher) = hasher.as_mut() {
+                    hasher.update(&buffer[..n]);
+                }
+                remaining -= n as u64;
+            }
+        }
+        // the map of the pax format is already padded
+        let padding = ((512 - (stored % 512)) % 512) as usize;
+        self.out_tar.write_all(&[0u8; 512][..padding])?;
+        self.stats.content_bytes += stored;
+        self.stats.padding_bytes += padding as u64;
+        self.stats.pax_bytes += map_bytes;
+        #[cfg(feature = "sha512")]
+        if let (Some((out_hash, options)), Some(mut hasher)) = (out_hash.as_mut(), hasher) {
+            // the file may end with a hole, which is marked by an empty region at its end
+            while hashed < *size {
+                let n = std::cmp::min(size - hashed, buffer.len() as u64) as usize;
+                hasher.update(&[0u8; 64 * 1024][..n]);
+                hashed += n as u64;
+            }
+            write_hash_line(out_hash, *options, hasher, tarname)?;
+        }
+        Ok(())
+    }
+
+    /// writes a GNU 'S' header: the first four regions are stored in the header, the others in
+    /// extension blocks of 21 regions each
+    fn _write_old_gnu_sparse_header(
+        &mut self,
+        tarname: &[u8],
+        map: &[(u64, u64)],
+        size: u64,
+        stored: u64,
+    ) -> Result<(), std::io::Error> {
+        let mode = b"0000644\x00";
+        let encoded = self.format.encode(tarname, stored)?;
+        if let Some(extended) = encoded.extended {
+            self._write_extended_header(&extended, mode)?;
+        }
+        let mut header = self._header(encoded.name, encoded.prefix, mode, &encoded.size, b'S');
+        let (first, rest) = map.split_at(std::cmp::min(map.len(), 4));
+        for (i, (offset, length)) in first.iter().enumerate() {
+            header[386 + i * 24..398 + i * 24].clone_from_slice(&gnu_number(*offset));
+            header[398 + i * 24..410 + i * 24].clone_from_slice(&gnu_number(*length));
+        }
+        header[482] = !rest.is_empty() as u8; // isextended
+        header[483..495].clone_from_slice(&gnu_number(size)); // real size of the file
+        self._write_header_block(header)?;
+        let blocks: Vec<&[(u64, u64)]> = rest.chunks(21).collect();
+        for (i, regions) in blocks.iter().enumerate() {
+            let mut block = [0u8; 512];
+            for (j, (offset, length)) in regions.iter().enumerate() {
+                block[j * 24..j * 24 + 12].clone_from_slice(&gnu_number(*offset));
+                block[j * 24 + 12..j * 24 + 24].clone_from_slice(&gnu_number(*length));
+            }
+            block[504] = (i + 1 < blocks.len()) as u8; // isextended
+            self.out_tar.write_all(&block)?;
+            self.stats.header_bytes += 512;
+        }
+        Ok(())
+    }
+
+    /// writes the extended header, the header and the map of a GNU sparse 1.0 entry, returns the
+    /// length of the map
+    fn _write_pax_sparse_header(
+        &mut self,
+        tarname: &[u8],
+        map: &[(u64, u64)],
+        size: u64,
+        stored: u64,
+    ) -> Result<u64, std::io::Error> {
+        let mode = b"0000644\x00";
+        let mut sparse_map = format!("{}\n", map.len());
+        for (offset, length) in map {
+            sparse_map.push_str(&format!("{}\n{}\n", offset, length));
+        }
+        let mut sparse_map = sparse_map.into_bytes();
+        sparse_map.resize(sparse_map.len().div_ceil(512) * 512, 0);
+        let entry_size = sparse_map.len() as u64 + stored;
+
+        // the records are always written in the same order
+        let mut records = Vec::new();
+        records.extend(pax_record("GNU.sparse.major", b"1"));
+        records.extend(pax_record("GNU.sparse.minor", b"0"));
+        records.extend(pax_record("GNU.sparse.name", tarname));
+        records.extend(pax_record(
+            "GNU.sparse.realsize",
+            size.to_string().as_bytes(),
+        ));
+        let size_field = octal_size(entry_size).unwrap_or_else(|| {
+            records.extend(pax_record("size", entry_size.to_string().as_bytes()));
+            octal_size(0).unwrap()
+        });
+        records.append(&mut self.pending_records);
+        let extended = ExtendedHeader {
+            headername: pax_header_name(tarname),
+            typeflag: b'x',
+            content: records,
+        };
+        self._write_extended_header(&extended, mode)?;
+
+        // the name of the header itself is "<dir>/GNUSparseFile.0/<basename>", like GNU tar does
+        let (dir, basename) = match tarname.iter().rposition(|b| *b == b'/') {
+            Some(i) => (&tarname[..i + 1], &tarname[i + 1..]),
+            None => (&b""[..], tarname),
+        };
+        let mut name = [dir, b"GNUSparseFile.0/", basename].concat();
+        name.truncate(100);
+        self._write_header(&name, b"", mode, &size_field, b'0')?;
+        self.out_tar.write_all(&sparse_map)?;
+        Ok(sparse_map.len() as u64)
+    }
+
+    pub fn tar_end_marker(&mut self) -> Result<(), std::io::Error> {
+        // tar archives ends with 2 blocks of zeros, each 512 bytes
+        // actually, gnu tar creates 10 empty blocks but 2 blocks are strictly spoken already sufficient
+        self.tar_end_marker_padded(10, 1)
+    }
+
+    /// writes eof_blocks blocks of zeros (at least 2) and pads the archive with further zero
+    /// blocks to a multiple of blocking_factor blocks, e.g. GNU tar uses 2 and 20
+    pub fn tar_end_marker_padded(
+        &mut self,
+        eof_blocks: u64,
+        blocking_factor: u64,
+    ) -> Result<(), std::io::Error> {
+        if eof_blocks < 2 || blocking_factor == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the end marker needs at least 2 blocks and the blocking factor must not be zero",
+            ));
+        }
+        let blocks = self.stats.total_bytes() / 512 + eof_blocks;
+        let padding_blocks = (blocking_factor - blocks % blocking_factor) % blocking_factor;
+        for _ in 0..eof_blocks + padding_blocks {
+            self.out_tar.write_all(&[0u8; 512])?;
+        }
+        self.stats.end_marker_bytes += (eof_blocks + padding_blocks) * 512;
+        Ok(())
+    }
+
+    /// returns the underlying writer, usually after the end marker was written
+    pub fn into_inner(self) -> W {
+        self.out_tar
+    }
+}
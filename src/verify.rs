@@ -0,0 +1,395 @@
+#[cfg(feature = "age")]
+use crate::read_passphrase;
+use crate::{
+    digest_of_file, escape_unsafe_components, excluded_globs, parse_regex, parse_size,
+    report_stdout, special_typeflag, validate_main_dir_name, InputFile,
+};
+use deterministic_tar::archive::NameEncoding;
+use deterministic_tar::glob::Glob;
+use deterministic_tar::hash::{FileHasher, HashAlgorithm};
+use deterministic_tar::reader::{invalid_data, ParseMode, ReaderOptions, TarReader};
+use deterministic_tar::walk::{
+    DanglingSymlinkPolicy, DirWalkItem, DirWalkIterator, DirWalkOptions, DirWalkType,
+    SpecialFilePolicy, SymlinkPolicy, UnsafeNamePolicy,
+};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "deterministic-tar verify",
+    about = "Verify the structure of an existing tar archive with constant memory. Each verified entry is printed, so an interrupted verification can be continued later with --start-at. If a directory is given, it is walked like when the archive was created and the name, size and content of each entry are compared with it, all mismatches are printed and the exit code is 1 if there was any."
+)]
+pub struct VerifyOpt {
+    /// tar archive which should be verified
+    #[structopt(parse(from_os_str))]
+    archive: PathBuf,
+
+    /// optionally, the directory from which the archive was created
+    #[structopt(parse(from_os_str))]
+    dir: Option<PathBuf>,
+
+    /// with a directory: the name of the main directory in the archive, if it was renamed with --main-dir-name
+    #[structopt(short, long)]
+    main_dir_name: Option<String>,
+
+    /// with a directory: the --ignored-names of the archive
+    #[structopt(short, long, parse(try_from_str = parse_regex))]
+    ignored_names: Vec<Regex>,
+
+    /// with a directory: the --ignored-paths of the archive
+    #[structopt(long, parse(try_from_str = parse_regex))]
+    ignored_paths: Vec<Regex>,
+
+    /// with a directory: the --exclude of the archive
+    #[structopt(long)]
+    exclude: Vec<Glob>,
+
+    /// with a directory: the --exclude-from of the archive
+    #[structopt(long, parse(from_os_str))]
+    exclude_from: Vec<PathBuf>,
+
+    /// with a directory: the --respect-gitignore of the archive
+    #[structopt(long)]
+    respect_gitignore: bool,
+
+    /// with a directory: the --exclude-link-target of the archive
+    #[structopt(long, parse(try_from_str = parse_regex))]
+    exclude_link_target: Vec<Regex>,
+
+    /// with a directory: the --include of the archive
+    #[structopt(long, parse(try_from_str = parse_regex))]
+    include: Vec<Regex>,
+
+    /// with a directory: the --symlink-policy of the archive
+    #[structopt(long, default_value = "follow")]
+    symlink_policy: SymlinkPolicy,
+
+    /// with a directory: the --dangling-symlinks of the archive
+    #[structopt(long, default_value = "error")]
+    dangling_symlinks: DanglingSymlinkPolicy,
+
+    /// with a directory: the --special-files of the archive
+    #[structopt(long, default_value = "error")]
+    special_files: SpecialFilePolicy,
+
+    /// with a directory: the --unsafe-names of the archive
+    #[structopt(long, default_value = "keep")]
+    unsafe_names: UnsafeNamePolicy,
+
+    /// with a directory: whether the archive was created with --empty-dirs-ignored
+    #[structopt(short, long)]
+    empty_dirs_ignored: bool,
+
+    /// with a directory: whether the archive was created with --dot-files-excluded
+    #[structopt(short, long)]
+    dot_files_excluded: bool,
+
+    /// with a directory: whether the archive was created with --one-file-system
+    #[structopt(long)]
+    one_file_system: bool,
+
+    /// with a directory: whether the archive was created with --exclude-caches
+    #[structopt(long)]
+    exclude_caches: bool,
+
+    /// with a directory: whether the archive was created with --keep-cachedir-tag
+    #[structopt(long)]
+    keep_cachedir_tag: bool,
+
+    /// with a directory: the --exclude-larger-than of the archive
+    #[structopt(long, parse(try_from_str = parse_size))]
+    exclude_larger_than: Option<u64>,
+
+    /// with a directory: the --exclude-smaller-than of the archive
+    #[structopt(long, parse(try_from_str = parse_size))]
+    exclude_smaller_than: Option<u64>,
+
+    /// with a directory: the --max-depth of the archive
+    #[structopt(long)]
+    max_depth: Option<usize>,
+
+    /// with a directory: the --min-depth of the archive
+    #[structopt(long)]
+    min_depth: Option<usize>,
+
+    /// with a directory: the --name-encoding of the archive
+    #[structopt(long, default_value = "utf8")]
+    name_encoding: NameEncoding,
+
+    /// skip all entries before the entry with this name without verifying their content
+    #[structopt(long)]
+    start_at: Option<String>,
+
+    /// stop after verifying this many entries and print the name of the next entry, which can be passed to --start-at
+    #[structopt(long)]
+    count: Option<u64>,
+
+    /// accept archives which are malformed but still readable (wrong signed checksums, unknown magic, non-zero padding, missing end-of-archive marker)
+    #[structopt(long)]
+    lenient: bool,
+
+    /// decrypt an archive written with --encrypt-passphrase with the passphrase in the first line of this file
+    #[structopt(long, parse(from_os_str))]
+    decrypt_passphrase: Option<PathBuf>,
+}
+
+/// returns the entries which an archive of the directory contains, by their name in the archive
+fn expected_entries(opt: &VerifyOpt, dir: &std::path::Path) -> BTreeMap<Vec<u8>, DirWalkItem> {
+    let input = dir
+        .canonicalize()
+        .unwrap_or_else(|_| panic!("could not open directory {:?}", dir));
+    let parent = input.parent().expect("input directory has no parent!");
+    let main_dir_name = validate_main_dir_name(&opt.main_dir_name)
+        .unwrap_or_else(|| input.file_name().unwrap().into());
+    let walk_options = DirWalkOptions {
+        empty_dirs_ignored: opt.empty_dirs_ignored,
+        symlinks_should_abort: false,
+        symlink_policy: opt.symlink_policy,
+        dangling_symlinks: opt.dangling_symlinks,
+        special_files: opt.special_files,
+        unsafe_names: opt.unsafe_names,
+        dot_files_excluded: opt.dot_files_excluded,
+        one_file_system: opt.one_file_system,
+        exclude_caches: opt.exclude_caches,
+        keep_cachedir_tag: opt.keep_cachedir_tag,
+        exclude_larger_than: opt.exclude_larger_than,
+        exclude_smaller_than: opt.exclude_smaller_than,
+        max_depth: opt.max_depth,
+        min_depth: opt.min_depth,
+        ignored_filenames: opt.ignored_names.clone(),
+        ignored_paths: opt.ignored_paths.clone(),
+        excluded_globs: excluded_globs(&opt.exclude, &opt.exclude_from),
+        respect_gitignore: opt.respect_gitignore,
+        excluded_link_targets: opt.exclude_link_target.clone(),
+        included_paths: opt.include.clone(),
+        first_names: Vec::new(),
+        paranoid: false,
+        max_entries: None,
+        max_entries_per_dir: None,
+    };
+    let remaining = vec![input.clone()];
+    let mut entries = BTreeMap::new();
+    for d in DirWalkIterator::new(parent, &remaining, &walk_options) {
+        if let DirWalkType::Excluded(_) = d.typ {
+            continue;
+        }
+        let mut tarname = main_dir_name.clone();
+        for p in d.relpath.iter().skip(1) {
+            tarname.push(p);
+        }
+        if opt.unsafe_names == UnsafeNamePolicy::Escape {
+            tarname = escape_unsafe_components(&tarname);
+        }
+        if let DirWalkType::Directory | DirWalkType::SymlinkToDirectory = d.typ {
+            tarname.push("");
+        }
+        let name = opt
+            .name_encoding
+            .encode(tarname.as_os_str())
+            .unwrap_or_else(|e| panic!("{}", e));
+        entries.insert(name, d);
+    }
+    entries
+}
+
+/// compares an entry of the archive with the same entry of the directory and returns the reason
+/// if they differ, `digest` is the SHA512 of the content of regular files and `linkname` the
+/// target of symlinks
+fn compare_entry(
+    typeflag: u8,
+    size: u64,
+    linkname: &[u8],
+    digest: Option<&str>,
+    expected: Option<DirWalkItem>,
+    name_encoding: NameEncoding,
+) -> Option<String> {
+    let Some(expected) = expected else {
+        return Some("not in the directory".to_string());
+    };
+    let is_dir = matches!(
+        expected.typ,
+        DirWalkType::Directory | DirWalkType::SymlinkToDirectory
+    );
+    match typeflag {
+        b'5' if is_dir => None,
+        b'5' => Some("not a directory in the directory".to_string()),
+        _ if is_dir => Some("a directory in the directory".to_string()),
+        b'2' => match &expected.typ {
+            DirWalkType::DanglingSymlink(target)
+                if name_encoding.encode(target.as_os_str()).ok().as_deref() == Some(linkname) =>
+            {
+                None
+            }
+            DirWalkType::DanglingSymlink(_) => Some("symlink target differs".to_string()),
+            _ => Some("not a dangling symlink in the directory".to_string()),
+        },
+        _ if matches!(expected.typ, DirWalkType::DanglingSymlink(_)) => {
+            Some("a dangling symlink in the directory".to_string())
+        }
+        b'3' | b'4' | b'6' => match expected.typ {
+            DirWalkType::Special(special) if special_typeflag(special).0 == typeflag => None,
+            _ => Some("not the same kind of special file in the directory".to_string()),
+        },
+        _ if matches!(expected.typ, DirWalkType::Special(_)) => {
+            Some("a special file in the directory".to_string())
+        }
+        _ if expected.size != Some(size) && digest.is_some() => Some(format!(
+            "size differs: {} in the archive, {} in the directory",
+            size,
+            expected.size.unwrap_or(0)
+        )),
+        _ => match digest {
+            Some(digest) => match digest_of_file(&expected.abspath, HashAlgorithm::Sha512) {
+                Ok(actual) if actual == digest => None,
+                Ok(_) => Some("content differs".to_string()),
+                Err(e) => Some(e.to_string()),
+            },
+            // hardlink entries of --dedup-content and sparse entries only have their name compared
+            None => None,
+        },
+    }
+}
+
+pub fn verify_main(opt: VerifyOpt) {
+    if opt.dir.is_some() && (opt.start_at.is_some() || opt.count.is_some()) {
+        panic!("a directory cannot be compared with --start-at or --count");
+    }
+    let mut expected = opt.dir.as_ref().map(|dir| expected_entries(&opt, dir));
+    let mut mismatches = 0u64;
+    let in_tar = BufReader::new(
+        File::open(&opt.archive)
+            .unwrap_or_else(|_| panic!("could not open file {:?}", &opt.archive)),
+    );
+    let in_tar = match &opt.decrypt_passphrase {
+        #[cfg(feature = "age")]
+        Some(path) => InputFile::decrypt_passphrase(in_tar, read_passphrase(path)),
+        #[cfg(not(feature = "age"))]
+        Some(_) => panic!("--decrypt-passphrase is not available, it was disabled at compile time"),
+        None => InputFile::Plain(in_tar),
+    };
+    let reader_options = ReaderOptions {
+        mode: if opt.lenient {
+            ParseMode::Lenient
+        } else {
+            ParseMode::Strict
+        },
+        ..ReaderOptions::default()
+    };
+    let mut reader = TarReader::with_options(in_tar, reader_options);
+    let mut stdout = report_stdout();
+    let mut started = opt.start_at.is_none();
+    let mut verified = 0u64;
+    loop {
+        let entry = match reader.next_entry() {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(e) => {
+                writeln!(stdout, "FAILED\t{}", e).unwrap();
+                std::process::exit(1);
+            }
+        };
+        let name = String::from_utf8_lossy(&entry.name).to_string();
+        if !started {
+            if Some(&name) != opt.start_at.as_ref() {
+                continue;
+            }
+            started = true;
+        }
+        if opt.count == Some(verified) {
+            writeln!(stdout, "NEXT\t{}", name).unwrap();
+            return;
+        }
+        // the digest of regular files is only needed to compare them with the directory
+        let result = match entry.typeflag {
+            b'0' | b'\x00' if expected.is_some() => {
+                let mut hasher = FileHasher::new(HashAlgorithm::Sha512);
+                reader
+                    .read_content(&mut hasher)
+                    .map(|_| Some(hasher.hex_digest()))
+            }
+            b'0' | b'\x00' | b'S' => reader.read_content(&mut std::io::sink()).map(|_| None),
+            b'5' if entry.size == 0 => Ok(None),
+            b'5' => Err(invalid_data("directory with non-zero size".to_string())),
+            b'1' if entry.size == 0 => Ok(None),
+            b'1' => Err(invalid_data("hardlink with non-zero size".to_string())),
+            b'2' if entry.size == 0 => Ok(None),
+            b'2' => Err(invalid_data("symlink with non-zero size".to_string())),
+            b'3' | b'4' | b'6' if entry.size == 0 => Ok(None),
+            b'3' | b'4' | b'6' => Err(invalid_data("special file with non-zero size".to_string())),
+            t => Err(invalid_data(format!(
+                "unsupported entry type {:?}",
+                t as char
+            ))),
+        };
+        let mismatch = match (&result, expected.as_mut()) {
+            (Ok(digest), Some(expected)) => compare_entry(
+                entry.typeflag,
+                entry.size,
+                &entry.linkname,
+                digest.as_deref(),
+                expected.remove(&entry.name),
+                opt.name_encoding,
+            ),
+            _ => None,
+        };
+        match result {
+            Ok(_) if mismatch.is_some() => {
+                writeln!(stdout, "FAILED\t{}\t{}", name, mismatch.unwrap()).unwrap();
+                mismatches += 1;
+            }
+            Ok(_) => writeln!(stdout, "OK\t{}", name).unwrap(),
+            Err(e) => {
+                writeln!(
+                    stdout,
+                    "FAILED\t{}\tentry at offset {}: {}",
+                    name, entry.offset, e
+                )
+                .unwrap();
+                std::process::exit(1);
+            }
+        }
+        verified += 1;
+    }
+    if !started {
+        writeln!(
+            stdout,
+            "FAILED\tentry {:?} not found",
+            opt.start_at.unwrap()
+        )
+        .unwrap();
+        std::process::exit(1);
+    }
+    // everything after the end marker must be zero as well
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = reader.in_tar.read(&mut buffer).unwrap();
+        if n == 0 {
+            break;
+        }
+        if buffer[..n].iter().any(|b| *b != 0) {
+            writeln!(stdout, "FAILED\tdata after end-of-archive marker").unwrap();
+            std::process::exit(1);
+        }
+    }
+    if let Some(expected) = expected {
+        for name in expected.keys() {
+            let name = String::from_utf8_lossy(name);
+            writeln!(stdout, "FAILED\t{}\tnot in the archive", name).unwrap();
+            mismatches += 1;
+        }
+        writeln!(
+            stdout,
+            "{} entries compared, {} mismatches",
+            verified, mismatches
+        )
+        .unwrap();
+        if mismatches > 0 {
+            std::process::exit(1);
+        }
+    }
+}
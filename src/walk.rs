@@ -0,0 +1,916 @@
+#[cfg(feature = "regex")]
+use crate::glob::{repository_dirs, Gitignore, Glob};
+#[cfg(feature = "regex")]
+use regex::Regex;
+use std::ffi::{OsStr, OsString};
+use std::io::Read;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Why an entry was left out of the archive.
+#[derive(Clone, Debug)]
+pub enum ExclusionReason {
+    #[cfg(feature = "regex")]
+    IgnoredName(Regex),
+    #[cfg(feature = "regex")]
+    IgnoredPath(Regex),
+    #[cfg(feature = "regex")]
+    Glob(Glob),
+    #[cfg(feature = "regex")]
+    Gitignore(Glob),
+    #[cfg(feature = "regex")]
+    GitDirectory,
+    #[cfg(feature = "regex")]
+    LinkTarget(Regex),
+    #[cfg(feature = "regex")]
+    NotIncluded,
+    MinDepth(usize),
+    DotFile,
+    EmptyDirectory,
+    CacheDirectory,
+    LargerThan(u64),
+    SmallerThan(u64),
+    SymlinkOutside,
+    DanglingSymlink,
+    SpecialFile,
+    UnsafeName,
+}
+
+impl std::fmt::Display for ExclusionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            #[cfg(feature = "regex")]
+            ExclusionReason::IgnoredName(regex) => write!(f, "regex:{}", regex),
+            #[cfg(feature = "regex")]
+            ExclusionReason::IgnoredPath(regex) => write!(f, "path-regex:{}", regex),
+            #[cfg(feature = "regex")]
+            ExclusionReason::Glob(glob) => write!(f, "glob:{}", glob),
+            #[cfg(feature = "regex")]
+            ExclusionReason::Gitignore(glob) => write!(f, "gitignore:{}", glob),
+            #[cfg(feature = "regex")]
+            ExclusionReason::GitDirectory => write!(f, "git-dir"),
+            #[cfg(feature = "regex")]
+            ExclusionReason::LinkTarget(regex) => write!(f, "link-target:{}", regex),
+            #[cfg(feature = "regex")]
+            ExclusionReason::NotIncluded => write!(f, "not-included"),
+            ExclusionReason::MinDepth(depth) => write!(f, "min-depth:{}", depth),
+            ExclusionReason::DotFile => write!(f, "dotfile"),
+            ExclusionReason::EmptyDirectory => write!(f, "empty-dir"),
+            ExclusionReason::CacheDirectory => write!(f, "cache-dir"),
+            ExclusionReason::LargerThan(size) => write!(f, "larger-than:{}", size),
+            ExclusionReason::SmallerThan(size) => write!(f, "smaller-than:{}", size),
+            ExclusionReason::SymlinkOutside => write!(f, "symlink-outside"),
+            ExclusionReason::DanglingSymlink => write!(f, "dangling-symlink"),
+            ExclusionReason::SpecialFile => write!(f, "special-file"),
+            ExclusionReason::UnsafeName => write!(f, "unsafe-name"),
+        }
+    }
+}
+
+/// What happens to symlinks whose resolved target is outside of the walked input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// follow all symlinks, wherever they point
+    #[default]
+    Follow,
+    /// panic if a symlink points outside of the input
+    InsideOnly,
+    /// exclude symlinks which point outside of the input
+    SkipOutside,
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<SymlinkPolicy, String> {
+        match s {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "inside-only" => Ok(SymlinkPolicy::InsideOnly),
+            "skip-outside" => Ok(SymlinkPolicy::SkipOutside),
+            _ => Err(format!(
+                "unknown symlink policy {:?}, expected follow, inside-only or skip-outside",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens to symlinks which cannot be resolved, because their target does not exist or
+/// they are part of a loop.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DanglingSymlinkPolicy {
+    /// panic when such a symlink is found
+    #[default]
+    Error,
+    /// exclude such symlinks
+    Skip,
+    /// keep them as symlink entries with the target as it is stored in the link
+    StoreAsSymlink,
+}
+
+impl std::str::FromStr for DanglingSymlinkPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<DanglingSymlinkPolicy, String> {
+        match s {
+            "error" => Ok(DanglingSymlinkPolicy::Error),
+            "skip" => Ok(DanglingSymlinkPolicy::Skip),
+            "store-as-symlink" => Ok(DanglingSymlinkPolicy::StoreAsSymlink),
+            _ => Err(format!(
+                "unknown dangling symlink policy {:?}, expected error, skip or store-as-symlink",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens to fifos, sockets and device nodes, or symlinks to them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpecialFilePolicy {
+    /// panic when such a file is found
+    #[default]
+    Error,
+    /// exclude such files
+    Skip,
+    /// keep fifos and devices as [`DirWalkType::Special`] entries, sockets are excluded because
+    /// archives cannot store them
+    Store,
+}
+
+impl std::str::FromStr for SpecialFilePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<SpecialFilePolicy, String> {
+        match s {
+            "error" => Ok(SpecialFilePolicy::Error),
+            "skip" => Ok(SpecialFilePolicy::Skip),
+            "store" => Ok(SpecialFilePolicy::Store),
+            _ => Err(format!(
+                "unknown special file policy {:?}, expected error, skip or store",
+                s
+            )),
+        }
+    }
+}
+
+/// A file which is neither a regular file, a directory nor a symlink.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpecialFile {
+    Fifo,
+    Socket,
+    /// with the major and minor device number
+    CharDevice(u32, u32),
+    BlockDevice(u32, u32),
+}
+
+impl SpecialFile {
+    /// returns the kind of a special file, None for regular files, directories and symlinks
+    pub fn of(metadata: &std::fs::Metadata) -> Option<SpecialFile> {
+        let file_type = metadata.file_type();
+        // the encoding of dev_t by glibc and musl
+        let rdev = metadata.rdev();
+        let major = (((rdev >> 32) & 0xfffff000) | ((rdev >> 8) & 0xfff)) as u32;
+        let minor = (((rdev >> 12) & 0xffffff00) | (rdev & 0xff)) as u32;
+        if file_type.is_fifo() {
+            Some(SpecialFile::Fifo)
+        } else if file_type.is_socket() {
+            Some(SpecialFile::Socket)
+        } else if file_type.is_char_device() {
+            Some(SpecialFile::CharDevice(major, minor))
+        } else if file_type.is_block_device() {
+            Some(SpecialFile::BlockDevice(major, minor))
+        } else {
+            None
+        }
+    }
+}
+
+/// What happens to entries with names which could confuse tools reading the archive or the
+/// hash list, see [`unsafe_name_reason`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnsafeNamePolicy {
+    /// keep the names as they are
+    #[default]
+    Keep,
+    /// panic when such a name is listed
+    Error,
+    /// exclude such entries, directories with everything below them
+    Skip,
+    /// keep the entries, but percent-escape their names with [`escape_unsafe_name`]. This is
+    /// done by the caller, the walker returns the original paths.
+    Escape,
+}
+
+impl std::str::FromStr for UnsafeNamePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<UnsafeNamePolicy, String> {
+        match s {
+            "keep" => Ok(UnsafeNamePolicy::Keep),
+            "error" => Ok(UnsafeNamePolicy::Error),
+            "skip" => Ok(UnsafeNamePolicy::Skip),
+            "escape" => Ok(UnsafeNamePolicy::Escape),
+            _ => Err(format!(
+                "unknown unsafe name policy {:?}, expected keep, error, skip or escape",
+                s
+            )),
+        }
+    }
+}
+
+/// the start of a CACHEDIR.TAG file, see <https://bford.info/cachedir/>
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Returns true if the directory is marked as cache by a CACHEDIR.TAG file which starts with the
+/// signature, like the caches of cargo, ccache or pip.
+pub fn is_cache_dir(dir: &Path) -> bool {
+    let mut signature = [0u8; CACHEDIR_TAG_SIGNATURE.len()];
+    std::fs::File::open(dir.join("CACHEDIR.TAG"))
+        .and_then(|mut file| file.read_exact(&mut signature))
+        .is_ok_and(|_| signature == CACHEDIR_TAG_SIGNATURE)
+}
+
+/// Returns why a file name (a single component) is unsafe: it is "..", which extractors could
+/// resolve outside of the target directory, or it contains a newline or another control
+/// character, which breaks line based lists like the hash manifest and terminal output.
+pub fn unsafe_name_reason(name: &OsStr) -> Option<&'static str> {
+    let bytes = name.as_bytes();
+    if bytes == b".." {
+        Some("is \"..\"")
+    } else if bytes.contains(&b'\n') {
+        Some("contains a newline")
+    } else if bytes.iter().any(|b| b.is_ascii_control()) {
+        Some("contains a control character")
+    } else {
+        None
+    }
+}
+
+/// Percent-escapes a file name for [`UnsafeNamePolicy::Escape`]: control characters and "%" are
+/// written as "%XX" and ".." as "%2E%2E". Names which are not unsafe are returned unchanged.
+pub fn escape_unsafe_name(name: &OsStr) -> OsString {
+    if unsafe_name_reason(name).is_none() {
+        return name.to_os_string();
+    }
+    if name.as_bytes() == b".." {
+        return OsString::from("%2E%2E");
+    }
+    let mut escaped = Vec::new();
+    for &b in name.as_bytes() {
+        if b.is_ascii_control() || b == b'%' {
+            escaped.extend_from_slice(format!("%{:02X}", b).as_bytes());
+        } else {
+            escaped.push(b);
+        }
+    }
+    OsString::from_vec(escaped)
+}
+
+/// returns the device and inode of a file with more than one name, see [`DirWalkItem::inode`]
+fn inode_of(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    Some((metadata.dev(), metadata.ino())).filter(|_| metadata.nlink() > 1)
+}
+
+#[derive(Clone, Debug)]
+pub enum DirWalkType {
+    Directory,
+    File,
+    SymlinkToFile(PathBuf),
+    SymlinkToDirectory,
+    /// a symlink which cannot be resolved, with its target, see
+    /// [`DanglingSymlinkPolicy::StoreAsSymlink`]
+    DanglingSymlink(PathBuf),
+    /// a fifo or device, or a symlink to one, see [`SpecialFilePolicy::Store`]
+    Special(SpecialFile),
+    Excluded(ExclusionReason),
+}
+
+#[derive(Clone, Debug)]
+pub struct DirWalkItem {
+    pub abspath: PathBuf,
+    pub relpath: PathBuf,
+    pub typ: DirWalkType,
+    pub size: Option<u64>,
+    /// true for directories without any included entries and for symlinks to directories, whose content is not walked,
+    /// false for mount points with one_file_system and directories at max_depth, whose content is not read
+    pub empty: bool,
+    /// device and inode of files (and of the targets of symlinks to files) which have more than one
+    /// name, so their content may be read more than once
+    pub inode: Option<(u64, u64)>,
+}
+
+/// Filters and policies applied while walking the input directory.
+#[derive(Clone, Debug, Default)]
+pub struct DirWalkOptions {
+    /// exclude directories which contain no or only excluded entries
+    pub empty_dirs_ignored: bool,
+    /// panic instead of following symlinks
+    pub symlinks_should_abort: bool,
+    /// whether symlinks may point outside of the paths which are walked
+    pub symlink_policy: SymlinkPolicy,
+    /// what happens to symlinks which cannot be resolved
+    pub dangling_symlinks: DanglingSymlinkPolicy,
+    /// what happens to fifos, sockets and devices
+    pub special_files: SpecialFilePolicy,
+    /// what happens to entries with unsafe names, the paths which are walked are not checked
+    pub unsafe_names: UnsafeNamePolicy,
+    /// exclude files and directories where the basename starts with a dot
+    pub dot_files_excluded: bool,
+    /// don't walk the content of directories on another device than the walked path which
+    /// contains them, e.g. /proc when / is walked. Like by GNU tar, the directories themselves
+    /// are included without content.
+    pub one_file_system: bool,
+    /// exclude directories which are marked as cache by a CACHEDIR.TAG file, see [`is_cache_dir`]
+    pub exclude_caches: bool,
+    /// with exclude_caches: keep the cache directories and their CACHEDIR.TAG and only exclude
+    /// their other entries
+    pub keep_cachedir_tag: bool,
+    /// exclude files (and symlinks to files) with more bytes than this
+    pub exclude_larger_than: Option<u64>,
+    /// exclude files (and symlinks to files) with less bytes than this
+    pub exclude_smaller_than: Option<u64>,
+    /// don't walk the content of directories which are this many levels below the walked path,
+    /// e.g. 1 only includes its direct entries. The directories themselves are included without
+    /// content, the deeper entries are not found at all.
+    pub max_depth: Option<usize>,
+    /// exclude files, symlinks and special files which are less than this many levels below the
+    /// walked path. Directories are always walked, like with included_paths.
+    pub min_depth: Option<usize>,
+    /// exclude files and directories where the basename matches any of these regular expressions
+    #[cfg(feature = "regex")]
+    pub ignored_filenames: Vec<Regex>,
+    /// exclude files and directories where the path below the walked path (e.g. "target/debug")
+    /// matches any of these regular expressions
+    #[cfg(feature = "regex")]
+    pub ignored_paths: Vec<Regex>,
+    /// exclude files and directories which match any of these gitignore-style globs
+    #[cfg(feature = "regex")]
+    pub excluded_globs: Vec<Glob>,
+    /// exclude ".git" and the entries which git ignores because of the .gitignore files (of the
+    /// walked paths and their parents up to the root of the repository) and .git/info/exclude
+    #[cfg(feature = "regex")]
+    pub respect_gitignore: bool,
+    /// exclude symlinks whose target (as stored in the link, not resolved) matches any of these
+    /// regular expressions
+    #[cfg(feature = "regex")]
+    pub excluded_link_targets: Vec<Regex>,
+    /// if not empty, only files and symlinks whose path below the walked path (e.g. "src/main.rs")
+    /// matches any of these regular expressions are included. Directories are always walked.
+    #[cfg(feature = "regex")]
+    pub included_paths: Vec<Regex>,
+    /// basenames which come before all other entries of their directory, in this order, e.g.
+    /// "debian-binary" which has to be the first member of a .deb package
+    pub first_names: Vec<String>,
+    /// list each directory again after all of its content was walked and panic if entries
+    /// appeared or disappeared in the meantime
+    pub paranoid: bool,
+    /// panic as soon as more entries than this were found, entries excluded by name are not
+    /// counted
+    pub max_entries: Option<usize>,
+    /// panic as soon as a directory with more entries than this (not counting the ones excluded
+    /// by name) is listed
+    pub max_entries_per_dir: Option<usize>,
+}
+
+/// Walks a directory tree in alphabetical order (except for [`DirWalkOptions::first_names`]),
+/// yielding directories before their content.
+#[derive(Clone, Debug)]
+pub struct DirWalkIterator {
+    options: DirWalkOptions,
+    // paths which still have to be visited, together with the reason why they are excluded (if they are)
+    // and the index of the glob which excludes the entries of a directory (see reincluded_below)
+    remaining: Vec<(PathBuf, Option<ExclusionReason>, Option<usize>)>,
+    // the paths which are walked, symlinks must point below them with SymlinkPolicy::InsideOnly
+    roots: Vec<PathBuf>,
+    basedir: PathBuf,
+    // with the paranoid option: directories whose content is still walked, together with the
+    // length of remaining before their entries were added and their sorted entries
+    relist: Vec<(usize, PathBuf, Vec<PathBuf>)>,
+    // number of entries which were found and not excluded by name, for max_entries
+    found: usize,
+    // with respect_gitignore: the rules of the directory which is listed and of its parents,
+    // outermost first
+    #[cfg(feature = "regex")]
+    gitignores: Vec<Gitignore>,
+}
+
+impl DirWalkIterator {
+    pub fn new(basedir: &Path, remaining: &[PathBuf], options: &DirWalkOptions) -> DirWalkIterator {
+        DirWalkIterator {
+            options: options.clone(),
+            remaining: remaining.iter().map(|r| (r.clone(), None, None)).collect(),
+            roots: remaining.to_vec(),
+            basedir: basedir.to_path_buf(),
+            relist: Vec::new(),
+            found: remaining.len(),
+            #[cfg(feature = "regex")]
+            gitignores: Vec::new(),
+        }
+    }
+
+    /// returns the sorted paths of all entries of a directory
+    fn list_dir(r: &Path) -> Vec<PathBuf> {
+        let mut entries: Vec<PathBuf> = r
+            .read_dir()
+            .unwrap_or_else(|_| panic!("can't read directory {:?}", r))
+            .map(|i| i.expect("intermittent i/o error").path())
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    /// counts the included entries of a directory and panics if a limit is exceeded
+    fn check_limits(&mut self, dir: &Path, included: usize) {
+        if let Some(max) = self.options.max_entries_per_dir {
+            if included > max {
+                panic!(
+                    "directory {:?} has {} entries, more than --max-entries-per-dir {}",
+                    dir, included, max
+                );
+            }
+        }
+        self.found += included;
+        if let Some(max) = self.options.max_entries {
+            if self.found > max {
+                panic!(
+                    "found more than --max-entries {} entries, the last directory was {:?}",
+                    max, dir
+                );
+            }
+        }
+    }
+
+    /// lists the directories again whose content was walked completely
+    fn check_relist(&mut self) {
+        while let Some((len, dir, entries)) = self.relist.last() {
+            if self.remaining.len() > *len {
+                break;
+            }
+            if Self::list_dir(dir) != *entries {
+                panic!(
+                    "--paranoid: the entries of directory {:?} changed while it was archived",
+                    dir
+                );
+            }
+            self.relist.pop();
+        }
+    }
+
+    /// returns true if the resolved path is one of the walked paths or below them
+    fn is_inside(&self, resolved: &Path) -> bool {
+        self.roots.iter().any(|root| resolved.starts_with(root))
+    }
+
+    /// with one_file_system: returns true if the directory is on another device than the walked
+    /// path which contains it, i.e. if it is a mount point
+    fn on_other_device(&self, dir: &Path, metadata: &std::fs::Metadata) -> bool {
+        let root = self
+            .roots
+            .iter()
+            .find(|root| dir.starts_with(root))
+            .expect("walked directory outside of the roots");
+        let root_meta =
+            std::fs::metadata(root).unwrap_or_else(|_| panic!("stat for {:?} failed", root));
+        metadata.dev() != root_meta.dev()
+    }
+
+    /// returns the number of levels which the path is below the walked path which contains it,
+    /// e.g. 1 for its direct entries
+    fn depth(&self, path: &Path) -> usize {
+        self.roots
+            .iter()
+            .find_map(|root| path.strip_prefix(root).ok())
+            .map_or(0, |relpath| relpath.components().count())
+    }
+
+    /// with respect_gitignore: keeps the rules which apply to the entries of the directory, which
+    /// is listed next
+    #[cfg(feature = "regex")]
+    fn update_gitignores(&mut self, dir: &Path) {
+        let dirs = if self.roots.iter().any(|root| root == dir) {
+            self.gitignores.clear();
+            repository_dirs(dir)
+        } else {
+            // the directories which are not parents were walked completely
+            self.gitignores.retain(|g| dir.starts_with(g.dir()));
+            vec![dir.to_path_buf()]
+        };
+        for d in dirs {
+            for file in Gitignore::files(&d) {
+                let gitignore = Gitignore::read(&file, &d)
+                    .unwrap_or_else(|_| panic!("could not read file {:?}", file));
+                self.gitignores.push(gitignore);
+            }
+        }
+    }
+
+    /// returns the path below the walked path which contains it, e.g. "src/main.rs", for the
+    /// regular expressions which match paths
+    #[cfg(feature = "regex")]
+    fn path_below_root<'a>(&self, path: &'a Path) -> std::borrow::Cow<'a, str> {
+        self.roots
+            .iter()
+            .find_map(|root| path.strip_prefix(root).ok())
+            .unwrap_or(path)
+            .to_string_lossy()
+    }
+
+    /// returns why the entry is excluded, inherited is the index of the glob which excludes its
+    /// directory if it is walked anyway (see reincluded_below)
+    #[cfg_attr(not(feature = "regex"), allow(unused_variables))]
+    fn exclusion_reason(&self, path: &Path, inherited: Option<usize>) -> Option<ExclusionReason> {
+        let name = path.file_name().unwrap();
+        match (self.options.unsafe_names, unsafe_name_reason(name)) {
+            (UnsafeNamePolicy::Error, Some(reason)) => {
+                panic!("the name of {:?} {}", path, reason)
+            }
+            (UnsafeNamePolicy::Skip, Some(_)) => return Some(ExclusionReason::UnsafeName),
+            _ => {}
+        }
+        // names which are not valid UTF-8 are matched with replacement characters
+        let p = name.to_string_lossy();
+        if self.options.dot_files_excluded && p.starts_with('.') {
+            return Some(ExclusionReason::DotFile);
+        }
+        // now check if we match any "ignored_filenames regex"
+        #[cfg(feature = "regex")]
+        if let Some(regex) = self
+            .options
+            .ignored_filenames
+            .iter()
+            .find(|regex| regex.is_match(&p))
+        {
+            return Some(ExclusionReason::IgnoredName(regex.clone()));
+        }
+        #[cfg(feature = "regex")]
+        if !self.options.ignored_paths.is_empty() {
+            let relpath = self.path_below_root(path);
+            if let Some(regex) = self
+                .options
+                .ignored_paths
+                .iter()
+                .find(|regex| regex.is_match(&relpath))
+            {
+                return Some(ExclusionReason::IgnoredPath(regex.clone()));
+            }
+        }
+        #[cfg(feature = "regex")]
+        if self.options.respect_gitignore {
+            if name == ".git" {
+                return Some(ExclusionReason::GitDirectory);
+            }
+            if !self.gitignores.is_empty() {
+                let is_dir = path.is_dir();
+                let matched = self
+                    .gitignores
+                    .iter()
+                    .rev()
+                    .find_map(|g| g.matched(path, is_dir));
+                if let Some(glob) = matched.filter(|glob| !glob.is_negated()) {
+                    return Some(ExclusionReason::Gitignore(glob.clone()));
+                }
+            }
+        }
+        #[cfg(feature = "regex")]
+        if !self.options.excluded_link_targets.is_empty() {
+            // fails for everything which is not a symlink
+            if let Ok(target) = std::fs::read_link(path) {
+                let target = target.to_string_lossy();
+                if let Some(regex) = self
+                    .options
+                    .excluded_link_targets
+                    .iter()
+                    .find(|regex| regex.is_match(&target))
+                {
+                    return Some(ExclusionReason::LinkTarget(regex.clone()));
+                }
+            }
+        }
+        #[cfg(feature = "regex")]
+        if !self.options.included_paths.is_empty() {
+            let is_dir = std::fs::symlink_metadata(path).is_ok_and(|m| m.is_dir());
+            let relpath = self.path_below_root(path);
+            if !is_dir
+                && !self
+                    .options
+                    .included_paths
+                    .iter()
+                    .any(|regex| regex.is_match(&relpath))
+            {
+                return Some(ExclusionReason::NotIncluded);
+            }
+        }
+        if let Some(min) = self.options.min_depth {
+            let is_dir = std::fs::symlink_metadata(path).is_ok_and(|m| m.is_dir());
+            if !is_dir && self.depth(path) < min {
+                return Some(ExclusionReason::MinDepth(min));
+            }
+        }
+        if self.options.exclude_caches && !self.options.keep_cachedir_tag && is_cache_dir(path) {
+            return Some(ExclusionReason::CacheDirectory);
+        }
+        if self.options.dangling_symlinks == DanglingSymlinkPolicy::Skip
+            && path.is_symlink()
+            && path.canonicalize().is_err()
+        {
+            return Some(ExclusionReason::DanglingSymlink);
+        }
+        if self.options.special_files != SpecialFilePolicy::Error {
+            // symlinks are replaced by their target
+            match std::fs::metadata(path)
+                .ok()
+                .and_then(|m| SpecialFile::of(&m))
+            {
+                Some(SpecialFile::Socket) => return Some(ExclusionReason::SpecialFile),
+                Some(_) if self.options.special_files == SpecialFilePolicy::Skip => {
+                    return Some(ExclusionReason::SpecialFile)
+                }
+                _ => {}
+            }
+        }
+        if self.options.symlink_policy == SymlinkPolicy::SkipOutside && path.is_symlink() {
+            // symlinks which cannot be resolved are handled by the dangling symlink policy
+            if let Ok(resolved) = path.canonicalize() {
+                if !self.is_inside(&resolved) {
+                    return Some(ExclusionReason::SymlinkOutside);
+                }
+            }
+        }
+        if self.options.exclude_larger_than.is_some() || self.options.exclude_smaller_than.is_some()
+        {
+            // the size of the target for symlinks, which are replaced by it
+            if let Some(size) = std::fs::metadata(path)
+                .ok()
+                .filter(|m| m.is_file())
+                .map(|m| m.len())
+            {
+                match (
+                    self.options.exclude_larger_than,
+                    self.options.exclude_smaller_than,
+                ) {
+                    (Some(max), _) if size > max => return Some(ExclusionReason::LargerThan(max)),
+                    (_, Some(min)) if size < min => return Some(ExclusionReason::SmallerThan(min)),
+                    _ => {}
+                }
+            }
+        }
+        // the globs come last, because negated globs only include entries again which were
+        // excluded by an earlier glob
+        #[cfg(feature = "regex")]
+        if let Some(i) = self.deciding_glob(path, inherited) {
+            let glob = &self.options.excluded_globs[i];
+            if !glob.is_negated() {
+                return Some(ExclusionReason::Glob(glob.clone()));
+            }
+        }
+        None
+    }
+
+    /// returns the index of the last glob which matches the entry or, if it is given later,
+    /// the one which excludes its directory
+    #[cfg(feature = "regex")]
+    fn deciding_glob(&self, path: &Path, inherited: Option<usize>) -> Option<usize> {
+        let globs = &self.options.excluded_globs;
+        if globs.is_empty() {
+            return None;
+        }
+        let relpath = self.path_below_root(path);
+        // symlinks to directories are archived as directories
+        let is_dir = globs.iter().any(Glob::dir_only) && path.is_dir();
+        let matched = (0..globs.len())
+            .rev()
+            .find(|&i| globs[i].is_match(&relpath, is_dir));
+        matched.max(inherited)
+    }
+
+    /// returns true if a negated glob after the one with the index, which excludes the
+    /// directory, includes an entry below it again, so the directory has to be walked
+    #[cfg(feature = "regex")]
+    fn reincluded_below(&self, dir: &Path, index: usize) -> bool {
+        if !self.options.excluded_globs[index + 1..]
+            .iter()
+            .any(Glob::is_negated)
+            || !std::fs::symlink_metadata(dir).is_ok_and(|m| m.is_dir())
+        {
+            return false;
+        }
+        Self::list_dir(dir)
+            .iter()
+            .any(|entry| match self.exclusion_reason(entry, Some(index)) {
+                None => true,
+                Some(ExclusionReason::Glob(_)) => self
+                    .deciding_glob(entry, Some(index))
+                    .is_some_and(|i| self.reincluded_below(entry, i)),
+                Some(_) => false,
+            })
+    }
+
+    /// returns the entry of a fifo or device (or a symlink to it) for SpecialFilePolicy::Store,
+    /// panics for SpecialFilePolicy::Error. Skip excluded it before.
+    fn special_item(
+        &self,
+        relpath: PathBuf,
+        abspath: PathBuf,
+        metadata: &std::fs::Metadata,
+    ) -> DirWalkItem {
+        let special = SpecialFile::of(metadata).expect("neither symlink, file nor dir!");
+        if self.options.special_files == SpecialFilePolicy::Error {
+            panic!(
+                "{:?} is a fifo, socket or device, see --special-files",
+                &abspath
+            );
+        }
+        DirWalkItem {
+            relpath,
+            abspath,
+            typ: DirWalkType::Special(special),
+            size: None,
+            empty: false,
+            inode: None,
+        }
+    }
+}
+
+impl Iterator for DirWalkIterator {
+    type Item = DirWalkItem;
+    fn next(&mut self) -> Option<DirWalkItem> {
+        if self.options.paranoid {
+            self.check_relist();
+        }
+        if let Some((r, exclusion, inherited)) = self.remaining.pop() {
+            let abspath = r.clone();
+            let relpath = r
+                .strip_prefix(&self.basedir)
+                .expect("could not strip prefix")
+                .to_path_buf();
+            if let Some(reason) = exclusion {
+                return Some(DirWalkItem {
+                    relpath,
+                    abspath,
+                    typ: DirWalkType::Excluded(reason),
+                    size: None,
+                    empty: false,
+                    inode: None,
+                });
+            }
+            let sym_meta = std::fs::symlink_metadata(&r)
+                .unwrap_or_else(|_| panic!("stat for {:?} failed", &r));
+            //dbg!(&relpath, &abspath);
+            if sym_meta.is_symlink() {
+                if self.options.symlinks_should_abort {
+                    panic!("Found symlink at {:?}, aborting.", &abspath);
+                };
+                let resolved_path = match r.canonicalize() {
+                    Ok(resolved_path) => resolved_path,
+                    Err(_)
+                        if self.options.dangling_symlinks
+                            == DanglingSymlinkPolicy::StoreAsSymlink =>
+                    {
+                        let target = std::fs::read_link(&r)
+                            .unwrap_or_else(|_| panic!("could not read symlink {:?}", &r));
+                        return Some(DirWalkItem {
+                            relpath,
+                            abspath,
+                            typ: DirWalkType::DanglingSymlink(target),
+                            size: None,
+                            empty: false,
+                            inode: None,
+                        });
+                    }
+                    Err(e) => panic!(
+                        "error resolving symlink {:?}: {}, see --dangling-symlinks",
+                        &r, e
+                    ),
+                };
+                if self.options.symlink_policy == SymlinkPolicy::InsideOnly
+                    && !self.is_inside(&resolved_path)
+                {
+                    panic!(
+                        "symlink {:?} points to {:?}, which is outside of the input",
+                        &abspath, &resolved_path
+                    );
+                }
+                let resolved_meta = std::fs::symlink_metadata(&resolved_path)
+                    .unwrap_or_else(|_| panic!("stat for {:?} failed", &resolved_path));
+                if resolved_meta.is_dir() {
+                    return Some(DirWalkItem {
+                        relpath,
+                        abspath,
+                        typ: DirWalkType::SymlinkToDirectory,
+                        size: Some(resolved_meta.size()),
+                        empty: true,
+                        inode: None,
+                    });
+                } else if resolved_meta.is_file() {
+                    return Some(DirWalkItem {
+                        relpath,
+                        abspath,
+                        typ: DirWalkType::SymlinkToFile(resolved_path),
+                        size: Some(resolved_meta.size()),
+                        empty: false,
+                        inode: inode_of(&resolved_meta),
+                    });
+                } else {
+                    return Some(self.special_item(relpath, abspath, &resolved_meta));
+                }
+            }
+            if sym_meta.is_file() {
+                return Some(DirWalkItem {
+                    relpath,
+                    abspath,
+                    typ: DirWalkType::File,
+                    size: Some(sym_meta.size()),
+                    empty: false,
+                    inode: inode_of(&sym_meta),
+                });
+            }
+            if sym_meta.is_dir() {
+                // mount points and directories at max_depth are included without their content
+                let not_listed = (self.options.one_file_system
+                    && self.on_other_device(&r, &sym_meta))
+                    || self
+                        .options
+                        .max_depth
+                        .is_some_and(|max| self.depth(&r) >= max);
+                #[cfg(feature = "regex")]
+                if self.options.respect_gitignore && !not_listed {
+                    self.update_gitignores(&r);
+                }
+                let listed = if not_listed {
+                    Vec::new()
+                } else {
+                    Self::list_dir(&r)
+                };
+                if self.options.paranoid && !not_listed {
+                    self.relist
+                        .push((self.remaining.len(), r.clone(), listed.clone()));
+                }
+                // only the walked paths can be cache directories without keep_cachedir_tag, the
+                // others were excluded before
+                let is_cache = self.options.exclude_caches && is_cache_dir(&r);
+                let mut subs: Vec<(PathBuf, Option<ExclusionReason>, Option<usize>)> = listed
+                    .into_iter()
+                    .map(|d| {
+                        let reason = if is_cache
+                            && !(self.options.keep_cachedir_tag
+                                && d.file_name() == Some(OsStr::new("CACHEDIR.TAG")))
+                        {
+                            Some(ExclusionReason::CacheDirectory)
+                        } else {
+                            self.exclusion_reason(&d, inherited)
+                        };
+                        // a directory excluded by a glob is kept if a negated glob includes
+                        // something below it again
+                        #[cfg(feature = "regex")]
+                        if let Some(ExclusionReason::Glob(_)) = reason {
+                            let index = self.deciding_glob(&d, inherited);
+                            if let Some(index) = index.filter(|&i| self.reincluded_below(&d, i)) {
+                                return (d, None, Some(index));
+                            }
+                        }
+                        (d, reason, None)
+                    })
+                    .collect();
+                // sort in reverse alphabetically order, the first names at the end
+                let first_names = &self.options.first_names;
+                let position = |p: &Path| {
+                    let name = p.file_name().unwrap().to_str();
+                    first_names
+                        .iter()
+                        .position(|f| Some(f.as_str()) == name)
+                        .unwrap_or(first_names.len())
+                };
+                subs.sort_by(|a, b| (position(&b.0), &b.0).cmp(&(position(&a.0), &a.0)));
+                let included = subs
+                    .iter()
+                    .filter(|(_, reason, _)| reason.is_none())
+                    .count();
+                self.check_limits(&r, included);
+                // a directory whose content was not read is not known to be empty, so it is neither
+                // left out by empty_dirs_ignored nor reported as empty
+                let is_empty = included == 0 && !not_listed;
+                self.remaining.append(&mut subs);
+                // if the directory is empty and we shouldn't include empty directories, then we report it as excluded
+                if is_empty && self.options.empty_dirs_ignored {
+                    return Some(DirWalkItem {
+                        relpath,
+                        abspath,
+                        typ: DirWalkType::Excluded(ExclusionReason::EmptyDirectory),
+                        size: None,
+                        empty: false,
+                        inode: None,
+                    });
+                }
+                return Some(DirWalkItem {
+                    relpath,
+                    abspath,
+                    typ: DirWalkType::Directory,
+                    size: None,
+                    empty: is_empty,
+                    inode: None,
+                });
+            }
+            Some(self.special_item(relpath, abspath, &sym_meta))
+        } else {
+            // nothing left
+            None
+        }
+    }
+}
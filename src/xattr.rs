@@ -0,0 +1,44 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Returns the value of an extended attribute of a file, e.g. "security.capability" for the
+/// capabilities of a binary, following symlinks. It is None if the file doesn't have the
+/// attribute or the file system doesn't support extended attributes.
+pub fn get_xattr(path: &Path, name: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+    loop {
+        // the size first, the attribute might change in between
+        let size =
+            unsafe { libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return missing_or_error();
+        }
+        let mut value = vec![0u8; size as usize];
+        let read = unsafe {
+            libc::getxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        if read >= 0 {
+            value.truncate(read as usize);
+            return Ok(Some(value));
+        }
+        if std::io::Error::last_os_error().raw_os_error() != Some(libc::ERANGE) {
+            return missing_or_error();
+        }
+    }
+}
+
+/// returns None for the errors of a missing attribute, the last error otherwise
+fn missing_or_error() -> Result<Option<Vec<u8>>, std::io::Error> {
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+        _ => Err(e),
+    }
+}
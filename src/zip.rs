@@ -0,0 +1,733 @@
+#[cfg(feature = "sha512")]
+use crate::hash::{FileHasher, HashOptions};
+use flate2::write::DeflateEncoder;
+use flate2::Crc;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::archive::{ZipMethod, ZIP_DEFLATE_LEVEL};
+use crate::tar::TarStats;
+
+/// DOS time and date of all entries: 1980-01-01 00:00:00, the earliest representable time
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+/// version needed to extract (2.0 for deflate and directories, 4.5 for zip64 extensions), the
+/// version made by is the same with the Unix host system in the upper byte
+const VERSION_NEEDED: u16 = 20;
+const VERSION_NEEDED_ZIP64: u16 = 45;
+const HOST_UNIX: u16 = 3 << 8;
+
+/// general purpose flags: bit 3 means sizes and CRC follow the data, bit 11 means UTF-8 names
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+
+const METHOD_STORE: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+/// fields which don't fit into 32 (or 16) bits are set to the maximum and stored in the zip64
+/// extended information extra field or the zip64 end of central directory record instead
+const ZIP64_EXTRA: u16 = 0x0001;
+const ZIP32_MAX: u64 = u32::MAX as u64;
+/// files which are at most this much smaller than 4 GiB are deflated with zip64 sizes as well, so
+/// whether an entry has zip64 sizes doesn't depend on how well the file compresses
+const ZIP64_DEFLATE_MARGIN: u64 = 16 * 1024 * 1024;
+/// files up to this size are deflated only once, into memory, larger ones again while writing
+const DEFLATE_BUFFER_SIZE: u64 = 16 * 1024 * 1024;
+
+/// the central directory entry of an already written entry
+struct CentralEntry {
+    name: Vec<u8>,
+    flags: u16,
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    external_attributes: u32,
+    offset: u64,
+    /// the sizes are stored in zip64 extra fields and the data descriptor has 8 byte sizes
+    zip64_sizes: bool,
+}
+
+impl CentralEntry {
+    fn version_needed(&self) -> u16 {
+        if self.zip64_sizes || self.offset >= ZIP32_MAX {
+            VERSION_NEEDED_ZIP64
+        } else {
+            VERSION_NEEDED
+        }
+    }
+
+    /// returns the sizes for the 32 bit fields and the zip64 extra field, which contains the
+    /// sizes (both of them) if zip64_sizes is set and the offset if it doesn't fit into 32 bits
+    fn zip64_fields(&self, with_offset: bool) -> (u32, u32, Vec<u8>) {
+        let mut extra = Vec::new();
+        let (compressed_size, size) = if self.zip64_sizes {
+            extra.extend_from_slice(&self.size.to_le_bytes());
+            extra.extend_from_slice(&self.compressed_size.to_le_bytes());
+            (u32::MAX, u32::MAX)
+        } else {
+            (self.compressed_size as u32, self.size as u32)
+        };
+        if with_offset && self.offset >= ZIP32_MAX {
+            extra.extend_from_slice(&self.offset.to_le_bytes());
+        }
+        if !extra.is_empty() {
+            let mut field = Vec::with_capacity(4 + extra.len());
+            field.extend_from_slice(&ZIP64_EXTRA.to_le_bytes());
+            field.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            field.extend_from_slice(&extra);
+            extra = field;
+        }
+        (compressed_size, size, extra)
+    }
+}
+
+/// counts the bytes written to the inner writer
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// reads the file a second time into out and panics if it has changed since the first pass
+fn copy_again(
+    in_filedescriptor: &mut impl Read,
+    out: &mut impl Write,
+    crc: &Crc,
+    amount: u64,
+) -> Result<(), std::io::Error> {
+    let mut buffer = [0u8; 64 * 1024];
+    let mut crc_again = Crc::new();
+    let mut amount_again = 0u64;
+    loop {
+        let n = in_filedescriptor.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        crc_again.update(&buffer[..n]);
+        amount_again += n as u64;
+        out.write_all(&buffer[..n])?;
+    }
+    if crc_again.sum() != crc.sum() || amount_again != amount {
+        panic!("file changed while reading");
+    }
+    Ok(())
+}
+
+/// Writes deterministic zip archives.
+///
+/// All entries have the DOS timestamp 1980-01-01 00:00:00 and Unix permissions 0755 for
+/// directories and 0644 for files in the external attributes. The central directory is sorted by
+/// name. Files are read twice: the first pass computes the CRC and, for the deflate method, the
+/// deflated data (only kept in memory for files up to 16 MiB). Files which don't get smaller are
+/// stored, like zip does, and have the sizes in the local header. Deflated files are written
+/// with the deflated data of the first pass or deflated again and followed by a data descriptor.
+///
+/// Zip64 extensions are only used where they are needed, so the result only depends on the input:
+/// files of 4 GiB or more (deflated files already 16 MiB before) have their sizes in a zip64 extra
+/// field and an 8 byte data descriptor, entries starting after 4 GiB have their offset in a zip64
+/// extra field of the central directory and archives with 65535 or more entries or a central
+/// directory after 4 GiB get a zip64 end of central directory record and locator.
+///
+/// The statistics use the fields of [`TarStats`]: local headers and data descriptors are header
+/// bytes, the stored (possibly compressed) data is content and the central directory counts as
+/// end marker.
+pub struct ZipOutput<W: Write> {
+    out_zip: W,
+    method: ZipMethod,
+    central: Vec<CentralEntry>,
+    utf8_names: bool,
+    store_next: bool,
+    /// files of at least this size get zip64 sizes, only lowered by the tests
+    zip64_size: u64,
+    pub stats: TarStats,
+}
+
+impl<W: Write> ZipOutput<W> {
+    pub fn new(out_zip: W, method: ZipMethod) -> ZipOutput<W> {
+        ZipOutput {
+            out_zip,
+            method,
+            central: Vec::new(),
+            utf8_names: true,
+            store_next: false,
+            zip64_size: ZIP32_MAX,
+            stats: TarStats::default(),
+        }
+    }
+
+    /// sets whether the names are marked as UTF-8, otherwise they are in the legacy encoding
+    pub fn set_utf8_names(&mut self, utf8_names: bool) {
+        self.utf8_names = utf8_names;
+    }
+
+    /// stores the next file uncompressed, whatever the method of the archive is, e.g. because its
+    /// content is already compressed
+    pub fn zip_store_next(&mut self) {
+        self.store_next = true;
+    }
+
+    /// writes the local file header and remembers the entry for the central directory
+    fn _write_local_header(&mut self, entry: CentralEntry) -> Result<(), std::io::Error> {
+        let (compressed_size, size, extra) = entry.zip64_fields(false);
+        let mut header = Vec::with_capacity(30 + entry.name.len() + extra.len());
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&entry.version_needed().to_le_bytes());
+        header.extend_from_slice(&entry.flags.to_le_bytes());
+        header.extend_from_slice(&entry.method.to_le_bytes());
+        header.extend_from_slice(&DOS_TIME.to_le_bytes());
+        header.extend_from_slice(&DOS_DATE.to_le_bytes());
+        header.extend_from_slice(&entry.crc.to_le_bytes());
+        header.extend_from_slice(&compressed_size.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        header.extend_from_slice(&entry.name);
+        header.extend_from_slice(&extra);
+        self.stats.header_bytes += header.len() as u64;
+        self.out_zip.write_all(&header)?;
+        self.central.push(entry);
+        Ok(())
+    }
+
+    /// returns the entry for an item with the given name at the current position
+    fn _new_entry(
+        &self,
+        name: &[u8],
+        mode: u32,
+        zip64_sizes: bool,
+    ) -> Result<CentralEntry, std::io::Error> {
+        if name.len() > u16::MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("name {:?} is too long", String::from_utf8_lossy(name)),
+            ));
+        }
+        Ok(CentralEntry {
+            name: name.to_vec(),
+            flags: if self.utf8_names { FLAG_UTF8 } else { 0 },
+            method: METHOD_STORE,
+            crc: 0,
+            compressed_size: 0,
+            size: 0,
+            // Unix mode in the upper 16 bits, MS-DOS directory attribute in the lowest byte
+            external_attributes: (mode << 16) | if mode & 0o040000 != 0 { 0x10 } else { 0 },
+            offset: self.stats.total_bytes(),
+            zip64_sizes,
+        })
+    }
+
+    /// writes a directory, the name ends with a slash
+    pub fn zip_write_dir(&mut self, name: &[u8]) -> Result<(), std::io::Error> {
+        let entry = self._new_entry(name, 0o040755, false)?;
+        self._write_local_header(entry)?;
+        self.stats.directories += 1;
+        Ok(())
+    }
+
+    pub fn zip_write_file(
+        &mut self,
+        #[cfg(feature = "sha512")] mut out_hash: Option<(&mut impl Write, HashOptions)>,
+        in_filedescriptor: &mut (impl Read + Seek),
+        size: &u64,
+        name: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let method = if std::mem::take(&mut self.store_next) {
+            ZipMethod::Store
+        } else {
+            self.method
+        };
+        let mut entry = self._new_entry(name, 0o100644, false)?;
+        let mut buffer = [0u8; 64 * 1024];
+        let start = in_filedescriptor.stream_position()?;
+        // Crc::amount() is only 32 bits, so count the bytes separately
+        let mut crc = Crc::new();
+        let mut amount = 0u64;
+        #[cfg(feature = "sha512")]
+        let mut hasher = out_hash
+            .as_ref()
+            .map(|(_, options)| FileHasher::new(options.algorithm));
+        // the first pass computes the CRC and deflates the file to find out whether it gets
+        // smaller, small files keep the deflated data
+        let keep_deflated = *size <= DEFLATE_BUFFER_SIZE;
+        let mut encoder = (method == ZipMethod::Deflate).then(|| {
+            DeflateEncoder::new(
+                CountingWriter {
+                    inner: Vec::new(),
+                    count: 0,
+                },
+                flate2::Compression::new(ZIP_DEFLATE_LEVEL),
+            )
+        });
+        loop {
+            let n = in_filedescriptor.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            crc.update(&buffer[..n]);
+            amount += n as u64;
+            if let Some(encoder) = encoder.as_mut() {
+                encoder.write_all(&buffer[..n])?;
+                if !keep_deflated {
+                    encoder.get_mut().inner.clear();
+                }
+            }
+            #[cfg(feature = "sha512")]
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&buffer[..n]);
+            }
+        }
+        if amount != *size {
+            panic!("size while reading different from stat");
+        }
+        let deflated = match encoder {
+            Some(encoder) => Some(encoder.finish()?).filter(|deflated| deflated.count < amount),
+            None => None,
+        };
+        in_filedescriptor.seek(SeekFrom::Start(start))?;
+        let compressed_size = match deflated {
+            None => {
+                // stored, so the sizes and the CRC are in the local header
+                entry.crc = crc.sum();
+                entry.size = amount;
+                entry.compressed_size = amount;
+                entry.zip64_sizes = amount >= self.zip64_size;
+                self._write_local_header(entry)?;
+                copy_again(in_filedescriptor, &mut self.out_zip, &crc, amount)?;
+                amount
+            }
+            Some(deflated) => {
+                entry.flags |= FLAG_DATA_DESCRIPTOR;
+                entry.method = METHOD_DEFLATE;
+                entry.zip64_sizes = amount >= self.zip64_size.saturating_sub(ZIP64_DEFLATE_MARGIN);
+                let zip64_sizes = entry.zip64_sizes;
+                if !zip64_sizes && deflated.count >= ZIP32_MAX {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "compressed size of {:?} is {}, which is more than the zip64 margin",
+                            String::from_utf8_lossy(name),
+                            deflated.count
+                        ),
+                    ));
+                }
+                let central_index = self.central.len();
+                self._write_local_header(entry)?;
+                if keep_deflated {
+                    self.out_zip.write_all(&deflated.inner)?;
+                } else {
+                    let mut encoder = DeflateEncoder::new(
+                        CountingWriter {
+                            inner: &mut self.out_zip,
+                            count: 0,
+                        },
+                        flate2::Compression::new(ZIP_DEFLATE_LEVEL),
+                    );
+                    copy_again(in_filedescriptor, &mut encoder, &crc, amount)?;
+                    if encoder.finish()?.count != deflated.count {
+                        panic!("file changed while reading");
+                    }
+                }
+                let entry = &mut self.central[central_index];
+                entry.crc = crc.sum();
+                entry.size = amount;
+                entry.compressed_size = deflated.count;
+                let mut descriptor = Vec::with_capacity(24);
+                descriptor.extend_from_slice(&0x08074b50u32.to_le_bytes());
+                descriptor.extend_from_slice(&entry.crc.to_le_bytes());
+                if zip64_sizes {
+                    descriptor.extend_from_slice(&entry.compressed_size.to_le_bytes());
+                    descriptor.extend_from_slice(&entry.size.to_le_bytes());
+                } else {
+                    descriptor.extend_from_slice(&(entry.compressed_size as u32).to_le_bytes());
+                    descriptor.extend_from_slice(&(entry.size as u32).to_le_bytes());
+                }
+                self.out_zip.write_all(&descriptor)?;
+                self.stats.header_bytes += descriptor.len() as u64;
+                deflated.count
+            }
+        };
+        self.stats.files += 1;
+        self.stats.content_bytes += compressed_size;
+        #[cfg(feature = "sha512")]
+        if let (Some((out_hash, options)), Some(hasher)) = (out_hash.as_mut(), hasher) {
+            crate::hash::write_hash_line(out_hash, *options, hasher, name)?;
+        }
+        Ok(())
+    }
+
+    /// writes the central directory sorted by name and the end of central directory record,
+    /// preceded by the zip64 end of central directory record and locator if necessary
+    pub fn zip_end_marker(&mut self) -> Result<(), std::io::Error> {
+        let central_offset = self.stats.total_bytes();
+        self.central.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut directory = Vec::new();
+        for entry in &self.central {
+            let version_needed = entry.version_needed();
+            let (compressed_size, size, extra) = entry.zip64_fields(true);
+            directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+            directory.extend_from_slice(&(HOST_UNIX | version_needed).to_le_bytes());
+            directory.extend_from_slice(&version_needed.to_le_bytes());
+            directory.extend_from_slice(&entry.flags.to_le_bytes());
+            directory.extend_from_slice(&entry.method.to_le_bytes());
+            directory.extend_from_slice(&DOS_TIME.to_le_bytes());
+            directory.extend_from_slice(&DOS_DATE.to_le_bytes());
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&compressed_size.to_le_bytes());
+            directory.extend_from_slice(&size.to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&0u16.to_le_bytes()); // no comment
+            directory.extend_from_slice(&0u16.to_le_bytes()); // disk number
+            directory.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+            directory.extend_from_slice(&entry.external_attributes.to_le_bytes());
+            directory.extend_from_slice(&(entry.offset.min(ZIP32_MAX) as u32).to_le_bytes());
+            directory.extend_from_slice(&entry.name);
+            directory.extend_from_slice(&extra);
+        }
+        let central_size = directory.len() as u64;
+        let entries = self.central.len() as u64;
+        if entries >= u16::MAX as u64 || central_size >= ZIP32_MAX || central_offset >= ZIP32_MAX {
+            let zip64_end_offset = central_offset + central_size;
+            directory.extend_from_slice(&0x06064b50u32.to_le_bytes());
+            directory.extend_from_slice(&44u64.to_le_bytes()); // size of the remaining record
+            directory.extend_from_slice(&(HOST_UNIX | VERSION_NEEDED_ZIP64).to_le_bytes());
+            directory.extend_from_slice(&VERSION_NEEDED_ZIP64.to_le_bytes());
+            directory.extend_from_slice(&0u32.to_le_bytes()); // number of this disk
+            directory.extend_from_slice(&0u32.to_le_bytes()); // disk with the central directory
+            directory.extend_from_slice(&entries.to_le_bytes());
+            directory.extend_from_slice(&entries.to_le_bytes());
+            directory.extend_from_slice(&central_size.to_le_bytes());
+            directory.extend_from_slice(&central_offset.to_le_bytes());
+            directory.extend_from_slice(&0x07064b50u32.to_le_bytes());
+            directory.extend_from_slice(&0u32.to_le_bytes()); // disk with the zip64 record
+            directory.extend_from_slice(&zip64_end_offset.to_le_bytes());
+            directory.extend_from_slice(&1u32.to_le_bytes()); // total number of disks
+        }
+        let entries = entries.min(u16::MAX as u64) as u16;
+        directory.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes()); // number of this disk
+        directory.extend_from_slice(&0u16.to_le_bytes()); // disk with the central directory
+        directory.extend_from_slice(&entries.to_le_bytes());
+        directory.extend_from_slice(&entries.to_le_bytes());
+        directory.extend_from_slice(&(central_size.min(ZIP32_MAX) as u32).to_le_bytes());
+        directory.extend_from_slice(&(central_offset.min(ZIP32_MAX) as u32).to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes()); // no comment
+        self.out_zip.write_all(&directory)?;
+        self.stats.end_marker_bytes += directory.len() as u64;
+        Ok(())
+    }
+
+    /// returns the underlying writer, usually after the end marker was written
+    pub fn into_inner(self) -> W {
+        self.out_zip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn write_file(out: &mut ZipOutput<Vec<u8>>, content: &[u8], name: &[u8]) {
+        out.zip_write_file(
+            #[cfg(feature = "sha512")]
+            None::<(&mut Vec<u8>, HashOptions)>,
+            &mut Cursor::new(content),
+            &(content.len() as u64),
+            name,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn small_archive_has_no_zip64_records() {
+        let mut out = ZipOutput::new(Vec::new(), ZipMethod::Store);
+        write_file(&mut out, b"content", b"a");
+        out.zip_end_marker().unwrap();
+        let zip = out.into_inner();
+        // local header, content, central directory entry and end of central directory record
+        assert_eq!(zip.len(), 31 + 7 + 47 + 22);
+        assert_eq!(zip[4..6], VERSION_NEEDED.to_le_bytes());
+        assert!(!zip.windows(4).any(|w| w == 0x06064b50u32.to_le_bytes()));
+    }
+
+    #[test]
+    fn zip64_fields_are_written() {
+        let mut out = ZipOutput::new(Vec::new(), ZipMethod::Store);
+        out.zip64_size = 7;
+        write_file(&mut out, b"content", b"a");
+        out.zip64_size = ZIP32_MAX;
+        // the next entry and the central directory start after 4 GiB
+        out.stats.content_bytes += ZIP32_MAX;
+        out.zip_write_dir(b"b/").unwrap();
+        out.zip_end_marker().unwrap();
+        let zip = out.into_inner();
+        let crc = [0xa9, 0x30, 0xc5, 0xfe];
+        let local_a = [
+            &[
+                0x50, 0x4b, 0x03, 0x04, 45, 0, 0x00, 0x08, 0, 0, 0, 0, 0x21, 0,
+            ][..],
+            &crc,
+            &[0xff; 8], // both sizes are in the zip64 extra field
+            &[1, 0, 20, 0],
+            b"a",
+            &[0x01, 0x00, 16, 0],
+            &7u64.to_le_bytes(), // size
+            &7u64.to_le_bytes(), // compressed size
+            b"content",
+        ]
+        .concat();
+        // version 4.5 because of the offset, although it is only in the central directory
+        let local_b = [
+            &[
+                0x50, 0x4b, 0x03, 0x04, 45, 0, 0x00, 0x08, 0, 0, 0, 0, 0x21, 0,
+            ][..],
+            &[0; 12],
+            &[2, 0, 0, 0],
+            b"b/",
+        ]
+        .concat();
+        let central_a = [
+            &[
+                0x50, 0x4b, 0x01, 0x02, 45, 3, 45, 0, 0x00, 0x08, 0, 0, 0, 0, 0x21, 0,
+            ][..],
+            &crc,
+            &[0xff; 8],
+            &[1, 0, 20, 0, 0, 0, 0, 0, 0, 0],
+            &(0o100644u32 << 16).to_le_bytes(),
+            &[0; 4],
+            b"a",
+            &[0x01, 0x00, 16, 0],
+            &7u64.to_le_bytes(),
+            &7u64.to_le_bytes(),
+        ]
+        .concat();
+        let offset_b = (local_a.len() as u64) + ZIP32_MAX;
+        let central_b = [
+            &[
+                0x50, 0x4b, 0x01, 0x02, 45, 3, 45, 0, 0x00, 0x08, 0, 0, 0, 0, 0x21, 0,
+            ][..],
+            &[0; 12],
+            &[2, 0, 12, 0, 0, 0, 0, 0, 0, 0],
+            &((0o040755u32 << 16) | 0x10).to_le_bytes(),
+            &[0xff; 4], // the offset is in the zip64 extra field
+            b"b/",
+            &[0x01, 0x00, 8, 0],
+            &offset_b.to_le_bytes(),
+        ]
+        .concat();
+        let central_offset = (local_a.len() + local_b.len()) as u64 + ZIP32_MAX;
+        let central_size = (central_a.len() + central_b.len()) as u64;
+        let zip64_end = [
+            &[0x50, 0x4b, 0x06, 0x06][..],
+            &44u64.to_le_bytes(),
+            &[45, 3, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &2u64.to_le_bytes(),
+            &2u64.to_le_bytes(),
+            &central_size.to_le_bytes(),
+            &central_offset.to_le_bytes(),
+        ]
+        .concat();
+        let locator = [
+            &[0x50, 0x4b, 0x06, 0x07, 0, 0, 0, 0][..],
+            &(central_offset + central_size).to_le_bytes(),
+            &[1, 0, 0, 0],
+        ]
+        .concat();
+        let end = [
+            &[0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0, 2, 0, 2, 0][..],
+            &(central_size as u32).to_le_bytes(),
+            &[0xff; 4], // the offset is in the zip64 end of central directory record
+            &[0, 0],
+        ]
+        .concat();
+        let expected = [
+            local_a, local_b, central_a, central_b, zip64_end, locator, end,
+        ]
+        .concat();
+        assert_eq!(zip, expected);
+    }
+
+    #[test]
+    fn deflated_zip64_file_has_a_large_data_descriptor() {
+        let mut out = ZipOutput::new(Vec::new(), ZipMethod::Deflate);
+        out.zip64_size = ZIP64_DEFLATE_MARGIN + 100;
+        // compressible, otherwise it would be stored
+        let content = [b'c'; 100];
+        write_file(&mut out, &content, b"a");
+        out.zip_end_marker().unwrap();
+        let zip = out.into_inner();
+        // the sizes of the local header are only in the data descriptor
+        assert_eq!(zip[4..6], VERSION_NEEDED_ZIP64.to_le_bytes());
+        assert_eq!(zip[18..26], [0xff; 8]);
+        assert_eq!(zip[28..30], 20u16.to_le_bytes());
+        assert_eq!(zip[31..35], [0x01, 0x00, 16, 0]);
+        assert_eq!(zip[35..51], [0; 16]);
+        let compressed_size = compressed_size_of(&zip);
+        let descriptor = 51 + compressed_size as usize;
+        assert_eq!(
+            zip[descriptor..descriptor + 24],
+            [
+                &0x08074b50u32.to_le_bytes()[..],
+                &crc_of(&content).to_le_bytes(),
+                &compressed_size.to_le_bytes(),
+                &100u64.to_le_bytes(),
+            ]
+            .concat()[..]
+        );
+    }
+
+    fn crc_of(content: &[u8]) -> u32 {
+        let mut crc = Crc::new();
+        crc.update(content);
+        crc.sum()
+    }
+
+    /// the method field of the local header at offset, after checking the one of the central
+    /// directory, which is the first entry there
+    fn method_at(zip: &[u8], offset: usize) -> u16 {
+        let central = zip
+            .windows(4)
+            .position(|w| w == 0x02014b50u32.to_le_bytes())
+            .unwrap();
+        assert_eq!(
+            zip[central + 10..central + 12],
+            zip[offset + 8..offset + 10]
+        );
+        u16::from_le_bytes(zip[offset + 8..offset + 10].try_into().unwrap())
+    }
+
+    /// bytes of a linear congruential generator, which deflate can't compress
+    fn incompressible(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn files_which_do_not_get_smaller_are_stored() {
+        let incompressible = incompressible(1000);
+        for (content, method) in [
+            (&incompressible[..], METHOD_STORE),
+            (&b""[..], METHOD_STORE),
+            (&[b'c'; 100][..], METHOD_DEFLATE),
+        ] {
+            let mut out = ZipOutput::new(Vec::new(), ZipMethod::Deflate);
+            write_file(&mut out, content, b"a");
+            out.zip_end_marker().unwrap();
+            let zip = out.into_inner();
+            assert_eq!(method_at(&zip, 0), method);
+            if method == METHOD_STORE {
+                // no data descriptor, the sizes and the CRC are in the local header
+                assert_eq!(zip[6..8], FLAG_UTF8.to_le_bytes());
+                assert_eq!(zip[14..18], crc_of(content).to_le_bytes());
+                assert_eq!(zip[18..22], (content.len() as u32).to_le_bytes());
+                assert_eq!(zip[22..26], (content.len() as u32).to_le_bytes());
+                assert_eq!(zip[31..31 + content.len()], *content);
+                assert_eq!(zip.len(), 31 + content.len() + 47 + 22);
+            }
+        }
+    }
+
+    /// the compressed size of the only entry, from the zip64 extra field of the central directory
+    fn compressed_size_of(zip: &[u8]) -> u64 {
+        let central = zip
+            .windows(4)
+            .position(|w| w == 0x02014b50u32.to_le_bytes())
+            .unwrap();
+        let extra = central + 46 + 1 + 4;
+        u64::from_le_bytes(zip[extra + 8..extra + 16].try_into().unwrap())
+    }
+
+    /// returns the name, method, Unix mode and content of each entry of the central directory,
+    /// after checking the CRC of the content
+    fn read_back(zip: &[u8]) -> Vec<(Vec<u8>, u16, u32, Vec<u8>)> {
+        let u16_at = |at: usize| u16::from_le_bytes(zip[at..at + 2].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(zip[at..at + 4].try_into().unwrap());
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(end), 0x06054b50);
+        let mut central = u32_at(end + 16) as usize;
+        let mut entries = Vec::new();
+        for _ in 0..u16_at(end + 10) {
+            assert_eq!(u32_at(central), 0x02014b50);
+            let method = u16_at(central + 10);
+            let compressed_size = u32_at(central + 20) as usize;
+            let name_len = u16_at(central + 28) as usize;
+            let extra_len = u16_at(central + 30) as usize;
+            let name = zip[central + 46..central + 46 + name_len].to_vec();
+            let local = u32_at(central + 42) as usize;
+            assert_eq!(u32_at(local), 0x04034b50);
+            assert_eq!(zip[local + 30..local + 30 + name_len], name);
+            let data = local + 30 + name_len + u16_at(local + 28) as usize;
+            let data = &zip[data..data + compressed_size];
+            let content = match method {
+                METHOD_STORE => data.to_vec(),
+                METHOD_DEFLATE => {
+                    let mut content = Vec::new();
+                    flate2::read::DeflateDecoder::new(data)
+                        .read_to_end(&mut content)
+                        .unwrap();
+                    content
+                }
+                _ => panic!("unknown method {}", method),
+            };
+            assert_eq!(content.len(), u32_at(central + 24) as usize);
+            assert_eq!(crc_of(&content), u32_at(central + 16));
+            entries.push((name, method, u32_at(central + 38) >> 16, content));
+            central += 46 + name_len + extra_len;
+        }
+        assert_eq!(central, end);
+        entries
+    }
+
+    #[test]
+    fn archives_are_read_back() {
+        let compressible = [b'c'; 1000];
+        let incompressible = incompressible(1000);
+        for (method, compressed) in [
+            (ZipMethod::Store, METHOD_STORE),
+            (ZipMethod::Deflate, METHOD_DEFLATE),
+        ] {
+            let mut out = ZipOutput::new(Vec::new(), method);
+            out.zip_write_dir(b"d/").unwrap();
+            write_file(&mut out, &compressible, b"d/a");
+            write_file(&mut out, &incompressible, b"d/b");
+            write_file(&mut out, b"", b"d/e");
+            out.zip_write_dir(b"d/s/").unwrap();
+            out.zip_end_marker().unwrap();
+            assert_eq!(
+                read_back(&out.into_inner()),
+                [
+                    (b"d/".to_vec(), METHOD_STORE, 0o040755, vec![]),
+                    (b"d/a".to_vec(), compressed, 0o100644, compressible.to_vec()),
+                    (
+                        b"d/b".to_vec(),
+                        METHOD_STORE,
+                        0o100644,
+                        incompressible.clone()
+                    ),
+                    (b"d/e".to_vec(), METHOD_STORE, 0o100644, vec![]),
+                    (b"d/s/".to_vec(), METHOD_STORE, 0o040755, vec![]),
+                ],
+                "{:?}",
+                method
+            );
+        }
+    }
+}